@@ -0,0 +1,120 @@
+//! Routing hot path: `cargo bench --bench routing`
+//!
+//! Measures routing decisions over 10, 100 and 10k rules, and the overhead of relaying a
+//! plain-HTTP request over the direct path.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use proxy_twister::config::{Profile, Rule, Switch};
+use proxy_twister::{Config, ProxyServer};
+use std::collections::HashMap;
+use std::hint::black_box;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A config with `rules` rules mixing exact hosts, suffixes and inner wildcards
+fn config_with_rules(rules: usize) -> Config {
+    let mut switch = Switch::new("direct");
+    for i in 0..rules {
+        let pattern = match i % 4 {
+            0 => format!("host{i}.example.com"),
+            1 => format!("*.domain{i}.net"),
+            2 => format!("*.sub{i}.corp.example"),
+            _ => format!("api-{i}-*.cdn.example"),
+        };
+        switch = switch.rule(Rule::new(pattern, format!("profile{}", i % 8)));
+    }
+    Config::from_parts(switch, HashMap::new())
+}
+
+/// Hosts hitting each kind of rule, plus some matching none
+fn hosts(rules: usize) -> Vec<String> {
+    (0..100)
+        .map(|i| match i % 5 {
+            0 => format!("host{}.example.com", i % rules),
+            1 => format!("www.domain{}.net", i % rules),
+            2 => format!("a.b.sub{}.corp.example", i % rules),
+            3 => format!("api-{}-edge.cdn.example", i % rules),
+            _ => format!("unmatched{i}.org"),
+        })
+        .collect()
+}
+
+fn bench_route(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route");
+    for rules in [10, 100, 10_000] {
+        let config = config_with_rules(rules);
+        let hosts = hosts(rules);
+        // Build the rule index outside the measurement
+        proxy_twister::route(&config, "", "warmup.example.com", None);
+        group.throughput(Throughput::Elements(hosts.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(rules), &hosts, |b, hosts| {
+            b.iter(|| {
+                for host in hosts {
+                    black_box(proxy_twister::route(&config, "", black_box(host), None));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Origin answering every connection with a tiny response, then closing it
+async fn spawn_origin() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let mut head = Vec::new();
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    )
+                    .await;
+            });
+        }
+    });
+    port
+}
+
+fn bench_direct_request(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (proxy_addr, origin_port) = runtime.block_on(async {
+        let origin_port = spawn_origin().await;
+        let config = Config::from_parts(
+            Switch::new("direct"),
+            HashMap::from([("direct".to_string(), Profile::direct())]),
+        );
+        let server = ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .build()
+            .await
+            .unwrap();
+        let proxy_addr = server.local_addrs()[0];
+        tokio::spawn(async move { server.run().await });
+        (proxy_addr, origin_port)
+    });
+    let request = format!(
+        "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\nHost: 127.0.0.1:{origin_port}\r\nConnection: close\r\n\r\n"
+    );
+
+    c.bench_function("direct_request", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200"));
+        })
+    });
+}
+
+criterion_group!(benches, bench_route, bench_direct_request);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Rule lookup with 10k rules: `cargo bench --bench rule_matching`
+
+use proxy_twister::config::{Rule, Switch};
+use std::hint::black_box;
+use std::time::{Instant, SystemTime};
+
+const RULES: usize = 10_000;
+const LOOKUPS: usize = 100_000;
+
+fn main() {
+    let mut switch = Switch::new("direct");
+    for i in 0..RULES {
+        let pattern = match i % 4 {
+            0 => format!("host{i}.example.com"),
+            1 => format!("*.domain{i}.net"),
+            2 => format!("*.sub{i}.corp.example"),
+            _ => format!("api-{i}-*.cdn.example"),
+        };
+        switch = switch.rule(Rule::new(pattern, format!("profile{}", i % 8)));
+    }
+    let hosts: Vec<String> = (0..LOOKUPS)
+        .map(|i| match i % 5 {
+            0 => format!("host{}.example.com", i % RULES),
+            1 => format!("www.domain{}.net", i % RULES),
+            2 => format!("a.b.sub{}.corp.example", i % RULES),
+            3 => format!("api-{}-edge.cdn.example", i % RULES),
+            _ => format!("unmatched{i}.org"),
+        })
+        .collect();
+
+    let now = SystemTime::now();
+    let started = Instant::now();
+    black_box(switch.select("warmup.example.com", now));
+    println!("index built in {:?}", started.elapsed());
+
+    let started = Instant::now();
+    let mut matched = 0;
+    for host in &hosts {
+        if black_box(switch.select(host, now)).is_some() {
+            matched += 1;
+        }
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "{LOOKUPS} lookups over {RULES} rules in {elapsed:?} ({:?}/lookup, {matched} matched)",
+        elapsed / LOOKUPS as u32
+    );
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::config::{AccountingOptions, ResetPeriod};
+use crate::metrics::Metrics;
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Byte counters as saved to the accounting file
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct Snapshot {
+    /// Start of the accounting period the counters belong to (unix seconds, UTC)
+    period_start: u64,
+    profiles: BTreeMap<String, ProfileBytes>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+struct ProfileBytes {
+    up: u64,
+    down: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Day of the month (1-based) of a day count since 1970-01-01
+fn day_of_month(days: u64) -> u64 {
+    // Civil-from-days conversion (Howard Hinnant), shifted to years starting in March
+    let z = days + 719_468;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    doy - (153 * mp + 2) / 5 + 1
+}
+
+/// Start (unix seconds, UTC) of the accounting period containing `now`
+fn period_start(reset: ResetPeriod, now: u64) -> u64 {
+    let days = now / DAY_SECS;
+    let first_day = match reset {
+        ResetPeriod::Never => return 0,
+        ResetPeriod::Daily => days,
+        // 1970-01-01 was a Thursday; weeks start on Monday
+        ResetPeriod::Weekly => days - (days + 3) % 7,
+        ResetPeriod::Monthly => days + 1 - day_of_month(days),
+    };
+    first_day * DAY_SECS
+}
+
+fn snapshot(metrics: &Metrics, period_start: u64) -> Snapshot {
+    Snapshot {
+        period_start,
+        profiles: metrics
+            .byte_totals()
+            .into_iter()
+            .map(|(name, up, down)| (name, ProfileBytes { up, down }))
+            .collect(),
+    }
+}
+
+fn restore(metrics: &Metrics, snapshot: &Snapshot) {
+    for (name, bytes) in &snapshot.profiles {
+        let meter = metrics.byte_meter(name);
+        meter.add_up(bytes.up);
+        meter.add_down(bytes.down);
+    }
+}
+
+fn load(path: &Path) -> Result<Option<Snapshot>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => json5::from_str(&contents)
+            .map(Some)
+            .map_err(|e| format!("failed to parse '{}': {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("failed to read '{}': {e}", path.display())),
+    }
+}
+
+/// Write through a temporary file so a crash never leaves a truncated file behind
+fn save(path: &Path, snapshot: &Snapshot) -> Result<(), String> {
+    let contents = json5::to_string(snapshot).map_err(|e| e.to_string())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("failed to write '{}': {e}", path.display()))
+}
+
+/// Restore the byte meters from the accounting file, then save them periodically and on shutdown
+///
+/// Counters from a previous period are discarded, and the meters are zeroed whenever a new
+/// period starts.
+pub fn spawn_accounting(
+    options: AccountingOptions,
+    metrics: Arc<Metrics>,
+    shutdown_token: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut period = period_start(options.reset, unix_now());
+        match load(&options.file) {
+            Ok(Some(saved)) if saved.period_start == period => {
+                restore(&metrics, &saved);
+                info!("Restored byte counters from {}", options.file.display());
+            }
+            Ok(Some(_)) => info!("Saved byte counters belong to an earlier period, starting over"),
+            Ok(None) => {}
+            Err(e) => warn!("Cannot restore byte counters: {e}"),
+        }
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(options.persist_secs.max(1)));
+        ticker.tick().await;
+        loop {
+            let stopping = tokio::select! {
+                _ = shutdown_token.cancelled() => true,
+                _ = ticker.tick() => false,
+            };
+            let current = period_start(options.reset, unix_now());
+            if current != period {
+                info!("New accounting period, resetting byte counters");
+                metrics.reset_bytes();
+                period = current;
+            }
+            match save(&options.file, &snapshot(&metrics, period)) {
+                Ok(()) => debug!("Saved byte counters to {}", options.file.display()),
+                Err(e) => warn!("Cannot save byte counters: {e}"),
+            }
+            if stopping {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_start() {
+        // Friday 2026-10-16 13:20:00 UTC
+        let now = 20742 * DAY_SECS + 13 * 3600 + 20 * 60;
+        assert_eq!(period_start(ResetPeriod::Never, now), 0);
+        assert_eq!(period_start(ResetPeriod::Daily, now), 20742 * DAY_SECS);
+        assert_eq!(period_start(ResetPeriod::Weekly, now), 20738 * DAY_SECS);
+        assert_eq!(period_start(ResetPeriod::Monthly, now), 20727 * DAY_SECS);
+
+        // Leap day, a Thursday
+        let now = 19782 * DAY_SECS;
+        assert_eq!(period_start(ResetPeriod::Weekly, now), 19779 * DAY_SECS);
+        assert_eq!(period_start(ResetPeriod::Monthly, now), 19754 * DAY_SECS);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let metrics = Metrics::new();
+        metrics.byte_meter("metered").add_up(1200);
+        metrics.byte_meter("metered").add_down(34_000);
+        let path = std::env::temp_dir().join(format!(
+            "proxy-twister-accounting-{}.json",
+            std::process::id()
+        ));
+        save(&path, &snapshot(&metrics, 86_400)).unwrap();
+
+        let restored = Metrics::new();
+        let saved = load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.period_start, 86_400);
+        restore(&restored, &saved);
+        assert_eq!(restored.bytes("metered"), (1200, 34_000));
+
+        restored.reset_bytes();
+        assert_eq!(restored.bytes("metered"), (0, 0));
+    }
+}
@@ -0,0 +1,430 @@
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::config::{Config, ConfigError};
+use crate::listeners::ListenerSet;
+use crate::metrics::Metrics;
+use crate::resolver;
+use crate::route_override::{ForcedRoute, RouteOverride};
+
+/// Shared state the admin endpoint operates on
+#[derive(Clone)]
+pub struct AdminState {
+    /// File profiles are reloaded from; reloading is unavailable without one
+    pub config_path: Option<PathBuf>,
+    pub config: Arc<RwLock<Config>>,
+    pub metrics: Arc<Metrics>,
+    pub listeners: Arc<ListenerSet>,
+    pub route_override: Arc<RouteOverride>,
+}
+
+fn text_response(status: StatusCode, body: impl Into<String>) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Full::new(Bytes::from(body.into())))
+        .expect("Static response parts are valid")
+}
+
+/// Reload only the `profiles` section from disk, keeping rules and live connections intact
+async fn reload_profiles(state: &AdminState) -> Response<Full<Bytes>> {
+    let Some(config_path) = &state.config_path else {
+        return text_response(
+            StatusCode::CONFLICT,
+            "No configuration file to reload profiles from\n",
+        );
+    };
+    let new_config = match Config::load(&config_path.to_string_lossy()) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Failed to reload profiles: {}", e);
+            let status = match e {
+                ConfigError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            return text_response(status, format!("{e}\n"));
+        }
+    };
+
+    // Like a reload by the watcher, refused if an upstream proxy does not resolve
+    if let Err(e) = resolver::check_upstreams(&new_config).await {
+        error!("Refusing to reload profiles: {}", e);
+        return text_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("unresolvable upstream proxies: {e}\n"),
+        );
+    }
+
+    let mut guard = state.config.write().await;
+    match guard.replace_profiles(new_config.profiles) {
+        Ok(()) => {
+            info!("Profiles reloaded ({} profiles)", guard.profiles.len());
+            text_response(StatusCode::OK, "Profiles reloaded\n")
+        }
+        Err(e) => {
+            error!("Refusing to reload profiles: {}", e);
+            text_response(StatusCode::CONFLICT, format!("{e}\n"))
+        }
+    }
+}
+
+/// Whether the proxy can take traffic: a listener is bound and, when `readiness` names
+/// profiles to probe, at least one of their upstream proxies accepts a TCP connection
+async fn readiness(state: &AdminState) -> Response<Full<Bytes>> {
+    if state.listeners.addresses().is_empty() {
+        return text_response(StatusCode::SERVICE_UNAVAILABLE, "No listener is bound\n");
+    }
+    let (endpoints, timeout) = {
+        let config = state.config.read().await;
+        let Some(options) = &config.readiness else {
+            return text_response(StatusCode::OK, "Ready\n");
+        };
+        let endpoints: Vec<_> = options
+            .probe_profiles
+            .iter()
+            .filter_map(|name| config.profiles.get(name))
+            .flat_map(|profile| profile.proxy_endpoints())
+            .collect();
+        (endpoints, Duration::from_millis(options.probe_timeout_ms))
+    };
+    if endpoints.is_empty() {
+        return text_response(StatusCode::OK, "Ready\n");
+    }
+
+    let mut probes = tokio::task::JoinSet::new();
+    for (host, port) in endpoints {
+        probes.spawn(async move {
+            let connected =
+                tokio::time::timeout(timeout, TcpStream::connect((host.as_str(), port)))
+                    .await
+                    .is_ok_and(|result| result.is_ok());
+            if !connected {
+                debug!("Readiness probe of {host}:{port} failed");
+            }
+            connected
+        });
+    }
+    while let Some(probe) = probes.join_next().await {
+        if probe.unwrap_or(false) {
+            return text_response(StatusCode::OK, "Ready\n");
+        }
+    }
+    text_response(StatusCode::SERVICE_UNAVAILABLE, "No upstream is healthy\n")
+}
+
+/// The running listeners, one `configured address -> bound address` per line
+fn list_listeners(state: &AdminState) -> Response<Full<Bytes>> {
+    let body: String = state
+        .listeners
+        .addresses()
+        .into_iter()
+        .map(|(addr, local_addr)| format!("{addr} -> {local_addr}\n"))
+        .collect();
+    text_response(StatusCode::OK, body)
+}
+
+/// Start or stop the listener on the address given as the request body
+async fn change_listener(
+    req: Request<Incoming>,
+    state: &AdminState,
+    add: bool,
+) -> Response<Full<Bytes>> {
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("{e}\n")),
+    };
+    let addr = String::from_utf8_lossy(&body).trim().to_string();
+    if addr.is_empty() {
+        return text_response(StatusCode::BAD_REQUEST, "Missing listen address\n");
+    }
+    if !add {
+        if state.listeners.remove(&addr).await {
+            return text_response(StatusCode::OK, format!("Stopped listening on {addr}\n"));
+        }
+        return text_response(StatusCode::NOT_FOUND, format!("Not listening on {addr}\n"));
+    }
+    match state.listeners.add(&addr).await {
+        Ok(local_addr) => text_response(StatusCode::OK, format!("Listening on {local_addr}\n")),
+        Err(e) => {
+            error!("Cannot add listener: {}", e);
+            text_response(StatusCode::CONFLICT, format!("{e}\n"))
+        }
+    }
+}
+
+/// The route forced on all traffic: `direct`, `emergency (<profile>)` or `off`
+async fn show_override(state: &AdminState) -> Response<Full<Bytes>> {
+    let body = match state.route_override.get() {
+        None => "off".to_string(),
+        Some(ForcedRoute::Direct) => "direct".to_string(),
+        Some(ForcedRoute::Emergency) => {
+            let config = state.config.read().await;
+            let profile = config.emergency_profile.as_deref().unwrap_or_default();
+            format!("emergency ({profile})")
+        }
+    };
+    text_response(StatusCode::OK, format!("{body}\n"))
+}
+
+/// Force all traffic `direct` or through the `emergency` profile, or turn the override `off`,
+/// as given by the request body
+async fn change_override(req: Request<Incoming>, state: &AdminState) -> Response<Full<Bytes>> {
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("{e}\n")),
+    };
+    let route = match String::from_utf8_lossy(&body).trim() {
+        "off" => None,
+        name => match ForcedRoute::parse(name) {
+            Some(route) => Some(route),
+            None => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    "Expected direct, emergency or off\n",
+                );
+            }
+        },
+    };
+    if route == Some(ForcedRoute::Emergency)
+        && state.config.read().await.emergency_profile.is_none()
+    {
+        return text_response(StatusCode::CONFLICT, "No emergencyProfile is configured\n");
+    }
+    state.route_override.set(route);
+    show_override(state).await
+}
+
+/// Set the log level to the directives given as the request body, e.g. `debug`
+async fn change_log_level(req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, format!("{e}\n")),
+    };
+    let directives = String::from_utf8_lossy(&body);
+    match crate::telemetry::set_log_level(&directives) {
+        Ok(()) => {
+            info!("Log level set to {}", directives.trim());
+            show_log_level()
+        }
+        Err(e) if crate::telemetry::log_level().is_none() => {
+            text_response(StatusCode::CONFLICT, format!("{e}\n"))
+        }
+        Err(e) => text_response(StatusCode::BAD_REQUEST, format!("{e}\n")),
+    }
+}
+
+/// The directives filtering the log lines
+fn show_log_level() -> Response<Full<Bytes>> {
+    match crate::telemetry::log_level() {
+        Some(level) => text_response(StatusCode::OK, format!("{level}\n")),
+        None => text_response(
+            StatusCode::CONFLICT,
+            "The log subscriber was not installed by proxy-twister\n",
+        ),
+    }
+}
+
+async fn handle_request(
+    req: Request<Incoming>,
+    state: AdminState,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    debug!("Admin request: {} {}", req.method(), req.uri().path());
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::POST, "/reload/profiles") => reload_profiles(&state).await,
+        (&Method::GET, "/healthz") => text_response(StatusCode::OK, "OK\n"),
+        (&Method::GET, "/readyz") => readiness(&state).await,
+        (&Method::GET, "/metrics") => text_response(StatusCode::OK, state.metrics.render()),
+        (&Method::GET, "/listeners") => list_listeners(&state),
+        (&Method::POST, "/listeners/add") => change_listener(req, &state, true).await,
+        (&Method::POST, "/listeners/remove") => change_listener(req, &state, false).await,
+        (&Method::GET, "/override") => show_override(&state).await,
+        (&Method::POST, "/override") => change_override(req, &state).await,
+        (&Method::GET, "/loglevel") => show_log_level(),
+        (&Method::POST, "/loglevel") => change_log_level(req).await,
+        _ => text_response(StatusCode::NOT_FOUND, "Not found\n"),
+    };
+    Ok(response)
+}
+
+/// Socket the admin endpoint accepts connections on
+enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl AdminListener {
+    /// Bind `addr`: `unix:/path/to/socket` for a Unix socket, `host:port` otherwise
+    ///
+    /// Unix sockets are only accessible to the owner, keeping the endpoint off the network and
+    /// away from other local users. They are bound in a private directory next to `path` and
+    /// linked into place once restricted, so there is no window in which others can connect.
+    /// Only a stale socket nobody listens on is replaced at `path`; a live socket or any other
+    /// file there is an error.
+    async fn bind(addr: &str) -> std::io::Result<Self> {
+        let Some(path) = addr.strip_prefix("unix:") else {
+            return Ok(AdminListener::Tcp(TcpListener::bind(addr).await?));
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+            // A socket left behind by a previous run is removed, anything else is left alone
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if !metadata.file_type().is_socket() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{path} exists and is not a socket"),
+                    ));
+                }
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AddrInUse,
+                        format!("{path} is served by another process"),
+                    ));
+                }
+                std::fs::remove_file(path)?;
+            }
+            // Bind inside a directory only the owner can enter, so the socket is never reachable
+            // by others before its mode is narrowed, then move it into place
+            let path = PathBuf::from(path);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let private = path.with_file_name(format!(".{name}.{}", std::process::id()));
+            std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+            let staged = private.join("admin.sock");
+            let bound = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+                std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+                // Unlike a rename, linking fails rather than replace whatever appeared at `path`
+                std::fs::hard_link(&staged, &path)?;
+                Ok(listener)
+            });
+            let _ = std::fs::remove_file(&staged);
+            let _ = std::fs::remove_dir(&private);
+            Ok(AdminListener::Unix(bound?, path))
+        }
+        #[cfg(not(unix))]
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unix sockets are not supported on this platform: {path}"),
+        ))
+    }
+
+    /// Accept the next connection and serve it in the background
+    async fn accept(&self, state: &AdminState) -> std::io::Result<()> {
+        match self {
+            AdminListener::Tcp(listener) => serve_connection(listener.accept().await?.0, state),
+            #[cfg(unix)]
+            AdminListener::Unix(listener, _) => serve_connection(listener.accept().await?.0, state),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AdminListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let AdminListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn serve_connection<S>(stream: S, state: &AdminState)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let state = state.clone();
+    tokio::spawn(async move {
+        let service = service_fn(move |req| handle_request(req, state.clone()));
+        if let Err(e) = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+        {
+            debug!("Admin connection error: {:?}", e);
+        }
+    });
+}
+
+/// Serve the admin HTTP endpoint until the shutdown token is cancelled
+pub async fn run_admin(addr: String, state: AdminState, shutdown_token: CancellationToken) {
+    let listener = match AdminListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to bind admin endpoint to {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Admin endpoint listening on {}", addr);
+    loop {
+        tokio::select! {
+            _ = shutdown_token.cancelled() => {
+                info!("Admin endpoint on {} received shutdown signal", addr);
+                break;
+            }
+            accept_result = listener.accept(&state) => {
+                if let Err(e) = accept_result {
+                    error!("Accept error on admin endpoint {}: {:?}", addr, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unix_bind_leaves_live_socket_alone() {
+        let path = std::env::temp_dir().join(format!(
+            "proxy-twister-admin-live-{}.sock",
+            std::process::id()
+        ));
+        let addr = format!("unix:{}", path.display());
+        let running = AdminListener::bind(&addr).await.unwrap();
+
+        let second = AdminListener::bind(&addr).await;
+        assert_eq!(
+            second.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AddrInUse)
+        );
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+
+        // Once nobody listens on it, the socket is stale and replaced
+        drop(running);
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let replaced = AdminListener::bind(&addr).await.unwrap();
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+        drop(replaced);
+    }
+
+    #[tokio::test]
+    async fn test_unix_bind_keeps_existing_file() {
+        let path = std::env::temp_dir().join(format!(
+            "proxy-twister-admin-file-{}.sock",
+            std::process::id()
+        ));
+        std::fs::write(&path, "not a socket").unwrap();
+
+        let bound = AdminListener::bind(&format!("unix:{}", path.display())).await;
+        assert_eq!(
+            bound.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::config::BreakerOptions;
+
+/// How long a profile whose last connect failed counts as unhealthy
+const UNHEALTHY_FOR: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+struct ProfileState {
+    consecutive_failures: u32,
+    /// Last failure, or the last trial connection let through while half-open
+    since: Option<Instant>,
+}
+
+impl ProfileState {
+    /// Whether connections are kept away: enough consecutive failures, the last one (or the
+    /// last trial) within the cooldown
+    fn is_open(&self, options: &BreakerOptions, now: Instant) -> bool {
+        let cooldown = Duration::from_secs(options.cooldown_secs);
+        self.consecutive_failures >= options.failures
+            && self
+                .since
+                .is_some_and(|since| now.saturating_duration_since(since) < cooldown)
+    }
+}
+
+/// Per-profile circuit breakers fed by connect outcomes
+///
+/// A profile whose last `failures` connects all failed is open: it is skipped for
+/// `cooldown_secs`, then half-open, letting a single trial connection through. A successful
+/// connect closes the breaker again, a failed one keeps it open for another cooldown.
+///
+/// The same outcomes serve as the health registry used by the `first-healthy` match strategy.
+#[derive(Debug, Default)]
+pub struct Breaker {
+    profiles: Mutex<HashMap<String, ProfileState>>,
+}
+
+impl Breaker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record whether establishing the upstream connection for `profile` succeeded
+    pub fn record(&self, profile: &str, success: bool) {
+        self.record_at(profile, success, Instant::now());
+    }
+
+    /// Like `record`, with the outcome observed at `now`
+    pub fn record_at(&self, profile: &str, success: bool, now: Instant) {
+        let mut profiles = self.profiles.lock().unwrap();
+        if success {
+            if let Some(state) = profiles.remove(profile)
+                && state.consecutive_failures > 0
+            {
+                debug!("Breaker for profile {profile} closed");
+            }
+            return;
+        }
+        let state = profiles.entry(profile.to_string()).or_default();
+        state.consecutive_failures += 1;
+        state.since = Some(now);
+    }
+
+    /// Whether the last connect through `profile` succeeded, or its failure is old enough to
+    /// be worth another attempt
+    pub fn is_healthy(&self, profile: &str) -> bool {
+        self.is_healthy_at(profile, Instant::now())
+    }
+
+    /// Like `is_healthy`, evaluated at `now`
+    pub fn is_healthy_at(&self, profile: &str, now: Instant) -> bool {
+        let profiles = self.profiles.lock().unwrap();
+        profiles.get(profile).is_none_or(|state| {
+            state
+                .since
+                .is_none_or(|since| now.saturating_duration_since(since) >= UNHEALTHY_FOR)
+        })
+    }
+
+    /// Whether new connections may be routed through `profile`: its breaker is closed, or
+    /// half-open with the trial connection still available
+    pub fn allows(&self, profile: &str, options: &BreakerOptions) -> bool {
+        self.allows_at(profile, options, Instant::now())
+    }
+
+    /// Like `allows`, evaluated at `now`
+    pub fn allows_at(&self, profile: &str, options: &BreakerOptions, now: Instant) -> bool {
+        let profiles = self.profiles.lock().unwrap();
+        profiles
+            .get(profile)
+            .is_none_or(|state| !state.is_open(options, now))
+    }
+
+    /// Commit to routing a connection through `profile`, taking the trial connection when its
+    /// breaker is half-open
+    ///
+    /// Returns whether the connection may go ahead: `false` when the breaker is open, including
+    /// when another connection took the trial since `allows` was checked. Once the trial is
+    /// taken, the profile stays skipped for everyone else until its outcome is recorded (or
+    /// another cooldown ends).
+    pub fn admit(&self, profile: &str, options: &BreakerOptions) -> bool {
+        self.admit_at(profile, options, Instant::now())
+    }
+
+    /// Like `admit`, at `now`
+    pub fn admit_at(&self, profile: &str, options: &BreakerOptions, now: Instant) -> bool {
+        let mut profiles = self.profiles.lock().unwrap();
+        let Some(state) = profiles.get_mut(profile) else {
+            return true;
+        };
+        if state.consecutive_failures < options.failures {
+            return true;
+        }
+        if state.is_open(options, now) {
+            return false;
+        }
+        info!("Breaker for profile {profile} half-open, letting a trial connection through");
+        state.since = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_trips_and_recovers() {
+        let options = BreakerOptions {
+            failures: 3,
+            cooldown_secs: 30,
+        };
+        let breaker = Breaker::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Failures below the threshold, or interrupted by a success, keep it closed
+        breaker.record_at("tor", false, at(0));
+        breaker.record_at("tor", false, at(0));
+        breaker.record_at("tor", true, at(0));
+        breaker.record_at("tor", false, at(1));
+        breaker.record_at("tor", false, at(1));
+        assert!(breaker.admit_at("tor", &options, at(1)));
+
+        breaker.record_at("tor", false, at(2));
+        assert!(!breaker.admit_at("tor", &options, at(2)));
+        assert!(!breaker.admit_at("tor", &options, at(31)));
+        assert!(breaker.admit_at("other", &options, at(2)));
+
+        // Half-open: checking does not take the trial, admitting does
+        assert!(breaker.allows_at("tor", &options, at(32)));
+        assert!(breaker.allows_at("tor", &options, at(32)));
+        assert!(breaker.admit_at("tor", &options, at(32)));
+        assert!(!breaker.allows_at("tor", &options, at(32)));
+        // The trial fails and reopens it
+        assert!(!breaker.admit_at("tor", &options, at(33)));
+        breaker.record_at("tor", false, at(34));
+        assert!(!breaker.admit_at("tor", &options, at(63)));
+
+        // Next trial succeeds and closes it
+        assert!(breaker.admit_at("tor", &options, at(64)));
+        breaker.record_at("tor", true, at(65));
+        assert!(breaker.admit_at("tor", &options, at(65)));
+        breaker.record_at("tor", false, at(66));
+        assert!(breaker.admit_at("tor", &options, at(66)));
+    }
+
+    #[test]
+    fn test_failed_profile_is_unhealthy_for_a_while() {
+        let breaker = Breaker::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(breaker.is_healthy_at("tor", at(0)));
+        breaker.record_at("tor", false, at(0));
+        assert!(!breaker.is_healthy_at("tor", at(29)));
+        assert!(breaker.is_healthy_at("tor", at(30)));
+
+        breaker.record_at("tor", false, at(40));
+        breaker.record_at("tor", true, at(41));
+        assert!(breaker.is_healthy_at("tor", at(41)));
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Why a configuration could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file is not valid JSON5 or does not fit the configuration schema
+    Parse {
+        path: PathBuf,
+        /// 1-based position of the problem, when the parser reports one
+        line: Option<usize>,
+        col: Option<usize>,
+        message: String,
+    },
+    /// The file parsed, but describes a configuration that cannot be used
+    Validation(Vec<String>),
+}
+
+impl ConfigError {
+    pub(crate) fn parse(path: &str, error: json5::Error) -> Self {
+        let json5::Error::Message { msg, location } = error;
+        ConfigError::Parse {
+            path: PathBuf::from(path),
+            line: location.as_ref().map(|l| l.line),
+            col: location.as_ref().map(|l| l.column),
+            message: msg,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "failed to read '{}': {source}", path.display())
+            }
+            ConfigError::Parse {
+                path,
+                line: Some(line),
+                col: Some(col),
+                message,
+            } => write!(
+                f,
+                "failed to parse '{}' at {line}:{col}: {message}",
+                path.display()
+            ),
+            ConfigError::Parse { path, message, .. } => {
+                write!(f, "failed to parse '{}': {message}", path.display())
+            }
+            ConfigError::Validation(errors) => {
+                write!(f, "invalid configuration: {}", errors.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::Rule;
+
+/// Index over the switch rules that keeps first-match semantics without scanning every rule
+///
+/// Exact hosts and `*.domain` suffixes are looked up in hash maps; everything else (inner
+/// wildcards, `$ip`-style tokens, scheduled rules) is checked in order, but only for rules that
+/// come before the best indexed match.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RuleMatcher {
+    /// Exact host -> index of the first rule naming it
+    exact: HashMap<String, usize>,
+    /// Domain of a `*.domain` pattern -> index of the first rule naming it
+    suffixes: HashMap<String, usize>,
+    /// Rules needing the general matcher, in config order; `None` for `$ip`-style tokens
+    linear: Vec<(usize, Option<Regex>)>,
+}
+
+impl RuleMatcher {
+    pub(crate) fn new(rules: &[Rule]) -> Self {
+        let mut matcher = Self::default();
+        for (index, rule) in rules.iter().enumerate() {
+            let pattern = rule.pattern.as_str();
+            if rule.schedule.is_none() && !pattern.starts_with('$') {
+                if let Some(domain) = pattern.strip_prefix("*.")
+                    && !domain.contains('*')
+                {
+                    matcher.suffixes.entry(domain.to_string()).or_insert(index);
+                    continue;
+                }
+                if !pattern.contains('*') {
+                    matcher.exact.entry(pattern.to_string()).or_insert(index);
+                    continue;
+                }
+            }
+            let regex = if crate::utils::HOST_TOKENS.contains(&pattern) {
+                None
+            } else {
+                Some(crate::utils::wildcard_to_regex(pattern))
+            };
+            matcher.linear.push((index, regex));
+        }
+        matcher
+    }
+
+    /// Index of the first rule in `rules` matching `host` at `now`
+    pub(crate) fn find(&self, rules: &[Rule], host: &str, now: SystemTime) -> Option<usize> {
+        let mut best = self.exact.get(host).copied();
+        // "*.example.com" matches "example.com" and anything below it
+        let mut suffix = Some(host);
+        while let Some(domain) = suffix {
+            if let Some(&index) = self.suffixes.get(domain) {
+                best = Some(best.map_or(index, |best| best.min(index)));
+            }
+            suffix = domain.split_once('.').map(|(_, parent)| parent);
+        }
+
+        self.linear
+            .iter()
+            .take_while(|(index, _)| best.is_none_or(|best| *index < best))
+            .find(|(index, regex)| {
+                let Some(rule) = rules.get(*index) else {
+                    return false;
+                };
+                rule.schedule.as_ref().is_none_or(|s| s.contains(now))
+                    && match regex {
+                        Some(regex) => regex.is_match(host),
+                        None => crate::utils::matches_pattern(host, &rule.pattern),
+                    }
+            })
+            .map(|(index, _)| *index)
+            .or(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::matches_pattern;
+
+    #[test]
+    fn test_matches_linear_scan() {
+        let patterns = [
+            "api.example.com",
+            "*.internal.example.com",
+            "*.example.com",
+            "test.*.example.com",
+            "$ip",
+            "$private",
+            "example.com",
+            "*.org",
+            "*",
+            "*.example.com",
+        ];
+        let rules: Vec<Rule> = patterns
+            .iter()
+            .enumerate()
+            .map(|(i, pattern)| Rule::new(*pattern, format!("p{i}")))
+            .collect();
+        let hosts = [
+            "api.example.com",
+            "db.internal.example.com",
+            "internal.example.com",
+            "test.a.example.com",
+            "example.com",
+            "fooexample.com",
+            "192.0.2.1",
+            "10.0.0.1",
+            "wiki.example.org",
+            "localhost",
+            "",
+        ];
+
+        // Every suffix of the rule list, so each rule gets a turn at being first
+        for start in 0..rules.len() {
+            let rules = &rules[start..];
+            let matcher = RuleMatcher::new(rules);
+            for host in hosts {
+                let expected = rules
+                    .iter()
+                    .position(|rule| matches_pattern(host, &rule.pattern));
+                let found = matcher.find(rules, host, SystemTime::now());
+                assert_eq!(found, expected, "host {host:?} with rules from {start}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_scheduled_rule_keeps_its_place() {
+        let schedule = json5::from_str(r#"{ from: "09:00", to: "17:00" }"#).unwrap();
+        let rules = vec![
+            Rule::new("*.example.com", "office").with_schedule(schedule),
+            Rule::new("*.example.com", "always"),
+        ];
+        let matcher = RuleMatcher::new(&rules);
+        let hour = std::time::Duration::from_secs(3600);
+        let at = |h| SystemTime::UNIX_EPOCH + h * hour;
+        assert_eq!(matcher.find(&rules, "a.example.com", at(10)), Some(0));
+        assert_eq!(matcher.find(&rules, "a.example.com", at(20)), Some(1));
+    }
+}
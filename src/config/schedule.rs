@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Day of the week, as written in the config (`"mon"` .. `"sun"`)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+}
+
+/// Minutes since midnight, written as `"HH:MM"`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u32);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time of day '{value}', expected HH:MM");
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(hours * 60 + minutes))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", time.0 / 60, time.0 % 60)
+    }
+}
+
+/// Fixed offset from UTC in minutes, written as `"+HH:MM"` or `"-HH:MM"`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub struct UtcOffset(i64);
+
+impl TryFrom<String> for UtcOffset {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid UTC offset '{value}', expected +HH:MM or -HH:MM");
+        let (sign, rest) = match value.split_at_checked(1) {
+            Some(("+", rest)) => (1, rest),
+            Some(("-", rest)) => (-1, rest),
+            _ => return Err(invalid()),
+        };
+        let TimeOfDay(minutes) = TimeOfDay::try_from(rest.to_string()).map_err(|_| invalid())?;
+        if minutes > 14 * 60 {
+            return Err(invalid());
+        }
+        Ok(UtcOffset(sign * minutes as i64))
+    }
+}
+
+impl From<UtcOffset> for String {
+    fn from(offset: UtcOffset) -> Self {
+        let sign = if offset.0 < 0 { '-' } else { '+' };
+        let minutes = offset.0.unsigned_abs();
+        format!("{sign}{:02}:{:02}", minutes / 60, minutes % 60)
+    }
+}
+
+/// Time window during which a rule applies
+///
+/// Windows with `from` after `to` wrap around midnight. Days refer to the local day at the
+/// moment of the check.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+    /// Days the window is active on; every day when empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Offset of the local time the window is expressed in (no DST handling)
+    #[serde(default)]
+    pub utc_offset: UtcOffset,
+}
+
+impl Schedule {
+    /// Whether `now` falls inside the window
+    pub fn contains(&self, now: SystemTime) -> bool {
+        let utc_secs = match now.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let local_minutes = utc_secs.div_euclid(60) + self.utc_offset.0;
+        let days = local_minutes.div_euclid(24 * 60);
+        let minute_of_day = local_minutes.rem_euclid(24 * 60) as u32;
+
+        // 1970-01-01 was a Thursday
+        let weekday = Weekday::ALL[(days + 3).rem_euclid(7) as usize];
+        if !self.days.is_empty() && !self.days.contains(&weekday) {
+            return false;
+        }
+
+        let TimeOfDay(from) = self.from;
+        let TimeOfDay(to) = self.to;
+        if from <= to {
+            (from..to).contains(&minute_of_day)
+        } else {
+            minute_of_day >= from || minute_of_day < to
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Monday 2024-01-01 00:00 UTC
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn schedule(contents: &str) -> Schedule {
+        json5::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn test_business_hours() {
+        let hours = schedule(
+            r#"{ from: "09:00", to: "18:00", days: ["mon", "tue", "wed", "thu", "fri"] }"#,
+        );
+        assert!(hours.contains(at(MONDAY + 9 * 3600)));
+        assert!(!hours.contains(at(MONDAY + 18 * 3600)));
+        assert!(!hours.contains(at(MONDAY + 5 * 86400 + 10 * 3600)));
+    }
+
+    #[test]
+    fn test_overnight_window_with_offset() {
+        let night = schedule(r#"{ from: "22:00", to: "06:00", utc_offset: "+03:00" }"#);
+        // 20:00 UTC is 23:00 at +03:00
+        assert!(night.contains(at(MONDAY + 20 * 3600)));
+        assert!(!night.contains(at(MONDAY + 12 * 3600)));
+    }
+
+    #[test]
+    fn test_rejects_bad_times() {
+        assert!(json5::from_str::<Schedule>(r#"{ from: "25:00", to: "06:00" }"#).is_err());
+        assert!(
+            json5::from_str::<Schedule>(r#"{ from: "09:00", to: "18:00", utc_offset: "3" }"#)
+                .is_err()
+        );
+    }
+}
@@ -1,11 +1,143 @@
-use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::PathBuf;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-use super::Config;
+use super::{Config, ConfigError, ReloadFailureMode, WatcherOptions};
+
+/// Stretch `base` by a pseudo-random amount of up to 25% so that several watchers don't align
+fn jittered(base: Duration) -> Duration {
+    let spread = base.as_millis() as u64 / 4;
+    if spread == 0 {
+        return base;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    base + Duration::from_millis(nanos % (spread + 1))
+}
+
+/// Hand a file event from the notify thread to the reload task without ever blocking
+///
+/// When the channel is full a reload is already pending and the debounce will pick up the
+/// latest file contents, so the event can be dropped.
+fn forward_event(tx: &Sender<notify::Result<Event>>, event: notify::Result<Event>) {
+    match tx.try_send(event) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => debug!("Config watcher channel full, coalescing event"),
+        Err(TrySendError::Closed(_)) => debug!("Config watcher stopped, dropping event"),
+    }
+}
+
+/// Wait until no file event arrived for a (jittered) debounce window, capped at `max_debounce_ms`
+async fn debounce(rx: &mut Receiver<notify::Result<Event>>, options: &WatcherOptions) {
+    let deadline = Instant::now() + Duration::from_millis(options.max_debounce_ms);
+    let window = jittered(Duration::from_millis(options.debounce_ms));
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(window.min(remaining), rx.recv()).await {
+            Ok(Some(_)) if !remaining.is_zero() => continue,
+            _ => break,
+        }
+    }
+}
+
+/// Wait until the file's size and mtime stop changing for `stable_for`, giving up after `max_wait`
+///
+/// Returns `false` if the file was still changing when `max_wait` elapsed.
+async fn wait_until_stable(path: &Path, stable_for: Duration, max_wait: Duration) -> bool {
+    let snapshot = |path: &Path| {
+        std::fs::metadata(path)
+            .ok()
+            .map(|m| (m.len(), m.modified().ok()))
+    };
+    let deadline = Instant::now() + max_wait;
+    let mut last = snapshot(path);
+    loop {
+        tokio::time::sleep(stable_for).await;
+        let current = snapshot(path);
+        if current.is_some() && current == last {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        last = current;
+    }
+}
+
+/// Act on a changed config file that failed to load, as its current `reloadFailureMode` says
+async fn reload_failed(config: &RwLock<Config>, reason: String) {
+    let mut guard = config.write().await;
+    match guard.reload_failure_mode {
+        ReloadFailureMode::Keep => error!("{}. Keeping old config.", reason),
+        ReloadFailureMode::Block => {
+            error!(
+                "{}. Refusing new connections until a valid config loads.",
+                reason
+            );
+            guard.reload_error = Some(reason);
+        }
+    }
+}
+
+/// Load the config file at `path` for a reload, checking it the way the watcher does
+///
+/// Returns the new config when it loaded, differs from the one in use and has resolvable
+/// upstream proxies. Failures are handled as `reloadFailureMode` says; an unchanged file
+/// clears an earlier failure instead.
+pub async fn load_changed(config: &RwLock<Config>, path: &Path) -> Option<Config> {
+    let new_config = match Config::load(&path.to_string_lossy()) {
+        Ok(cfg) => {
+            debug!("Config loaded successfully from disk");
+            cfg
+        }
+        Err(ConfigError::Io { source, .. }) => {
+            // Editors that save by rename briefly leave no file behind
+            warn!(
+                "Config file is unreadable ({}). Keeping old config.",
+                source
+            );
+            return None;
+        }
+        Err(ConfigError::Validation(problems)) => {
+            for problem in &problems {
+                error!("Invalid config: {}", problem);
+            }
+            let reason = format!(
+                "Rejected reloaded config with {} problem(s)",
+                problems.len()
+            );
+            reload_failed(config, reason).await;
+            return None;
+        }
+        Err(e) => {
+            reload_failed(config, format!("Failed to reload config: {}", e)).await;
+            return None;
+        }
+    };
+    // Editors often rewrite or touch the file without changing it
+    if new_config.content_hash == config.read().await.content_hash {
+        // Back to the config in use, which is valid after all
+        if config.write().await.reload_error.take().is_some() {
+            info!("Config file is valid again, accepting new connections");
+        } else {
+            debug!("Config file content unchanged, skipping reload");
+        }
+        return None;
+    }
+    if let Err(e) = crate::resolver::check_upstreams(&new_config).await {
+        reload_failed(config, format!("Unresolvable upstream proxies: {}", e)).await;
+        return None;
+    }
+    Some(new_config)
+}
 
 /// Spawns a config watcher task that reloads config on file changes and exits on shutdown signal.
 pub fn spawn_config_watcher(
@@ -15,11 +147,10 @@ pub fn spawn_config_watcher(
     cancel_token: CancellationToken,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let capacity = config.read().await.watcher.channel_capacity.max(1);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(capacity);
         let mut watcher = RecommendedWatcher::new(
-            move |res| {
-                let _ = tx.blocking_send(res);
-            },
+            move |res| forward_event(&tx, res),
             notify::Config::default(),
         )
         .expect("Failed to create watcher");
@@ -33,81 +164,76 @@ pub fn spawn_config_watcher(
                     break;
                 }
                 maybe_event = rx.recv() => {
-                    if let Some(Ok(event)) = maybe_event {
-                        if matches!(event.kind, EventKind::Modify(_)) {
-                            // Debounce: wait 200ms, drain any further events
-                            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-                            while let Ok(Some(Ok(ev))) = tokio::time::timeout(
-                                std::time::Duration::from_millis(10),
-                                rx.recv()
-                            ).await {
-                                if !matches!(ev.kind, EventKind::Modify(_)) {
-                                    break;
-                                }
-                            }
+                    if let Some(Ok(event)) = maybe_event
+                        && matches!(event.kind, EventKind::Modify(_))
+                    {
+                        // Debounce bursts of events, then make sure the writer is done
+                        let options = config.read().await.watcher.clone();
+                        debounce(&mut rx, &options).await;
+                        if !wait_until_stable(
+                            &config_path,
+                            Duration::from_millis(options.stable_ms),
+                            Duration::from_millis(options.max_debounce_ms),
+                        )
+                        .await
+                        {
+                            warn!("Config file is still changing, reloading anyway");
+                        }
+
+                        // First load the new config
+                        let Some(new_config) = load_changed(&config, &config_path).await else {
+                            continue;
+                        };
 
-                            // First load the new config
-                            let new_config = match Config::load(config_path.to_str().unwrap()) {
-                                Ok(cfg) => {
-                                    debug!("Config loaded successfully from disk");
-                                    cfg
+                        // Cancel existing connections to free up any read locks - important fix:
+                        // We must not hold the MutexGuard across an await point
+                        {
+                            // Scope for MutexGuard to ensure it's dropped before any awaits
+                            match connections_token.lock() {
+                                Ok(mut token_guard) => {
+                                    debug!("Cancelling all active connections before config update");
+                                    token_guard.cancel();
+                                    *token_guard = CancellationToken::new();
+                                    // MutexGuard is dropped at the end of this scope
                                 },
                                 Err(e) => {
-                                    error!("Failed to reload config: {}. Keeping old config.", e);
-                                    continue;
+                                    error!("Failed to acquire lock on connections token: {:?}", e);
                                 }
-                            };
-
-                            // Cancel existing connections to free up any read locks - important fix:
-                            // We must not hold the MutexGuard across an await point
-                            {
-                                // Scope for MutexGuard to ensure it's dropped before any awaits
-                                match connections_token.lock() {
-                                    Ok(mut token_guard) => {
-                                        debug!("Cancelling all active connections before config update");
-                                        token_guard.cancel();
-                                        *token_guard = CancellationToken::new();
-                                        // MutexGuard is dropped at the end of this scope
+                            }
+                        } // MutexGuard is definitely dropped here
+
+                        // Now we can safely await without holding the MutexGuard
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+                        // Now try to update the config with a timeout
+                        match tokio::time::timeout(
+                            std::time::Duration::from_secs(3),
+                            config.write()
+                        ).await {
+                            Ok(mut guard) => {
+                                debug!("Acquired write lock for config");
+                                *guard = new_config;
+                                info!("Config updated successfully");
+                            },
+                            Err(_) => {
+                                error!("Timeout while acquiring write lock for config");
+                                warn!("The new config is loaded but not applied, connections were reset anyway");
+
+                                // Try one more time with a shorter timeout after giving more time for locks to clear
+                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+                                // Using a direct approach instead of try_write()
+                                match tokio::time::timeout(
+                                    std::time::Duration::from_millis(500),
+                                    config.write()
+                                ).await {
+                                    Ok(mut guard) => {
+                                        debug!("Acquired write lock for config on second attempt");
+                                        *guard = new_config;
+                                        info!("Config updated successfully on second attempt");
                                     },
-                                    Err(e) => {
-                                        error!("Failed to acquire lock on connections token: {:?}", e);
-                                    }
-                                }
-                            } // MutexGuard is definitely dropped here
-
-                            // Now we can safely await without holding the MutexGuard
-                            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-
-                            // Now try to update the config with a timeout
-                            match tokio::time::timeout(
-                                std::time::Duration::from_secs(3),
-                                config.write()
-                            ).await {
-                                Ok(mut guard) => {
-                                    debug!("Acquired write lock for config");
-                                    *guard = new_config;
-                                    info!("Config updated successfully");
-                                },
-                                Err(_) => {
-                                    error!("Timeout while acquiring write lock for config");
-                                    warn!("The new config is loaded but not applied, connections were reset anyway");
-
-                                    // Try one more time with a shorter timeout after giving more time for locks to clear
-                                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-
-                                    // Using a direct approach instead of try_write()
-                                    match tokio::time::timeout(
-                                        std::time::Duration::from_millis(500),
-                                        config.write()
-                                    ).await {
-                                        Ok(mut guard) => {
-                                            debug!("Acquired write lock for config on second attempt");
-                                            *guard = new_config;
-                                            info!("Config updated successfully on second attempt");
-                                        },
-                                        Err(_) => {
-                                            error!("Timeout on second attempt to acquire write lock");
-                                        }
+                                    Err(_) => {
+                                        error!("Timeout on second attempt to acquire write lock");
                                     }
                                 }
                             }
@@ -118,3 +244,117 @@ pub fn spawn_config_watcher(
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_jitter_is_capped() {
+        let base = Duration::from_millis(200);
+        for _ in 0..100 {
+            let d = jittered(base);
+            assert!(d >= base && d <= Duration::from_millis(250));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_burst_triggers_one_reload() {
+        let options = WatcherOptions {
+            debounce_ms: 200,
+            max_debounce_ms: 5000,
+            channel_capacity: 4,
+            ..Default::default()
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(options.channel_capacity);
+
+        // Far more saves than the channel holds, from a plain thread like notify's
+        let saver = std::thread::spawn(move || {
+            for _ in 0..100 {
+                let event = Event::new(EventKind::Modify(notify::event::ModifyKind::Any));
+                forward_event(&tx, Ok(event));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let mut reloads = 0;
+        while rx.recv().await.is_some() {
+            reloads += 1;
+            debounce(&mut rx, &options).await;
+        }
+        saver.join().unwrap();
+        assert_eq!(reloads, 1);
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_stable_covers_chunked_write() {
+        let path =
+            std::env::temp_dir().join(format!("proxy-twister-stable-{}.json", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(b"{ switch: { default: ").unwrap();
+        file.flush().unwrap();
+
+        let writer_path = path.clone();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&writer_path)
+                .unwrap();
+            file.write_all(b"\"direct\", rules: [] }, profiles: {} }")
+                .unwrap();
+        });
+
+        let stable =
+            wait_until_stable(&path, Duration::from_millis(150), Duration::from_secs(2)).await;
+        writer.await.unwrap();
+
+        assert!(stable);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(json5::from_str::<Config>(&contents).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_content_does_not_reload() {
+        let path = std::env::temp_dir().join(format!(
+            "proxy-twister-unchanged-{}.json",
+            std::process::id()
+        ));
+        let contents = r#"{
+            switch: { default: "direct", rules: [] },
+            profiles: { direct: { scheme: "direct" } },
+            watcher: { debounce_ms: 20, max_debounce_ms: 200, stable_ms: 20 },
+        }"#;
+        std::fs::write(&path, contents).unwrap();
+        let config = Arc::new(RwLock::new(Config::load(path.to_str().unwrap()).unwrap()));
+        let connections_token = Arc::new(Mutex::new(CancellationToken::new()));
+        let first_token = connections_token.lock().unwrap().clone();
+        let shutdown_token = CancellationToken::new();
+        let watcher = spawn_config_watcher(
+            path.clone(),
+            config.clone(),
+            connections_token.clone(),
+            shutdown_token.clone(),
+        );
+        // Let the watcher register before the file changes
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        std::fs::write(&path, contents).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!first_token.is_cancelled());
+
+        // A real change still goes through
+        std::fs::write(&path, contents.replace("rules: []", "rules: [ ]")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !first_token.is_cancelled() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(first_token.is_cancelled());
+
+        shutdown_token.cancel();
+        watcher.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// How long a credential command may run before the connection needing it fails
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Username and password printed by a credential command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Output of a credential command and when it was printed, if it has run
+type Entry = Arc<Mutex<Option<(Credentials, Instant)>>>;
+
+/// Output of credential commands, reused until it expires or the proxy rejects it
+///
+/// Each command runs once at a time, so that connections opened while a token is being fetched
+/// wait for it instead of each running the command again. Different commands do not wait for
+/// each other.
+#[derive(Debug, Default)]
+pub struct CredentialCache {
+    entries: std::sync::Mutex<HashMap<String, Entry>>,
+}
+
+impl CredentialCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The entry of `command`, created empty on first use
+    fn entry(&self, command: &str) -> Entry {
+        let mut entries = self.entries.lock().unwrap();
+        entries.entry(command.to_string()).or_default().clone()
+    }
+
+    /// Credentials printed by `command`, running it when nothing younger than `ttl` is cached
+    pub async fn get(&self, command: &str, ttl: Duration) -> io::Result<Credentials> {
+        let entry = self.entry(command);
+        let mut entry = entry.lock().await;
+        if let Some((credentials, fetched)) = &*entry
+            && fetched.elapsed() < ttl
+        {
+            return Ok(credentials.clone());
+        }
+        debug!("Running credential command");
+        let credentials = run(command).await?;
+        *entry = Some((credentials.clone(), Instant::now()));
+        Ok(credentials)
+    }
+
+    /// Forget what `command` printed, so that the next connection runs it again
+    pub async fn invalidate(&self, command: &str) {
+        self.entry(command).lock().await.take();
+    }
+}
+
+/// Run `command` through the shell and parse what it prints
+async fn run(command: &str) -> io::Result<Credentials> {
+    let output = tokio::time::timeout(
+        COMMAND_TIMEOUT,
+        tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Credential command timed out"))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        warn!(
+            "Credential command failed ({}): {}",
+            output.status,
+            stderr.trim()
+        );
+        return Err(io::Error::other(format!(
+            "Credential command failed ({})",
+            output.status
+        )));
+    }
+    parse_credentials(&String::from_utf8_lossy(&output.stdout))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parse `username=` and `password=` lines, as printed by git credential helpers
+///
+/// Other lines are ignored; a missing password is taken as empty.
+pub fn parse_credentials(output: &str) -> Result<Credentials, String> {
+    let mut username = None;
+    let mut password = None;
+    for line in output.lines() {
+        match line.trim_end_matches('\r').split_once('=') {
+            Some(("username", value)) => username = Some(value.to_string()),
+            Some(("password", value)) => password = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Ok(Credentials {
+        username: username.ok_or("Credential command printed no username= line")?,
+        password: password.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credentials() {
+        assert_eq!(
+            parse_credentials("protocol=http\nusername=alice\npassword=to=ken\r\n"),
+            Ok(Credentials {
+                username: "alice".to_string(),
+                password: "to=ken".to_string(),
+            })
+        );
+        assert!(parse_credentials("password=secret\n").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_output_is_cached_until_invalidated() {
+        let counter =
+            std::env::temp_dir().join(format!("proxy-twister-credentials-{}", std::process::id()));
+        let command = format!(
+            "echo run >> '{0}'; echo username=u$(wc -l < '{0}' | tr -d ' '); echo password=p",
+            counter.display()
+        );
+        let cache = CredentialCache::new();
+        let ttl = Duration::from_secs(60);
+
+        assert_eq!(cache.get(&command, ttl).await.unwrap().username, "u1");
+        assert_eq!(cache.get(&command, ttl).await.unwrap().username, "u1");
+        cache.invalidate(&command).await;
+        assert_eq!(cache.get(&command, ttl).await.unwrap().username, "u2");
+        assert_eq!(
+            cache.get(&command, Duration::ZERO).await.unwrap().username,
+            "u3"
+        );
+        std::fs::remove_file(&counter).unwrap();
+
+        let failing = cache.get("echo username=x; exit 3", ttl).await.unwrap_err();
+        assert!(failing.to_string().contains("failed"), "{failing}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_slow_command_does_not_hold_up_others() {
+        let cache = CredentialCache::new();
+        let ttl = Duration::from_secs(60);
+        let slow = cache.get("sleep 1; echo username=slow", ttl);
+        let fast = tokio::time::timeout(
+            Duration::from_millis(500),
+            cache.get("echo username=fast", ttl),
+        );
+        let (slow, fast) = tokio::join!(slow, fast);
+        assert_eq!(slow.unwrap().username, "slow");
+        assert_eq!(fast.unwrap().unwrap().username, "fast");
+    }
+}
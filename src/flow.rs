@@ -0,0 +1,322 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::OnceCell;
+
+use crate::config::{FlowExportOptions, FlowField};
+use crate::metrics::ByteMeter;
+use crate::protocols::proxy_protocol::ClientAddrs;
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const TEMPLATE_ID_V4: u16 = 256;
+const TEMPLATE_ID_V6: u16 = 257;
+/// Field length announcing a variable-length value
+const VARIABLE_LENGTH: u16 = 0xffff;
+/// Enterprise number of the reverse information elements of RFC 5103
+const REVERSE_PEN: u32 = 29305;
+const PROTOCOL_TCP: u8 = 6;
+
+/// Addresses of an established upstream connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamAddrs {
+    /// Local address the connection leaves from
+    pub egress: SocketAddr,
+    /// Address connected to: the target itself, or the upstream proxy
+    pub remote: SocketAddr,
+}
+
+impl UpstreamAddrs {
+    /// Addresses of `stream`, unless the socket is already gone
+    pub fn of(stream: &TcpStream) -> Option<Self> {
+        Some(Self {
+            egress: stream.local_addr().ok()?,
+            remote: stream.peer_addr().ok()?,
+        })
+    }
+}
+
+/// Traffic of a single client connection, summarized in its flow record when it closes
+#[derive(Debug)]
+pub struct Flow {
+    started: SystemTime,
+    bytes: ByteMeter,
+    profile: Mutex<String>,
+    upstream: Mutex<Option<UpstreamAddrs>>,
+    /// Bytes the connection may relay in either direction
+    max_bytes: Option<u64>,
+}
+
+impl Default for Flow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Flow {
+    /// A flow starting now
+    pub fn new() -> Self {
+        Self {
+            started: SystemTime::now(),
+            bytes: ByteMeter::default(),
+            profile: Mutex::new(String::new()),
+            upstream: Mutex::new(None),
+            max_bytes: None,
+        }
+    }
+
+    /// Cap the bytes the flow may relay in either direction
+    pub fn with_max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Note the upstream connection just established, replacing that of an earlier request
+    pub fn set_upstream(&self, addrs: UpstreamAddrs) {
+        *self.upstream.lock().unwrap() = Some(addrs);
+    }
+
+    /// Summary of the flow between `addrs`, ending at `ended`
+    pub fn record(&self, addrs: ClientAddrs, ended: SystemTime) -> FlowRecord {
+        let (bytes_up, bytes_down) = self.bytes.totals();
+        FlowRecord {
+            addrs,
+            bytes_up,
+            bytes_down,
+            started: self.started,
+            ended,
+            profile: self.profile.lock().unwrap().clone(),
+            upstream: *self.upstream.lock().unwrap(),
+        }
+    }
+}
+
+/// Counts relayed bytes both for a profile and for the connection's flow
+pub struct FlowMeter {
+    profile: Arc<ByteMeter>,
+    flow: Arc<Flow>,
+}
+
+impl FlowMeter {
+    /// Meter for bytes relayed through `profile`, which becomes the profile of `flow`
+    pub fn new(profile_name: &str, profile: Arc<ByteMeter>, flow: Arc<Flow>) -> Self {
+        *flow.profile.lock().unwrap() = profile_name.to_string();
+        Self { profile, flow }
+    }
+
+    /// Count bytes sent from the client towards the upstream
+    pub fn add_up(&self, bytes: u64) {
+        self.profile.add_up(bytes);
+        self.flow.bytes.add_up(bytes);
+    }
+
+    /// Count bytes sent from the upstream back to the client
+    pub fn add_down(&self, bytes: u64) {
+        self.profile.add_down(bytes);
+        self.flow.bytes.add_down(bytes);
+    }
+
+    /// Bytes the flow may still send towards the upstream, `None` when uncapped
+    pub fn remaining_up(&self) -> Option<u64> {
+        let (up, _) = self.flow.bytes.totals();
+        self.flow.max_bytes.map(|max| max.saturating_sub(up))
+    }
+
+    /// Bytes the flow may still send back to the client, `None` when uncapped
+    pub fn remaining_down(&self) -> Option<u64> {
+        let (_, down) = self.flow.bytes.totals();
+        self.flow.max_bytes.map(|max| max.saturating_sub(down))
+    }
+}
+
+/// What is known about a closed client connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowRecord {
+    pub addrs: ClientAddrs,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub started: SystemTime,
+    pub ended: SystemTime,
+    pub profile: String,
+    /// Last upstream connection established, if any
+    pub upstream: Option<UpstreamAddrs>,
+}
+
+/// Sends flow records to the configured collector, one IPFIX message per record
+///
+/// Every message carries its template along, so collectors started (or restarted) after
+/// proxy-twister can decode the very next record.
+#[derive(Debug, Default)]
+pub struct FlowExporter {
+    /// Records exported so far, the sequence number of the next message
+    sequence: AtomicU32,
+    v4: OnceCell<UdpSocket>,
+    v6: OnceCell<UdpSocket>,
+}
+
+impl FlowExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `record` to the collector of `options`
+    pub async fn export(
+        &self,
+        options: &FlowExportOptions,
+        record: &FlowRecord,
+    ) -> tokio::io::Result<()> {
+        let collector: SocketAddr = options.collector.parse().map_err(|_| {
+            tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidInput,
+                format!("invalid flow collector '{}'", options.collector),
+            )
+        })?;
+        let socket = match collector {
+            SocketAddr::V4(_) => {
+                self.v4
+                    .get_or_try_init(|| UdpSocket::bind("0.0.0.0:0"))
+                    .await?
+            }
+            SocketAddr::V6(_) => {
+                self.v6
+                    .get_or_try_init(|| UdpSocket::bind("[::]:0"))
+                    .await?
+            }
+        };
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let message = encode(options, sequence, record, SystemTime::now());
+        socket.send_to(&message, collector).await?;
+        Ok(())
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Unspecified address standing in for an upstream never connected to
+fn unspecified(v6: bool) -> IpAddr {
+    if v6 {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    }
+}
+
+fn address_octets(ip: IpAddr, v6: bool) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) if v6 => ip.to_ipv6_mapped().octets().to_vec(),
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Information element announcing `field` in a template, as (id, length, enterprise number)
+fn field_specifier(field: FlowField, v6: bool) -> (u16, u16, Option<u32>) {
+    match field {
+        FlowField::SourceAddress if v6 => (27, 16, None),
+        FlowField::SourceAddress => (8, 4, None),
+        FlowField::SourcePort => (7, 2, None),
+        FlowField::DestinationAddress if v6 => (28, 16, None),
+        FlowField::DestinationAddress => (12, 4, None),
+        FlowField::DestinationPort => (11, 2, None),
+        FlowField::Protocol => (4, 1, None),
+        // octetDeltaCount, and its reverse counterpart for the other direction
+        FlowField::BytesUp => (1, 8, None),
+        FlowField::BytesDown => (1, 8, Some(REVERSE_PEN)),
+        FlowField::Start => (152, 8, None),
+        FlowField::End => (153, 8, None),
+        // applicationName
+        FlowField::Profile => (96, VARIABLE_LENGTH, None),
+        // postNATSourceIPv4Address and postNATDestinationIPv4Address, and their IPv6 variants
+        FlowField::EgressAddress if v6 => (281, 16, None),
+        FlowField::EgressAddress => (225, 4, None),
+        FlowField::UpstreamAddress if v6 => (282, 16, None),
+        FlowField::UpstreamAddress => (226, 4, None),
+    }
+}
+
+/// IPFIX message (RFC 7011) with the template of `options` and `record` as its only data record
+fn encode(
+    options: &FlowExportOptions,
+    sequence: u32,
+    record: &FlowRecord,
+    exported: SystemTime,
+) -> Vec<u8> {
+    let v6 = record.addrs.source.is_ipv6()
+        || record.addrs.destination.is_ipv6()
+        || record
+            .upstream
+            .is_some_and(|upstream| upstream.egress.is_ipv6() || upstream.remote.is_ipv6());
+    let template_id = if v6 { TEMPLATE_ID_V6 } else { TEMPLATE_ID_V4 };
+
+    let mut template = Vec::new();
+    template.extend_from_slice(&template_id.to_be_bytes());
+    template.extend_from_slice(&(options.fields.len() as u16).to_be_bytes());
+    let mut data = Vec::new();
+    for &field in &options.fields {
+        let (id, length, enterprise) = field_specifier(field, v6);
+        match enterprise {
+            Some(enterprise) => {
+                template.extend_from_slice(&(id | 0x8000).to_be_bytes());
+                template.extend_from_slice(&length.to_be_bytes());
+                template.extend_from_slice(&enterprise.to_be_bytes());
+            }
+            None => {
+                template.extend_from_slice(&id.to_be_bytes());
+                template.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+        match field {
+            FlowField::SourceAddress => {
+                data.extend(address_octets(record.addrs.source.ip(), v6));
+            }
+            FlowField::SourcePort => {
+                data.extend_from_slice(&record.addrs.source.port().to_be_bytes());
+            }
+            FlowField::DestinationAddress => {
+                data.extend(address_octets(record.addrs.destination.ip(), v6));
+            }
+            FlowField::DestinationPort => {
+                data.extend_from_slice(&record.addrs.destination.port().to_be_bytes());
+            }
+            FlowField::Protocol => data.push(PROTOCOL_TCP),
+            FlowField::BytesUp => data.extend_from_slice(&record.bytes_up.to_be_bytes()),
+            FlowField::BytesDown => data.extend_from_slice(&record.bytes_down.to_be_bytes()),
+            FlowField::Start => data.extend_from_slice(&unix_millis(record.started).to_be_bytes()),
+            FlowField::End => data.extend_from_slice(&unix_millis(record.ended).to_be_bytes()),
+            FlowField::Profile => {
+                // Kept to the short form of variable-length values
+                let name = &record.profile.as_bytes()[..record.profile.len().min(254)];
+                data.push(name.len() as u8);
+                data.extend_from_slice(name);
+            }
+            FlowField::EgressAddress => {
+                let ip = record.upstream.map_or(unspecified(v6), |u| u.egress.ip());
+                data.extend(address_octets(ip, v6));
+            }
+            FlowField::UpstreamAddress => {
+                let ip = record.upstream.map_or(unspecified(v6), |u| u.remote.ip());
+                data.extend(address_octets(ip, v6));
+            }
+        }
+    }
+
+    let length = 16 + 4 + template.len() + 4 + data.len();
+    let mut message = Vec::with_capacity(length);
+    message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+    message.extend_from_slice(&(length as u16).to_be_bytes());
+    message.extend_from_slice(&((unix_millis(exported) / 1000) as u32).to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(&options.observation_domain_id.to_be_bytes());
+    message.extend_from_slice(&TEMPLATE_SET_ID.to_be_bytes());
+    message.extend_from_slice(&(4 + template.len() as u16).to_be_bytes());
+    message.extend(template);
+    message.extend_from_slice(&template_id.to_be_bytes());
+    message.extend_from_slice(&(4 + data.len() as u16).to_be_bytes());
+    message.extend(data);
+    message
+}
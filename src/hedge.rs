@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Secondary attempts of hedged profiles in flight, counted per secondary profile
+///
+/// When the primary of a hedged profile is slow for everyone, every connection would double
+/// its upstream attempts; past `max_hedges`, connections wait for the primary alone instead.
+#[derive(Debug, Default)]
+pub struct HedgeSlots {
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// A secondary attempt counted against its profile until dropped
+#[derive(Debug)]
+pub struct HedgeSlot {
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    profile: String,
+}
+
+impl Drop for HedgeSlot {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.profile) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.profile);
+            }
+        }
+    }
+}
+
+impl HedgeSlots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A slot for one more attempt through the secondary `profile`, unless `max` are in flight
+    pub fn try_take(&self, profile: &str, max: usize) -> Option<HedgeSlot> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(profile.to_string()).or_default();
+        if *count >= max {
+            if *count == 0 {
+                in_flight.remove(profile);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(HedgeSlot {
+            in_flight: self.in_flight.clone(),
+            profile: profile.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_bounded_per_profile() {
+        let slots = HedgeSlots::new();
+        let first = slots.try_take("hedged", 2).unwrap();
+        let _second = slots.try_take("hedged", 2).unwrap();
+        assert!(slots.try_take("hedged", 2).is_none());
+        // Other profiles have their own slots
+        assert!(slots.try_take("other", 2).is_some());
+        assert!(slots.try_take("other", 0).is_none());
+
+        drop(first);
+        assert!(slots.try_take("hedged", 2).is_some());
+    }
+}
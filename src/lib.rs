@@ -0,0 +1,447 @@
+//! Proxy switcher routing HTTP and SOCKS5 clients through different upstream proxies
+//! based on target host patterns.
+//!
+//! The `proxy-twister` binary is a thin wrapper around [`ProxyServer`], which can be embedded
+//! in other applications:
+//!
+//! ```no_run
+//! # async fn example(config: proxy_twister::Config) -> Result<(), String> {
+//! let server = proxy_twister::ProxyServer::builder(config)
+//!     .listen("127.0.0.1:1080")
+//!     .build()
+//!     .await?;
+//! server.run().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+mod accounting;
+mod admin;
+mod breaker;
+pub mod config;
+mod credentials;
+mod flow;
+mod hedge;
+mod limiter;
+mod listeners;
+pub mod metrics;
+mod protocols;
+mod resolver;
+mod route_override;
+mod server;
+mod systemd;
+pub mod telemetry;
+mod transparent;
+mod utils;
+
+pub use config::{Config, ConfigError, validate_config_str};
+pub use protocols::http::Headers;
+pub use route_override::ForcedRoute;
+pub use server::route;
+
+/// How long closed connections get to unwind, exporting their flow records, at shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long exporters get to flush at shutdown
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often shutdown checks whether the open connections have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Log a one-line status report (uptime, connections, profile counters) on every SIGUSR1
+#[cfg(unix)]
+fn spawn_status_reporter(
+    started: std::time::Instant,
+    config: Arc<RwLock<Config>>,
+    metrics: Arc<metrics::Metrics>,
+    shutdown_token: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    tokio::spawn(async move {
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                warn!("Cannot listen for SIGUSR1, status reports disabled: {e}");
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = shutdown_token.cancelled() => break,
+                _ = signals.recv() => {
+                    let config_hash = config.read().await.content_hash.clone();
+                    info!("{}", metrics.status_line(started.elapsed(), &config_hash));
+                }
+            }
+        }
+    })
+}
+
+/// Builder for a [`ProxyServer`]
+pub struct ProxyServerBuilder {
+    config: Config,
+    addresses: Vec<String>,
+    config_path: Option<PathBuf>,
+    admin_address: Option<String>,
+    transparent: bool,
+    /// Already bound sockets to serve on, besides those bound from `addresses`
+    inherited: Vec<std::net::TcpListener>,
+    systemd_sockets: bool,
+    status_signal: bool,
+}
+
+impl ProxyServerBuilder {
+    /// Add an address to listen on; the port may be a range like `1080-1090`
+    ///
+    /// Addresses added here replace the config's `listen` list.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.addresses.push(addr.into());
+        self
+    }
+
+    /// Serve on an already bound, listening socket, e.g. one inherited from a service manager
+    ///
+    /// Its `listeners` options are looked up by its local address. Like addresses added with
+    /// `listen`, inherited sockets replace the config's `listen` list.
+    pub fn listen_on(mut self, listener: std::net::TcpListener) -> Self {
+        self.inherited.push(listener);
+        self
+    }
+
+    /// Serve on the sockets passed by systemd socket activation (Linux, `systemd` feature)
+    pub fn systemd_sockets(mut self, enabled: bool) -> Self {
+        self.systemd_sockets = enabled;
+        self
+    }
+
+    /// Log a status report on every SIGUSR1 (Unix only)
+    ///
+    /// Off by default, since signal handlers belong to the process rather than to a library.
+    pub fn status_signal(mut self, enabled: bool) -> Self {
+        self.status_signal = enabled;
+        self
+    }
+
+    /// Hot-reload the configuration from this file when it changes
+    pub fn watch_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Serve the admin HTTP endpoint on this address, or on a Unix socket given as `unix:<path>`
+    pub fn admin(mut self, addr: impl Into<String>) -> Self {
+        self.admin_address = Some(addr.into());
+        self
+    }
+
+    /// Treat accepted connections as iptables-redirected traffic (Linux, `transparent` feature)
+    pub fn transparent(mut self, enabled: bool) -> Self {
+        self.transparent = enabled;
+        self
+    }
+
+    /// Validate the configuration and bind all listeners
+    pub async fn build(mut self) -> Result<ProxyServer, String> {
+        if self.transparent && !transparent::SUPPORTED {
+            return Err(
+                "Transparent mode requires Linux and the `transparent` feature".to_string(),
+            );
+        }
+        if self.systemd_sockets {
+            self.inherited.extend(systemd::listeners()?);
+        }
+        self.config.compose_listener_rules();
+        self.config.validate().map_err(|e| e.to_string())?;
+        self.config.load_error_pages().map_err(|e| e.to_string())?;
+        self.config.load_proxy_tls().map_err(|e| e.to_string())?;
+        resolver::check_upstreams(&self.config)
+            .await
+            .map_err(|e| format!("unresolvable upstream proxies: {e}"))?;
+
+        let default_options = config::ListenerOptions::default();
+        let mut listeners = Vec::new();
+        for listener in self.inherited {
+            let addr = listener
+                .local_addr()
+                .map_err(|e| format!("Failed to use inherited listener: {e}"))?;
+            let listener = listener
+                .set_nonblocking(true)
+                .and_then(|()| TcpListener::from_std(listener))
+                .map_err(|e| format!("Failed to use inherited listener on {addr}: {e}"))?;
+            let addr = addr.to_string();
+            let options = self.config.listeners.get(&addr).unwrap_or(&default_options);
+            let tls_acceptor = server::listener_tls_acceptor(&addr, options)?;
+            listeners.push((addr, listener, tls_acceptor));
+        }
+        let addresses = if !self.addresses.is_empty() || !listeners.is_empty() {
+            &self.addresses
+        } else {
+            &self.config.listen
+        };
+        for addr in addresses {
+            for addr in utils::expand_listen_address(addr)? {
+                let options = self.config.listeners.get(&addr).unwrap_or(&default_options);
+                let tls_acceptor = server::listener_tls_acceptor(&addr, options)?;
+                let listener = server::bind_listener(&addr, options)
+                    .await
+                    .map_err(|e| format!("Failed to bind to {addr}: {e}"))?;
+                listeners.push((addr, listener, tls_acceptor));
+            }
+        }
+
+        let config = Arc::new(RwLock::new(self.config));
+        let metrics = Arc::new(metrics::Metrics::new());
+        let closing_token = CancellationToken::new();
+        let route_override = Arc::new(route_override::RouteOverride::new());
+        let state = server::ProxyState {
+            config: config.clone(),
+            metrics: metrics.clone(),
+            resolver: Arc::new(resolver::UpstreamResolver::new()),
+            round_robin: Arc::new(resolver::RoundRobin::new()),
+            dns: Arc::new(resolver::DnsCache::new()),
+            breaker: Arc::new(breaker::Breaker::new()),
+            route_override: route_override.clone(),
+            hedges: Arc::new(hedge::HedgeSlots::new()),
+            credentials: Arc::new(credentials::CredentialCache::new()),
+            missing_profile_reload: Arc::new(tokio::sync::Mutex::new(None)),
+            limiter: Arc::new(limiter::ConnectionLimiter::new(metrics.clone())),
+            flow_exporter: Arc::new(flow::FlowExporter::new()),
+            flow: Arc::new(flow::Flow::new()),
+            leases: Arc::default(),
+            closing: closing_token.clone(),
+            listener: String::new(),
+            client_addrs: None,
+            connection_id: 0,
+            connect_timeout: None,
+            fallbacks: Vec::new(),
+            route_headers: None,
+            transparent: self.transparent,
+        };
+        // Use a shared holder for the current CancellationToken
+        let connections_token = Arc::new(Mutex::new(CancellationToken::new()));
+        // Separate token for graceful shutdown of the listeners and background tasks
+        let shutdown_token = CancellationToken::new();
+        let listener_set = Arc::new(listeners::ListenerSet::new(
+            state,
+            connections_token.clone(),
+            shutdown_token.clone(),
+        ));
+
+        Ok(ProxyServer {
+            config,
+            listeners: Mutex::new(listeners),
+            listener_set,
+            config_path: self.config_path,
+            admin_address: self.admin_address,
+            status_signal: self.status_signal,
+            metrics,
+            route_override,
+            connections_token,
+            shutdown_token,
+            closing_token,
+        })
+    }
+}
+
+/// A configured proxy switcher with its listeners bound
+pub struct ProxyServer {
+    config: Arc<RwLock<Config>>,
+    /// Bound listeners, with the TLS acceptor of those that terminate TLS, waiting for `run`
+    /// to start them
+    listeners: Mutex<Vec<(String, TcpListener, Option<TlsAcceptor>)>>,
+    listener_set: Arc<listeners::ListenerSet>,
+    config_path: Option<PathBuf>,
+    admin_address: Option<String>,
+    status_signal: bool,
+    metrics: Arc<metrics::Metrics>,
+    route_override: Arc<route_override::RouteOverride>,
+    connections_token: Arc<Mutex<CancellationToken>>,
+    shutdown_token: CancellationToken,
+    /// Closes the client connections left open once the shutdown grace period is over
+    closing_token: CancellationToken,
+}
+
+impl ProxyServer {
+    pub fn builder(config: Config) -> ProxyServerBuilder {
+        ProxyServerBuilder {
+            config,
+            addresses: Vec::new(),
+            config_path: None,
+            admin_address: None,
+            transparent: false,
+            inherited: Vec::new(),
+            systemd_sockets: false,
+            status_signal: false,
+        }
+    }
+
+    /// Addresses the listeners are bound to (useful when listening on port 0)
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let pending = self.listeners.lock().unwrap();
+        pending
+            .iter()
+            .filter_map(|(_, listener, _)| listener.local_addr().ok())
+            .chain(
+                self.listener_set
+                    .addresses()
+                    .into_iter()
+                    .map(|(_, addr)| addr),
+            )
+            .collect()
+    }
+
+    /// Start accepting connections on another address while the server runs
+    ///
+    /// The address uses its `listeners` options from the current configuration. Returns the
+    /// address the new listener is bound to.
+    pub async fn add_listener(&self, addr: &str) -> Result<SocketAddr, String> {
+        self.listener_set.add(addr).await
+    }
+
+    /// Stop accepting connections on `addr`, as given to `listen` or `add_listener`
+    ///
+    /// Connections already accepted on it are left to finish. Returns whether the server was
+    /// listening on `addr`.
+    pub async fn remove_listener(&self, addr: &str) -> bool {
+        self.listener_set.remove(addr).await
+    }
+
+    /// Runtime counters, as served on the admin endpoint's `/metrics`
+    pub fn metrics(&self) -> &metrics::Metrics {
+        &self.metrics
+    }
+
+    /// Route every new connection along `route` regardless of the rules, e.g. straight to the
+    /// targets while a proxy provider has an outage; `None` puts the rules back in charge
+    ///
+    /// Forcing the emergency route fails unless `emergencyProfile` is configured.
+    pub async fn force_route(&self, route: Option<ForcedRoute>) -> Result<(), String> {
+        if route == Some(ForcedRoute::Emergency)
+            && self.config.read().await.emergency_profile.is_none()
+        {
+            return Err("No emergencyProfile is configured".to_string());
+        }
+        self.route_override.set(route);
+        Ok(())
+    }
+
+    /// The route forced by [`force_route`](Self::force_route), if any
+    pub fn forced_route(&self) -> Option<ForcedRoute> {
+        self.route_override.get()
+    }
+
+    /// Ask a running server to stop accepting connections and close the open ones
+    ///
+    /// [`run`](Self::run) then gives open connections `shutdownGraceSecs` to finish, closes the
+    /// rest, and lets exporters flush what they hold before returning.
+    pub fn shutdown(&self) {
+        self.shutdown_token.cancel();
+    }
+
+    /// Serve until [`shutdown`](Self::shutdown) is called
+    pub async fn run(&self) {
+        #[cfg(unix)]
+        let started = std::time::Instant::now();
+        let listeners = std::mem::take(&mut *self.listeners.lock().unwrap());
+        let connections_token = self.connections_token.clone();
+        let shutdown_token = self.shutdown_token.clone();
+
+        let mut join_handles = Vec::new();
+        if let Some(config_path) = &self.config_path {
+            join_handles.push(config::watcher::spawn_config_watcher(
+                config_path.clone(),
+                self.config.clone(),
+                connections_token.clone(),
+                shutdown_token.clone(),
+            ));
+        }
+        #[cfg(unix)]
+        if self.status_signal {
+            join_handles.push(spawn_status_reporter(
+                started,
+                self.config.clone(),
+                self.metrics.clone(),
+                shutdown_token.clone(),
+            ));
+        }
+        // Exporters outlive the connections, so they still get the last ones' records
+        let exporters_token = CancellationToken::new();
+        let mut exporter_handles = Vec::new();
+        if let Some(options) = self.config.read().await.accounting.clone() {
+            exporter_handles.push(accounting::spawn_accounting(
+                options,
+                self.metrics.clone(),
+                exporters_token.clone(),
+            ));
+        }
+        if let Some(admin_address) = self.admin_address.clone() {
+            let state = admin::AdminState {
+                config_path: self.config_path.clone(),
+                config: self.config.clone(),
+                metrics: self.metrics.clone(),
+                listeners: self.listener_set.clone(),
+                route_override: self.route_override.clone(),
+            };
+            let shutdown_token = shutdown_token.clone();
+            join_handles.push(tokio::spawn(async move {
+                admin::run_admin(admin_address, state, shutdown_token).await;
+            }));
+        }
+        for (addr, listener, tls_acceptor) in listeners {
+            if let Err(e) = self.listener_set.start(addr, listener, tls_acceptor) {
+                error!("{e}");
+            }
+        }
+
+        shutdown_token.cancelled().await;
+        // Stop accepting first, so no connection shows up after the drain
+        connections_token.lock().unwrap().cancel();
+        self.listener_set.join().await;
+
+        let grace = Duration::from_secs(self.config.read().await.shutdown_grace_secs);
+        if !self.drain_connections(grace).await {
+            info!(
+                "Closing {} connection(s) still open after the shutdown grace period",
+                self.metrics.connections().1
+            );
+        }
+        self.closing_token.cancel();
+        // Closed connections still export their flow records on the way out
+        if !self.drain_connections(CLOSE_TIMEOUT).await {
+            warn!("Some connections did not close in time");
+        }
+
+        exporters_token.cancel();
+        for handle in exporter_handles {
+            if tokio::time::timeout(FLUSH_TIMEOUT, handle).await.is_err() {
+                warn!("Exporter did not flush in time");
+            }
+        }
+        for handle in join_handles {
+            let _ = handle.await;
+        }
+    }
+
+    /// Wait up to `within` for every client connection to finish, returning whether they did
+    async fn drain_connections(&self, within: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + within;
+        while self.metrics.connections().1 > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        true
+    }
+}
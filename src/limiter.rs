@@ -0,0 +1,171 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+use crate::config::ConnectionLimits;
+use crate::metrics::Metrics;
+
+/// Serving of an accepted client connection, run once a slot is free
+pub type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[derive(Default)]
+struct Queue {
+    /// Connections being served
+    active: usize,
+    /// Connections waiting, across all clients
+    queued: usize,
+    /// Waiting connections of each client, oldest first
+    clients: HashMap<IpAddr, VecDeque<Job>>,
+    /// Clients with waiting connections, in the order they get their next turn
+    turns: VecDeque<IpAddr>,
+}
+
+/// Bounds the client connections served at once, queueing the rest fairly across clients
+///
+/// Up to `max_active` connections are served by workers, each picking up a queued connection
+/// once its own is done. The queue hands out turns client by client, so one client opening
+/// many connections does not starve the others. When it is full, the newest connection of the
+/// client with the most waiting is dropped, or the new one if that client is its own.
+pub struct ConnectionLimiter {
+    queue: Mutex<Queue>,
+    metrics: Arc<Metrics>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            metrics,
+        }
+    }
+
+    /// Serve `job`, a connection from `client`, now or once a slot frees up; `limits` of
+    /// `None` serves it right away
+    pub fn submit(self: &Arc<Self>, client: IpAddr, job: Job, limits: Option<&ConnectionLimits>) {
+        let mut guard = self.queue.lock().unwrap();
+        let queue = &mut *guard;
+        let Some(limits) = limits.filter(|limits| queue.active >= limits.max_active) else {
+            queue.active += 1;
+            drop(guard);
+            tokio::spawn(self.clone().work(job));
+            return;
+        };
+
+        if queue.queued >= limits.max_queued {
+            let own = queue.clients.get(&client).map_or(0, VecDeque::len);
+            let busiest = queue
+                .clients
+                .iter_mut()
+                .max_by_key(|(_, waiting)| waiting.len())
+                .filter(|(_, waiting)| waiting.len() > own + 1);
+            self.metrics.record_connection_rejected();
+            let Some((busiest, waiting)) = busiest else {
+                debug!("Connection queue full, dropping connection from {client}");
+                return;
+            };
+            // Holding more than one connection, it keeps its place in the turns
+            waiting.pop_back();
+            queue.queued -= 1;
+            debug!("Connection queue full, dropping a queued connection from {busiest}");
+        }
+
+        let waiting = queue.clients.entry(client).or_default();
+        if waiting.is_empty() {
+            queue.turns.push_back(client);
+        }
+        waiting.push_back(job);
+        queue.queued += 1;
+        self.metrics.set_connections_queued(queue.queued);
+    }
+
+    /// Serve `job`, then queued connections until none are left
+    async fn work(self: Arc<Self>, mut job: Job) {
+        loop {
+            // A separate task, so a panicking connection does not take the worker's slot along
+            let _ = tokio::spawn(job).await;
+            match self.next() {
+                Some(next) => job = next,
+                None => break,
+            }
+        }
+    }
+
+    /// Take the next client's oldest waiting connection, or release the caller's slot
+    fn next(&self) -> Option<Job> {
+        let mut guard = self.queue.lock().unwrap();
+        let queue = &mut *guard;
+        while let Some(client) = queue.turns.pop_front() {
+            let Some(waiting) = queue.clients.get_mut(&client) else {
+                continue;
+            };
+            let job = waiting.pop_front();
+            if waiting.is_empty() {
+                queue.clients.remove(&client);
+            } else {
+                queue.turns.push_back(client);
+            }
+            if let Some(job) = job {
+                queue.queued -= 1;
+                self.metrics.set_connections_queued(queue.queued);
+                return Some(job);
+            }
+        }
+        queue.active -= 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn test_queue_stays_bounded_under_flood() {
+        let metrics = Arc::new(Metrics::new());
+        let limiter = Arc::new(ConnectionLimiter::new(metrics.clone()));
+        let limits = ConnectionLimits {
+            max_active: 2,
+            max_queued: 8,
+        };
+        let release = CancellationToken::new();
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let done = Arc::new(AtomicUsize::new(0));
+        let job = |client: IpAddr| -> Job {
+            let (release, started, done) = (release.clone(), started.clone(), done.clone());
+            Box::pin(async move {
+                started.lock().unwrap().push(client);
+                release.cancelled().await;
+                done.fetch_add(1, Ordering::Relaxed);
+            })
+        };
+
+        let flooder: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..10_000 {
+            limiter.submit(flooder, job(flooder), Some(&limits));
+        }
+        assert_eq!(metrics.connection_queue(), (8, 10_000 - 2 - 8));
+        // A full queue still takes another client, at the flooder's expense
+        limiter.submit(other, job(other), Some(&limits));
+        assert_eq!(metrics.connection_queue(), (8, 10_000 - 2 - 8 + 1));
+
+        release.cancel();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while done.load(Ordering::Relaxed) < 10 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(metrics.connection_queue().0, 0);
+        // Served on its turn rather than behind all of the flooder's queued connections
+        let started = started.lock().unwrap();
+        assert_eq!(started.len(), 10);
+        assert!(started[..4].contains(&other), "{started:?}");
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::server::{self, ProxyState};
+
+struct RunningListener {
+    local_addr: SocketAddr,
+    /// Stops this listener alone; a child of the server's shutdown token
+    token: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+/// The listeners a server accepts connections on, which can change while it runs
+///
+/// Listeners are keyed by their address as configured, which is also what their `listeners`
+/// options are looked up by. Removing one stops accepting on it; connections it already
+/// accepted are left to finish.
+pub struct ListenerSet {
+    /// Template for the state of each listener, filled in with its address
+    state: ProxyState,
+    connections_token: Arc<Mutex<CancellationToken>>,
+    shutdown_token: CancellationToken,
+    running: Mutex<HashMap<String, RunningListener>>,
+}
+
+impl ListenerSet {
+    pub fn new(
+        state: ProxyState,
+        connections_token: Arc<Mutex<CancellationToken>>,
+        shutdown_token: CancellationToken,
+    ) -> Self {
+        Self {
+            state,
+            connections_token,
+            shutdown_token,
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start accepting connections on an already bound listener, over TLS with `tls_acceptor`
+    pub fn start(
+        &self,
+        addr: String,
+        listener: TcpListener,
+        tls_acceptor: Option<TlsAcceptor>,
+    ) -> Result<SocketAddr, String> {
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| format!("Failed to start listener on {addr}: {e}"))?;
+        let mut running = self.running.lock().unwrap();
+        if running.contains_key(&addr) {
+            return Err(format!("Already listening on {addr}"));
+        }
+        let state = ProxyState {
+            listener: addr.clone(),
+            ..self.state.clone()
+        };
+        let token = self.shutdown_token.child_token();
+        let handle = tokio::spawn(server::run_listener(
+            listener,
+            addr.clone(),
+            tls_acceptor,
+            state,
+            self.connections_token.clone(),
+            token.clone(),
+        ));
+        running.insert(
+            addr,
+            RunningListener {
+                local_addr,
+                token,
+                handle,
+            },
+        );
+        Ok(local_addr)
+    }
+
+    /// Bind `addr` with its configured options and start accepting connections on it
+    pub async fn add(&self, addr: &str) -> Result<SocketAddr, String> {
+        if self.running.lock().unwrap().contains_key(addr) {
+            return Err(format!("Already listening on {addr}"));
+        }
+        let options = self
+            .state
+            .config
+            .read()
+            .await
+            .listeners
+            .get(addr)
+            .cloned()
+            .unwrap_or_default();
+        // Bad certificates are reported here rather than by a listener that never accepts
+        let tls_acceptor = server::listener_tls_acceptor(addr, &options)?;
+        let listener = server::bind_listener(addr, &options)
+            .await
+            .map_err(|e| format!("Failed to bind to {addr}: {e}"))?;
+        self.start(addr.to_string(), listener, tls_acceptor)
+    }
+
+    /// Stop accepting connections on `addr`, returning whether it was listened on
+    ///
+    /// Returns once the listening socket is closed.
+    pub async fn remove(&self, addr: &str) -> bool {
+        let Some(listener) = self.running.lock().unwrap().remove(addr) else {
+            return false;
+        };
+        listener.token.cancel();
+        let _ = listener.handle.await;
+        info!("Stopped listening on {}", addr);
+        true
+    }
+
+    /// Configured and bound address of every running listener, sorted by the former
+    pub fn addresses(&self) -> Vec<(String, SocketAddr)> {
+        let running = self.running.lock().unwrap();
+        let mut addresses: Vec<_> = running
+            .iter()
+            .map(|(addr, listener)| (addr.clone(), listener.local_addr))
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
+    /// Wait for every listener to stop, once the shutdown token is cancelled
+    pub async fn join(&self) {
+        let running = std::mem::take(&mut *self.running.lock().unwrap());
+        for listener in running.into_values() {
+            let _ = listener.handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::Breaker;
+    use crate::flow::{Flow, FlowExporter};
+    use crate::limiter::ConnectionLimiter;
+    use crate::metrics::Metrics;
+    use crate::resolver::{DnsCache, RoundRobin, UpstreamResolver};
+    use tokio::net::TcpStream;
+    use tokio::sync::RwLock;
+
+    fn test_set(shutdown_token: CancellationToken) -> ListenerSet {
+        let config =
+            json5::from_str(r#"{ switch: { default: "direct", rules: [] }, profiles: {} }"#)
+                .unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let state = ProxyState {
+            config: Arc::new(RwLock::new(config)),
+            metrics: metrics.clone(),
+            resolver: Arc::new(UpstreamResolver::new()),
+            round_robin: Arc::new(RoundRobin::new()),
+            dns: Arc::new(DnsCache::new()),
+            breaker: Arc::new(Breaker::new()),
+            route_override: Arc::new(crate::route_override::RouteOverride::new()),
+            hedges: Arc::new(crate::hedge::HedgeSlots::new()),
+            credentials: Arc::new(crate::credentials::CredentialCache::new()),
+            missing_profile_reload: Arc::new(tokio::sync::Mutex::new(None)),
+            limiter: Arc::new(ConnectionLimiter::new(metrics)),
+            flow_exporter: Arc::new(FlowExporter::new()),
+            flow: Arc::new(Flow::new()),
+            leases: Arc::default(),
+            closing: CancellationToken::new(),
+            listener: String::new(),
+            client_addrs: None,
+            connection_id: 0,
+            connect_timeout: None,
+            fallbacks: Vec::new(),
+            route_headers: None,
+            transparent: false,
+        };
+        ListenerSet::new(
+            state,
+            Arc::new(Mutex::new(CancellationToken::new())),
+            shutdown_token,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_listener_at_runtime() {
+        let shutdown_token = CancellationToken::new();
+        let set = test_set(shutdown_token.clone());
+
+        let first = set.add("127.0.0.1:0").await.unwrap();
+        assert!(set.add("127.0.0.1:0").await.is_err());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = set.start("second".to_string(), listener, None).unwrap();
+        assert_eq!(
+            set.addresses(),
+            vec![
+                ("127.0.0.1:0".to_string(), first),
+                ("second".to_string(), second)
+            ]
+        );
+        TcpStream::connect(first).await.unwrap();
+
+        assert!(set.remove("127.0.0.1:0").await);
+        assert!(!set.remove("127.0.0.1:0").await);
+        assert!(TcpStream::connect(first).await.is_err());
+        TcpStream::connect(second).await.unwrap();
+        assert_eq!(set.addresses().len(), 1);
+
+        shutdown_token.cancel();
+        set.join().await;
+        assert!(set.addresses().is_empty());
+        assert!(TcpStream::connect(second).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_rejects_listener_with_unreadable_certificate() {
+        let set = test_set(CancellationToken::new());
+        let options = crate::config::ListenerOptions {
+            tls_cert: Some("/nonexistent/cert.pem".into()),
+            tls_key: Some("/nonexistent/key.pem".into()),
+            ..Default::default()
+        };
+        set.state
+            .config
+            .write()
+            .await
+            .listeners
+            .insert("127.0.0.1:0".to_string(), options);
+
+        let err = set.add("127.0.0.1:0").await.unwrap_err();
+        assert!(err.contains("TLS"), "{err}");
+        assert!(set.addresses().is_empty());
+    }
+}
@@ -1,17 +1,10 @@
 use clap::Parser;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use tokio::sync::RwLock;
-use tokio_util::sync::CancellationToken;
+use proxy_twister::{Config, ConfigError, ProxyServer};
+use std::sync::Arc;
 use tracing::info;
 
-mod config;
-mod protocols;
-mod server;
-mod utils;
-
-use config::Config;
-use config::watcher::spawn_config_watcher;
+/// Listen address when neither the command line nor the config file names one
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:1080";
 
 /// SOCKS5 proxy switcher that routes traffic based on target host patterns
 #[derive(Parser, Debug)]
@@ -21,58 +14,103 @@ struct Args {
     #[arg(short, long)]
     config: String,
 
-    /// Addresses to listen on (can be specified multiple times)
-    #[arg(short = 'l', long = "listen", default_value = "127.0.0.1:1080")]
+    /// Addresses to listen on (can be specified multiple times, ports may be a range like 1080-1090);
+    /// replaces the config's `listen` list, defaults to 127.0.0.1:1080 when neither is set
+    #[arg(short = 'l', long = "listen")]
     addresses: Vec<String>,
+
+    /// Treat incoming connections as iptables-redirected traffic (Linux, `transparent` feature)
+    #[arg(long)]
+    transparent: bool,
+
+    /// Serve on the sockets passed by systemd socket activation (Linux, `systemd` feature)
+    #[arg(long)]
+    systemd: bool,
+
+    /// Address for the admin HTTP endpoint, or `unix:<path>` (disabled when not set)
+    #[arg(long = "admin")]
+    admin_address: Option<String>,
+
+    /// Print the effective config as JSON, with defaults filled in, and exit
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Show proxy passwords, credential headers and URL passwords in `--dump-config` output
+    /// instead of redacting them
+    #[arg(long, requires = "dump_config")]
+    unsafe_show_secrets: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
-
     let args = Args::parse();
-    let config_path = args.config.clone();
-    let config = Arc::new(RwLock::new(match Config::load(&config_path) {
+    let config = match Config::load(&args.config) {
         Ok(config) => config,
+        Err(ConfigError::Validation(problems)) => {
+            eprintln!("Configuration error in '{}':", args.config);
+            for problem in problems {
+                eprintln!("  - {problem}");
+            }
+            std::process::exit(1);
+        }
         Err(e) => {
             eprintln!("Configuration error: {e}");
             std::process::exit(1);
         }
-    }));
-
-    // Use a shared holder for the current CancellationToken
-    let connections_token = Arc::new(Mutex::new(CancellationToken::new()));
-    let watcher_token = CancellationToken::new(); // Separate token for graceful shutdown
-    let watcher_handle = spawn_config_watcher(
-        PathBuf::from(config_path.clone()),
-        config.clone(),
-        connections_token.clone(),
-        watcher_token.clone(),
-    );
+    };
+    if args.dump_config {
+        match config.to_json(args.unsafe_show_secrets) {
+            Ok(json) => println!("{json}"),
+            Err(e) => {
+                eprintln!("Failed to serialize the configuration: {e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    // Flushes exported spans when main returns
+    let _telemetry = match proxy_twister::telemetry::init(config.telemetry.as_ref()) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Telemetry error: {e}");
+            std::process::exit(1);
+        }
+    };
 
-    let mut join_handles = vec![watcher_handle];
+    // `-l` flags and sockets passed by systemd replace the config's `listen` list
+    let use_default_address =
+        args.addresses.is_empty() && config.listen.is_empty() && !args.systemd;
+    let mut builder = ProxyServer::builder(config)
+        .watch_config(&args.config)
+        .transparent(args.transparent)
+        .systemd_sockets(args.systemd)
+        .status_signal(true);
     for addr in &args.addresses {
-        let config = config.clone();
-        let token = connections_token.clone();
-        let shutdown_token = watcher_token.clone();
-        let addr = addr.clone();
-        join_handles.push(tokio::spawn(async move {
-            server::run_listener(addr, config, token, shutdown_token).await;
-        }));
+        builder = builder.listen(addr);
     }
+    if use_default_address {
+        builder = builder.listen(DEFAULT_LISTEN_ADDRESS);
+    }
+    if let Some(admin_address) = &args.admin_address {
+        builder = builder.admin(admin_address);
+    }
+    let server = match builder.build().await {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            eprintln!("Configuration error: {e}");
+            std::process::exit(1);
+        }
+    };
 
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to listen for Ctrl-C");
-    info!("Ctrl-C received, shutting down...");
-
-    // Cancel the watcher first to stop config reloading
-    watcher_token.cancel();
-    // Cancel all connections
-    connections_token.lock().unwrap().cancel();
+    let signal_server = server.clone();
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+        info!("Ctrl-C received, shutting down...");
+        signal_server.shutdown();
+    });
 
-    for handle in join_handles {
-        let _ = handle.await;
-    }
+    server.run().await;
     Ok(())
 }
@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Connect outcome counters for a single profile
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileCounters {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+/// Bytes relayed through a single profile since the last accounting reset
+#[derive(Debug, Default)]
+pub struct ByteMeter {
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+impl ByteMeter {
+    /// Count bytes sent from the client towards the upstream
+    pub fn add_up(&self, bytes: u64) {
+        self.up.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count bytes sent from the upstream back to the client
+    pub fn add_down(&self, bytes: u64) {
+        self.down.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Current totals as (up, down)
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.up.load(Ordering::Relaxed),
+            self.down.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Shared registry of runtime counters, keyed by profile name
+#[derive(Debug, Default)]
+pub struct Metrics {
+    profiles: Mutex<HashMap<String, ProfileCounters>>,
+    tags: Mutex<HashMap<String, u64>>,
+    bytes: Mutex<HashMap<String, Arc<ByteMeter>>>,
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    client_timeouts: AtomicU64,
+    connections_queued: AtomicU64,
+    connections_rejected: AtomicU64,
+}
+
+/// Keeps a client connection counted as active until dropped
+pub struct ActiveConnection(Arc<Metrics>, u64);
+
+impl ActiveConnection {
+    /// Sequence number of the connection since the server started, counting from 1
+    pub fn id(&self) -> u64 {
+        self.1
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record whether establishing the upstream connection for `profile` succeeded
+    pub fn record_connect(&self, profile: &str, success: bool) {
+        let mut profiles = self.profiles.lock().unwrap();
+        let counters = profiles.entry(profile.to_string()).or_default();
+        if success {
+            counters.successes += 1;
+        } else {
+            counters.failures += 1;
+        }
+    }
+
+    /// Count a newly accepted client connection, active until the returned guard is dropped
+    pub fn connection_opened(self: &Arc<Self>) -> ActiveConnection {
+        let id = self.connections_total.fetch_add(1, Ordering::Relaxed) + 1;
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(self.clone(), id)
+    }
+
+    /// Accepted client connections as (total, currently active)
+    pub fn connections(&self) -> (u64, u64) {
+        (
+            self.connections_total.load(Ordering::Relaxed),
+            self.connections_active.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Count a client that did not send a complete request in time
+    pub fn record_client_timeout(&self) {
+        self.client_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Clients that did not send a complete request in time
+    pub fn client_timeouts(&self) -> u64 {
+        self.client_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Record how many client connections are waiting for a free slot
+    pub fn set_connections_queued(&self, queued: usize) {
+        self.connections_queued
+            .store(queued as u64, Ordering::Relaxed);
+    }
+
+    /// Count a client connection dropped because the connection queue was full
+    pub fn record_connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Client connections as (currently waiting for a free slot, dropped from a full queue)
+    pub fn connection_queue(&self) -> (u64, u64) {
+        (
+            self.connections_queued.load(Ordering::Relaxed),
+            self.connections_rejected.load(Ordering::Relaxed),
+        )
+    }
+
+    /// One-line summary for quick operational checks
+    pub fn status_line(&self, uptime: Duration, config_hash: &str) -> String {
+        let (total, active) = self.connections();
+        let profiles: Vec<String> = self
+            .profiles()
+            .into_iter()
+            .map(|(name, c)| format!("{name} {}/{}", c.successes, c.failures))
+            .collect();
+        format!(
+            "Status: uptime {}s, connections {total} total / {active} active, config sha256 {config_hash}, profiles (ok/failed): {}",
+            uptime.as_secs(),
+            if profiles.is_empty() {
+                "none".to_string()
+            } else {
+                profiles.join(", ")
+            }
+        )
+    }
+
+    /// Record a connection routed by a rule carrying `tag`
+    pub fn record_tag(&self, tag: &str) {
+        *self
+            .tags
+            .lock()
+            .unwrap()
+            .entry(tag.to_string())
+            .or_default() += 1;
+    }
+
+    /// Number of connections routed by rules carrying `tag`
+    pub fn tag(&self, tag: &str) -> u64 {
+        self.tags
+            .lock()
+            .unwrap()
+            .get(tag)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The byte meter of `profile`, for relays to update as data passes
+    pub fn byte_meter(&self, profile: &str) -> Arc<ByteMeter> {
+        self.bytes
+            .lock()
+            .unwrap()
+            .entry(profile.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Bytes relayed through `profile` as (up, down)
+    pub fn bytes(&self, profile: &str) -> (u64, u64) {
+        self.bytes
+            .lock()
+            .unwrap()
+            .get(profile)
+            .map(|meter| meter.totals())
+            .unwrap_or_default()
+    }
+
+    /// Snapshot of all byte meters as (profile, up, down), sorted by profile name
+    pub fn byte_totals(&self) -> Vec<(String, u64, u64)> {
+        let mut totals: Vec<_> = self
+            .bytes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, meter)| {
+                let (up, down) = meter.totals();
+                (name.clone(), up, down)
+            })
+            .collect();
+        totals.sort();
+        totals
+    }
+
+    /// Zero every byte meter, e.g. when a new accounting period starts
+    pub fn reset_bytes(&self) {
+        // Keep the meters themselves: open relays hold on to them
+        for meter in self.bytes.lock().unwrap().values() {
+            meter.up.store(0, Ordering::Relaxed);
+            meter.down.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Snapshot of the counters for one profile
+    pub fn profile(&self, profile: &str) -> ProfileCounters {
+        self.profiles
+            .lock()
+            .unwrap()
+            .get(profile)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Snapshot of all profile counters, sorted by profile name
+    pub fn profiles(&self) -> Vec<(String, ProfileCounters)> {
+        let mut profiles: Vec<_> = self
+            .profiles
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, counters)| (name.clone(), *counters))
+            .collect();
+        profiles.sort_by(|a, b| a.0.cmp(&b.0));
+        profiles
+    }
+
+    /// Render all counters in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let (total, active) = self.connections();
+        out.push_str("# TYPE proxy_twister_connections_total counter\n");
+        let _ = writeln!(out, "proxy_twister_connections_total {total}");
+        out.push_str("# TYPE proxy_twister_connections_active gauge\n");
+        let _ = writeln!(out, "proxy_twister_connections_active {active}");
+        out.push_str("# TYPE proxy_twister_client_request_timeouts_total counter\n");
+        let _ = writeln!(
+            out,
+            "proxy_twister_client_request_timeouts_total {}",
+            self.client_timeouts()
+        );
+        let (queued, rejected) = self.connection_queue();
+        out.push_str("# TYPE proxy_twister_connection_queue_depth gauge\n");
+        let _ = writeln!(out, "proxy_twister_connection_queue_depth {queued}");
+        out.push_str("# TYPE proxy_twister_connections_rejected_total counter\n");
+        let _ = writeln!(out, "proxy_twister_connections_rejected_total {rejected}");
+        out.push_str("# TYPE proxy_twister_profile_connects_total counter\n");
+        for (name, counters) in self.profiles() {
+            let _ = writeln!(
+                out,
+                "proxy_twister_profile_connects_total{{profile=\"{name}\",outcome=\"success\"}} {}",
+                counters.successes
+            );
+            let _ = writeln!(
+                out,
+                "proxy_twister_profile_connects_total{{profile=\"{name}\",outcome=\"failure\"}} {}",
+                counters.failures
+            );
+        }
+
+        let mut tags: Vec<_> = self
+            .tags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tag, count)| (tag.clone(), *count))
+            .collect();
+        tags.sort();
+        out.push_str("# TYPE proxy_twister_tag_connections_total counter\n");
+        for (tag, count) in tags {
+            let _ = writeln!(
+                out,
+                "proxy_twister_tag_connections_total{{tag=\"{tag}\"}} {count}"
+            );
+        }
+
+        out.push_str("# TYPE proxy_twister_profile_bytes_total counter\n");
+        for (name, up, down) in self.byte_totals() {
+            let _ = writeln!(
+                out,
+                "proxy_twister_profile_bytes_total{{profile=\"{name}\",direction=\"up\"}} {up}"
+            );
+            let _ = writeln!(
+                out,
+                "proxy_twister_profile_bytes_total{{profile=\"{name}\",direction=\"down\"}} {down}"
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_connect() {
+        let metrics = Metrics::new();
+        metrics.record_connect("tor", true);
+        metrics.record_connect("tor", false);
+        metrics.record_connect("tor", false);
+
+        assert_eq!(
+            metrics.profile("tor"),
+            ProfileCounters {
+                successes: 1,
+                failures: 2
+            }
+        );
+        assert_eq!(metrics.profile("unknown"), ProfileCounters::default());
+        assert!(
+            metrics
+                .render()
+                .contains("profile=\"tor\",outcome=\"failure\"} 2")
+        );
+        metrics.record_tag("streaming");
+        assert_eq!(metrics.tag("streaming"), 1);
+        assert!(
+            metrics
+                .render()
+                .contains("proxy_twister_tag_connections_total{tag=\"streaming\"} 1")
+        );
+    }
+
+    #[test]
+    fn test_status_line_tracks_active_connections() {
+        let metrics = Arc::new(Metrics::new());
+        let first = metrics.connection_opened();
+        let second = metrics.connection_opened();
+        assert_eq!((first.id(), second.id()), (1, 2));
+        drop(first);
+        metrics.record_connect("tor", true);
+
+        assert_eq!(metrics.connections(), (2, 1));
+        let line = metrics.status_line(Duration::from_secs(90), "abc123");
+        assert_eq!(
+            line,
+            "Status: uptime 90s, connections 2 total / 1 active, config sha256 abc123, profiles (ok/failed): tor 1/0"
+        );
+    }
+}
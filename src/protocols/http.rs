@@ -5,31 +5,286 @@ use http_body_util::{BodyExt, Full};
 use hyper::{Method, Request, StatusCode, Uri};
 use hyper_rustls::HttpsConnectorBuilder;
 use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::{HttpConnector, HttpInfo};
 use hyper_util::rt::TokioExecutor;
 use std::collections::HashMap;
 use std::io;
 use std::str::FromStr;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
 use tokio::time::{Duration, timeout};
 use tracing::{error, trace};
 
+use crate::config::{AddressFamily, TlsOptions};
+use crate::flow::UpstreamAddrs;
+use crate::resolver::DirectResolver;
+
 pub const HTTP_SERVER_ERROR: &str = "HTTP/1.1 500 Internal Server Error\r\n\r\n";
+pub const HTTP_GATEWAY_TIMEOUT: &str = "HTTP/1.1 504 Gateway Timeout\r\n\r\n";
+pub const HTTP_CONTINUE: &str = "HTTP/1.1 100 Continue\r\n\r\n";
+
+/// How long a client may stay silent on each read of its request before it is dropped
+pub const CLIENT_READ_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub struct HttpRequest {
     pub method: String,
     pub target: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Vec<u8>, // Add body field for POST/PUT requests
 }
 
-pub async fn parse_request(stream: &mut TcpStream) -> io::Result<HttpRequest> {
-    let mut reader = BufReader::new(stream);
+impl HttpRequest {
+    /// Body size announced by `Content-Length`, 0 when absent or invalid
+    pub fn content_length(&self) -> usize {
+        self.headers
+            .get("content-length")
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Whether the client waits for `100 Continue` before sending the body, which has not
+    /// been read yet
+    pub fn body_pending(&self) -> bool {
+        self.expects_continue() && self.body.len() < self.content_length()
+    }
+
+    /// Why the body's length cannot be told reliably, if so
+    ///
+    /// Requests with several `Content-Length` headers or values, an invalid one, or one next to
+    /// `Transfer-Encoding` could be read differently by the upstream (request smuggling).
+    pub fn framing_problem(&self) -> Option<&'static str> {
+        let mut lengths = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value);
+        let length = lengths.next()?;
+        if lengths.next().is_some() || length.contains(',') {
+            return Some("Multiple Content-Length values");
+        }
+        if length.trim().parse::<usize>().is_err() {
+            return Some("Invalid Content-Length");
+        }
+        if self.headers.contains_key("transfer-encoding") {
+            return Some("Content-Length together with Transfer-Encoding");
+        }
+        None
+    }
+
+    /// Whether the client sent `Expect: 100-continue`
+    pub fn expects_continue(&self) -> bool {
+        self.headers
+            .get("expect")
+            .is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether the body is sent with `Transfer-Encoding: chunked`
+    pub fn is_chunked(&self) -> bool {
+        self.headers
+            .get("transfer-encoding")
+            .is_some_and(|encoding| {
+                encoding
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+            })
+    }
+
+    /// Point an absolute-form target (`http://host:port/path`) at `authority` instead,
+    /// leaving the `Host` header as the client sent it
+    pub fn set_authority(&mut self, authority: &str) {
+        let Some((scheme, rest)) = self.target.split_once("://") else {
+            return;
+        };
+        let path = rest.find(['/', '?']).map_or("", |start| &rest[start..]);
+        self.target = format!("{scheme}://{authority}{path}");
+    }
+}
+
+/// Request headers in the order and case the client sent them, looked up case-insensitively
+///
+/// Forwarding them as they are avoids changing a request's fingerprint, which some strict
+/// origins and WAFs check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of the first header called `name`
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Add a header after the others, even if one with the same name is already present
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    /// Set the only value of `name`, in place of the first header of that name if any
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let (name, value) = (name.into(), value.into());
+        let Some(first) = self
+            .0
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(&name))
+        else {
+            self.0.push((name, value));
+            return;
+        };
+        let rest = self.0.split_off(first + 1);
+        self.0.extend(
+            rest.into_iter()
+                .filter(|(key, _)| !key.eq_ignore_ascii_case(&name)),
+        );
+        self.0[first] = (name, value);
+    }
+
+    /// Remove every header called `name`, returning the first one's value
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let first = self.get(name).cloned();
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        first
+    }
+
+    /// Names and values, in order
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, String)> {
+        self.0.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a (String, String);
+    type IntoIter = std::slice::Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Into<String>, V: Into<String>, const N: usize> From<[(K, V); N]> for Headers {
+    fn from(headers: [(K, V); N]) -> Self {
+        Self(
+            headers
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+/// Hop-by-hop headers from RFC 7230 section 6.1, plus the proxy-specific ones
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Remove hop-by-hop headers, including any listed in the `Connection` header
+///
+/// `Transfer-Encoding` is only removed when the body is re-framed before forwarding;
+/// otherwise the client's chunked stream is relayed as-is and must keep its header.
+pub fn strip_hop_by_hop(headers: &mut Headers, reframed: bool) {
+    if let Some(connection) = headers.get("connection").cloned() {
+        for token in connection.split(',') {
+            let token = token.trim();
+            if !token.is_empty() && !token.eq_ignore_ascii_case("host") {
+                headers.remove(token);
+            }
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+    if reframed {
+        headers.remove("transfer-encoding");
+    }
+}
+
+/// Methods a client's first bytes are compared against to tell HTTP from other protocols
+const KNOWN_METHODS: &[&str] = &[
+    "GET",
+    "HEAD",
+    "POST",
+    "PUT",
+    "DELETE",
+    "CONNECT",
+    "OPTIONS",
+    "TRACE",
+    "PATCH",
+    // WebDAV
+    "PROPFIND",
+    "PROPPATCH",
+    "MKCOL",
+    "COPY",
+    "MOVE",
+    "LOCK",
+    "UNLOCK",
+    "REPORT",
+    "SEARCH",
+];
+
+/// Whether `start`, the first bytes a client sent, begin with a known method and a space
+///
+/// Bytes that could still grow into a known method, such as a lone `G`, count as HTTP too.
+pub fn starts_with_method(start: &[u8]) -> bool {
+    match start.iter().position(|&byte| byte == b' ') {
+        Some(end) => KNOWN_METHODS
+            .iter()
+            .any(|method| method.as_bytes() == &start[..end]),
+        None => KNOWN_METHODS
+            .iter()
+            .any(|method| method.as_bytes().starts_with(start)),
+    }
+}
+
+/// Serialize a request back into HTTP/1.1 wire format, headers in their original case and order
+pub fn serialize_request(request: &HttpRequest) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, request.target);
+    for (k, v) in &request.headers {
+        head.push_str(&format!("{k}: {v}\r\n"));
+    }
+    head.push_str("\r\n");
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    bytes
+}
+
+#[cfg(test)]
+pub async fn parse_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<HttpRequest> {
+    read_request(&mut BufReader::new(stream)).await
+}
+
+/// Like `parse_request`, leaving any bytes after the request (pipelining) in `reader`
+#[cfg(test)]
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<HttpRequest> {
+    let mut request = read_request_head(reader).await?;
+    request.body = read_body(reader, request.content_length()).await?;
+    Ok(request)
+}
+
+/// Read a request's line and headers, leaving its body in `reader`
+pub async fn read_request_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<HttpRequest> {
     let mut first_line = String::new();
 
     // Add timeout for reading the first line to prevent hanging
-    match timeout(Duration::from_secs(30), reader.read_line(&mut first_line)).await {
+    match timeout(CLIENT_READ_TIMEOUT, reader.read_line(&mut first_line)).await {
         Ok(Ok(_)) => {}
         Ok(Err(e)) => return Err(e),
         Err(_) => {
@@ -50,13 +305,12 @@ pub async fn parse_request(stream: &mut TcpStream) -> io::Result<HttpRequest> {
 
     let method = parts[0].to_string();
     let target = parts[1].to_string();
-    let mut headers = HashMap::new();
-    let mut content_length = 0;
+    let mut headers = Headers::new();
 
     // Read headers with timeout
     loop {
         let mut line = String::new();
-        match timeout(Duration::from_secs(30), reader.read_line(&mut line)).await {
+        match timeout(CLIENT_READ_TIMEOUT, reader.read_line(&mut line)).await {
             Ok(Ok(_)) => {}
             Ok(Err(e)) => return Err(e),
             Err(_) => {
@@ -72,26 +326,34 @@ pub async fn parse_request(stream: &mut TcpStream) -> io::Result<HttpRequest> {
         }
 
         if let Some((key, value)) = line.split_once(':') {
-            let key = key.trim().to_lowercase();
-            let value = value.trim().to_string();
-
-            if key == "content-length" {
-                content_length = value.parse().unwrap_or_else(|_| {
-                    trace!("Invalid content-length value: {}", value);
-                    0
-                });
+            let key = key.trim();
+            let value = value.trim();
+
+            if key.eq_ignore_ascii_case("content-length") && value.parse::<usize>().is_err() {
+                trace!("Invalid content-length value: {}", value);
             }
 
-            headers.insert(key, value);
+            headers.append(key, value);
         }
     }
 
-    // Read body if present with timeout
-    let mut body = Vec::new();
+    Ok(HttpRequest {
+        method,
+        target,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Read a request body of `content_length` bytes
+pub async fn read_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    content_length: usize,
+) -> io::Result<Vec<u8>> {
+    let mut body = vec![0u8; content_length];
     if content_length > 0 {
-        let mut buffer = vec![0u8; content_length];
-        match timeout(Duration::from_secs(30), reader.read_exact(&mut buffer)).await {
-            Ok(Ok(_)) => body = buffer,
+        match timeout(CLIENT_READ_TIMEOUT, reader.read_exact(&mut body)).await {
+            Ok(Ok(_)) => {}
             Ok(Err(e)) => return Err(e),
             Err(_) => {
                 return Err(io::Error::new(
@@ -101,17 +363,92 @@ pub async fn parse_request(stream: &mut TcpStream) -> io::Result<HttpRequest> {
             }
         }
     }
+    Ok(body)
+}
 
-    Ok(HttpRequest {
-        method,
-        target,
-        headers,
-        body,
-    })
+/// Largest decoded chunked request body accepted from a client
+const MAX_CHUNKED_BODY: usize = 64 * 1024 * 1024;
+
+/// A client's chunked request body is longer than `MAX_CHUNKED_BODY`
+#[derive(Debug)]
+pub struct BodyTooLarge;
+
+impl std::fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request body is larger than {MAX_CHUNKED_BODY} bytes")
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+impl BodyTooLarge {
+    /// Whether `error` was caused by a body that is too large
+    pub fn is_cause_of(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<Self>())
+    }
 }
 
-pub async fn handle_connect(
-    stream: &mut TcpStream,
+/// Read a chunked request body, returning it decoded; trailers are dropped
+///
+/// Bodies larger than `MAX_CHUNKED_BODY` fail with a [`BodyTooLarge`] error.
+pub async fn read_chunked_body<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let decode = async {
+        let mut body = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(invalid("Connection closed in the middle of a chunked body"));
+            }
+            let size = String::from_utf8_lossy(&line);
+            let size = size.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| invalid("Invalid chunk size in request"))?;
+            if size == 0 {
+                // Trailers, terminated by an empty line
+                loop {
+                    let mut line = Vec::new();
+                    if reader.read_until(b'\n', &mut line).await? == 0 {
+                        return Err(invalid("Connection closed in the middle of a chunked body"));
+                    }
+                    if line.trim_ascii().is_empty() {
+                        return Ok(body);
+                    }
+                }
+            }
+            // The size is the client's to choose, so the body only grows by what is received
+            if body
+                .len()
+                .checked_add(size)
+                .is_none_or(|total| total > MAX_CHUNKED_BODY)
+            {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, BodyTooLarge));
+            }
+            let read = (&mut *reader)
+                .take(size as u64)
+                .read_to_end(&mut body)
+                .await?;
+            if read < size {
+                return Err(invalid("Connection closed in the middle of a chunked body"));
+            }
+            let mut crlf = [0u8; 2];
+            reader.read_exact(&mut crlf).await?;
+            if &crlf != b"\r\n" {
+                return Err(invalid("Chunk data is not followed by CRLF"));
+            }
+        }
+    };
+    match timeout(CLIENT_READ_TIMEOUT, decode).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Timeout reading HTTP body",
+        )),
+    }
+}
+
+pub async fn handle_connect<S: AsyncWrite + Unpin>(
+    stream: &mut S,
     request: HttpRequest,
 ) -> io::Result<(String, u16)> {
     if request.method != "CONNECT" {
@@ -124,38 +461,139 @@ pub async fn handle_connect(
         ));
     }
 
-    let target_parts: Vec<&str> = request.target.split(':').collect();
-    if target_parts.len() != 2 {
-        stream
-            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
-            .await?;
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid target format",
-        ));
+    // Some tools send an absolute URI (or a path) where CONNECT expects `host:port`
+    if request.target.contains("://") || request.target.starts_with('/') {
+        let reason = format!(
+            "CONNECT expects an authority-form target (host:port), got '{}'",
+            request.target
+        );
+        return Err(bad_request(stream, &reason).await);
+    }
+
+    let (host, port) = match crate::utils::parse_authority(&request.target) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let reason = format!("CONNECT target '{}' is invalid: {e}", request.target);
+            return Err(bad_request(stream, &reason).await);
+        }
+    };
+    match port {
+        Some(port) if port != 0 && !host.is_empty() => Ok((host, port)),
+        _ => {
+            let reason = format!(
+                "CONNECT target '{}' must be host:port with a non-zero port",
+                request.target
+            );
+            Err(bad_request(stream, &reason).await)
+        }
+    }
+}
+
+/// Plain-text error response closing the connection, e.g. `status` = "400 Bad Request"
+pub fn error_response(status: &str, reason: &str) -> String {
+    let body = format!("{reason}\n");
+    format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Answer with `400 Bad Request` explaining `reason`, returning the matching error
+pub async fn bad_request<S: AsyncWrite + Unpin>(stream: &mut S, reason: &str) -> io::Error {
+    let response = error_response("400 Bad Request", reason);
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        return e;
+    }
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Longest status or header line accepted from an upstream proxy's CONNECT response
+const MAX_PROXY_RESPONSE_LINE: u64 = 8 * 1024;
+
+/// Largest total size of the header lines following the status line of that response
+const MAX_PROXY_RESPONSE_HEADERS: usize = 64 * 1024;
+
+/// The upstream answered with something that is not an HTTP response (e.g. a captive portal)
+#[derive(Debug)]
+pub struct InvalidUpstreamResponse(String);
+
+impl std::fmt::Display for InvalidUpstreamResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upstream returned an invalid response: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidUpstreamResponse {}
+
+impl InvalidUpstreamResponse {
+    /// Whether `error` was caused by an invalid upstream response
+    pub fn is_cause_of(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<Self>())
     }
+}
 
-    let host = target_parts[0].to_string();
-    let port = target_parts[1]
-        .parse::<u16>()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid port number"))?;
+fn invalid_upstream_response(line: &str) -> io::Error {
+    // Enough of the junk to recognise it in logs, without control characters
+    let preview: String = line.trim_end().chars().take(64).collect();
+    let detail = if preview.is_empty() {
+        "empty status line".to_string()
+    } else {
+        format!("{preview:?}")
+    };
+    io::Error::new(io::ErrorKind::InvalidData, InvalidUpstreamResponse(detail))
+}
 
-    Ok((host, port))
+/// Read one CRLF-terminated line of at most `MAX_PROXY_RESPONSE_LINE` bytes
+async fn read_proxy_response_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let mut raw = Vec::new();
+    (&mut *reader)
+        .take(MAX_PROXY_RESPONSE_LINE)
+        .read_until(b'\n', &mut raw)
+        .await?;
+    let line = String::from_utf8_lossy(&raw).into_owned();
+    if !raw.ends_with(b"\n") {
+        return Err(invalid_upstream_response(&line));
+    }
+    Ok(line)
 }
 
-pub async fn forward_to_proxy(
+/// Status code of an `HTTP/1.x NNN reason` line
+fn parse_status_line(line: &str) -> Option<u16> {
+    let rest = line.strip_prefix("HTTP/1.")?;
+    let (version, rest) = rest.split_at_checked(1)?;
+    let rest = rest.strip_prefix(' ')?;
+    let code = rest.get(..3)?;
+    let followed_by_reason = rest[3..].starts_with([' ', '\r', '\n']);
+    if !matches!(version, "0" | "1")
+        || !code.bytes().all(|b| b.is_ascii_digit())
+        || !followed_by_reason
+    {
+        return None;
+    }
+    code.parse().ok()
+}
+
+/// Ask the HTTP proxy at the other end of `stream` to CONNECT to `target_host:target_port`
+///
+/// `stream` may itself be a tunnel through other proxies, which is how chains are built.
+pub async fn connect_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     target_host: &str,
     target_port: u16,
-    proxy_host: &str,
-    proxy_port: u16,
     auth: Option<(&str, &str)>,
-) -> io::Result<TcpStream> {
-    let mut stream = TcpStream::connect(format!("{proxy_host}:{proxy_port}")).await?;
-
+    user_agent: &str,
+) -> io::Result<()> {
+    let authority = crate::utils::join_host_port(target_host, target_port);
     let mut request = format!(
-        "CONNECT {target_host}:{target_port} HTTP/1.1\r\n\
-         Host: {target_host}:{target_port}\r\n"
+        "CONNECT {authority} HTTP/1.1\r\n\
+         Host: {authority}\r\n"
     );
+    if !user_agent.is_empty() {
+        request.push_str(&format!("User-Agent: {user_agent}\r\n"));
+    }
 
     // Add Proxy-Authorization if credentials are provided
     if let Some((username, password)) = auth {
@@ -170,10 +608,17 @@ pub async fn forward_to_proxy(
     stream.write_all(request.as_bytes()).await?;
 
     trace!("Waiting for proxy response with timeout");
-    let mut reader = BufReader::new(&mut stream);
-    let mut response = String::new();
-    match timeout(Duration::from_secs(10), reader.read_line(&mut response)).await {
-        Ok(Ok(_)) => trace!("Received proxy response: {}", response.trim()),
+    let mut reader = BufReader::new(stream);
+    let response = match timeout(
+        Duration::from_secs(10),
+        read_proxy_response_line(&mut reader),
+    )
+    .await
+    {
+        Ok(Ok(response)) => {
+            trace!("Received proxy response: {}", response.trim());
+            response
+        }
         Ok(Err(e)) => {
             error!("Failed to read proxy response header: {}", e);
             return Err(e);
@@ -185,24 +630,51 @@ pub async fn forward_to_proxy(
                 "Timed out while waiting for proxy response",
             ));
         }
-    }
+    };
 
-    if !response.starts_with("HTTP/1.1 200") && !response.starts_with("HTTP/1.0 200") {
+    let Some(status) = parse_status_line(&response) else {
+        let e = invalid_upstream_response(&response);
+        error!("Proxy connection failed: {e}");
+        return Err(e);
+    };
+    if status != 200 {
         error!("Proxy connection failed: {}", response.trim());
-        return Err(io::Error::other(format!(
-            "Proxy connection failed: {}",
-            response.trim()
-        )));
+        // Rejected credentials are told apart, so that fresh ones can be fetched
+        let kind = if status == 407 {
+            io::ErrorKind::PermissionDenied
+        } else {
+            io::ErrorKind::Other
+        };
+        return Err(io::Error::new(
+            kind,
+            format!("Proxy connection failed: {}", response.trim()),
+        ));
     }
 
     trace!("Reading and discarding proxy response headers");
+    let mut header_bytes = 0;
     loop {
-        let mut line = String::new();
-        match timeout(Duration::from_secs(10), reader.read_line(&mut line)).await {
-            Ok(Ok(_)) => {
+        match timeout(
+            Duration::from_secs(10),
+            read_proxy_response_line(&mut reader),
+        )
+        .await
+        {
+            Ok(Ok(line)) => {
                 if line.trim().is_empty() {
                     break;
                 }
+                header_bytes += line.len();
+                if header_bytes > MAX_PROXY_RESPONSE_HEADERS {
+                    let e = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        InvalidUpstreamResponse(format!(
+                            "headers longer than {MAX_PROXY_RESPONSE_HEADERS} bytes"
+                        )),
+                    );
+                    error!("Proxy connection failed: {e}");
+                    return Err(e);
+                }
                 trace!("Proxy response header: {}", line.trim());
             }
             Ok(Err(e)) => {
@@ -219,25 +691,26 @@ pub async fn forward_to_proxy(
         }
     }
 
-    Ok(stream)
+    Ok(())
 }
 
-pub async fn forward_http_request(
+/// Send a plain-HTTP request to an HTTP proxy over an open connection, returning the bytes sent
+pub async fn write_proxy_request<S: AsyncWrite + Unpin>(
+    stream: &mut S,
     request: &HttpRequest,
     target_host: &str,
     target_port: u16,
-    proxy_host: &str,
-    proxy_port: u16,
     auth: Option<(&str, &str)>,
-) -> io::Result<TcpStream> {
-    let mut stream = TcpStream::connect(format!("{proxy_host}:{proxy_port}")).await?;
-
+) -> io::Result<u64> {
     // For HTTP proxy, modify the request
     let mut modified_request = format!("{} {} HTTP/1.1\r\n", request.method, request.target);
 
-    // Copy original headers
+    // Copy original headers; the body is framed anew below
     for (key, value) in &request.headers {
-        if key != "proxy-connection" {
+        if !key.eq_ignore_ascii_case("proxy-connection")
+            && !key.eq_ignore_ascii_case("content-length")
+            && !key.eq_ignore_ascii_case("transfer-encoding")
+        {
             modified_request.push_str(&format!("{key}: {value}\r\n"));
         }
     }
@@ -251,12 +724,18 @@ pub async fn forward_http_request(
 
     // Ensure host header is present
     if !request.headers.contains_key("host") {
-        modified_request.push_str(&format!("Host: {target_host}:{target_port}\r\n"));
+        let authority = crate::utils::join_host_port(target_host, target_port);
+        modified_request.push_str(&format!("Host: {authority}\r\n"));
     }
 
-    // Add content length if body present
-    if !request.body.is_empty() {
-        modified_request.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    // A body still to come after `100 Continue` has the length the client announced
+    let length = if request.body_pending() {
+        request.content_length()
+    } else {
+        request.body.len()
+    };
+    if length > 0 || request.headers.contains_key("content-length") {
+        modified_request.push_str(&format!("Content-Length: {length}\r\n"));
     }
 
     modified_request.push_str("\r\n");
@@ -269,15 +748,267 @@ pub async fn forward_http_request(
         stream.write_all(&request.body).await?;
     }
 
-    Ok(stream)
+    Ok((modified_request.len() + request.body.len()) as u64)
+}
+
+/// How the connection a response arrived on can be used afterwards
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResponseEnd {
+    /// The response was delimited; the next request may follow on the same connection
+    KeepAlive,
+    /// The upstream closes the connection after this response
+    Close,
+    /// `101 Switching Protocols`: both sides now speak another protocol
+    Upgraded,
+}
+
+/// Read one line (up to and including `\n`) into `out`, failing on EOF
+async fn read_line_into<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    out: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let n = reader.read_until(b'\n', out).await?;
+    if n == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed in the middle of a response",
+        ));
+    }
+    Ok(n)
+}
+
+/// Copy exactly `len` bytes from `reader` to `writer`
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, len: u64) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(&mut (&mut *reader).take(len), writer).await?;
+    if copied < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed in the middle of a response body",
+        ));
+    }
+    Ok(())
+}
+
+/// Relay a chunked body, including the last chunk and any trailers, returning its size on the wire
+async fn relay_chunked<R, W>(upstream: &mut R, client: &mut W) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut relayed = 0;
+    loop {
+        let mut line = Vec::new();
+        read_line_into(upstream, &mut line).await?;
+        client.write_all(&line).await?;
+        relayed += line.len() as u64;
+        let size = String::from_utf8_lossy(&line);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk size in response")
+        })?;
+        if size == 0 {
+            // Trailers, terminated by an empty line
+            loop {
+                let mut line = Vec::new();
+                read_line_into(upstream, &mut line).await?;
+                client.write_all(&line).await?;
+                relayed += line.len() as u64;
+                if line.trim_ascii().is_empty() {
+                    return Ok(relayed);
+                }
+            }
+        }
+        // The chunk data and its trailing CRLF
+        copy_exact(upstream, client, size + 2).await?;
+        relayed += size + 2;
+    }
+}
+
+/// Relay exactly one response from `upstream` to `client`, following HTTP/1.1 message framing
+///
+/// Interim `1xx` responses are passed through along with the final one. Returns how the
+/// upstream connection may be used next and the number of bytes relayed.
+#[cfg(test)]
+pub async fn relay_response<R, W>(
+    upstream: &mut R,
+    client: &mut W,
+    head_request: bool,
+) -> io::Result<(ResponseEnd, u64)>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (_, end, relayed) = relay_response_with(upstream, client, head_request, "").await?;
+    Ok((end, relayed))
+}
+
+/// Wait for the upstream's answer to a request sent with `Expect: 100-continue` but no body yet
+///
+/// Interim responses are relayed to the client up to `100 Continue`, after which the body
+/// should be sent; `None` is returned then. Should the final response (or `101`) come first
+/// instead, its head is returned, read but not relayed, and the body must not be sent. Also
+/// returns the number of bytes relayed.
+pub async fn await_continue<R, W>(
+    upstream: &mut R,
+    client: &mut W,
+) -> io::Result<(Option<Vec<u8>>, u64)>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut relayed = 0;
+    loop {
+        let mut head = Vec::new();
+        read_line_into(upstream, &mut head).await?;
+        let status = parse_status_line(&String::from_utf8_lossy(&head))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid status line"))?;
+        loop {
+            let start = head.len();
+            read_line_into(upstream, &mut head).await?;
+            if head[start..].trim_ascii().is_empty() {
+                break;
+            }
+        }
+        if status == 101 || status >= 200 {
+            return Ok((Some(head), relayed));
+        }
+        client.write_all(&head).await?;
+        relayed += head.len() as u64;
+        if status == 100 {
+            return Ok((None, relayed));
+        }
+    }
+}
+
+/// Like `relay_response`, adding `extra_headers` (complete `Name: value\r\n` lines) to the
+/// final response's head, whose status code is returned as well
+pub async fn relay_response_with<R, W>(
+    upstream: &mut R,
+    client: &mut W,
+    head_request: bool,
+    extra_headers: &str,
+) -> io::Result<(u16, ResponseEnd, u64)>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut relayed = 0;
+    loop {
+        let mut head = Vec::new();
+        read_line_into(upstream, &mut head).await?;
+        let status: u16 = String::from_utf8_lossy(&head)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid status line"))?;
+
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut close = false;
+        loop {
+            let start = head.len();
+            read_line_into(upstream, &mut head).await?;
+            let line = String::from_utf8_lossy(&head[start..]).to_string();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim().to_ascii_lowercase();
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.parse::<u64>().ok(),
+                    "transfer-encoding" => chunked = value.contains("chunked"),
+                    "connection" => close = value.split(',').any(|t| t.trim() == "close"),
+                    _ => {}
+                }
+            }
+        }
+        if !(100..=199).contains(&status) && !extra_headers.is_empty() {
+            // Ahead of the blank line ending the head, whether it is "\r\n" or a bare "\n"
+            let blank = if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\r\n") {
+                2
+            } else {
+                1
+            };
+            let at = head.len() - blank;
+            head.splice(at..at, extra_headers.bytes());
+        }
+        client.write_all(&head).await?;
+        relayed += head.len() as u64;
+
+        match status {
+            101 => return Ok((status, ResponseEnd::Upgraded, relayed)),
+            100..=199 => continue,
+            _ => {}
+        }
+        let end = if close {
+            ResponseEnd::Close
+        } else {
+            ResponseEnd::KeepAlive
+        };
+        if head_request || status == 204 || status == 304 {
+            return Ok((status, end, relayed));
+        }
+        if chunked {
+            relayed += relay_chunked(upstream, client).await?;
+            return Ok((status, end, relayed));
+        }
+        if let Some(len) = content_length {
+            copy_exact(upstream, client, len).await?;
+            return Ok((status, end, relayed + len));
+        }
+        // Neither length nor chunking: the body runs until the upstream closes
+        relayed += tokio::io::copy(upstream, client).await?;
+        return Ok((status, ResponseEnd::Close, relayed));
+    }
+}
+
+/// Time limits of a request sent with `send_http_request`, none when unset
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExchangeTimeouts {
+    /// For connecting to the origin
+    pub connect: Option<Duration>,
+    /// For the whole exchange, connecting included, until the complete response is in
+    pub response: Option<Duration>,
+}
+
+/// Whether `error` or one of its causes is an I/O timeout
+fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cause = Some(error);
+    while let Some(error) = cause {
+        if error
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        cause = error.source();
+    }
+    false
 }
 
 // Helper function to send HTTP requests using hyper
+//
+// With `http2`, HTTPS origins are offered HTTP/2 through ALPN; the response comes back the same
+// way whichever version was negotiated. Running out of one of the `timeouts` fails with
+// `TimedOut`. The addresses of the connection the response came over are returned along with it.
 pub async fn send_http_request(
     request: &HttpRequest,
     target_host: &str,
     port: u16,
-) -> io::Result<(StatusCode, HashMap<String, String>, Bytes)> {
+    tls: &TlsOptions,
+    resolver: DirectResolver,
+    http2: bool,
+    timeouts: ExchangeTimeouts,
+) -> io::Result<(
+    StatusCode,
+    HashMap<String, String>,
+    Bytes,
+    Option<UpstreamAddrs>,
+)> {
     // Create the URI - use HTTPS for port 443 or if request target starts with https://
     let uri_string =
         if request.target.starts_with("http://") || request.target.starts_with("https://") {
@@ -290,7 +1021,8 @@ pub async fn send_http_request(
             };
             // Use HTTPS for port 443, HTTP for other ports
             let scheme = if port == 443 { "https" } else { "http" };
-            format!("{scheme}://{target_host}:{port}{path}")
+            let authority = crate::utils::join_host_port(target_host, port);
+            format!("{scheme}://{authority}{path}")
         };
 
     let uri = Uri::from_str(&uri_string)
@@ -303,15 +1035,16 @@ pub async fn send_http_request(
     let mut req_builder = Request::builder().method(method).uri(uri);
 
     // Add all headers
+    // hyper sends header names in lowercase, only their order survives
     for (name, value) in &request.headers {
-        if !name.starts_with("proxy-") {
+        if !name.to_ascii_lowercase().starts_with("proxy-") {
             req_builder = req_builder.header(name, value);
         }
     }
 
     // Ensure host header is present
     if !request.headers.contains_key("host") {
-        req_builder = req_builder.header("host", format!("{target_host}:{port}"));
+        req_builder = req_builder.header("host", crate::utils::join_host_port(target_host, port));
     }
 
     // Create the request body
@@ -325,39 +1058,533 @@ pub async fn send_http_request(
         )
     })?;
 
+    // Binding to the unspecified address of one family makes hyper skip the other family, even
+    // for IP literals, which are not resolved
+    let family = resolver.family;
+    let mut http_connector = HttpConnector::new_with_resolver(resolver);
+    http_connector.enforce_http(false);
+    http_connector.set_connect_timeout(timeouts.connect);
+    http_connector.set_local_address(match family {
+        AddressFamily::Ipv4 => Some(std::net::Ipv4Addr::UNSPECIFIED.into()),
+        AddressFamily::Ipv6 => Some(std::net::Ipv6Addr::UNSPECIFIED.into()),
+        AddressFamily::Dual => None,
+    });
+
     // Create a hyper client with HTTPS support
-    let https_connector = HttpsConnectorBuilder::new()
-        .with_native_roots()
-        .map_err(|e| io::Error::other(format!("Failed to load native roots: {e}")))?
+    let builder = HttpsConnectorBuilder::new()
+        .with_tls_config(super::tls::client_config(tls)?)
         .https_or_http()
-        .enable_http1()
-        .build();
+        .enable_http1();
+    let https_connector = if http2 {
+        builder.enable_http2().wrap_connector(http_connector)
+    } else {
+        builder.wrap_connector(http_connector)
+    };
     let client = Client::builder(TokioExecutor::new()).build::<_, Full<Bytes>>(https_connector);
 
-    // Send the request
-    trace!("Sending HTTP request to {target_host}:{port}");
-    let res = client
-        .request(req)
-        .await
-        .map_err(|e| io::Error::other(format!("Failed to send request: {e}")))?;
+    let exchange = async {
+        // Send the request
+        trace!("Sending HTTP request to {target_host}:{port}");
+        let res = client.request(req).await.map_err(|e| {
+            let kind = if is_timeout(&e) {
+                io::ErrorKind::TimedOut
+            } else {
+                io::ErrorKind::Other
+            };
+            io::Error::new(kind, format!("Failed to send request: {e}"))
+        })?;
 
-    // Extract the status code
-    let status = res.status();
+        // Extract the status code
+        let status = res.status();
+        let version = res.version();
+        let upstream = res
+            .extensions()
+            .get::<HttpInfo>()
+            .map(|info| UpstreamAddrs {
+                egress: info.local_addr(),
+                remote: info.remote_addr(),
+            });
 
-    // Extract the headers
-    let mut headers = HashMap::new();
-    for (name, value) in res.headers() {
-        if let Ok(value_str) = value.to_str() {
-            headers.insert(name.to_string(), value_str.to_string());
+        // Extract the headers
+        let mut headers = HashMap::new();
+        for (name, value) in res.headers() {
+            if let Ok(value_str) = value.to_str() {
+                headers.insert(name.to_string(), value_str.to_string());
+            }
         }
+
+        // Collect the body
+        let body_bytes = res
+            .collect()
+            .await
+            .map_err(|e| io::Error::other(format!("Failed to collect response body: {e}")))?
+            .to_bytes();
+        Ok((status, version, headers, body_bytes, upstream))
+    };
+    let (status, version, mut headers, body_bytes, upstream) = match timeouts.response {
+        Some(limit) => timeout(limit, exchange).await.unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No complete response within {} ms", limit.as_millis()),
+            ))
+        })?,
+        None => exchange.await?,
+    };
+
+    // HTTP/2 frames the body itself, so the HTTP/1.1 client needs a length
+    if version == hyper::Version::HTTP_2 && request.method != "HEAD" {
+        headers
+            .entry("content-length".to_string())
+            .or_insert_with(|| body_bytes.len().to_string());
+    }
+
+    Ok((status, headers, body_bytes, upstream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_relay_response_framing() {
+        let upstream: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello\
+            HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n0\r\nX-Sum: 1\r\n\r\n\
+            HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\n\
+            HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nuntil the end";
+        let mut upstream = BufReader::new(upstream);
+
+        let mut out = Vec::new();
+        let (end, n) = relay_response(&mut upstream, &mut out, false)
+            .await
+            .unwrap();
+        assert_eq!(end, ResponseEnd::KeepAlive);
+        assert!(out.starts_with(b"HTTP/1.1 100") && out.ends_with(b"hello"));
+        assert_eq!(n, out.len() as u64);
+
+        let mut out = Vec::new();
+        let (end, _) = relay_response(&mut upstream, &mut out, false)
+            .await
+            .unwrap();
+        assert_eq!(end, ResponseEnd::KeepAlive);
+        assert!(out.ends_with(b"0\r\nX-Sum: 1\r\n\r\n"));
+
+        let mut out = Vec::new();
+        let (end, _) = relay_response(&mut upstream, &mut out, false)
+            .await
+            .unwrap();
+        assert_eq!(end, ResponseEnd::KeepAlive);
+        assert!(out.ends_with(b"Content-Length: 10\r\n\r\n"));
+
+        let mut out = Vec::new();
+        let (end, _) = relay_response(&mut upstream, &mut out, false)
+            .await
+            .unwrap();
+        assert_eq!(end, ResponseEnd::Close);
+        assert!(out.ends_with(b"until the end"));
     }
 
-    // Collect the body
-    let body_bytes = res
-        .collect()
+    #[tokio::test]
+    async fn test_relay_response_adds_headers_to_final_head() {
+        let upstream: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let mut out = Vec::new();
+        relay_response_with(
+            &mut BufReader::new(upstream),
+            &mut out,
+            false,
+            "X-Extra: 1\r\n",
+        )
         .await
-        .map_err(|e| io::Error::other(format!("Failed to collect response body: {e}")))?
-        .to_bytes();
+        .unwrap();
+        assert_eq!(
+            out,
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Extra: 1\r\n\r\nok"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_await_continue() {
+        let upstream: &[u8] = b"HTTP/1.1 102 Processing\r\n\r\nHTTP/1.1 100 Continue\r\n\r\nrest";
+        let mut upstream = BufReader::new(upstream);
+        let mut out = Vec::new();
+        let (early, relayed) = await_continue(&mut upstream, &mut out).await.unwrap();
+        assert_eq!(early, None);
+        assert_eq!(
+            out,
+            b"HTTP/1.1 102 Processing\r\n\r\nHTTP/1.1 100 Continue\r\n\r\n"
+        );
+        assert_eq!(relayed, out.len() as u64);
+
+        // A final response coming first is handed back unrelayed
+        let upstream: &[u8] = b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n";
+        let mut upstream = BufReader::new(upstream);
+        let mut out = Vec::new();
+        let (early, relayed) = await_continue(&mut upstream, &mut out).await.unwrap();
+        assert_eq!(
+            early.as_deref(),
+            Some(&b"HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\r\n"[..])
+        );
+        assert!(out.is_empty());
+        assert_eq!(relayed, 0);
+    }
+
+    #[test]
+    fn test_body_pending_only_for_expect_continue_with_a_body() {
+        let request = |headers: &[(&str, &str)]| HttpRequest {
+            method: "POST".to_string(),
+            target: "/".to_string(),
+            headers: Headers(
+                headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            body: Vec::new(),
+        };
+        assert!(request(&[("Expect", "100-Continue"), ("Content-Length", "3")]).body_pending());
+        assert!(!request(&[("Expect", "100-continue")]).body_pending());
+        assert!(!request(&[("Content-Length", "3")]).body_pending());
+    }
+
+    #[test]
+    fn test_starts_with_method() {
+        for start in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"CONNECT example.com:443 HTTP/1.1",
+            b"PROPFIND /dav/ HTTP/1.1",
+            b"OPT",
+        ] {
+            assert!(starts_with_method(start), "{start:?}");
+        }
+        for start in [
+            // Binary protocols that happen to start with an upper-case letter
+            &b"SSH-2.0-OpenSSH_9.6\r\n"[..],
+            b"AMQP\x00\x00\x09\x01",
+            b"GETX / HTTP/1.1",
+            b"\x16\x03\x01",
+        ] {
+            assert!(!starts_with_method(start), "{start:?}");
+        }
+    }
+
+    #[test]
+    fn test_framing_problems() {
+        let request = |headers: &[(&str, &str)]| HttpRequest {
+            method: "POST".to_string(),
+            target: "/".to_string(),
+            headers: Headers(
+                headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            body: Vec::new(),
+        };
+        assert_eq!(request(&[("Content-Length", "3")]).framing_problem(), None);
+        assert_eq!(
+            request(&[("Transfer-Encoding", "chunked")]).framing_problem(),
+            None
+        );
+        for headers in [
+            &[("Content-Length", "3"), ("content-length", "3")][..],
+            &[("Content-Length", "3, 4")],
+            &[("Content-Length", "-1")],
+            &[("Content-Length", "3"), ("Transfer-Encoding", "chunked")],
+        ] {
+            assert!(request(headers).framing_problem().is_some(), "{headers:?}");
+        }
+    }
+
+    #[test]
+    fn test_set_authority_keeps_path_and_query() {
+        let request = |target: &str| HttpRequest {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            headers: Headers::new(),
+            body: Vec::new(),
+        };
+        for (target, expected) in [
+            (
+                "http://old.example.com/a/b?c=d",
+                "http://new.internal:8080/a/b?c=d",
+            ),
+            ("http://old.example.com:80?q", "http://new.internal:8080?q"),
+            ("http://[::1]:80", "http://new.internal:8080"),
+            ("/origin-form", "/origin-form"),
+        ] {
+            let mut request = request(target);
+            request.set_authority("new.internal:8080");
+            assert_eq!(request.target, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_case_and_order_round_trip() {
+        let raw: &[u8] = b"POST http://example.com/form HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            user-agent: curl/8.5.0\r\n\
+            X-Custom-ID: 7\r\n\
+            Accept: */*\r\n\
+            x-custom-id: 8\r\n\
+            CONTENT-LENGTH: 2\r\n\
+            \r\n\
+            ok";
+        let mut request = parse_request(&mut &raw[..]).await.unwrap();
+        assert_eq!(request.headers.get("content-length").unwrap(), "2");
+        assert_eq!(request.headers.get("x-custom-id").unwrap(), "7");
+        assert_eq!(serialize_request(&request), raw);
+
+        // Rewrites keep the position of the header they replace; new ones go last
+        request.headers.insert("X-Custom-Id", "9");
+        request.headers.insert("Via", "proxy-twister");
+        let names: Vec<_> = request
+            .headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "Host",
+                "user-agent",
+                "X-Custom-Id",
+                "Accept",
+                "CONTENT-LENGTH",
+                "Via"
+            ]
+        );
+
+        let mut forwarded = Vec::new();
+        write_proxy_request(&mut forwarded, &request, "example.com", 80, None)
+            .await
+            .unwrap();
+        let forwarded = String::from_utf8(forwarded).unwrap();
+        assert!(
+            forwarded.starts_with(
+                "POST http://example.com/form HTTP/1.1\r\nHost: example.com\r\n\
+                 user-agent: curl/8.5.0\r\nX-Custom-Id: 9\r\nAccept: */*\r\n"
+            ),
+            "{forwarded}"
+        );
+        // The client's own Content-Length is replaced, not repeated
+        assert_eq!(
+            forwarded
+                .to_ascii_lowercase()
+                .matches("content-length:")
+                .count(),
+            1,
+            "{forwarded}"
+        );
+        assert!(
+            forwarded.ends_with("Via: proxy-twister\r\nContent-Length: 2\r\n\r\nok"),
+            "{forwarded}"
+        );
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut h = Headers::from([
+            ("Host", "example.com"),
+            ("connection", "close, X-Session-Hint"),
+            ("x-session-hint", "abc"),
+            ("keep-alive", "timeout=5"),
+            ("te", "trailers"),
+            ("trailer", "Expires"),
+            ("upgrade", "websocket"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("transfer-encoding", "chunked"),
+            ("accept", "*/*"),
+        ]);
+
+        strip_hop_by_hop(&mut h, false);
+
+        let remaining: Vec<_> = h.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(remaining, ["Host", "transfer-encoding", "accept"]);
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop_reframed() {
+        let mut h = Headers::from([("Transfer-Encoding", "chunked"), ("accept", "*/*")]);
+
+        strip_hop_by_hop(&mut h, true);
+
+        assert!(!h.contains_key("transfer-encoding"));
+        assert!(h.contains_key("accept"));
+    }
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(parse_status_line("HTTP/1.1 200 OK\r\n"), Some(200));
+        assert_eq!(parse_status_line("HTTP/1.0 407 Proxy Auth\r\n"), Some(407));
+        assert_eq!(parse_status_line("HTTP/1.1 200\r\n"), Some(200));
+        assert_eq!(parse_status_line("HTTP/1.1 2000 OK\r\n"), None);
+        assert_eq!(parse_status_line("HTTP/2 200\r\n"), None);
+        assert_eq!(parse_status_line("<html><body>Log in</body>\r\n"), None);
+        assert_eq!(parse_status_line("SSH-2.0-OpenSSH_9.6\r\n"), None);
+        assert_eq!(parse_status_line(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_size_is_bounded() {
+        let mut ok: &[u8] = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        assert_eq!(read_chunked_body(&mut ok).await.unwrap(), b"Wikipedia");
+
+        // Rejected before anything is allocated for the chunk
+        let mut huge: &[u8] = b"40000000\r\nabc";
+        let err = read_chunked_body(&mut huge).await.unwrap_err();
+        assert!(BodyTooLarge::is_cause_of(&err), "{err}");
+
+        let mut overflow: &[u8] = b"1\r\na\r\nffffffffffffffff\r\n";
+        let err = read_chunked_body(&mut overflow).await.unwrap_err();
+        assert!(BodyTooLarge::is_cause_of(&err), "{err}");
+
+        let mut truncated: &[u8] = b"10\r\nshort";
+        let err = read_chunked_body(&mut truncated).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut no_crlf: &[u8] = b"2\r\nokXX0\r\n\r\n";
+        let err = read_chunked_body(&mut no_crlf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_proxy_response_headers() {
+        let long_line = format!("X-Junk: {}\r\n", "a".repeat(16 * 1024));
+        let many_lines = format!("X-Junk: {}\r\n", "a".repeat(1000)).repeat(100);
+        for headers in [long_line, many_lines] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_port = listener.local_addr().unwrap().port();
+            let proxy = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 256];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.1 200 Connection Established\r\n{headers}\r\n");
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+
+            let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+            let err = connect_handshake(&mut stream, "example.com", 443, None, "")
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(InvalidUpstreamResponse::is_cause_of(&err), "{err}");
+            proxy.await.unwrap();
+        }
+    }
 
-    Ok((status, headers, body_bytes))
+    fn connect_request(target: &str) -> HttpRequest {
+        HttpRequest {
+            method: "CONNECT".to_string(),
+            target: target.to_string(),
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_rejects_malformed_targets() {
+        for target in [
+            "http://example.com:443/",
+            "/index.html",
+            "example.com:",
+            ":443",
+            "user@bad_host!:443",
+        ] {
+            let mut response = Vec::new();
+            let err = handle_connect(&mut response, connect_request(target))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+            assert!(response.contains(&format!("'{target}'")));
+        }
+
+        let mut response = Vec::new();
+        let target = handle_connect(&mut response, connect_request("example.com:443"))
+            .await
+            .unwrap();
+        assert_eq!(target, ("example.com".to_string(), 443));
+        assert!(response.is_empty());
+
+        let target = handle_connect(&mut response, connect_request("user@example.com:443"))
+            .await
+            .unwrap();
+        assert_eq!(target, ("example.com".to_string(), 443));
+    }
+
+    #[tokio::test]
+    async fn test_direct_request_over_http2() {
+        use hyper::service::service_fn;
+        use hyper_util::rt::TokioIo;
+        use sha2::{Digest, Sha256};
+        use std::sync::Arc;
+
+        // HTTPS origin speaking only HTTP/2, answering with the version it was reached over
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("proxy-twister-h2-cert-{}.pem", std::process::id()));
+        let key_path = dir.join(format!("proxy-twister-h2-key-{}.pem", std::process::id()));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        let mut server_config = super::super::tls::server_config(&cert_path, &key_path).unwrap();
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let tls = acceptor.accept(socket).await.unwrap();
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                let body = format!("{:?}", req.version());
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(Full::new(Bytes::from(
+                    body,
+                ))))
+            });
+            let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(tls), service)
+                .await;
+        });
+
+        let fingerprint: [u8; 32] = Sha256::digest(cert.der().as_ref()).into();
+        let tls = TlsOptions {
+            pinned_fingerprints: vec![fingerprint.iter().map(|b| format!("{b:02x}")).collect()],
+            ..Default::default()
+        };
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            target: format!("https://localhost:{port}/"),
+            headers: Headers::from([("host", format!("localhost:{port}"))]),
+            body: Vec::new(),
+        };
+        let (status, headers, body, _) = send_http_request(
+            &request,
+            "localhost",
+            port,
+            &tls,
+            DirectResolver {
+                dns: Default::default(),
+                options: Default::default(),
+                family: AddressFamily::Ipv4,
+            },
+            true,
+            ExchangeTimeouts::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"HTTP/2.0");
+        assert_eq!(headers.get("content-length").map(String::as_str), Some("8"));
+    }
 }
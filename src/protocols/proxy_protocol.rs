@@ -0,0 +1,268 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ProxyProtocol;
+
+/// Start of every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header line the specification allows, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Both ends of a client connection, as seen by the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddrs {
+    /// The client
+    pub source: SocketAddr,
+    /// The listener address the client connected to
+    pub destination: SocketAddr,
+}
+
+/// HAProxy PROXY protocol header announcing `client` to an upstream
+///
+/// Without addresses, or with addresses of different IP versions, the header tells the
+/// upstream to use the connection's own addresses instead (`UNKNOWN` in v1, `LOCAL` in v2).
+pub fn header(version: ProxyProtocol, client: Option<&ClientAddrs>) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => v1_header(client).into_bytes(),
+        ProxyProtocol::V2 => v2_header(client),
+    }
+}
+
+fn v1_header(client: Option<&ClientAddrs>) -> String {
+    let Some(ClientAddrs {
+        source,
+        destination,
+    }) = client.filter(|c| c.source.is_ipv4() == c.destination.is_ipv4())
+    else {
+        return "PROXY UNKNOWN\r\n".to_string();
+    };
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {family} {} {} {} {}\r\n",
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+}
+
+fn v2_header(client: Option<&ClientAddrs>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let addresses = client.and_then(|client| {
+        let (family, mut payload) = match (client.source.ip(), client.destination.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                (0x11, [source.octets(), destination.octets()].concat())
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                (0x21, [source.octets(), destination.octets()].concat())
+            }
+            _ => return None,
+        };
+        payload.extend_from_slice(&client.source.port().to_be_bytes());
+        payload.extend_from_slice(&client.destination.port().to_be_bytes());
+        Some((family, payload))
+    });
+    let Some((family, payload)) = addresses else {
+        // Version 2, LOCAL command, unspecified family, no addresses
+        header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        return header;
+    };
+    // Version 2, PROXY command, TCP over the family's IP version
+    header.extend_from_slice(&[0x21, family]);
+    header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    header.extend_from_slice(&payload);
+    header
+}
+
+fn invalid_header(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {reason}"),
+    )
+}
+
+/// Read the PROXY protocol header (v1 or v2) a load balancer sends ahead of the client's data
+///
+/// Nothing past the header is consumed. Returns `None` for headers without client addresses,
+/// such as the load balancer's own health checks.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<ClientAddrs>> {
+    // Both versions are at least this long
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2_header(reader).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid_header("missing signature"));
+    }
+
+    // One byte at a time, so the client's first bytes stay unread
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid_header("v1 line too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid_header("v1 line is not text"))?;
+    parse_v1_line(line)
+}
+
+fn parse_v1_line(line: &str) -> io::Result<Option<ClientAddrs>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            "TCP4" | "TCP6",
+            source,
+            destination,
+            source_port,
+            destination_port,
+        ] => {
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid_header("bad v1 address"))?;
+                let port: u16 = port.parse().map_err(|_| invalid_header("bad v1 port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some(ClientAddrs {
+                source: addr(source, source_port)?,
+                destination: addr(destination, destination_port)?,
+            }))
+        }
+        _ => Err(invalid_header("malformed v1 line")),
+    }
+}
+
+async fn read_v2_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<ClientAddrs>> {
+    let mut fixed = [0u8; 4];
+    reader.read_exact(&mut fixed).await?;
+    let [version_command, family, len_high, len_low] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("unsupported v2 version"));
+    }
+    // Addresses are followed by optional TLVs, which are skipped along with them
+    let mut payload = vec![0u8; u16::from_be_bytes([len_high, len_low]) as usize];
+    reader.read_exact(&mut payload).await?;
+    match version_command & 0x0f {
+        // LOCAL: the connection was made by the load balancer itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid_header("unsupported v2 command")),
+    }
+
+    let addr_len = match family >> 4 {
+        1 => 4,
+        2 => 16,
+        // Unspecified or Unix socket addresses say nothing about an IP client
+        _ => return Ok(None),
+    };
+    let Some(addresses) = payload.get(..2 * addr_len + 4) else {
+        return Err(invalid_header("v2 addresses truncated"));
+    };
+    let (ips, ports) = addresses.split_at(2 * addr_len);
+    let ip = |bytes: &[u8]| -> IpAddr {
+        match <[u8; 16]>::try_from(bytes) {
+            Ok(octets) => Ipv6Addr::from(octets).into(),
+            Err(_) => Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into(),
+        }
+    };
+    Ok(Some(ClientAddrs {
+        source: SocketAddr::new(
+            ip(&ips[..addr_len]),
+            u16::from_be_bytes([ports[0], ports[1]]),
+        ),
+        destination: SocketAddr::new(
+            ip(&ips[addr_len..]),
+            u16::from_be_bytes([ports[2], ports[3]]),
+        ),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> ClientAddrs {
+        ClientAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_v1_header() {
+        let v4 = addrs("192.0.2.10:51000", "10.0.0.1:8080");
+        assert_eq!(
+            header(ProxyProtocol::V1, Some(&v4)),
+            b"PROXY TCP4 192.0.2.10 10.0.0.1 51000 8080\r\n"
+        );
+        let v6 = addrs("[2001:db8::10]:51000", "[::1]:8080");
+        assert_eq!(
+            header(ProxyProtocol::V1, Some(&v6)),
+            b"PROXY TCP6 2001:db8::10 ::1 51000 8080\r\n"
+        );
+        let mixed = addrs("192.0.2.10:51000", "[::1]:8080");
+        assert_eq!(
+            header(ProxyProtocol::V1, Some(&mixed)),
+            b"PROXY UNKNOWN\r\n"
+        );
+        assert_eq!(header(ProxyProtocol::V1, None), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn test_v2_header() {
+        let v4 = addrs("192.0.2.10:51000", "10.0.0.1:8080");
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[192, 0, 2, 10, 10, 0, 0, 1]);
+        expected.extend_from_slice(&[0xc7, 0x38, 0x1f, 0x90]);
+        assert_eq!(header(ProxyProtocol::V2, Some(&v4)), expected);
+
+        let v6 = header(
+            ProxyProtocol::V2,
+            Some(&addrs("[2001:db8::10]:1", "[::1]:2")),
+        );
+        assert_eq!(&v6[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(v6.len(), 16 + 36);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(header(ProxyProtocol::V2, None), local);
+    }
+
+    #[tokio::test]
+    async fn test_read_header_round_trip() {
+        let cases = [
+            Some(addrs("192.0.2.10:51000", "10.0.0.1:8080")),
+            Some(addrs("[2001:db8::10]:51000", "[::1]:8080")),
+            None,
+        ];
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            for client in &cases {
+                let mut stream = header(version, client.as_ref());
+                stream.extend_from_slice(b"CONNECT example.com:443 HTTP/1.1\r\n");
+                let mut reader = stream.as_slice();
+
+                let parsed = read_header(&mut reader).await.unwrap();
+                assert_eq!(parsed, *client, "{version:?}");
+                assert!(reader.starts_with(b"CONNECT "), "{version:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_header_rejects_garbage() {
+        for input in [
+            &b"CONNECT example.com:443 HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.0.2.10 10.0.0.1 51000\r\n",
+            &[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(30),
+        ] {
+            let err = read_header(&mut &input[..]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{input:?}");
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::time::{Duration, timeout};
 use tracing::{error, trace};
@@ -7,47 +8,142 @@ use tracing::{error, trace};
 // SOCKS5 protocol constants
 pub const SOCKS_VERSION: u8 = 0x05;
 pub const NO_AUTHENTICATION: u8 = 0x00;
+pub const USERNAME_PASSWORD_AUTH: u8 = 0x02;
 pub const CONNECT_COMMAND: u8 = 0x01;
+pub const BIND_COMMAND: u8 = 0x02;
+pub const UDP_ASSOCIATE_COMMAND: u8 = 0x03;
 pub const IPV4_TYPE: u8 = 0x01;
 pub const DOMAIN_TYPE: u8 = 0x03;
 pub const IPV6_TYPE: u8 = 0x04;
 pub const SUCCESS_REPLY: u8 = 0x00;
+pub const GENERAL_FAILURE_REPLY: u8 = 0x01;
+pub const HOST_UNREACHABLE_REPLY: u8 = 0x04;
+pub const COMMAND_NOT_SUPPORTED_REPLY: u8 = 0x07;
+pub const ADDRESS_NOT_SUPPORTED_REPLY: u8 = 0x08;
+pub const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+/// Tor's RESOLVE extension: answer with the address of a name instead of connecting
+pub const RESOLVE_COMMAND: u8 = 0xF0;
 
 pub struct Socks5Request {
     pub target: String,
     pub port: u16,
 }
 
-pub async fn forward_to_proxy(
-    request: &Socks5Request,
-    proxy_host: &str,
-    proxy_port: u16,
-) -> io::Result<TcpStream> {
-    trace!("Connecting to proxy at {}:{}", proxy_host, proxy_port);
-    let mut proxy = TcpStream::connect(format!("{proxy_host}:{proxy_port}")).await?;
+impl Socks5Request {
+    /// CONNECT request bytes; IP literals are sent as addresses, anything else as a domain name
+    ///
+    /// Fails for domain names longer than the 255 bytes SOCKS5 can carry.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![SOCKS_VERSION, CONNECT_COMMAND, 0x00];
+        match self.target.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                bytes.push(IPV4_TYPE);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                bytes.push(IPV6_TYPE);
+                bytes.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let len = u8::try_from(self.target.len()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Target name of {} bytes is too long for a SOCKS5 request (at most 255)",
+                            self.target.len()
+                        ),
+                    )
+                })?;
+                bytes.push(DOMAIN_TYPE);
+                bytes.push(len);
+                bytes.extend_from_slice(self.target.as_bytes());
+            }
+        }
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        Ok(bytes)
+    }
+}
 
-    proxy
-        .write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])
-        .await?;
+/// Greet the proxy and authenticate, offering RFC 1929 username/password when `auth` is set
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &mut S,
+    auth: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let greeting: &[u8] = match auth {
+        Some(_) => &[SOCKS_VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD_AUTH],
+        None => &[SOCKS_VERSION, 1, NO_AUTHENTICATION],
+    };
+    proxy.write_all(greeting).await?;
     trace!("Sent authentication request to proxy");
     let mut response = [0u8; 2];
     proxy.read_exact(&mut response).await?;
     trace!("Received authentication response: {:?}", response);
 
-    if response[0] != SOCKS_VERSION || response[1] != NO_AUTHENTICATION {
-        error!("Proxy authentication failed: {:?}", response);
-        return Err(io::Error::other("Proxy authentication failed"));
+    match (response, auth) {
+        ([SOCKS_VERSION, NO_AUTHENTICATION], _) => Ok(()),
+        ([SOCKS_VERSION, USERNAME_PASSWORD_AUTH], Some((username, password))) => {
+            let too_long = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "SOCKS5 username and password must be at most 255 bytes",
+                )
+            };
+            let username_len = u8::try_from(username.len()).map_err(|_| too_long())?;
+            let password_len = u8::try_from(password.len()).map_err(|_| too_long())?;
+            let mut request = vec![0x01, username_len];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password_len);
+            request.extend_from_slice(password.as_bytes());
+            proxy.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            proxy.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                error!("Proxy rejected the username/password: {:?}", status);
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Proxy rejected the username/password",
+                ));
+            }
+            Ok(())
+        }
+        _ => {
+            error!("Proxy authentication failed: {:?}", response);
+            Err(io::Error::other("Proxy authentication failed"))
+        }
     }
+}
 
-    proxy.write_all(&[SOCKS_VERSION]).await?;
-    proxy.write_all(&[CONNECT_COMMAND]).await?;
-    proxy.write_all(&[0x00]).await?;
-    trace!("Sending SOCKS5 request to proxy");
-    proxy.write_all(&[DOMAIN_TYPE]).await?;
-    proxy.write_all(&[request.target.len() as u8]).await?;
-    proxy.write_all(request.target.as_bytes()).await?;
+/// Open a tunnel through a SOCKS5 proxy, sending `preface` (e.g. a PROXY protocol header)
+/// before the handshake
+pub async fn forward_to_proxy(
+    request: &Socks5Request,
+    proxy_host: &str,
+    proxy_port: u16,
+    auth: Option<(&str, &str)>,
+    preface: &[u8],
+) -> io::Result<TcpStream> {
+    trace!("Connecting to proxy at {}:{}", proxy_host, proxy_port);
+    let mut proxy = TcpStream::connect((proxy_host, proxy_port)).await?;
+    proxy.write_all(preface).await?;
+    connect_handshake(&mut proxy, request, auth).await?;
+    Ok(proxy)
+}
+
+/// Greet the SOCKS5 proxy at the other end of `proxy` and have it CONNECT to `request`
+///
+/// `proxy` may itself be a tunnel through other proxies, which is how chains are built.
+pub async fn connect_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &mut S,
+    request: &Socks5Request,
+    auth: Option<(&str, &str)>,
+) -> io::Result<()> {
+    // Checked up front, so an unencodable target never reaches the proxy
+    let request = request.encode()?;
+    authenticate(proxy, auth).await?;
 
-    proxy.write_all(&request.port.to_be_bytes()).await?;
+    trace!("Sending SOCKS5 request to proxy");
+    proxy.write_all(&request).await?;
     trace!("Forwarded request to proxy");
 
     trace!("Waiting for proxy response with timeout");
@@ -164,5 +260,212 @@ pub async fn forward_to_proxy(
         }
     }
 
-    Ok(proxy)
+    Ok(())
+}
+
+/// Command sent by a SOCKS5 client connecting to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCommand {
+    Connect,
+    Bind,
+    UdpAssociate,
+    Resolve,
+}
+
+/// Request read from a SOCKS5 client connecting to us
+#[derive(Debug)]
+pub struct ClientRequest {
+    pub command: ClientCommand,
+    pub target: String,
+    pub port: u16,
+}
+
+/// Serve the greeting of a SOCKS5 client and read its request
+///
+/// The version byte must already have been consumed. Unsupported commands and address types
+/// are answered with the matching SOCKS5 error reply before returning an error.
+pub async fn accept_client_request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> io::Result<ClientRequest> {
+    let mut nmethods = [0u8; 1];
+    stream.read_exact(&mut nmethods).await?;
+    let mut methods = vec![0u8; nmethods[0] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream
+            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 client offers no supported authentication method",
+        ));
+    }
+    stream
+        .write_all(&[SOCKS_VERSION, NO_AUTHENTICATION])
+        .await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid SOCKS version in request",
+        ));
+    }
+    let target = match header[3] {
+        IPV4_TYPE => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        IPV6_TYPE => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        DOMAIN_TYPE => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut domain = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "SOCKS5 domain is not UTF-8")
+            })?
+        }
+        other => {
+            write_reply(stream, ADDRESS_NOT_SUPPORTED_REPLY, None).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported SOCKS5 address type {other:#04x}"),
+            ));
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+
+    let command = match header[1] {
+        CONNECT_COMMAND => ClientCommand::Connect,
+        BIND_COMMAND => ClientCommand::Bind,
+        UDP_ASSOCIATE_COMMAND => ClientCommand::UdpAssociate,
+        RESOLVE_COMMAND => ClientCommand::Resolve,
+        other => {
+            write_reply(stream, COMMAND_NOT_SUPPORTED_REPLY, None).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported SOCKS5 command {other:#04x}"),
+            ));
+        }
+    };
+    Ok(ClientRequest {
+        command,
+        target,
+        port: u16::from_be_bytes(port),
+    })
+}
+
+/// Send a reply to a SOCKS5 client, with `bound` as BND.ADDR (`0.0.0.0:0` when `None`)
+pub async fn write_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    reply: u8,
+    bound: Option<SocketAddr>,
+) -> io::Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut buf = vec![SOCKS_VERSION, reply, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(IPV4_TYPE);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(IPV6_TYPE);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&bound.port().to_be_bytes());
+    stream.write_all(&buf).await
+}
+
+/// Resolve `name` through an upstream SOCKS5 proxy supporting Tor's RESOLVE extension
+pub async fn resolve_via_proxy(
+    name: &str,
+    proxy_host: &str,
+    proxy_port: u16,
+    auth: Option<(&str, &str)>,
+) -> io::Result<IpAddr> {
+    let name_len = u8::try_from(name.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Name is too long for a SOCKS5 request",
+        )
+    })?;
+    let mut proxy = TcpStream::connect((proxy_host, proxy_port)).await?;
+    authenticate(&mut proxy, auth).await?;
+
+    let mut request = vec![SOCKS_VERSION, RESOLVE_COMMAND, 0x00, DOMAIN_TYPE, name_len];
+    request.extend_from_slice(name.as_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    proxy.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    match timeout(Duration::from_secs(10), proxy.read_exact(&mut header)).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out while waiting for proxy RESOLVE response",
+            ));
+        }
+    };
+    if header[1] != SUCCESS_REPLY {
+        return Err(io::Error::other(format!(
+            "Proxy RESOLVE failed with status: {}",
+            header[1]
+        )));
+    }
+    let ip = match header[3] {
+        IPV4_TYPE => {
+            let mut addr = [0u8; 4];
+            proxy.read_exact(&mut addr).await?;
+            IpAddr::from(addr)
+        }
+        IPV6_TYPE => {
+            let mut addr = [0u8; 16];
+            proxy.read_exact(&mut addr).await?;
+            IpAddr::from(addr)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Proxy RESOLVE response carries no IP address",
+            ));
+        }
+    };
+    trace!("Proxy resolved {name} to {ip}");
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overlong_domain_is_refused_before_sending() {
+        let request = Socks5Request {
+            target: format!("{}.example", "a".repeat(292)),
+            port: 443,
+        };
+        let err = request.encode().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("300 bytes"), "{err}");
+
+        let (mut proxy, mut upstream) = tokio::io::duplex(1024);
+        let err = connect_handshake(&mut proxy, &request, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        drop(proxy);
+        let mut sent = Vec::new();
+        upstream.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty(), "{sent:?}");
+    }
 }
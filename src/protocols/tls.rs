@@ -0,0 +1,549 @@
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore,
+    ServerConfig, SignatureScheme, SupportedProtocolVersion,
+};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Once};
+use tracing::{trace, warn};
+
+use crate::config::{TlsOptions, TlsVersion};
+
+/// Parse a hex SHA-256 fingerprint, tolerating `:` separators and either case
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], String> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!(
+            "fingerprint '{fingerprint}' must be 32 hex-encoded bytes (SHA-256)"
+        ));
+    }
+
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("fingerprint '{fingerprint}' is not valid hex"))?;
+    }
+    Ok(bytes)
+}
+
+/// Select the crypto provider, preferring one installed for the process
+fn crypto_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// Crypto provider and protocol versions narrowed down to `min_version` and `cipher_suites`
+///
+/// Fails on unknown suite names, and when none of the suites left can be used with the
+/// versions allowed (e.g. only TLS 1.2 suites with a minimum of TLS 1.3).
+pub fn restricted_provider(
+    options: &TlsOptions,
+) -> Result<
+    (
+        Arc<CryptoProvider>,
+        &'static [&'static SupportedProtocolVersion],
+    ),
+    String,
+> {
+    static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+    let versions = match options.min_version {
+        None | Some(TlsVersion::Tls12) => rustls::DEFAULT_VERSIONS,
+        Some(TlsVersion::Tls13) => TLS13_ONLY,
+    };
+    let provider = crypto_provider();
+    if options.cipher_suites.is_empty() {
+        return Ok((provider, versions));
+    }
+
+    let name = |suite: &rustls::SupportedCipherSuite| format!("{:?}", suite.suite());
+    let mut cipher_suites = Vec::new();
+    for wanted in &options.cipher_suites {
+        match provider
+            .cipher_suites
+            .iter()
+            .find(|suite| name(suite).eq_ignore_ascii_case(wanted))
+        {
+            Some(suite) => cipher_suites.push(*suite),
+            None => {
+                let known: Vec<_> = provider.cipher_suites.iter().map(name).collect();
+                return Err(format!(
+                    "unsupported cipher suite '{wanted}' (supported: {})",
+                    known.join(", ")
+                ));
+            }
+        }
+    }
+    if !cipher_suites.iter().any(|suite| {
+        versions
+            .iter()
+            .any(|v| v.version == suite.version().version)
+    }) {
+        return Err("none of the cipher_suites can be used with min_version".to_string());
+    }
+    Ok((
+        Arc::new(CryptoProvider {
+            cipher_suites,
+            ..(*provider).clone()
+        }),
+        versions,
+    ))
+}
+
+/// Certificate verifier that honours `insecure_skip_verify` and pinned fingerprints
+/// before falling back to the regular WebPKI verification against system roots
+#[derive(Debug)]
+struct UpstreamCertVerifier {
+    insecure_skip_verify: bool,
+    pins: Vec<[u8; 32]>,
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for UpstreamCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        if self.insecure_skip_verify {
+            trace!("Skipping certificate verification for {server_name:?}");
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if self.pins.contains(&fingerprint) {
+            trace!("Certificate for {server_name:?} matches a pinned fingerprint");
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        match &self.webpki {
+            Some(webpki) => webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            ),
+            None => Err(TlsError::InvalidCertificate(
+                CertificateError::UnknownIssuer,
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Trust anchors for upstream servers: the system's, or the bundled Mozilla roots when none of
+/// the system's could be loaded (unless `native_only`)
+fn root_store(native: Vec<CertificateDer<'static>>, native_only: bool) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(native);
+    if roots.is_empty() && !native_only {
+        static WARNED: Once = Once::new();
+        WARNED.call_once(|| {
+            warn!("No native root certificates could be loaded, using the bundled Mozilla roots")
+        });
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    roots
+}
+
+/// Build a rustls client config for upstream connections according to `options`
+pub fn client_config(options: &TlsOptions) -> io::Result<ClientConfig> {
+    let (provider, versions) = restricted_provider(options).map_err(io::Error::other)?;
+    let pins = options
+        .pinned_fingerprints
+        .iter()
+        .map(|f| parse_fingerprint(f))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io::Error::other)?;
+
+    let webpki = if options.insecure_skip_verify {
+        None
+    } else {
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            warn!("Failed to load a native root certificate: {e}");
+        }
+        let roots = root_store(native.certs, options.native_roots_only);
+        if roots.is_empty() {
+            None
+        } else {
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()
+                    .map_err(|e| io::Error::other(format!("Failed to build verifier: {e}")))?;
+            Some(verifier)
+        }
+    };
+
+    let verifier = UpstreamCertVerifier {
+        insecure_skip_verify: options.insecure_skip_verify,
+        pins,
+        webpki,
+        provider: provider.clone(),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .map_err(|e| io::Error::other(format!("Failed to configure TLS: {e}")))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let (certs, key) = load_identity(cert_path, key_path)?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| io::Error::other(format!("Invalid client certificate or key: {e}")))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(io::Error::other(
+            "client_cert and client_key must be set together",
+        )),
+    }
+}
+
+/// Open TLS over `stream`, verifying the server as `server_name` according to `options`
+///
+/// The client config `Config::load_proxy_tls` built from `options` is used when there is one.
+pub async fn connect<S>(
+    options: &TlsOptions,
+    server_name: &str,
+    stream: S,
+) -> io::Result<tokio_rustls::client::TlsStream<S>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let client_config = match &options.client_config {
+        Some(client_config) => client_config.clone(),
+        None => Arc::new(client_config(options)?),
+    };
+    let connector = tokio_rustls::TlsConnector::from(client_config);
+    let name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid TLS server name '{server_name}': {e}"),
+        )
+    })?;
+    trace!("Starting TLS with {}", server_name);
+    connector.connect(name, stream).await
+}
+
+/// Read a PEM certificate chain and its private key
+pub fn load_identity(
+    cert_path: &Path,
+    key_path: &Path,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            io::Error::other(format!(
+                "Failed to read certificates from '{}': {e}",
+                cert_path.display()
+            ))
+        })?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| {
+        io::Error::other(format!(
+            "Failed to read private key from '{}': {e}",
+            key_path.display()
+        ))
+    })?;
+    Ok((certs, key))
+}
+
+/// Build a rustls server config for TLS-terminated listeners from PEM certificate and key files
+pub fn server_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let (certs, key) = load_identity(cert_path, key_path)?;
+    ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| io::Error::other(format!("Failed to configure TLS: {e}")))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::other(format!("Invalid certificate or key: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verifier_with_pins(pins: Vec<[u8; 32]>) -> UpstreamCertVerifier {
+        UpstreamCertVerifier {
+            insecure_skip_verify: false,
+            pins,
+            webpki: None,
+            provider: crypto_provider(),
+        }
+    }
+
+    fn verify(verifier: &UpstreamCertVerifier, cert: &CertificateDer<'_>) -> bool {
+        let server_name = ServerName::try_from("internal.example").unwrap();
+        verifier
+            .verify_server_cert(cert, &[], &server_name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let colon_separated = ["ab"; 32].join(":");
+        assert_eq!(parse_fingerprint(&colon_separated).unwrap(), [0xab; 32]);
+        assert_eq!(parse_fingerprint(&"CD".repeat(32)).unwrap(), [0xcd; 32]);
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_root_store_falls_back_to_bundled_roots() {
+        let bundled = root_store(Vec::new(), false);
+        assert_eq!(bundled.len(), webpki_roots::TLS_SERVER_ROOTS.len());
+        assert!(root_store(Vec::new(), true).is_empty());
+
+        // Unparsable native certificates count as none
+        let garbage = vec![CertificateDer::from(b"not a certificate".to_vec())];
+        assert!(!root_store(garbage.clone(), false).is_empty());
+        assert!(root_store(garbage, true).is_empty());
+    }
+
+    #[test]
+    fn test_pinned_fingerprint_match() {
+        let cert = CertificateDer::from(b"self-signed internal certificate".to_vec());
+        let pin: [u8; 32] = Sha256::digest(cert.as_ref()).into();
+
+        assert!(verify(&verifier_with_pins(vec![pin]), &cert));
+    }
+
+    #[test]
+    fn test_pinned_fingerprint_mismatch() {
+        let cert = CertificateDer::from(b"self-signed internal certificate".to_vec());
+        let other: [u8; 32] = Sha256::digest(b"some other certificate").into();
+
+        assert!(!verify(&verifier_with_pins(vec![other]), &cert));
+    }
+
+    #[test]
+    fn test_insecure_skip_verify() {
+        let cert = CertificateDer::from(b"anything at all".to_vec());
+        let verifier = UpstreamCertVerifier {
+            insecure_skip_verify: true,
+            ..verifier_with_pins(Vec::new())
+        };
+
+        assert!(verify(&verifier, &cert));
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_auth() {
+        let dir = std::env::temp_dir();
+        let write = |name: &str, pem: String| {
+            let path = dir.join(format!("proxy-twister-{name}-{}.pem", std::process::id()));
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+
+        // CA issuing the client certificate, and the server's own self-signed certificate
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let ca = rcgen::Issuer::new(ca_params, ca_key);
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let client_cert = rcgen::CertificateParams::new(vec!["client.example".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca)
+            .unwrap();
+        let server_key = rcgen::KeyPair::generate().unwrap();
+        let server_cert = rcgen::CertificateParams::new(vec!["proxy.example".to_string()])
+            .unwrap()
+            .self_signed(&server_key)
+            .unwrap();
+
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(ca_cert.der().clone()).unwrap();
+        let client_verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            Arc::new(client_roots),
+            crypto_provider(),
+        )
+        .build()
+        .unwrap();
+        let server_config = Arc::new(
+            ServerConfig::builder_with_provider(crypto_provider())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_client_cert_verifier(client_verifier)
+                .with_single_cert(
+                    vec![server_cert.der().clone()],
+                    PrivateKeyDer::try_from(server_key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+        );
+
+        let pin: [u8; 32] = Sha256::digest(server_cert.der().as_ref()).into();
+        let anonymous = TlsOptions {
+            pinned_fingerprints: vec![pin.iter().map(|b| format!("{b:02x}")).collect()],
+            ..Default::default()
+        };
+        let identified = TlsOptions {
+            client_cert: Some(write("client-cert", client_cert.pem())),
+            client_key: Some(write("client-key", client_key.serialize_pem())),
+            ..anonymous.clone()
+        };
+
+        for (options, accepted) in [(&anonymous, false), (&identified, true)] {
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let acceptor = tokio_rustls::TlsAcceptor::from(server_config.clone());
+            let server = tokio::spawn(async move { acceptor.accept(server_io).await.is_ok() });
+            let connector =
+                tokio_rustls::TlsConnector::from(Arc::new(client_config(options).unwrap()));
+            let server_name = ServerName::try_from("proxy.example").unwrap();
+            // TLS 1.3 reports a rejected client certificate only to the server reliably, so the
+            // client side is kept open until the server is done
+            let _client = connector.connect(server_name, client_io).await;
+            assert_eq!(server.await.unwrap(), accepted);
+        }
+
+        std::fs::remove_file(identified.client_cert.unwrap()).unwrap();
+        std::fs::remove_file(identified.client_key.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_restricted_provider_validation() {
+        let options = |min_version, cipher_suites: &[&str]| TlsOptions {
+            min_version,
+            cipher_suites: cipher_suites.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let (provider, versions) = restricted_provider(&options(
+            Some(TlsVersion::Tls13),
+            &["tls13_aes_256_gcm_sha384"],
+        ))
+        .unwrap();
+        assert_eq!(provider.cipher_suites.len(), 1);
+        assert_eq!(versions.len(), 1);
+
+        let err = restricted_provider(&options(None, &["TLS_RSA_WITH_RC4_128_MD5"])).unwrap_err();
+        assert!(err.contains("unsupported cipher suite"), "{err}");
+        let err = restricted_provider(&options(
+            Some(TlsVersion::Tls13),
+            &["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"],
+        ))
+        .unwrap_err();
+        assert!(err.contains("min_version"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_min_version_refuses_older_servers() {
+        let server_name = || ServerName::try_from("legacy.example").unwrap();
+
+        // A TLS 1.1 server: answers any ClientHello with a TLS 1.1 ServerHello
+        let (client_io, mut server_io) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let mut hello = [0u8; 512];
+            let _ = server_io.read(&mut hello).await;
+            let mut server_hello = vec![0x16, 0x03, 0x02, 0x00, 0x2a, 0x02, 0x00, 0x00, 0x26];
+            server_hello.extend_from_slice(&[0x03, 0x02]);
+            server_hello.extend_from_slice(&[0x42; 32]);
+            server_hello.extend_from_slice(&[0x00, 0x00, 0x2f, 0x00]);
+            let _ = server_io.write_all(&server_hello).await;
+            let _ = server_io.read(&mut hello).await;
+        });
+        let options = TlsOptions {
+            min_version: Some(TlsVersion::Tls12),
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let connector =
+            tokio_rustls::TlsConnector::from(Arc::new(client_config(&options).unwrap()));
+        let err = connector
+            .connect(server_name(), client_io)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("incompatible"), "{err}");
+
+        // A TLS 1.2-only server is refused with a minimum of TLS 1.3 only
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["legacy.example".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let server_config = Arc::new(
+            ServerConfig::builder_with_provider(crypto_provider())
+                .with_protocol_versions(&[&rustls::version::TLS12])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+        );
+        let pin: [u8; 32] = Sha256::digest(cert.der().as_ref()).into();
+        for (min_version, accepted) in [(TlsVersion::Tls12, true), (TlsVersion::Tls13, false)] {
+            let options = TlsOptions {
+                pinned_fingerprints: vec![pin.iter().map(|b| format!("{b:02x}")).collect()],
+                min_version: Some(min_version),
+                ..Default::default()
+            };
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let acceptor = tokio_rustls::TlsAcceptor::from(server_config.clone());
+            tokio::spawn(async move { acceptor.accept(server_io).await.is_ok() });
+            let connector =
+                tokio_rustls::TlsConnector::from(Arc::new(client_config(&options).unwrap()));
+            let result = connector.connect(server_name(), client_io).await;
+            assert_eq!(result.is_ok(), accepted, "{min_version:?}");
+        }
+    }
+
+    #[test]
+    fn test_missing_client_identity_is_reported() {
+        let options = TlsOptions {
+            client_cert: Some("/nonexistent/client.pem".into()),
+            client_key: Some("/nonexistent/client.key".into()),
+            ..Default::default()
+        };
+        let err = client_config(&options).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/client.pem"));
+    }
+}
@@ -0,0 +1,1004 @@
+use hyper_util::client::legacy::connect::dns::Name;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::{trace, warn};
+
+use crate::config::{AddressFamily, BalanceOptions, BalanceStrategy, Config, DnsCacheOptions};
+
+/// Upstream proxy endpoints referenced by the profiles, as (profile, host, port)
+fn upstream_endpoints(config: &Config) -> Vec<(&str, String, u16)> {
+    let mut endpoints: Vec<_> = config
+        .profiles
+        .iter()
+        .flat_map(|(name, profile)| {
+            profile
+                .proxy_endpoints()
+                .into_iter()
+                .map(|(host, port)| (name.as_str(), host, port))
+        })
+        .collect();
+    endpoints.sort();
+    endpoints
+}
+
+/// Resolve every upstream proxy host of `config`
+///
+/// Failures are logged as warnings; they only become an error when `upstreamDns.strict` is set.
+/// Upstream proxies on loopback that nothing listens on are logged as well.
+pub async fn check_upstreams(config: &Config) -> Result<(), String> {
+    for warning in unreachable_loopback_upstreams(config).await {
+        warn!("{warning}");
+    }
+    let mut failures = Vec::new();
+    for (name, host, port) in upstream_endpoints(config) {
+        match tokio::net::lookup_host((host.as_str(), port)).await {
+            Ok(addrs) => {
+                if addrs.count() == 0 {
+                    failures.push(format!("profile '{name}': '{host}' has no addresses"));
+                } else {
+                    trace!("Upstream '{host}' of profile '{name}' resolves");
+                }
+            }
+            Err(e) => failures.push(format!("profile '{name}': cannot resolve '{host}': {e}")),
+        }
+    }
+
+    for failure in &failures {
+        warn!("Upstream DNS check failed for {failure}");
+    }
+    if config.upstream_dns.strict && !failures.is_empty() {
+        return Err(failures.join("; "));
+    }
+    Ok(())
+}
+
+/// Loopback upstream proxies of `config` that refuse connections, most likely not running
+///
+/// Ports of our own listen addresses are left out, as they may not be bound yet.
+async fn unreachable_loopback_upstreams(config: &Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (name, host, port) in config.loopback_upstreams() {
+        let addr = (host.trim_start_matches('[').trim_end_matches(']'), port);
+        // Loopback answers at once, one way or the other
+        let probe = tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr));
+        if !matches!(probe.await, Ok(Ok(_))) {
+            warnings.push(format!(
+                "profile '{name}': nothing listens on upstream {}, is that proxy running?",
+                crate::utils::join_host_port(&host, port)
+            ));
+        }
+    }
+    warnings
+}
+
+struct CachedAddrs {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+}
+
+/// Cache of resolved upstream proxy addresses, refreshed once entries are older than the TTL
+#[derive(Default)]
+pub struct UpstreamResolver {
+    entries: Mutex<HashMap<(String, u16), CachedAddrs>>,
+}
+
+impl UpstreamResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve `host:port`, reusing a cached answer younger than `ttl`
+    pub async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        ttl: Duration,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let key = (host.to_string(), port);
+        if let Some(cached) = self.entries.lock().unwrap().get(&key)
+            && cached.resolved_at.elapsed() < ttl
+        {
+            return Ok(cached.addrs.clone());
+        }
+
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{host}' has no addresses"),
+            ));
+        }
+        trace!("Resolved upstream {host}:{port} to {addrs:?}");
+        self.entries.lock().unwrap().insert(
+            key,
+            CachedAddrs {
+                addrs: addrs.clone(),
+                resolved_at: Instant::now(),
+            },
+        );
+        Ok(addrs)
+    }
+}
+
+/// Where a `DnsCache` gets its answers from
+pub trait Lookup: Send + Sync {
+    fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = io::Result<Vec<SocketAddr>>> + Send;
+}
+
+/// The system resolver
+#[derive(Default)]
+pub struct SystemLookup;
+
+impl Lookup for SystemLookup {
+    async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// A lookup gave up waiting while `max_pending` others were in flight
+#[derive(Debug)]
+pub struct LookupsSaturated;
+
+impl std::fmt::Display for LookupsSaturated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many DNS lookups pending")
+    }
+}
+
+impl std::error::Error for LookupsSaturated {}
+
+impl LookupsSaturated {
+    /// Whether `error` was caused by the DNS lookup limit
+    pub fn is_cause_of(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<Self>())
+    }
+}
+
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    last_used: Instant,
+}
+
+/// Bounded cache of locally resolved hostnames, evicting the least recently used one when full
+#[derive(Default)]
+pub struct DnsCache<L = SystemLookup> {
+    lookup: L,
+    entries: Mutex<HashMap<(String, u16), CacheEntry>>,
+    /// Slots for lookups in flight, replaced when `max_pending` changes
+    pending: Mutex<Option<(usize, Arc<Semaphore>)>>,
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<L: Lookup> DnsCache<L> {
+    /// A cache answering misses through `lookup` instead of the system resolver
+    #[cfg(test)]
+    pub fn with_lookup(lookup: L) -> Self {
+        Self {
+            lookup,
+            entries: Mutex::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Slots bounding lookups in flight to `max_pending`
+    fn pending_slots(&self, max_pending: usize) -> Arc<Semaphore> {
+        let mut pending = self.pending.lock().unwrap();
+        match &*pending {
+            Some((max, slots)) if *max == max_pending => slots.clone(),
+            _ => {
+                // Lookups holding a slot of the previous semaphore just release it there
+                let slots = Arc::new(Semaphore::new(max_pending));
+                *pending = Some((max_pending, slots.clone()));
+                slots
+            }
+        }
+    }
+
+    /// Resolve `host:port`, reusing a cached answer younger than `options.ttl_secs`
+    pub async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        options: &DnsCacheOptions,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let ttl = Duration::from_secs(options.ttl_secs);
+        let enabled = !ttl.is_zero() && options.max_entries > 0;
+        let key = (host.to_string(), port);
+        if enabled
+            && let Some(entry) = self.entries.lock().unwrap().get_mut(&key)
+            && entry.resolved_at.elapsed() < ttl
+        {
+            entry.last_used = Instant::now();
+            return Ok(entry.addrs.clone());
+        }
+
+        // IP literals are not looked up, so they need no slot
+        let _slot = if options.max_pending > 0 && host.parse::<std::net::IpAddr>().is_err() {
+            let slots = self.pending_slots(options.max_pending);
+            let wait = Duration::from_millis(options.pending_wait_ms);
+            match tokio::time::timeout(wait, slots.acquire_owned()).await {
+                Ok(Ok(slot)) => Some(slot),
+                Ok(Err(_)) | Err(_) => return Err(io::Error::other(LookupsSaturated)),
+            }
+        } else {
+            None
+        };
+        let addrs = self.lookup.lookup(host, port).await?;
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{host}' has no addresses"),
+            ));
+        }
+        if enabled {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, entry| entry.resolved_at.elapsed() < ttl);
+            while entries.len() >= options.max_entries && !entries.contains_key(&key) {
+                let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                entries.remove(&oldest);
+            }
+            let now = Instant::now();
+            entries.insert(
+                key,
+                CacheEntry {
+                    addrs: addrs.clone(),
+                    resolved_at: now,
+                    last_used: now,
+                },
+            );
+        }
+        Ok(addrs)
+    }
+}
+
+/// Resolve `host:port` for a direct connection, keeping only addresses of `family`
+pub async fn lookup_direct(
+    dns: &DnsCache,
+    options: &DnsCacheOptions,
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = dns
+        .resolve(host, port, options)
+        .await?
+        .into_iter()
+        .filter(|addr| family.allows(addr))
+        .collect();
+    if addrs.is_empty() {
+        let kind = match family {
+            AddressFamily::Ipv4 => "IPv4 ",
+            AddressFamily::Ipv6 => "IPv6 ",
+            AddressFamily::Dual => "",
+        };
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("'{host}' has no {kind}addresses"),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Resolver for hyper's `HttpConnector` answering through `lookup_direct`, so plain-HTTP
+/// requests sent with hyper share the DNS cache and its lookup limit
+#[derive(Clone)]
+pub struct DirectResolver {
+    pub dns: Arc<DnsCache>,
+    pub options: DnsCacheOptions,
+    pub family: AddressFamily,
+}
+
+impl tower_service::Service<Name> for DirectResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move {
+            // The connector puts the port of the request on the addresses
+            let addrs = lookup_direct(
+                &resolver.dns,
+                &resolver.options,
+                name.as_str(),
+                0,
+                resolver.family,
+            )
+            .await?;
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// How long each address but the last is tried when no connect timeout is shared among them
+const ADDR_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Try `addrs` in order, returning the first connection that succeeds
+///
+/// Each address gets an even share of what is left of `budget`, or `ADDR_ATTEMPT_TIMEOUT`
+/// without one, so an address that never answers does not keep the others from being tried.
+/// Addresses that time out count as failed like those that refuse.
+async fn connect_first(
+    host: &str,
+    addrs: Vec<SocketAddr>,
+    budget: Option<Duration>,
+    mut on_failure: impl FnMut(SocketAddr),
+) -> io::Result<TcpStream> {
+    let deadline = budget.map(|budget| Instant::now() + budget);
+    let count = addrs.len();
+    let mut last_error = None;
+    for (index, addr) in addrs.into_iter().enumerate() {
+        let remaining = (count - index) as u32;
+        let limit = match deadline {
+            Some(deadline) => Some(deadline.saturating_duration_since(Instant::now()) / remaining),
+            None => (remaining > 1).then_some(ADDR_ATTEMPT_TIMEOUT),
+        };
+        let attempt = TcpStream::connect(addr);
+        let result = match limit {
+            Some(limit) => tokio::time::timeout(limit, attempt)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Timed out connecting to {addr}"),
+                    ))
+                }),
+            None => attempt.await,
+        };
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                trace!("Direct connect to {addr} for {host} failed: {e}");
+                on_failure(addr);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("'{host}' has no addresses"),
+        )
+    }))
+}
+
+/// Connect to `host:port` over `family`, trying its addresses in resolver order within
+/// `budget`
+pub async fn connect_direct(
+    dns: &DnsCache,
+    options: &DnsCacheOptions,
+    host: &str,
+    port: u16,
+    family: AddressFamily,
+    budget: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let addrs = lookup_direct(dns, options, host, port, family).await?;
+    connect_first(host, addrs, budget, |_| {}).await
+}
+
+/// How long an address that failed to connect is tried last
+const FAILED_ADDR_PENALTY: Duration = Duration::from_secs(30);
+
+/// Targets whose rotation is remembered; the least recently connected one is forgotten first
+const MAX_ROTATED_TARGETS: usize = 1024;
+
+/// Counts a tunnel as open to its address until dropped, for least-connections balancing
+#[derive(Debug)]
+pub struct Lease(Arc<AtomicUsize>);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spreads direct tunnels across the addresses of multi-record targets
+///
+/// By default each connection to a host starts one address further along its record list;
+/// the other strategies pick addresses at random by weight or by their open tunnels. Either
+/// way, addresses that recently failed are moved to the back.
+#[derive(Default)]
+pub struct RoundRobin {
+    /// Position in the rotation of each target, and when it last moved
+    next: Mutex<HashMap<(String, u16), (usize, Instant)>>,
+    failed: Mutex<HashMap<SocketAddr, Instant>>,
+    /// Tunnels open to each address, as counted by outstanding leases
+    active: Mutex<HashMap<SocketAddr, Arc<AtomicUsize>>>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Order `addrs` for the next connection to `host:port`
+    fn order(
+        &self,
+        host: &str,
+        port: u16,
+        mut addrs: Vec<SocketAddr>,
+        balance: &BalanceOptions,
+    ) -> Vec<SocketAddr> {
+        if addrs.is_empty() {
+            return addrs;
+        }
+        match balance.strategy {
+            BalanceStrategy::RoundRobin | BalanceStrategy::LeastConnections => {
+                let start = {
+                    let mut next = self.next.lock().unwrap();
+                    let key = (host.to_string(), port);
+                    while next.len() >= MAX_ROTATED_TARGETS && !next.contains_key(&key) {
+                        let Some(oldest) = next
+                            .iter()
+                            .min_by_key(|(_, (_, used_at))| *used_at)
+                            .map(|(key, _)| key.clone())
+                        else {
+                            break;
+                        };
+                        next.remove(&oldest);
+                    }
+                    let (counter, used_at) = next.entry(key).or_insert((0, Instant::now()));
+                    let start = *counter % addrs.len();
+                    *counter = counter.wrapping_add(1);
+                    *used_at = Instant::now();
+                    start
+                };
+                addrs.rotate_left(start);
+            }
+            BalanceStrategy::WeightedRandom => {
+                addrs = crate::utils::weighted_shuffle(
+                    addrs
+                        .into_iter()
+                        .map(|addr| (addr, u64::from(balance.weight(addr.ip())))),
+                )
+            }
+        }
+        if balance.strategy == BalanceStrategy::LeastConnections {
+            // Stable sort keeps the rotation among equally busy addresses
+            addrs.sort_by_key(|addr| self.open_tunnels(*addr));
+        }
+
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|_, failed_at| failed_at.elapsed() < FAILED_ADDR_PENALTY);
+        // Stable sort keeps the order among healthy and among failed addresses
+        addrs.sort_by_key(|addr| failed.contains_key(addr));
+        addrs
+    }
+
+    fn mark_failed(&self, addr: SocketAddr) {
+        self.failed.lock().unwrap().insert(addr, Instant::now());
+    }
+
+    /// Tunnels currently open to `addr`
+    pub fn open_tunnels(&self, addr: SocketAddr) -> usize {
+        self.active
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Count a tunnel as open to `addr` until the lease is dropped
+    fn lease(&self, addr: SocketAddr) -> Lease {
+        let mut active = self.active.lock().unwrap();
+        // Forget the addresses left without tunnels, which count as idle all the same
+        active.retain(|_, count| count.load(Ordering::Relaxed) > 0);
+        let count = active.entry(addr).or_default().clone();
+        count.fetch_add(1, Ordering::Relaxed);
+        Lease(count)
+    }
+
+    /// Connect to `host:port` over `family`, trying its addresses in the order `balance` puts
+    /// them within `budget`
+    ///
+    /// The lease must be held for as long as the connection is open.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn connect(
+        &self,
+        dns: &DnsCache,
+        options: &DnsCacheOptions,
+        host: &str,
+        port: u16,
+        family: AddressFamily,
+        balance: &BalanceOptions,
+        budget: Option<Duration>,
+    ) -> io::Result<(TcpStream, Lease)> {
+        let addrs = lookup_direct(dns, options, host, port, family).await?;
+        let addrs = self.order(host, port, addrs, balance);
+        let stream = connect_first(host, addrs, budget, |addr| self.mark_failed(addr)).await?;
+        let lease = self.lease(stream.peer_addr()?);
+        Ok((stream, lease))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_upstream(host: &str, strict: bool) -> Config {
+        json5::from_str(&format!(
+            r#"{{
+                switch: {{ default: "proxy", rules: [] }},
+                profiles: {{ proxy: {{ scheme: "socks5", host: "{host}", port: 1080 }} }},
+                upstreamDns: {{ strict: {strict} }},
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bogus_upstream_lenient() {
+        let config = config_with_upstream("no-such-proxy.invalid", false);
+        assert!(check_upstreams(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bogus_upstream_strict() {
+        let config = config_with_upstream("no-such-proxy.invalid", true);
+        let err = check_upstreams(&config).await.unwrap_err();
+        assert!(err.contains("no-such-proxy.invalid"));
+    }
+
+    #[tokio::test]
+    async fn test_ip_upstream_strict() {
+        let config = config_with_upstream("127.0.0.1", true);
+        assert!(check_upstreams(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_warns_about_loopback_upstream_nothing_listens_on() {
+        // A port that was free a moment ago, and one something listens on
+        let free_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let running = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let running_port = running.local_addr().unwrap().port();
+        let config = |listen: &str| -> Config {
+            json5::from_str(&format!(
+                r#"{{
+                    listen: [{listen}],
+                    switch: {{ default: "stopped", rules: [] }},
+                    profiles: {{
+                        stopped: {{ scheme: "socks5", host: "127.0.0.1", port: {free_port} }},
+                        running: {{ scheme: "http", host: "localhost", port: {running_port} }},
+                        remote: {{ scheme: "http", host: "192.0.2.1", port: {free_port} }},
+                    }},
+                }}"#
+            ))
+            .unwrap()
+        };
+
+        let warnings = unreachable_loopback_upstreams(&config("")).await;
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(
+            warnings[0].contains(&format!(
+                "'stopped': nothing listens on upstream 127.0.0.1:{free_port}"
+            )),
+            "{warnings:?}"
+        );
+        let config = config(&format!(r#""127.0.0.1:{free_port}""#));
+        assert!(unreachable_loopback_upstreams(&config).await.is_empty());
+        // Only a warning
+        config.validate().unwrap();
+        check_upstreams(&config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolve_caches_within_ttl() {
+        let resolver = UpstreamResolver::new();
+        let ttl = Duration::from_secs(60);
+        let first = resolver.resolve("127.0.0.1", 1080, ttl).await.unwrap();
+        let resolved_at =
+            resolver.entries.lock().unwrap()[&("127.0.0.1".to_string(), 1080)].resolved_at;
+
+        let second = resolver.resolve("127.0.0.1", 1080, ttl).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(
+            resolver.entries.lock().unwrap()[&("127.0.0.1".to_string(), 1080)].resolved_at,
+            resolved_at
+        );
+    }
+
+    /// Answers every lookup with 192.0.2.1, counting the calls
+    #[derive(Default)]
+    struct CountingLookup {
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountingLookup {
+        fn calls(&self) -> usize {
+            self.calls.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl Lookup for CountingLookup {
+        async fn lookup(&self, _host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(vec![SocketAddr::from(([192, 0, 2, 1], port))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_hits_within_ttl() {
+        let cache = DnsCache::with_lookup(CountingLookup::default());
+        let options = DnsCacheOptions::default();
+
+        let first = cache.resolve("example.com", 443, &options).await.unwrap();
+        let second = cache.resolve("example.com", 443, &options).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(cache.lookup.calls(), 1);
+
+        // Another port is another entry
+        cache.resolve("example.com", 80, &options).await.unwrap();
+        assert_eq!(cache.lookup.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_evicts_least_recently_used() {
+        let cache = DnsCache::with_lookup(CountingLookup::default());
+        let options = DnsCacheOptions {
+            max_entries: 2,
+            ..DnsCacheOptions::default()
+        };
+
+        cache.resolve("a.example", 443, &options).await.unwrap();
+        cache.resolve("b.example", 443, &options).await.unwrap();
+        cache.resolve("a.example", 443, &options).await.unwrap();
+        // Evicts b, the least recently used
+        cache.resolve("c.example", 443, &options).await.unwrap();
+        assert_eq!(cache.lookup.calls(), 3);
+        cache.resolve("a.example", 443, &options).await.unwrap();
+        assert_eq!(cache.lookup.calls(), 3);
+        cache.resolve("b.example", 443, &options).await.unwrap();
+        assert_eq!(cache.lookup.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_disabled() {
+        let cache = DnsCache::with_lookup(CountingLookup::default());
+        for options in [
+            DnsCacheOptions {
+                ttl_secs: 0,
+                ..DnsCacheOptions::default()
+            },
+            DnsCacheOptions {
+                max_entries: 0,
+                ..DnsCacheOptions::default()
+            },
+        ] {
+            cache.resolve("example.com", 443, &options).await.unwrap();
+        }
+        assert_eq!(cache.lookup.calls(), 2);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    /// Answers every lookup with 192.0.2.1 after a delay, recording how many were in flight
+    #[derive(Default)]
+    struct SlowLookup {
+        in_flight: AtomicUsize,
+        most_in_flight: AtomicUsize,
+    }
+
+    impl Lookup for SlowLookup {
+        async fn lookup(&self, _host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::from(([192, 0, 2, 1], port))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pending_lookups_are_bounded() {
+        let cache = Arc::new(DnsCache::with_lookup(SlowLookup::default()));
+        let resolve_all = |options: DnsCacheOptions, prefix: &'static str| {
+            let lookups: Vec<_> = (0..20)
+                .map(|i| {
+                    let cache = cache.clone();
+                    let options = options.clone();
+                    tokio::spawn(async move {
+                        cache
+                            .resolve(&format!("{prefix}{i}.example"), 443, &options)
+                            .await
+                    })
+                })
+                .collect();
+            async move {
+                let mut results = Vec::new();
+                for lookup in lookups {
+                    results.push(lookup.await.unwrap());
+                }
+                results
+            }
+        };
+
+        // Only the first two get a slot; the rest give up long before either finishes
+        let results = resolve_all(
+            DnsCacheOptions {
+                max_pending: 2,
+                pending_wait_ms: 50,
+                ..DnsCacheOptions::default()
+            },
+            "a",
+        )
+        .await;
+        let saturated = results
+            .iter()
+            .filter(|result| result.as_ref().is_err_and(LookupsSaturated::is_cause_of))
+            .count();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        assert_eq!(saturated, 18);
+        assert_eq!(cache.lookup.most_in_flight.load(Ordering::SeqCst), 2);
+
+        // Waiting long enough, all of them take turns
+        let results = resolve_all(
+            DnsCacheOptions {
+                max_pending: 5,
+                pending_wait_ms: 5_000,
+                ..DnsCacheOptions::default()
+            },
+            "b",
+        )
+        .await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(cache.lookup.most_in_flight.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_direct_filters_by_family() {
+        // "localhost" may not have an IPv6 entry here, so use literals of each kind
+        let dns = DnsCache::new();
+        let options = DnsCacheOptions::default();
+        for (host, family, usable) in [
+            ("127.0.0.1", AddressFamily::Dual, true),
+            ("::1", AddressFamily::Dual, true),
+            ("127.0.0.1", AddressFamily::Ipv4, true),
+            ("::1", AddressFamily::Ipv4, false),
+            ("127.0.0.1", AddressFamily::Ipv6, false),
+            ("::1", AddressFamily::Ipv6, true),
+        ] {
+            let result = lookup_direct(&dns, &options, host, 80, family).await;
+            assert_eq!(result.is_ok(), usable, "{host} with {family:?}");
+        }
+        let err = lookup_direct(&dns, &options, "::1", 80, AddressFamily::Ipv4)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no IPv4 addresses"));
+    }
+
+    #[tokio::test]
+    async fn test_direct_resolver_answers_through_the_cache() {
+        use tower_service::Service;
+
+        let mut resolver = DirectResolver {
+            dns: Arc::new(DnsCache::new()),
+            options: DnsCacheOptions::default(),
+            family: AddressFamily::Ipv4,
+        };
+        let addrs: Vec<_> = resolver
+            .call("localhost".parse().unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert!(!addrs.is_empty(), "{addrs:?}");
+        assert!(addrs.iter().all(SocketAddr::is_ipv4), "{addrs:?}");
+        let entries = resolver.dns.entries.lock().unwrap();
+        assert!(entries.contains_key(&("localhost".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_address_family_on_dual_stack_records() {
+        let dual_stack: Vec<SocketAddr> =
+            ["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443"]
+                .iter()
+                .map(|addr| addr.parse().unwrap())
+                .collect();
+        let usable = |family: AddressFamily| -> Vec<SocketAddr> {
+            dual_stack
+                .iter()
+                .copied()
+                .filter(|addr| family.allows(addr))
+                .collect()
+        };
+        assert_eq!(usable(AddressFamily::Ipv4), [dual_stack[1]]);
+        assert_eq!(usable(AddressFamily::Ipv6), [dual_stack[0], dual_stack[2]]);
+        assert_eq!(usable(AddressFamily::Dual), dual_stack);
+    }
+
+    #[test]
+    fn test_round_robin_rotates_and_demotes_failures() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:443", "10.0.0.2:443", "10.0.0.3:443"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let round_robin = RoundRobin::new();
+        let balance = BalanceOptions::default();
+
+        let starts: Vec<_> = (0..4)
+            .map(|_| round_robin.order("cdn.example", 443, addrs.clone(), &balance)[0])
+            .collect();
+        assert_eq!(starts, [addrs[0], addrs[1], addrs[2], addrs[0]]);
+
+        round_robin.mark_failed(addrs[1]);
+        // Would start at the second address, which is now tried last
+        assert_eq!(
+            round_robin.order("cdn.example", 443, addrs.clone(), &balance),
+            [addrs[2], addrs[0], addrs[1]]
+        );
+    }
+
+    #[test]
+    fn test_round_robin_forgets_targets_beyond_cap() {
+        let addrs: Vec<SocketAddr> = vec!["10.0.0.1:443".parse().unwrap()];
+        let round_robin = RoundRobin::new();
+        let balance = BalanceOptions::default();
+
+        for i in 0..MAX_ROTATED_TARGETS + 10 {
+            round_robin.order(&format!("host{i}.example"), 443, addrs.clone(), &balance);
+        }
+        let next = round_robin.next.lock().unwrap();
+        assert_eq!(next.len(), MAX_ROTATED_TARGETS);
+        let last = format!("host{}.example", MAX_ROTATED_TARGETS + 9);
+        assert!(next.contains_key(&(last, 443)));
+    }
+
+    #[test]
+    fn test_least_connections_prefers_idlest_address() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:443", "10.0.0.2:443", "10.0.0.3:443"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let balancer = RoundRobin::new();
+        let balance = BalanceOptions {
+            strategy: BalanceStrategy::LeastConnections,
+            ..Default::default()
+        };
+
+        let busy = [
+            balancer.lease(addrs[0]),
+            balancer.lease(addrs[0]),
+            balancer.lease(addrs[1]),
+        ];
+        for _ in 0..3 {
+            assert_eq!(
+                balancer.order("cdn.example", 443, addrs.clone(), &balance),
+                [addrs[2], addrs[1], addrs[0]]
+            );
+        }
+
+        // Closing the tunnels makes the addresses equally idle again
+        drop(busy);
+        assert_eq!(balancer.open_tunnels(addrs[0]), 0);
+        let starts: Vec<_> = (0..3)
+            .map(|_| balancer.order("cdn.example", 443, addrs.clone(), &balance)[0])
+            .collect();
+        assert_eq!(starts.len(), 3);
+        assert!(addrs.iter().all(|addr| starts.contains(addr)), "{starts:?}");
+
+        // Addresses left without tunnels are forgotten by the next lease
+        let _open = balancer.lease(addrs[2]);
+        assert_eq!(balancer.active.lock().unwrap().len(), 1);
+    }
+
+    /// A loopback address whose accept queue is full, so new connections to it hang
+    async fn blackholed_addr() -> (SocketAddr, Vec<TcpStream>) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Never accepted; the listener is leaked so the queue stays full
+        std::mem::forget(listener);
+        let mut queued = Vec::new();
+        for _ in 0..16 {
+            let attempt = TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(200), attempt).await {
+                Ok(stream) => queued.push(stream.unwrap()),
+                Err(_) => return (addr, queued),
+            }
+        }
+        panic!("the accept queue of {addr} never filled up");
+    }
+
+    #[tokio::test]
+    async fn test_connect_moves_past_hanging_address_and_demotes_it() {
+        let (blackholed, _queued) = blackholed_addr().await;
+        let live = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let balancer = RoundRobin::new();
+
+        let stream = connect_first(
+            "cdn.example",
+            vec![blackholed, live_addr],
+            Some(Duration::from_secs(2)),
+            |addr| balancer.mark_failed(addr),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live_addr);
+        // The next connection tries the live address first
+        let ordered = balancer.order(
+            "cdn.example",
+            443,
+            vec![blackholed, live_addr],
+            &BalanceOptions::default(),
+        );
+        assert_eq!(ordered, [live_addr, blackholed]);
+    }
+
+    #[tokio::test]
+    async fn test_lease_counts_open_tunnel_until_dropped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let balancer = RoundRobin::new();
+        let balance = BalanceOptions {
+            strategy: BalanceStrategy::LeastConnections,
+            ..Default::default()
+        };
+
+        let (stream, lease) = balancer
+            .connect(
+                &DnsCache::new(),
+                &DnsCacheOptions::default(),
+                "127.0.0.1",
+                addr.port(),
+                AddressFamily::Dual,
+                &balance,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(balancer.open_tunnels(addr), 1);
+        drop((stream, lease));
+        assert_eq!(balancer.open_tunnels(addr), 0);
+    }
+
+    #[test]
+    fn test_weighted_random_skips_zero_weights_until_last() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:443", "10.0.0.2:443"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let balance = BalanceOptions {
+            strategy: BalanceStrategy::WeightedRandom,
+            weights: HashMap::from([("10.0.0.1".to_string(), 0)]),
+        };
+        let balancer = RoundRobin::new();
+        for _ in 0..20 {
+            assert_eq!(
+                balancer.order("cdn.example", 443, addrs.clone(), &balance),
+                [addrs[1], addrs[0]]
+            );
+        }
+    }
+}
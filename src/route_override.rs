@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Where every connection goes while an operator overrides the rules, e.g. during an outage
+/// of a proxy provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForcedRoute {
+    /// Straight to the target, bypassing every proxy
+    Direct,
+    /// Through the profile named by `emergencyProfile`
+    Emergency,
+}
+
+impl ForcedRoute {
+    /// Parse the name the admin endpoint takes: `direct` or `emergency`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "direct" => Some(ForcedRoute::Direct),
+            "emergency" => Some(ForcedRoute::Emergency),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ForcedRoute::Direct => "direct",
+            ForcedRoute::Emergency => "emergency",
+        }
+    }
+}
+
+/// Runtime switch taking precedence over the rules of every listener until it is cleared
+///
+/// It is not part of the configuration, so it survives reloads.
+#[derive(Debug, Default)]
+pub struct RouteOverride(Mutex<Option<ForcedRoute>>);
+
+impl RouteOverride {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<ForcedRoute> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Force every new connection along `route`, or back under the rules with `None`
+    pub fn set(&self, route: Option<ForcedRoute>) {
+        let previous = std::mem::replace(&mut *self.0.lock().unwrap(), route);
+        if previous != route {
+            match route {
+                Some(route) => warn!("Routing overridden: all traffic goes {}", route.name()),
+                None => warn!("Routing override cleared, rules apply again"),
+            }
+        }
+    }
+}
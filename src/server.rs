@@ -1,26 +1,474 @@
-use crate::config::Config;
+use crate::breaker::Breaker;
+use crate::config::{
+    Config, DefaultProfile, ListenerProtocol, MatchStrategy, Rule, SocksCommand, Switch,
+    WeightedProfile,
+};
+use crate::credentials::CredentialCache;
+use crate::flow::{Flow, FlowExporter, FlowMeter, UpstreamAddrs};
+use crate::hedge::HedgeSlots;
+use crate::limiter::ConnectionLimiter;
+use crate::metrics::Metrics;
+use crate::protocols::proxy_protocol::{self, ClientAddrs};
 use crate::protocols::{http, socks};
+use crate::resolver::{DnsCache, Lease, LookupsSaturated, RoundRobin, UpstreamResolver};
+use crate::route_override::{ForcedRoute, RouteOverride};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info, trace};
-
-fn select_profile(config: &Config, target_host: &str) -> String {
-    let mut selected = config.switch.default.clone();
-    for rule in config.switch.rules.iter() {
-        let pattern = &rule.pattern;
-        if crate::utils::matches_pattern(target_host, pattern) {
-            selected = rule.profile.clone();
-            break;
+use tracing::{Instrument, debug, error, info, trace, warn};
+
+/// How long a mirrored request may take before its response is abandoned
+const MIRROR_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long a load balancer may take to send the PROXY protocol header of a connection
+const PROXY_HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long a client of a TLS listener may take to complete the handshake
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long an upstream may take to answer `Expect: 100-continue` before the body is sent anyway
+const CONTINUE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Least time between two re-reads of the config file for a profile it did not define
+const MISSING_PROFILE_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Byte stream of an accepted client, either plain TCP or TLS-terminated
+pub trait ClientIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientIo for T {}
+
+pub type ClientStream = Box<dyn ClientIo>;
+
+/// Byte stream to an upstream, either plain TCP or TLS to an HTTPS proxy
+pub trait UpstreamIo: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The TCP connection the stream runs over
+    fn tcp(&self) -> &tokio::net::TcpStream;
+}
+
+impl UpstreamIo for tokio::net::TcpStream {
+    fn tcp(&self) -> &tokio::net::TcpStream {
+        self
+    }
+}
+
+impl<S: UpstreamIo> UpstreamIo for tokio_rustls::client::TlsStream<S> {
+    fn tcp(&self) -> &tokio::net::TcpStream {
+        self.get_ref().0.tcp()
+    }
+}
+
+impl<S: UpstreamIo + ?Sized> UpstreamIo for Box<S> {
+    fn tcp(&self) -> &tokio::net::TcpStream {
+        (**self).tcp()
+    }
+}
+
+pub type UpstreamStream = Box<dyn UpstreamIo>;
+
+/// Shared state handed to every accepted connection
+#[derive(Clone)]
+pub struct ProxyState {
+    pub config: Arc<RwLock<Config>>,
+    pub metrics: Arc<Metrics>,
+    pub resolver: Arc<UpstreamResolver>,
+    pub round_robin: Arc<RoundRobin>,
+    /// Cache for hostnames resolved locally
+    pub dns: Arc<DnsCache>,
+    pub breaker: Arc<Breaker>,
+    /// Operator override of the rules, set through the admin endpoint
+    pub route_override: Arc<RouteOverride>,
+    /// Secondary attempts of hedged profiles in flight
+    pub hedges: Arc<HedgeSlots>,
+    /// Output of the proxy profiles' credential commands
+    pub credentials: Arc<CredentialCache>,
+    /// When the config file was last re-read for a profile it did not define, held while
+    /// re-reading so concurrent connections wait for that reload instead of starting their own
+    pub missing_profile_reload: Arc<tokio::sync::Mutex<Option<tokio::time::Instant>>>,
+    /// Bounds the client connections served at once, shared by all listeners
+    pub limiter: Arc<ConnectionLimiter>,
+    /// Sends a flow record for each closed client connection, when configured
+    pub flow_exporter: Arc<FlowExporter>,
+    /// Traffic of the client connection being served, started once it is accepted
+    pub flow: Arc<Flow>,
+    /// Upstream addresses the client connection being served has tunnels open to, counted
+    /// as busy by least-connections balancing until it closes; upstream connections pooled by
+    /// a keep-alive session take theirs along instead
+    pub leases: Arc<Mutex<Vec<Lease>>>,
+    /// Cancelled to close the client connections still open at shutdown
+    pub closing: CancellationToken,
+    /// Listen address the connections were accepted on, selecting its rules
+    pub listener: String,
+    /// Addresses of the client connection being served, filled in once it is accepted
+    pub client_addrs: Option<ClientAddrs>,
+    /// Sequence number of the client connection being served, put on its log spans
+    pub connection_id: u64,
+    /// Cap on connecting upstream for the target being served, set once its rule is known
+    pub connect_timeout: Option<std::time::Duration>,
+    /// Profiles a tunnel to the target being served fails over to, in order, when connecting
+    /// through the selected one fails: the rest of a weighted default
+    pub fallbacks: Vec<(String, crate::config::Profile)>,
+    /// Header lines naming the profile and rule of the request being served, added to its
+    /// plain-HTTP response when `debugRouteHeaders` is on
+    pub route_headers: Option<String>,
+    /// Treat accepted connections as iptables-redirected traffic instead of proxy requests
+    pub transparent: bool,
+}
+
+impl ProxyState {
+    /// Count a connect outcome for `profile` and feed it to the profile's breaker
+    fn record_connect(&self, profile: &str, success: bool) {
+        self.metrics.record_connect(profile, success);
+        self.breaker.record(profile, success);
+    }
+
+    /// Meter for bytes relayed through `profile` on the connection being served
+    fn byte_meter(&self, profile: &str) -> FlowMeter {
+        FlowMeter::new(profile, self.metrics.byte_meter(profile), self.flow.clone())
+    }
+
+    /// Note the addresses of the upstream connection just established on the connection's
+    /// span and flow record
+    fn record_upstream(&self, addrs: UpstreamAddrs) {
+        let span = tracing::Span::current();
+        span.record("egress", tracing::field::display(addrs.egress.ip()));
+        span.record("upstream_addr", tracing::field::display(addrs.remote));
+        self.flow.set_upstream(addrs);
+    }
+}
+
+/// Name of the built-in direct profile that forced-direct routing uses
+const FORCED_DIRECT: &str = "forced-direct";
+
+/// Pick the profile for `target_host`, along with the rule that selected it (if any)
+///
+/// A `forced` route takes precedence over everything else. Otherwise, rules pointing at a
+/// profile with an open breaker (or, with the `first-healthy` strategy, a profile whose last
+/// connect failed) are passed over, falling through to the next matching rule or the default.
+/// `headers` are those of a plain-HTTP request and `None` for tunnels, which pass over rules
+/// matching on a header or limited to plain HTTP.
+fn select_profile<'a>(
+    config: &'a Config,
+    listener: &str,
+    breaker: &Breaker,
+    forced: Option<ForcedRoute>,
+    target_host: &str,
+    headers: Option<&http::Headers>,
+) -> (String, Option<&'a Rule>) {
+    select_profile_at(
+        config,
+        listener,
+        breaker,
+        forced,
+        target_host,
+        headers,
+        std::time::SystemTime::now(),
+    )
+}
+
+/// The profile called `name`, with `FORCED_DIRECT` standing for plain direct connections
+fn profile_named(config: &Config, name: &str) -> Option<crate::config::Profile> {
+    match config.profiles.get(name) {
+        Some(profile) => Some(profile.clone()),
+        None => (name == FORCED_DIRECT).then(crate::config::Profile::direct),
+    }
+}
+
+/// The profile a tunnel called `name` goes through: a mirror's primary, as only plain HTTP
+/// can be mirrored, and otherwise the profile itself
+fn tunnel_profile_named(config: &Config, name: &str) -> Option<crate::config::Profile> {
+    match profile_named(config, name) {
+        Some(crate::config::Profile::Mirror { primary, .. }) => {
+            config.profiles.get(&primary).cloned()
+        }
+        profile => profile,
+    }
+}
+
+/// The profile `target_host` is routed through on `listener`, as a freshly started server
+/// with every profile healthy would pick it, along with the rule that selected it (if any)
+///
+/// `headers` are those of a plain-HTTP request, for rules matching on a header, and `None`
+/// for tunnels.
+pub fn route<'a>(
+    config: &'a Config,
+    listener: &str,
+    target_host: &str,
+    headers: Option<&http::Headers>,
+) -> (String, Option<&'a Rule>) {
+    // Nothing is ever recorded in it, so every profile stays healthy
+    static FRESH: std::sync::LazyLock<Breaker> = std::sync::LazyLock::new(Breaker::new);
+    select_profile(config, listener, &FRESH, None, target_host, headers)
+}
+
+/// Like `select_profile`, evaluating rule schedules at `now`
+fn select_profile_at<'a>(
+    config: &'a Config,
+    listener: &str,
+    breaker: &Breaker,
+    forced: Option<ForcedRoute>,
+    target_host: &str,
+    headers: Option<&http::Headers>,
+    now: std::time::SystemTime,
+) -> (String, Option<&'a Rule>) {
+    match forced {
+        Some(ForcedRoute::Direct) => return (FORCED_DIRECT.to_string(), None),
+        // Validation makes sure it is defined; without one the rules keep applying
+        Some(ForcedRoute::Emergency) => {
+            if let Some(profile) = &config.emergency_profile {
+                return (profile.clone(), None);
+            }
+        }
+        None => {}
+    }
+    let switch = config.switch_for(listener);
+    let allowed = |profile: &str| {
+        let allowed = config
+            .breaker
+            .is_none_or(|options| breaker.allows(profile, &options));
+        if !allowed {
+            debug!("Skipping profile {} while its breaker is open", profile);
+        }
+        allowed
+    };
+    // Letting a half-open breaker's trial through is a commitment, so it is only made for the
+    // profile about to be picked; another connection may have taken the trial since the check
+    let admitted = |profile: &str| {
+        config
+            .breaker
+            .is_none_or(|options| breaker.admit(profile, &options))
+    };
+    let usable = |rule: &Rule| {
+        if rule
+            .tunnel
+            .is_some_and(|tunnel| tunnel != headers.is_none())
+        {
+            return false;
+        }
+        if let Some(header) = &rule.header
+            && !headers.is_some_and(|headers| header.matches(headers))
+        {
+            return false;
+        }
+        if switch.match_strategy == MatchStrategy::FirstHealthy
+            && !breaker.is_healthy(&rule.profile)
+        {
+            debug!("Skipping unhealthy profile {}", rule.profile);
+            return false;
+        }
+        allowed(&rule.profile)
+    };
+    if let Some(rule) = switch
+        .matching(target_host, now)
+        .find(|rule| usable(rule) && admitted(&rule.profile))
+    {
+        return (rule.profile.clone(), Some(rule));
+    }
+    let default = match &switch.default {
+        DefaultProfile::Single(profile) => profile.clone(),
+        DefaultProfile::Weighted(entries) => {
+            let order = weighted_order(entries, |profile| breaker.is_healthy(profile));
+            order
+                .iter()
+                .find(|profile| allowed(profile) && admitted(profile))
+                .or(order.first())
+                .map(|profile| profile.to_string())
+                .unwrap_or_default()
+        }
+    };
+    (default, None)
+}
+
+/// Profiles of a weighted default in the order they should be tried
+///
+/// Healthy profiles come first, shuffled so that each is equally likely to lead as its share
+/// of the total weight; unhealthy ones follow in the order they were given.
+fn weighted_order(entries: &[WeightedProfile], is_healthy: impl Fn(&str) -> bool) -> Vec<&str> {
+    let (healthy, unhealthy): (Vec<_>, Vec<_>) = entries
+        .iter()
+        .filter(|entry| entry.weight > 0)
+        .partition(|entry| is_healthy(&entry.profile));
+    let mut order = crate::utils::weighted_shuffle(
+        healthy
+            .into_iter()
+            .map(|entry| (entry.profile.as_str(), u64::from(entry.weight))),
+    );
+    order.extend(unhealthy.iter().map(|entry| entry.profile.as_str()));
+    order
+}
+
+/// The other profiles of a weighted default to fail over to, in the order to try them, when
+/// the switch routed to `chosen` by its default
+fn default_fallbacks(
+    config: &Config,
+    listener: &str,
+    breaker: &Breaker,
+    forced: Option<ForcedRoute>,
+    chosen: &str,
+    rule: Option<&Rule>,
+) -> Vec<(String, crate::config::Profile)> {
+    let DefaultProfile::Weighted(entries) = &config.switch_for(listener).default else {
+        return Vec::new();
+    };
+    if forced.is_some() || rule.is_some() {
+        return Vec::new();
+    }
+    weighted_order(entries, |profile| breaker.is_healthy(profile))
+        .into_iter()
+        .filter(|profile| *profile != chosen)
+        .filter_map(|profile| Some((profile.to_string(), config.profiles.get(profile)?.clone())))
+        .collect()
+}
+
+/// `X-Proxy-Twister-Profile` and `X-Proxy-Twister-Rule` header lines describing a route
+///
+/// Rules are numbered from 1 in the order they are checked, along with their pattern.
+fn route_headers(switch: &Switch, profile_name: &str, rule: Option<&Rule>) -> String {
+    let rule = match rule {
+        Some(rule) => {
+            let number = switch
+                .rules()
+                .iter()
+                .position(|candidate| std::ptr::eq(candidate, rule))
+                .map_or(0, |index| index + 1);
+            format!("{number} ({})", rule.pattern)
+        }
+        None => "default".to_string(),
+    };
+    format!("X-Proxy-Twister-Profile: {profile_name}\r\nX-Proxy-Twister-Rule: {rule}\r\n")
+}
+
+/// Span covering a routed connection, carrying the target, profile and matched rule's tag
+///
+/// `conn_id` is shared by every request of a keep-alive client connection, so one client's
+/// logs can be picked out. `upstream_connect_ms`, `egress` (the local IP the upstream connection
+/// left from) and `upstream_addr` (the address it reached) are filled in once the upstream
+/// connection is established.
+fn connection_span(
+    state: &ProxyState,
+    profile_name: &str,
+    tag: Option<&str>,
+    target_host: &str,
+) -> tracing::Span {
+    tracing::info_span!(
+        "connection",
+        conn_id = state.connection_id,
+        profile = profile_name,
+        tag,
+        target = target_host,
+        upstream_connect_ms = tracing::field::Empty,
+        egress = tracing::field::Empty,
+        upstream_addr = tracing::field::Empty,
+    )
+}
+
+/// Resolve an upstream proxy host through the shared cache
+///
+/// Falls back to the configured host on failure so the connect attempt reports the error.
+async fn upstream_host(state: &ProxyState, host: &str, port: u16) -> String {
+    let ttl = {
+        let config_guard = state.config.read().await;
+        std::time::Duration::from_secs(config_guard.upstream_dns.refresh_secs)
+    };
+    match state.resolver.resolve(host, port, ttl).await {
+        Ok(addrs) => addrs[0].ip().to_string(),
+        Err(e) => {
+            debug!("Failed to resolve upstream {}: {}", host, e);
+            host.to_string()
+        }
+    }
+}
+
+/// PROXY protocol header announcing the current client, for profiles configured to send one
+fn proxy_preface(state: &ProxyState, profile: &crate::config::Profile) -> Vec<u8> {
+    profile
+        .proxy_protocol()
+        .map(|version| proxy_protocol::header(version, state.client_addrs.as_ref()))
+        .unwrap_or_default()
+}
+
+/// Credentials to offer the upstream proxy of `profile`: printed by its credential command
+/// when it has one, the configured username and password otherwise
+async fn proxy_auth(
+    state: &ProxyState,
+    profile: &crate::config::Profile,
+) -> tokio::io::Result<Option<(String, String)>> {
+    if let Some((command, ttl)) = profile.credential_command() {
+        let credentials = state.credentials.get(command, ttl).await?;
+        return Ok(Some((credentials.username, credentials.password)));
+    }
+    Ok(match profile {
+        crate::config::Profile::Socks5 {
+            username, password, ..
+        }
+        | crate::config::Profile::Http {
+            username, password, ..
+        } => username
+            .clone()
+            .map(|username| (username, password.clone().unwrap_or_default())),
+        crate::config::Profile::Direct { .. }
+        | crate::config::Profile::Mirror { .. }
+        | crate::config::Profile::Fastest { .. }
+        | crate::config::Profile::Hedged { .. }
+        | crate::config::Profile::Chain { .. }
+        | crate::config::Profile::Tarpit { .. } => None,
+    })
+}
+
+/// Pass `result` on, first forgetting the credential command output of `profile` when the
+/// proxy rejected it, so that the next connection fetches fresh credentials
+async fn forget_rejected_credentials<T>(
+    state: &ProxyState,
+    profile: &crate::config::Profile,
+    result: tokio::io::Result<T>,
+) -> tokio::io::Result<T> {
+    if let Err(e) = &result
+        && e.kind() == tokio::io::ErrorKind::PermissionDenied
+        && let Some((command, _)) = profile.credential_command()
+    {
+        debug!("Proxy rejected the credentials, the command will be run again");
+        state.credentials.invalidate(command).await;
+    }
+    result
+}
+
+/// Run `attempt` against each endpoint of a SOCKS5 or HTTP proxy profile in turn, until one
+/// succeeds
+///
+/// Endpoint hosts go through the shared resolver cache first; `attempt` is given the address
+/// to connect to, the port and the host as configured, which HTTPS proxies are verified as.
+async fn try_endpoints<T, F, Fut>(
+    state: &ProxyState,
+    profile: &crate::config::Profile,
+    mut attempt: F,
+) -> tokio::io::Result<T>
+where
+    F: FnMut(String, u16, String) -> Fut,
+    Fut: std::future::Future<Output = tokio::io::Result<T>>,
+{
+    let mut last_error = None;
+    for (host, port) in profile.proxy_endpoints() {
+        let proxy_host = upstream_host(state, &host, port).await;
+        match attempt(proxy_host, port, host.clone()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                debug!("Proxy endpoint {host}:{port} failed: {e}");
+                last_error = Some(e);
+            }
         }
     }
-    selected
+    Err(last_error.unwrap_or_else(|| {
+        tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Proxy profile has no endpoints",
+        )
+    }))
 }
 
-async fn extract_host_and_port(
-    client: &mut tokio::net::TcpStream,
+async fn extract_host_and_port<S: AsyncWrite + Unpin>(
+    client: &mut S,
     request: &http::HttpRequest,
 ) -> tokio::io::Result<(String, u16)> {
     trace!(
@@ -31,44 +479,44 @@ async fn extract_host_and_port(
     if request.method == "CONNECT" {
         return http::handle_connect(client, request.clone()).await;
     }
-    let host = request
-        .headers
-        .get("host")
-        .cloned()
-        .or_else(|| {
-            let uri = request.target.clone();
-            trace!(
-                "extract_host_and_port: trying to extract host from URI: {}",
-                uri
-            );
-            if let Some(uri) = uri.strip_prefix("http://") {
-                uri.split('/').next().map(|h| h.to_string())
-            } else {
-                None
-            }
-        })
-        .ok_or_else(|| {
-            tokio::io::Error::new(
-                tokio::io::ErrorKind::InvalidData,
-                "No host header in request",
-            )
-        })?;
+    let host = request.headers.get("host").cloned().or_else(|| {
+        let uri = request.target.clone();
+        trace!(
+            "extract_host_and_port: trying to extract host from URI: {}",
+            uri
+        );
+        if let Some(uri) = uri.strip_prefix("http://") {
+            uri.split('/').next().map(|h| h.to_string())
+        } else {
+            None
+        }
+    });
+    let Some(host) = host else {
+        let reason = "Request has neither a Host header nor an absolute http:// target";
+        return Err(http::bad_request(client, reason).await);
+    };
 
     trace!("extract_host_and_port: extracted host string: '{}'", host);
 
-    let parts: Vec<&str> = host.split(':').collect();
-    let host_without_port = parts[0].to_string();
-    let port = if parts.len() > 1 {
-        parts[1].parse().unwrap_or(80)
-    } else {
-        // Use the url crate to parse the scheme and determine the default port
-        match url::Url::parse(&request.target) {
-            Ok(url) => match url.scheme().to_ascii_lowercase().as_str() {
-                "https" => 443,
-                "http" => 80,
-                _ => 80,
-            },
-            Err(_) => 80,
+    let (host_without_port, explicit_port) = match crate::utils::parse_authority(&host) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            let reason = format!("Invalid target host '{host}': {e}");
+            return Err(http::bad_request(client, &reason).await);
+        }
+    };
+    let port = match explicit_port {
+        Some(port) => port,
+        None => {
+            // Use the url crate to parse the scheme and determine the default port
+            match url::Url::parse(&request.target) {
+                Ok(url) => match url.scheme().to_ascii_lowercase().as_str() {
+                    "https" => 443,
+                    "http" => 80,
+                    _ => 80,
+                },
+                Err(_) => 80,
+            }
         }
     };
 
@@ -79,20 +527,88 @@ async fn extract_host_and_port(
     Ok((host_without_port, port))
 }
 
+/// Copy `reader` into `writer` until EOF, reporting each chunk to `record` once written
+///
+/// With a `remaining` budget, copying stops with an error once it is used up: the bytes up to
+/// the cap are written, the rest of the read is dropped.
+async fn copy_metered<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    remaining: impl Fn() -> Option<u64>,
+    record: impl Fn(u64),
+) -> tokio::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // Pass the half-close on, the other side may be reading until EOF
+            return writer.shutdown().await;
+        }
+        let allowed = remaining().map_or(n, |left| left.min(n as u64) as usize);
+        writer.write_all(&buf[..allowed]).await?;
+        record(allowed as u64);
+        if allowed < n {
+            writer.flush().await?;
+            warn!("Closing connection that exceeded maxBytesPerConnection");
+            return Err(tokio::io::Error::other(
+                "Connection exceeded maxBytesPerConnection",
+            ));
+        }
+    }
+}
+
+/// Relay both directions between client and upstream, counting bytes on `meter` as they pass
+///
+/// Both directions end once either of them exceeds the connection's byte cap.
+async fn relay<C, U>(client: C, upstream: U, meter: &FlowMeter) -> tokio::io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ci, mut co) = tokio::io::split(client);
+    let (mut ui, mut uo) = tokio::io::split(upstream);
+    tokio::try_join!(
+        copy_metered(
+            &mut ci,
+            &mut uo,
+            || meter.remaining_up(),
+            |n| meter.add_up(n)
+        ),
+        copy_metered(
+            &mut ui,
+            &mut co,
+            || meter.remaining_down(),
+            |n| meter.add_down(n)
+        )
+    )?;
+    Ok(())
+}
+
 async fn handle_direct_connection(
-    mut client: tokio::net::TcpStream,
+    mut client: ClientStream,
     request: &http::HttpRequest,
     target_host: &str,
     port: u16,
+    profile_name: &str,
+    direct: &crate::config::Profile,
+    state: &ProxyState,
 ) -> tokio::io::Result<()> {
     if request.method == "CONNECT" {
         trace!("Attempting direct CONNECT to {}:{}", target_host, port);
-        match tokio::net::TcpStream::connect(format!("{target_host}:{port}")).await {
+        let (profile_name, result) =
+            connect_with_failover(state, profile_name, direct, target_host, port).await;
+        let profile_name = profile_name.as_str();
+        match result {
             Ok(target_stream) => {
                 trace!("Successfully connected to {}:{}", target_host, port);
+                state.record_connect(profile_name, true);
 
                 // Set socket options for better performance
-                if let Err(e) = target_stream.set_nodelay(true) {
+                if let Err(e) = target_stream.tcp().set_nodelay(true) {
                     trace!("Failed to set TCP_NODELAY on target stream: {}", e);
                 }
 
@@ -101,14 +617,10 @@ async fn handle_direct_connection(
                     .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
                     .await?;
 
-                let (mut ri, mut wi) = client.into_split();
-                let (mut ro, mut wo) = target_stream.into_split();
-                tokio::try_join!(
-                    tokio::io::copy(&mut ri, &mut wo),
-                    tokio::io::copy(&mut ro, &mut wi)
-                )?;
+                relay(client, target_stream, &state.byte_meter(profile_name)).await?;
             }
             Err(e) => {
+                state.record_connect(profile_name, false);
                 error!(
                     "Could not connect directly to {}:{}: {} (error kind: {:?})",
                     target_host,
@@ -116,258 +628,5209 @@ async fn handle_direct_connection(
                     e,
                     e.kind()
                 );
-                client.write_all(http::HTTP_SERVER_ERROR.as_bytes()).await?;
+                let response = connect_failure_response(state, target_host, profile_name, &e).await;
+                client.write_all(response.as_bytes()).await?;
             }
         }
     } else {
-        trace!(
-            "Attempting direct HTTP connection to {}:{} using hyper",
-            target_host, port
-        );
+        send_direct_request(
+            &mut client,
+            request,
+            target_host,
+            port,
+            profile_name,
+            direct,
+            state,
+        )
+        .await?;
+    }
+    Ok(())
+}
 
-        // Use our helper function to send the HTTP request
-        match http::send_http_request(request, target_host, port).await {
-            Ok((status, headers, body_bytes)) => {
-                trace!(
-                    "Received response from {}:{}: {:?}",
-                    target_host, port, status
-                );
+/// Send a plain-HTTP request straight to the origin and write its response to `client`
+///
+/// The response body is collected whole and always framed by `Content-Length`, whatever the
+/// origin used, so that the client connection can carry the next request (including one
+/// already pipelined behind this).
+async fn send_direct_request<W: AsyncWrite + Unpin>(
+    client: &mut W,
+    request: &http::HttpRequest,
+    target_host: &str,
+    port: u16,
+    profile_name: &str,
+    direct: &crate::config::Profile,
+    state: &ProxyState,
+) -> tokio::io::Result<()> {
+    let crate::config::Profile::Direct {
+        tls,
+        address_family,
+        enable_http2,
+        ..
+    } = direct
+    else {
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Not a direct profile",
+        ));
+    };
+    trace!(
+        "Attempting direct HTTP connection to {}:{} using hyper",
+        target_host, port
+    );
+
+    // Use our helper function to send the HTTP request
+    let (timeouts, resolver) = {
+        let config = state.config.read().await;
+        let timeouts = http::ExchangeTimeouts {
+            connect: state.connect_timeout,
+            response: config
+                .response_timeout_ms
+                .map(std::time::Duration::from_millis),
+        };
+        let resolver = crate::resolver::DirectResolver {
+            dns: state.dns.clone(),
+            options: config.dns_cache.clone(),
+            family: *address_family,
+        };
+        (timeouts, resolver)
+    };
+    let response = http::send_http_request(
+        request,
+        target_host,
+        port,
+        tls,
+        resolver,
+        *enable_http2,
+        timeouts,
+    );
+    match response.await {
+        Ok((status, headers, body_bytes, upstream)) => {
+            state.record_connect(profile_name, true);
+            if let Some(addrs) = upstream {
+                state.record_upstream(addrs);
+            }
+            trace!(
+                "Received response from {}:{}: {:?}",
+                target_host, port, status
+            );
 
-                // Convert to HTTP/1.1 response string
-                let status_code = status.as_u16();
-                let reason = status.canonical_reason().unwrap_or("");
+            // Convert to HTTP/1.1 response string
+            let status_code = status.as_u16();
+            let reason = status.canonical_reason().unwrap_or("");
 
-                let mut response_string = format!("HTTP/1.1 {status_code} {reason}\r\n");
+            let mut response_string = format!("HTTP/1.1 {status_code} {reason}\r\n");
 
-                // Add response headers
-                for (name, value) in headers {
+            // Responses to HEAD keep the length the origin announced
+            let bodiless = request.method == "HEAD" || status_code == 204 || status_code == 304;
+            for (name, value) in headers {
+                let reframed = matches!(
+                    name.as_str(),
+                    "transfer-encoding" | "connection" | "keep-alive"
+                ) || (name == "content-length" && !bodiless);
+                if !reframed {
                     response_string.push_str(&format!("{name}: {value}\r\n"));
                 }
+            }
+            if !bodiless {
+                response_string.push_str(&format!("Content-Length: {}\r\n", body_bytes.len()));
+            }
+            if let Some(route_headers) = &state.route_headers {
+                response_string.push_str(route_headers);
+            }
 
-                // End headers section
-                response_string.push_str("\r\n");
-
-                // Write response headers to client
-                client.write_all(response_string.as_bytes()).await?;
+            // End headers section
+            response_string.push_str("\r\n");
 
-                // Write response body to client
-                if !body_bytes.is_empty() {
-                    client.write_all(&body_bytes).await?;
-                }
+            // Write response headers to client
+            client.write_all(response_string.as_bytes()).await?;
 
-                trace!("HTTP response sent successfully to client");
+            // Write response body to client
+            if !body_bytes.is_empty() {
+                client.write_all(&body_bytes).await?;
             }
-            Err(e) => {
-                error!("Failed to send request to {}:{}: {}", target_host, port, e);
-                client.write_all(http::HTTP_SERVER_ERROR.as_bytes()).await?;
-                return Err(std::io::Error::other(e.to_string()));
+
+            let meter = state.byte_meter(profile_name);
+            meter.add_up(http::serialize_request(request).len() as u64);
+            meter.add_down((response_string.len() + body_bytes.len()) as u64);
+
+            trace!("HTTP response sent successfully to client");
+        }
+        Err(e) => {
+            state.record_connect(profile_name, false);
+            error!("Failed to send request to {}:{}: {}", target_host, port, e);
+            if let Some(response) = retry_response(state, target_host, profile_name, &e).await {
+                client.write_all(response.as_bytes()).await?;
+                return Ok(());
             }
+            let (status, fallback) = if e.kind() == tokio::io::ErrorKind::TimedOut {
+                (504, http::HTTP_GATEWAY_TIMEOUT)
+            } else {
+                (500, http::HTTP_SERVER_ERROR)
+            };
+            let response = error_page(state, status, target_host, profile_name, &e.to_string())
+                .await
+                .unwrap_or_else(|| fallback.to_string());
+            client.write_all(response.as_bytes()).await?;
+            return Err(std::io::Error::other(e.to_string()));
         }
     }
     Ok(())
 }
 
+/// Response with status `code` rendered from its configured error page, if there is one
+async fn error_page(
+    state: &ProxyState,
+    code: u16,
+    target_host: &str,
+    profile_name: &str,
+    reason: &str,
+) -> Option<String> {
+    let status = hyper::StatusCode::from_u16(code).ok()?;
+    let status = format!("{code} {}", status.canonical_reason().unwrap_or_default());
+    let config_guard = state.config.read().await;
+    config_guard.error_page(&status, target_host, profile_name, reason)
+}
+
+/// Response to a client whose upstream connection could not be opened
+///
+/// Running out of the connect timeout is a `504`. An upstream that answered with junk, or a
+/// target that could not be resolved because too many lookups were pending, is a `502`;
+/// anything else a `500`.
+async fn connect_failure_response(
+    state: &ProxyState,
+    target_host: &str,
+    profile_name: &str,
+    e: &tokio::io::Error,
+) -> String {
+    if e.kind() == tokio::io::ErrorKind::TimedOut {
+        error_page(state, 504, target_host, profile_name, &e.to_string())
+            .await
+            .unwrap_or_else(|| http::HTTP_GATEWAY_TIMEOUT.to_string())
+    } else if http::InvalidUpstreamResponse::is_cause_of(e) || LookupsSaturated::is_cause_of(e) {
+        error_page(state, 502, target_host, profile_name, &e.to_string())
+            .await
+            .unwrap_or_else(|| http::error_response("502 Bad Gateway", &e.to_string()))
+    } else {
+        error_page(state, 500, target_host, profile_name, &e.to_string())
+            .await
+            .unwrap_or_else(|| http::HTTP_SERVER_ERROR.to_string())
+    }
+}
+
+/// With `keepAliveOnUpstreamFailure`, a `502` that leaves the client connection open for a
+/// retry, which is routed afresh
+async fn retry_response(
+    state: &ProxyState,
+    target_host: &str,
+    profile_name: &str,
+    e: &tokio::io::Error,
+) -> Option<String> {
+    if !state.config.read().await.keep_alive_on_upstream_failure {
+        return None;
+    }
+    let response = error_page(state, 502, target_host, profile_name, &e.to_string())
+        .await
+        .unwrap_or_else(|| http::error_response("502 Bad Gateway", &e.to_string()));
+    // Either is framed by Content-Length, so the next request can follow it
+    Some(response.replacen(
+        "\r\nConnection: close\r\n",
+        "\r\nConnection: keep-alive\r\n",
+        1,
+    ))
+}
+
+/// Relay a CONNECT tunnel through a proxy profile
+///
+/// Plain-HTTP requests through proxies are relayed per request by `HttpSession::forward`.
 async fn handle_proxy_connection(
-    mut client: tokio::net::TcpStream,
-    request: &http::HttpRequest,
+    mut client: ClientStream,
     target_host: &str,
     port: u16,
+    profile_name: &str,
     proxy: &crate::config::Profile,
+    state: &ProxyState,
 ) -> tokio::io::Result<()> {
-    match proxy {
-        crate::config::Profile::Socks5 {
-            host,
-            port: proxy_port,
-        } => {
-            trace!(
-                "Using Socks5 proxy {}:{} for {}:{}",
-                host, proxy_port, target_host, port
+    trace!(
+        "Tunnelling through profile '{}' to {}:{}",
+        profile_name, target_host, port
+    );
+    let (profile_name, result) =
+        connect_with_failover(state, profile_name, proxy, target_host, port).await;
+    let profile_name = profile_name.as_str();
+    match result {
+        Ok(proxy_stream) => {
+            state.record_connect(profile_name, true);
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            relay(client, proxy_stream, &state.byte_meter(profile_name)).await?;
+        }
+        Err(e) => {
+            state.record_connect(profile_name, false);
+            error!(
+                "Could not connect through proxy to {}:{} : {}",
+                target_host, port, e
             );
-            let socks5_request = socks::Socks5Request {
-                target: target_host.to_string(),
-                port,
-            };
-            let proxy_stream_result =
-                socks::forward_to_proxy(&socks5_request, host, *proxy_port).await;
-            match proxy_stream_result {
-                Ok(mut proxy_stream) => {
-                    if request.method == "CONNECT" {
-                        // Send 200 Connection Established to the client for CONNECT requests
-                        client
-                            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
-                            .await?;
+            let response = connect_failure_response(state, target_host, profile_name, &e).await;
+            client.write_all(response.as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
 
-                        let (mut ci, mut co) = client.into_split();
-                        let (mut pi, mut po) = proxy_stream.into_split();
-                        tokio::try_join!(
-                            tokio::io::copy(&mut ci, &mut po),
-                            tokio::io::copy(&mut pi, &mut co)
-                        )?;
-                    } else {
-                        let mut http_req =
-                            format!("{} {} HTTP/1.1\r\n", request.method, request.target);
-                        for (k, v) in &request.headers {
-                            http_req.push_str(&format!("{k}: {v}\r\n"));
-                        }
-                        http_req.push_str("\r\n");
-                        proxy_stream.write_all(http_req.as_bytes()).await?;
-                        if !request.body.is_empty() {
-                            proxy_stream.write_all(&request.body).await?;
-                        }
-                        let (mut ci, mut co) = client.into_split();
-                        let (mut pi, mut po) = proxy_stream.into_split();
-                        tokio::try_join!(
-                            tokio::io::copy(&mut pi, &mut co),
-                            tokio::io::copy(&mut ci, &mut po)
-                        )?;
-                    }
-                }
-                Err(e) => {
-                    error!(
-                        "Could not connect through proxy to {}:{} : {}",
-                        target_host, port, e
-                    );
-                    client.write_all(http::HTTP_SERVER_ERROR.as_bytes()).await?;
-                }
-            }
+/// Waste a suspected scanner's time instead of connecting anywhere
+///
+/// `response` is trickled one byte every `delay_ms`; the client then gets nothing more while
+/// whatever it sends is swallowed. The connection is closed once `max_duration_ms` has passed.
+async fn tarpit<C>(
+    mut client: C,
+    response: &[u8],
+    delay_ms: u64,
+    max_duration_ms: u64,
+) -> tokio::io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let trickle = async {
+        for byte in response {
+            tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+            client.write_all(std::slice::from_ref(byte)).await?;
+            client.flush().await?;
         }
-        crate::config::Profile::Http {
-            host,
-            port: proxy_port,
+        tokio::io::copy(&mut client, &mut tokio::io::sink()).await?;
+        Ok(())
+    };
+    tokio::time::timeout(std::time::Duration::from_millis(max_duration_ms), trickle)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// Send a copy of a plain-HTTP request through `profile`, discarding whatever comes back
+async fn mirror_request(
+    mut state: ProxyState,
+    profile: crate::config::Profile,
+    mut request: http::HttpRequest,
+    target_host: String,
+    port: u16,
+) -> tokio::io::Result<()> {
+    // The copy's upstream connection is not the one the client's flow went through
+    state.flow = Arc::new(Flow::new());
+    // Have the secondary close the connection so draining its response terminates
+    request
+        .headers
+        .insert("connection".to_string(), "close".to_string());
+
+    let mut upstream = match &profile {
+        crate::config::Profile::Direct {
+            tls,
+            address_family,
+            enable_http2,
+            ..
         } => {
-            trace!(
-                "Using HTTP proxy {}:{} for {}:{}",
-                host, proxy_port, target_host, port
-            );
-            let proxy_stream = if request.method == "CONNECT" {
-                http::forward_to_proxy(target_host, port, host, *proxy_port, None).await
-            } else {
-                http::forward_http_request(request, target_host, port, host, *proxy_port, None)
-                    .await
+            let resolver = crate::resolver::DirectResolver {
+                dns: state.dns.clone(),
+                options: state.config.read().await.dns_cache.clone(),
+                family: *address_family,
             };
-            match proxy_stream {
-                Ok(proxy_stream) => {
-                    if request.method == "CONNECT" {
-                        // Send 200 Connection Established to the client for CONNECT requests
-                        client
-                            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
-                            .await?;
-                    }
-
-                    let (mut ci, mut co) = client.into_split();
-                    let (mut pi, mut po) = proxy_stream.into_split();
-                    tokio::try_join!(
-                        tokio::io::copy(&mut ci, &mut po),
-                        tokio::io::copy(&mut pi, &mut co)
-                    )?;
-                }
-                Err(e) => {
-                    error!(
-                        "Could not connect through proxy to {}:{} : {}",
-                        target_host, port, e
-                    );
-                    client.write_all(http::HTTP_SERVER_ERROR.as_bytes()).await?;
-                }
-            }
+            http::send_http_request(
+                &request,
+                &target_host,
+                port,
+                tls,
+                resolver,
+                *enable_http2,
+                http::ExchangeTimeouts {
+                    connect: state.connect_timeout,
+                    response: Some(MIRROR_TIMEOUT),
+                },
+            )
+            .await?;
+            return Ok(());
+        }
+        crate::config::Profile::Http { .. } => {
+            let auth = proxy_auth(&state, &profile).await?;
+            let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            let (request, target_host, proxy) = (&request, target_host.as_str(), &profile);
+            try_endpoints(
+                &state,
+                &profile,
+                |proxy_host, proxy_port, name| async move {
+                    let mut stream = open_proxy(proxy, &proxy_host, proxy_port, &name, &[]).await?;
+                    http::write_proxy_request(&mut stream, request, target_host, port, auth)
+                        .await?;
+                    Ok(stream)
+                },
+            )
+            .await?
         }
-        _ => {
+        crate::config::Profile::Socks5 { .. }
+        | crate::config::Profile::Fastest { .. }
+        | crate::config::Profile::Hedged { .. }
+        | crate::config::Profile::Chain { .. } => {
+            let mut stream = connect_upstream(&state, &profile, &target_host, port).await?;
+            stream.write_all(&http::serialize_request(&request)).await?;
+            stream
+        }
+        crate::config::Profile::Mirror { .. } => {
             return Err(tokio::io::Error::new(
                 tokio::io::ErrorKind::InvalidInput,
-                "Invalid proxy type",
+                "Mirror profiles cannot be nested",
             ));
         }
+        // Nobody is waiting for the mirrored response
+        crate::config::Profile::Tarpit { .. } => return Ok(()),
+    };
+
+    let mut sink = tokio::io::sink();
+    let drain = tokio::io::copy(&mut upstream, &mut sink);
+    if tokio::time::timeout(MIRROR_TIMEOUT, drain).await.is_err() {
+        debug!("Mirrored request to {target_host}:{port} timed out");
     }
     Ok(())
 }
 
-async fn handle_client(
-    mut client: tokio::net::TcpStream,
-    config: Arc<RwLock<Config>>,
-    cancel_token: CancellationToken,
-) -> tokio::io::Result<()> {
-    // Check for cancellation before starting
-    if cancel_token.is_cancelled() {
-        return Ok(());
-    }
-
-    let request = http::parse_request(&mut client).await?;
-    let (target_host, port) = extract_host_and_port(&mut client, &request).await?;
-
-    trace!(
-        "Extracted target_host: '{}', port: {}, method: '{}'",
-        target_host, port, request.method
-    );
-
-    // IMPORTANT: Scope the read lock to ensure it's released as soon as we extract what we need
-    let proxy_config = {
-        let config_guard = config.read().await;
-        let profile_name = select_profile(&config_guard, &target_host);
-        debug!(
-            "Target is '{}', using '{}' profile",
-            target_host, profile_name
-        );
-
-        // Clone what we need from the config to avoid holding the lock
-
-        match config_guard.profiles.get(&profile_name) {
-            Some(p) => p.clone(),
-            None => {
-                error!("Profile {} not found in configuration", profile_name);
-                client.write_all(http::HTTP_SERVER_ERROR.as_bytes()).await?;
-                return Ok(());
+/// Open a raw tunnel to `target_host:port` through the given profile
+async fn connect_upstream(
+    state: &ProxyState,
+    profile: &crate::config::Profile,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    let started = std::time::Instant::now();
+    let connect = async {
+        match profile {
+            crate::config::Profile::Fastest { candidates } => {
+                connect_fastest(state, candidates, target_host, port).await
             }
+            crate::config::Profile::Hedged {
+                primary,
+                secondary,
+                budget_ms,
+                max_hedges,
+            } => {
+                let budget = std::time::Duration::from_millis(*budget_ms);
+                connect_hedged(
+                    state,
+                    primary,
+                    secondary,
+                    budget,
+                    *max_hedges,
+                    target_host,
+                    port,
+                )
+                .await
+            }
+            profile => connect_via(state, profile, target_host, port).await,
         }
-    }; // read lock is released here
-
-    // Process the request with our cloned data, without holding the lock
-    match proxy_config {
-        crate::config::Profile::Direct => {
-            handle_direct_connection(client, &request, &target_host, port).await?;
-        }
-        crate::config::Profile::Socks5 { .. } | crate::config::Profile::Http { .. } => {
-            handle_proxy_connection(client, &request, &target_host, port, &proxy_config).await?;
+    };
+    let result = with_connect_timeout(state, connect).await;
+    if let Ok(stream) = &result {
+        tracing::Span::current()
+            .record("upstream_connect_ms", started.elapsed().as_millis() as u64);
+        if let Some(addrs) = UpstreamAddrs::of(stream.tcp()) {
+            state.record_upstream(addrs);
         }
     }
+    result
+}
 
-    Ok(())
+/// Open a tunnel through `profile`, failing over to the state's `fallbacks` in turn
+///
+/// Returns the name of the profile the tunnel goes through, or of the last one tried when
+/// all of them failed. Failures of the profiles before it are recorded here.
+async fn connect_with_failover(
+    state: &ProxyState,
+    profile_name: &str,
+    profile: &crate::config::Profile,
+    target_host: &str,
+    port: u16,
+) -> (String, tokio::io::Result<UpstreamStream>) {
+    let mut name = profile_name.to_string();
+    let mut result = connect_upstream(state, profile, target_host, port).await;
+    for (fallback, profile) in &state.fallbacks {
+        let Err(e) = &result else {
+            break;
+        };
+        warn!(
+            "Could not connect through profile {} to {}:{} ({}), failing over to {}",
+            name, target_host, port, e, fallback
+        );
+        state.record_connect(&name, false);
+        name = fallback.clone();
+        result = connect_upstream(state, profile, target_host, port).await;
+    }
+    (name, result)
 }
 
-pub async fn run_listener(
-    addr: String,
-    config: Arc<RwLock<Config>>,
-    connections_token: Arc<Mutex<CancellationToken>>,
-    shutdown_token: CancellationToken,
+/// Give up on `connect` once the state's connect timeout, if any, has passed
+async fn with_connect_timeout<T>(
+    state: &ProxyState,
+    connect: impl Future<Output = tokio::io::Result<T>>,
+) -> tokio::io::Result<T> {
+    let Some(limit) = state.connect_timeout else {
+        return connect.await;
+    };
+    tokio::time::timeout(limit, connect)
+        .await
+        .unwrap_or_else(|_| {
+            Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::TimedOut,
+                format!(
+                    "Timed out connecting upstream after {} ms",
+                    limit.as_millis()
+                ),
+            ))
+        })
+}
+
+/// Tunnel attempts through single profiles racing each other
+type Attempts = tokio::task::JoinSet<Attempt>;
+
+/// Profile name, outcome and leases taken of an attempt
+type Attempt = (
+    String,
+    tokio::io::Result<UpstreamStream>,
+    Arc<Mutex<Vec<Lease>>>,
+);
+
+/// Start an attempt to tunnel through `profile` alongside the others in `attempts`
+fn spawn_attempt(
+    attempts: &mut Attempts,
+    state: &ProxyState,
+    name: String,
+    profile: crate::config::Profile,
+    target_host: &str,
+    port: u16,
 ) {
-    let listener = match TcpListener::bind(&addr).await {
-        Ok(l) => l,
+    let (mut state, target_host) = (state.clone(), target_host.to_string());
+    // Only the winner's tunnel stays open and counts against its address
+    state.leases = Arc::default();
+    attempts.spawn(async move {
+        let result = connect_via(&state, &profile, &target_host, port).await;
+        (name, result, state.leases)
+    });
+}
+
+/// Outcome of a finished attempt, handing the leases of a tunnel that came up to `state`
+fn settle(
+    state: &ProxyState,
+    attempt: Result<Attempt, tokio::task::JoinError>,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    match attempt {
+        Ok((name, Ok(stream), leases)) => {
+            debug!(
+                "Candidate '{}' won the race to {}:{}",
+                name, target_host, port
+            );
+            let leases = std::mem::take(&mut *leases.lock().unwrap());
+            state.leases.lock().unwrap().extend(leases);
+            Ok(stream)
+        }
+        Ok((name, Err(e), _)) => {
+            debug!(
+                "Candidate '{}' failed to reach {}:{}: {}",
+                name, target_host, port, e
+            );
+            Err(e)
+        }
+        Err(e) => Err(tokio::io::Error::other(e)),
+    }
+}
+
+/// Keep the first attempt that comes up, or return the last error once all have failed
+///
+/// The remaining attempts are aborted, which closes whatever connections they opened.
+async fn first_established(
+    state: &ProxyState,
+    mut attempts: Attempts,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    let mut last_error = None;
+    while let Some(attempt) = attempts.join_next().await {
+        match settle(state, attempt, target_host, port) {
+            // Dropping the set aborts the slower attempts
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        tokio::io::Error::new(
+            tokio::io::ErrorKind::NotFound,
+            "No candidate profiles to race",
+        )
+    }))
+}
+
+/// Race a tunnel through every candidate profile, keeping the first one that comes up
+async fn connect_fastest(
+    state: &ProxyState,
+    candidates: &[String],
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    let profiles: Vec<_> = {
+        let config_guard = state.config.read().await;
+        candidates
+            .iter()
+            .filter_map(|name| Some((name.clone(), config_guard.profiles.get(name)?.clone())))
+            .collect()
+    };
+
+    let mut attempts = Attempts::new();
+    for (name, profile) in profiles {
+        spawn_attempt(&mut attempts, state, name, profile, target_host, port);
+    }
+    first_established(state, attempts, target_host, port).await
+}
+
+/// Tunnel through `primary`, racing `secondary` against it once `budget` has passed without
+/// a tunnel
+///
+/// A primary that fails within the budget is not hedged: that is for failover to handle.
+/// With `max_hedges` secondary attempts already in flight, the primary alone is waited for.
+async fn connect_hedged(
+    state: &ProxyState,
+    primary: &str,
+    secondary: &str,
+    budget: std::time::Duration,
+    max_hedges: usize,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    let (primary_profile, secondary_profile) = {
+        let config_guard = state.config.read().await;
+        (
+            config_guard.profiles.get(primary).cloned(),
+            config_guard.profiles.get(secondary).cloned(),
+        )
+    };
+    let Some(primary_profile) = primary_profile else {
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::NotFound,
+            format!("Profile '{primary}' is not defined"),
+        ));
+    };
+
+    let mut attempts = Attempts::new();
+    spawn_attempt(
+        &mut attempts,
+        state,
+        primary.to_string(),
+        primary_profile,
+        target_host,
+        port,
+    );
+    if let Ok(Some(attempt)) = tokio::time::timeout(budget, attempts.join_next()).await {
+        return settle(state, attempt, target_host, port);
+    }
+
+    let _slot = match (
+        secondary_profile,
+        state.hedges.try_take(secondary, max_hedges),
+    ) {
+        (Some(profile), Some(slot)) => {
+            debug!(
+                "No tunnel through '{}' to {}:{} within {} ms, hedging through '{}'",
+                primary,
+                target_host,
+                port,
+                budget.as_millis(),
+                secondary
+            );
+            spawn_attempt(
+                &mut attempts,
+                state,
+                secondary.to_string(),
+                profile,
+                target_host,
+                port,
+            );
+            Some(slot)
+        }
+        (Some(_), None) => {
+            debug!("Too many hedged attempts through '{secondary}', waiting for '{primary}'");
+            None
+        }
+        (None, _) => None,
+    };
+    first_established(state, attempts, target_host, port).await
+}
+
+/// Open a raw tunnel through a single direct, SOCKS5, HTTP or chain profile
+async fn connect_via(
+    state: &ProxyState,
+    profile: &crate::config::Profile,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    match profile {
+        crate::config::Profile::Direct {
+            round_robin,
+            balance,
+            address_family,
+            ..
+        } => {
+            let options = state.config.read().await.dns_cache.clone();
+            let balance = balance
+                .clone()
+                .or_else(|| round_robin.then(Default::default));
+            if let Some(balance) = balance {
+                let (stream, lease) = state
+                    .round_robin
+                    .connect(
+                        &state.dns,
+                        &options,
+                        target_host,
+                        port,
+                        *address_family,
+                        &balance,
+                        state.connect_timeout,
+                    )
+                    .await?;
+                state.leases.lock().unwrap().push(lease);
+                Ok(Box::new(stream))
+            } else {
+                let stream = crate::resolver::connect_direct(
+                    &state.dns,
+                    &options,
+                    target_host,
+                    port,
+                    *address_family,
+                    state.connect_timeout,
+                )
+                .await?;
+                Ok(Box::new(stream))
+            }
+        }
+        crate::config::Profile::Socks5 { local_dns, .. } => {
+            let target = if *local_dns {
+                resolve_local(state, target_host, port).await?[0]
+                    .ip()
+                    .to_string()
+            } else {
+                target_host.to_string()
+            };
+            let socks5_request = socks::Socks5Request { target, port };
+            let auth = proxy_auth(state, profile).await?;
+            let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            let (socks5_request, preface) = (&socks5_request, &proxy_preface(state, profile));
+            let result = try_endpoints(state, profile, |proxy_host, proxy_port, _| async move {
+                socks::forward_to_proxy(socks5_request, &proxy_host, proxy_port, auth, preface)
+                    .await
+                    .map(|stream| Box::new(stream) as UpstreamStream)
+            })
+            .await;
+            forget_rejected_credentials(state, profile, result).await
+        }
+        crate::config::Profile::Http { .. } => {
+            let auth = proxy_auth(state, profile).await?;
+            let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            let user_agent = state.config.read().await.proxy_user_agent.clone();
+            let (user_agent, preface) = (user_agent.as_str(), &proxy_preface(state, profile));
+            let result = try_endpoints(state, profile, |proxy_host, proxy_port, name| async move {
+                let mut stream =
+                    open_proxy(profile, &proxy_host, proxy_port, &name, preface).await?;
+                http::connect_handshake(&mut stream, target_host, port, auth, user_agent).await?;
+                Ok(stream)
+            })
+            .await;
+            forget_rejected_credentials(state, profile, result).await
+        }
+        crate::config::Profile::Mirror { .. } => Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Mirror profiles only apply to plain-HTTP requests",
+        )),
+        crate::config::Profile::Fastest { .. } => Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Fastest profiles cannot be nested",
+        )),
+        crate::config::Profile::Hedged { .. } => Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Hedged profiles cannot be nested",
+        )),
+        crate::config::Profile::Chain { hops } => {
+            let hops: Vec<_> = {
+                let config_guard = state.config.read().await;
+                hops.iter()
+                    .filter_map(|name| config_guard.profiles.get(name).cloned())
+                    .collect()
+            };
+            connect_chain(state, &hops, target_host, port).await
+        }
+        crate::config::Profile::Tarpit { .. } => Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Tarpit profiles never connect upstream",
+        )),
+    }
+}
+
+/// Connect to an endpoint of the proxy of `profile`, sending `preface` first and starting TLS
+/// with HTTPS proxies, which are verified as `name`
+async fn open_proxy(
+    profile: &crate::config::Profile,
+    proxy_host: &str,
+    proxy_port: u16,
+    name: &str,
+    preface: &[u8],
+) -> tokio::io::Result<UpstreamStream> {
+    let mut stream = tokio::net::TcpStream::connect((proxy_host, proxy_port)).await?;
+    stream.write_all(preface).await?;
+    match profile.proxy_tls() {
+        Some(tls) => Ok(Box::new(
+            crate::protocols::tls::connect(tls, name, stream).await?,
+        )),
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Open a tunnel through every hop in turn, each one reached through the tunnel of the hops
+/// before it
+///
+/// Only the first hop is connected to directly, trying each of its endpoints; the later ones
+/// are reached at their first endpoint, by the proxy before them.
+async fn connect_chain(
+    state: &ProxyState,
+    hops: &[crate::config::Profile],
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<UpstreamStream> {
+    let Some(first) = hops.first() else {
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Chain profile has no hops",
+        ));
+    };
+    let preface = &proxy_preface(state, first);
+    let mut stream = try_endpoints(state, first, |proxy_host, proxy_port, name| async move {
+        open_proxy(first, &proxy_host, proxy_port, &name, preface).await
+    })
+    .await?;
+
+    for (index, hop) in hops.iter().enumerate() {
+        let Some(next) = hops.get(index + 1) else {
+            hop_handshake(state, hop, &mut stream, target_host, port).await?;
+            break;
+        };
+        let Some((next_host, next_port)) = next.proxy_endpoints().into_iter().next() else {
+            return Err(tokio::io::Error::new(
+                tokio::io::ErrorKind::InvalidInput,
+                "Chained proxy profile has no endpoints",
+            ));
+        };
+        trace!("Chaining to {}:{}", next_host, next_port);
+        hop_handshake(state, hop, &mut stream, &next_host, next_port).await?;
+        stream.write_all(&proxy_preface(state, next)).await?;
+        if let Some(tls) = next.proxy_tls() {
+            stream = Box::new(crate::protocols::tls::connect(tls, &next_host, stream).await?);
+        }
+    }
+    Ok(stream)
+}
+
+/// Have `hop`, the SOCKS5 or HTTP proxy at the other end of `stream`, tunnel on to
+/// `target_host:port`
+async fn hop_handshake(
+    state: &ProxyState,
+    hop: &crate::config::Profile,
+    stream: &mut UpstreamStream,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<()> {
+    match hop {
+        crate::config::Profile::Socks5 { local_dns, .. } => {
+            let target = if *local_dns {
+                resolve_local(state, target_host, port).await?[0]
+                    .ip()
+                    .to_string()
+            } else {
+                target_host.to_string()
+            };
+            let auth = proxy_auth(state, hop).await?;
+            let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            let result =
+                socks::connect_handshake(stream, &socks::Socks5Request { target, port }, auth)
+                    .await;
+            forget_rejected_credentials(state, hop, result).await
+        }
+        crate::config::Profile::Http { .. } => {
+            let auth = proxy_auth(state, hop).await?;
+            let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            let user_agent = state.config.read().await.proxy_user_agent.clone();
+            let result =
+                http::connect_handshake(stream, target_host, port, auth, &user_agent).await;
+            forget_rejected_credentials(state, hop, result).await
+        }
+        crate::config::Profile::Direct { .. }
+        | crate::config::Profile::Mirror { .. }
+        | crate::config::Profile::Fastest { .. }
+        | crate::config::Profile::Hedged { .. }
+        | crate::config::Profile::Chain { .. }
+        | crate::config::Profile::Tarpit { .. } => Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Only http and socks5 profiles can be chained",
+        )),
+    }
+}
+
+/// Route a connection redirected by iptables using its original destination
+async fn handle_transparent_client(
+    client: tokio::net::TcpStream,
+    state: ProxyState,
+    cancel_token: CancellationToken,
+) -> tokio::io::Result<()> {
+    if cancel_token.is_cancelled() {
+        return Ok(());
+    }
+    if state.config.read().await.reload_error.is_some() {
+        debug!("Refusing connection while the reloaded config is invalid");
+        return Ok(());
+    }
+    let original = crate::transparent::original_destination(&client)?;
+    // A client that was not redirected reports the listener itself, which would loop forever
+    if original == client.local_addr()? {
+        debug!("Refusing connection to the transparent listener itself");
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "Connection was not redirected to the transparent listener",
+        ));
+    }
+    tunnel_raw(client, state, &original.ip().to_string(), original.port()).await
+}
+
+/// Reload the config file when the switch picked a profile it does not define, in case the
+/// watcher has not applied a change adding it yet
+///
+/// Only done with `reloadOnMissingProfile`. Returns whether the profile is defined afterwards,
+/// in which case the lookup should be retried.
+async fn reload_for_missing_profile(state: &ProxyState, profile_name: &str) -> bool {
+    let mut last_reload = state.missing_profile_reload.lock().await;
+    let path = {
+        let config = state.config.read().await;
+        if config.profiles.contains_key(profile_name) {
+            // Another connection's reload brought it in while this one waited
+            return true;
+        }
+        match &config.source_path {
+            Some(path) if config.reload_on_missing_profile => path.clone(),
+            _ => return false,
+        }
+    };
+    if last_reload.is_some_and(|at| at.elapsed() < MISSING_PROFILE_RELOAD_INTERVAL) {
+        debug!(
+            "Config was re-read moments ago, not reloading for profile {}",
+            profile_name
+        );
+        return false;
+    }
+    *last_reload = Some(tokio::time::Instant::now());
+    if let Some(new_config) = crate::config::watcher::load_changed(&state.config, &path).await {
+        info!("Config reloaded looking for profile {}", profile_name);
+        *state.config.write().await = new_config;
+    }
+    state
+        .config
+        .read()
+        .await
+        .profiles
+        .contains_key(profile_name)
+}
+
+/// Tunnel a client's stream to `target_host:port` as is, through the profile the switch picks
+async fn tunnel_raw<C>(
+    client: C,
+    mut state: ProxyState,
+    target_host: &str,
+    port: u16,
+) -> tokio::io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let target = crate::utils::join_host_port(target_host, port);
+    let mut reloaded = false;
+    let (profile_name, tag, profile, rewrite) = loop {
+        let config_guard = state.config.read().await;
+        let forced = state.route_override.get();
+        let (profile_name, rule) = select_profile(
+            &config_guard,
+            &state.listener,
+            &state.breaker,
+            forced,
+            target_host,
+            None,
+        );
+        let tag = rule.and_then(|rule| rule.tag.clone());
+        let rewrite = rule.and_then(|rule| rule.rewrite.clone());
+        state.connect_timeout = config_guard.connect_timeout(rule);
+        state.fallbacks = default_fallbacks(
+            &config_guard,
+            &state.listener,
+            &state.breaker,
+            forced,
+            &profile_name,
+            rule,
+        );
+        debug!(
+            "Raw stream target is '{}', using '{}' profile",
+            target, profile_name
+        );
+        let Some(profile) = tunnel_profile_named(&config_guard, &profile_name) else {
+            drop(config_guard);
+            if !std::mem::replace(&mut reloaded, true)
+                && reload_for_missing_profile(&state, &profile_name).await
+            {
+                continue;
+            }
+            error!("Profile {} not found in configuration", profile_name);
+            return Ok(());
+        };
+        break (profile_name, tag, profile, rewrite);
+    };
+
+    if let Some(tag) = &tag {
+        state.metrics.record_tag(tag);
+    }
+    let span = connection_span(&state, &profile_name, tag.as_deref(), target_host);
+    async {
+        if let crate::config::Profile::Tarpit {
+            delay_ms,
+            max_duration_ms,
+        } = profile
+        {
+            debug!("Tarpitting raw stream to {}", target);
+            return tarpit(client, &[], delay_ms, max_duration_ms).await;
+        }
+        let (target_host, port) = match &rewrite {
+            Some(rewrite) => rewrite.apply(target_host, port),
+            None => (target_host.to_string(), port),
+        };
+        let (profile_name, result) =
+            connect_with_failover(&state, &profile_name, &profile, &target_host, port).await;
+        match result {
+            Ok(upstream) => {
+                state.record_connect(&profile_name, true);
+                relay(client, upstream, &state.byte_meter(&profile_name)).await?;
+            }
+            Err(e) => {
+                state.record_connect(&profile_name, false);
+                error!("Could not connect to {}:{} : {}", target_host, port, e);
+            }
+        }
+        Ok::<_, tokio::io::Error>(())
+    }
+    .instrument(span)
+    .await
+}
+
+/// A client connection carrying plain-HTTP requests, with the upstream proxy connections opened
+/// for it so far (one per profile)
+struct HttpSession {
+    client: tokio::io::BufReader<ClientStream>,
+    upstreams: HashMap<String, PooledUpstream>,
+}
+
+/// An idle upstream connection of a session, with the leases counting it as open
+struct PooledUpstream {
+    stream: tokio::io::BufReader<UpstreamStream>,
+    leases: Vec<Lease>,
+}
+
+/// Whether an idle upstream connection is still usable: no EOF, error or stray data pending
+async fn is_idle_open(upstream: &mut tokio::io::BufReader<UpstreamStream>) -> bool {
+    // The timeout polls the read once before expiring
+    tokio::time::timeout(std::time::Duration::ZERO, upstream.fill_buf())
+        .await
+        .is_err()
+}
+
+impl HttpSession {
+    /// Open an upstream connection for `profile`: to the proxy itself for HTTP proxies, or a
+    /// tunnel to the target for everything else
+    async fn connect(
+        state: &ProxyState,
+        profile: &crate::config::Profile,
+        target_host: &str,
+        port: u16,
+    ) -> tokio::io::Result<UpstreamStream> {
+        match profile {
+            crate::config::Profile::Http { .. } => {
+                trace!("Using HTTP proxy for {}:{}", target_host, port);
+                let preface = &proxy_preface(state, profile);
+                let connect =
+                    try_endpoints(state, profile, |proxy_host, proxy_port, name| async move {
+                        open_proxy(profile, &proxy_host, proxy_port, &name, preface).await
+                    });
+                with_connect_timeout(state, connect).await
+            }
+            profile => connect_upstream(state, profile, target_host, port).await,
+        }
+    }
+
+    /// Send one plain-HTTP request through a proxy profile and relay exactly its response
+    ///
+    /// The upstream connection is kept for the next request while responses are delimited.
+    /// Returns whether the client connection can carry another request.
+    async fn forward(
+        &mut self,
+        request: &http::HttpRequest,
+        target_host: &str,
+        port: u16,
+        profile_name: &str,
+        profile: &crate::config::Profile,
+        state: &ProxyState,
+    ) -> tokio::io::Result<bool> {
+        // HTTP proxies take requests for any target; tunnels only lead to one
+        let key = match profile {
+            crate::config::Profile::Http { .. } => profile_name.to_string(),
+            crate::config::Profile::Direct { .. }
+            | crate::config::Profile::Socks5 { .. }
+            | crate::config::Profile::Mirror { .. }
+            | crate::config::Profile::Fastest { .. }
+            | crate::config::Profile::Hedged { .. }
+            | crate::config::Profile::Chain { .. }
+            | crate::config::Profile::Tarpit { .. } => format!(
+                "{profile_name} {}",
+                crate::utils::join_host_port(target_host, port)
+            ),
+        };
+        let meter = state.byte_meter(profile_name);
+
+        let pooled = match self.upstreams.remove(&key) {
+            Some(mut pooled) => is_idle_open(&mut pooled.stream).await.then_some(pooled),
+            None => None,
+        };
+        let PooledUpstream {
+            stream: mut upstream,
+            leases,
+        } = match pooled {
+            Some(pooled) => pooled,
+            None => match Self::connect(state, profile, target_host, port).await {
+                Ok(stream) => {
+                    state.record_connect(profile_name, true);
+                    // The connection counts as open for as long as it is, not the session
+                    PooledUpstream {
+                        stream: tokio::io::BufReader::new(stream),
+                        leases: std::mem::take(&mut *state.leases.lock().unwrap()),
+                    }
+                }
+                Err(e) => {
+                    state.record_connect(profile_name, false);
+                    error!(
+                        "Could not connect through proxy to {}:{} : {}",
+                        target_host, port, e
+                    );
+                    if !request.body_pending()
+                        && let Some(response) =
+                            retry_response(state, target_host, profile_name, &e).await
+                    {
+                        self.client.write_all(response.as_bytes()).await?;
+                        return Ok(true);
+                    }
+                    let response =
+                        connect_failure_response(state, target_host, profile_name, &e).await;
+                    self.client.write_all(response.as_bytes()).await?;
+                    return Ok(false);
+                }
+            },
+        };
+
+        let sent = match profile {
+            crate::config::Profile::Http { .. } => {
+                let auth = proxy_auth(state, profile).await?;
+                let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+                http::write_proxy_request(upstream.get_mut(), request, target_host, port, auth)
+                    .await?
+            }
+            crate::config::Profile::Direct { .. }
+            | crate::config::Profile::Socks5 { .. }
+            | crate::config::Profile::Mirror { .. }
+            | crate::config::Profile::Fastest { .. }
+            | crate::config::Profile::Hedged { .. }
+            | crate::config::Profile::Chain { .. }
+            | crate::config::Profile::Tarpit { .. } => {
+                let head = http::serialize_request(request);
+                upstream.get_mut().write_all(&head).await?;
+                head.len() as u64
+            }
+        };
+        meter.add_up(sent);
+
+        // The body follows once the upstream asks for it; a final response coming first is
+        // relayed below, after which the unsent body leaves neither connection reusable.
+        // Upstreams that never answer (HTTP/1.0 ones) get the body after a while regardless,
+        // as does a client that stops waiting and sends it.
+        let mut early_response = None;
+        if request.body_pending() {
+            let upstream_answered = tokio::select! {
+                answer = upstream.fill_buf() => answer.map(|_| true)?,
+                body = self.client.fill_buf() => body.map(|_| false)?,
+                _ = tokio::time::sleep(CONTINUE_TIMEOUT) => {
+                    debug!("Upstream did not answer Expect: 100-continue, sending the body");
+                    self.client.write_all(http::HTTP_CONTINUE.as_bytes()).await?;
+                    false
+                }
+            };
+            if upstream_answered {
+                let (response, relayed) =
+                    http::await_continue(&mut upstream, &mut self.client).await?;
+                meter.add_down(relayed);
+                early_response = response;
+            }
+            if early_response.is_none() {
+                let body = http::read_body(&mut self.client, request.content_length()).await?;
+                upstream.get_mut().write_all(&body).await?;
+                meter.add_up(body.len() as u64);
+            }
+        }
+
+        let head_request = request.method == "HEAD";
+        let early_head = early_response.as_deref().unwrap_or_default();
+        let (status, end, received) = http::relay_response_with(
+            &mut early_head.chain(&mut upstream),
+            &mut self.client,
+            head_request,
+            state.route_headers.as_deref().unwrap_or(""),
+        )
+        .await?;
+        meter.add_down(received);
+        if status == 407
+            && let crate::config::Profile::Http { .. } = profile
+            && let Some((command, _)) = profile.credential_command()
+        {
+            debug!("Proxy rejected the credentials, the command will be run again");
+            state.credentials.invalidate(command).await;
+        }
+        if early_response.is_some() && end != http::ResponseEnd::Upgraded {
+            return Ok(false);
+        }
+        match end {
+            http::ResponseEnd::KeepAlive => {
+                let pooled = PooledUpstream {
+                    stream: upstream,
+                    leases,
+                };
+                self.upstreams.insert(key, pooled);
+                Ok(true)
+            }
+            // The client learns the end of the response from the connection closing as well
+            http::ResponseEnd::Close => Ok(false),
+            http::ResponseEnd::Upgraded => {
+                relay(&mut self.client, &mut upstream, &meter).await?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Read the client's next request, leaving the body of one sent with `Expect: 100-continue`
+/// unread until the client is told to go ahead
+///
+/// A chunked body is read in full and decoded, and the request re-framed with
+/// `Content-Length`, so that every path can forward it.
+async fn read_client_request<R>(client: &mut R) -> tokio::io::Result<http::HttpRequest>
+where
+    R: tokio::io::AsyncBufRead + AsyncWrite + Unpin,
+{
+    let mut request = http::read_request_head(client).await?;
+    if let Some(reason) = request.framing_problem() {
+        return Err(http::bad_request(client, reason).await);
+    }
+    if request.is_chunked() {
+        if request.expects_continue() {
+            client.write_all(http::HTTP_CONTINUE.as_bytes()).await?;
+            request.headers.remove("expect");
+        }
+        request.body = match http::read_chunked_body(client).await {
+            Ok(body) => body,
+            Err(e) if http::BodyTooLarge::is_cause_of(&e) => {
+                let response = http::error_response("413 Content Too Large", &e.to_string());
+                client.write_all(response.as_bytes()).await?;
+                return Err(e);
+            }
+            Err(e) if e.kind() == tokio::io::ErrorKind::InvalidData => {
+                return Err(http::bad_request(client, &e.to_string()).await);
+            }
+            Err(e) => return Err(e),
+        };
+        request.headers.remove("transfer-encoding");
+        request
+            .headers
+            .insert("Content-Length", request.body.len().to_string());
+    } else if !request.body_pending() {
+        request.body = http::read_body(client, request.content_length()).await?;
+    }
+    Ok(request)
+}
+
+async fn handle_client(
+    client: ClientStream,
+    mut state: ProxyState,
+    cancel_token: CancellationToken,
+) -> tokio::io::Result<()> {
+    // Check for cancellation before starting
+    if cancel_token.is_cancelled() {
+        return Ok(());
+    }
+
+    // SOCKS5 clients announce themselves with the version byte, anything else should be HTTP
+    let mut session = HttpSession {
+        client: tokio::io::BufReader::new(client),
+        upstreams: HashMap::new(),
+    };
+    // Clients that connect and stay silent would otherwise hold the socket forever
+    let start =
+        match tokio::time::timeout(http::CLIENT_READ_TIMEOUT, session.client.fill_buf()).await {
+            Ok(start) => start?,
+            Err(_) => {
+                debug!("Client did not send anything in time");
+                state.metrics.record_client_timeout();
+                return Err(tokio::io::Error::new(
+                    tokio::io::ErrorKind::TimedOut,
+                    "Timeout waiting for the client to send anything",
+                ));
+            }
+        };
+    let (first, is_http) = (start.first().copied(), http::starts_with_method(start));
+    let (reload_failed, protocol) = {
+        let config_guard = state.config.read().await;
+        let protocol = config_guard
+            .listeners
+            .get(&state.listener)
+            .map_or(ListenerProtocol::Auto, |options| options.protocol);
+        (config_guard.reload_error.is_some(), protocol)
+    };
+    if first.is_some() && reload_failed {
+        debug!("Refusing connection while the reloaded config is invalid");
+        // Only HTTP clients get told why
+        if is_http {
+            // Nothing of the request has been read, so there is no host or profile to name
+            let reason = "Proxy configuration failed to reload";
+            let response = error_page(&state, 503, "", "", reason)
+                .await
+                .unwrap_or_else(|| http::error_response("503 Service Unavailable", reason));
+            session.client.write_all(response.as_bytes()).await?;
+        }
+        return Ok(());
+    }
+    match first {
+        None => return Ok(()),
+        Some(socks::SOCKS_VERSION) if protocol == ListenerProtocol::Http => {
+            debug!("Closing SOCKS5 connection on an HTTP-only listener");
+            return Ok(());
+        }
+        Some(socks::SOCKS_VERSION) => {
+            // The SOCKS5 handshake is read from past the version byte
+            session.client.consume(1);
+            return handle_socks_client(Box::new(session.client), state).await;
+        }
+        Some(_) if is_http && protocol == ListenerProtocol::Socks5 => {
+            debug!("Closing HTTP connection on a SOCKS5-only listener");
+            return Ok(());
+        }
+        Some(_) if !is_http => {
+            let passthrough = {
+                let config_guard = state.config.read().await;
+                config_guard
+                    .listeners
+                    .get(&state.listener)
+                    .and_then(|options| options.raw_passthrough_target())
+            };
+            if let Some((host, port)) = passthrough {
+                return tunnel_raw(session.client, state, &host, port).await;
+            }
+        }
+        Some(_) => {}
+    }
+
+    let mut request = match read_client_request(&mut session.client).await {
+        Ok(request) => request,
         Err(e) => {
-            error!("Failed to bind to {}: {}", addr, e);
-            return;
+            if e.kind() == tokio::io::ErrorKind::TimedOut {
+                debug!("Client did not send a complete request in time: {}", e);
+                state.metrics.record_client_timeout();
+            }
+            return Err(e);
         }
     };
-    info!("Listening on {}", addr);
     loop {
-        tokio::select! {
-            _ = shutdown_token.cancelled() => {
-                info!("Listener on {} received shutdown signal", addr);
-                break;
+        let (target_host, port) = extract_host_and_port(&mut session.client, &request).await?;
+
+        trace!(
+            "Extracted target_host: '{}', port: {}, method: '{}'",
+            target_host, port, request.method
+        );
+
+        // IMPORTANT: Scope the read lock to ensure it's released as soon as we extract what we need
+        let mut reloaded = false;
+        let (profile_name, tag, proxy_config, mirror, strip_hop_by_hop, rewrite) = loop {
+            let config_guard = state.config.read().await;
+            // A tunnel's headers are inside the TLS stream, out of reach
+            let headers = (request.method != "CONNECT").then_some(&request.headers);
+            let forced = state.route_override.get();
+            let (profile_name, rule) = select_profile(
+                &config_guard,
+                &state.listener,
+                &state.breaker,
+                forced,
+                &target_host,
+                headers,
+            );
+            let tag = rule.and_then(|rule| rule.tag.clone());
+            state.connect_timeout = config_guard.connect_timeout(rule);
+            state.fallbacks = default_fallbacks(
+                &config_guard,
+                &state.listener,
+                &state.breaker,
+                forced,
+                &profile_name,
+                rule,
+            );
+            state.route_headers = config_guard.debug_route_headers.then(|| {
+                route_headers(
+                    config_guard.switch_for(&state.listener),
+                    &profile_name,
+                    rule,
+                )
+            });
+            debug!(
+                "Target is '{}', using '{}' profile",
+                target_host, profile_name
+            );
+
+            let policy = rule
+                .and_then(|rule| rule.method_policy.as_ref())
+                .unwrap_or(&config_guard.method_policy);
+            let refusal = if !policy.allows_method(&request.method) {
+                Some(("405 Method Not Allowed", "method"))
+            } else if request.method == "CONNECT" && !policy.allows_connect_port(port) {
+                Some(("403 Forbidden", "CONNECT port"))
+            } else {
+                None
+            };
+            if let Some((status, what)) = refusal {
+                debug!(
+                    "Refusing {} {}:{}: {} not allowed",
+                    request.method, target_host, port, what
+                );
+                let reason = format!("{what} not allowed by the proxy policy");
+                let response = config_guard
+                    .error_page(status, &target_host, &profile_name, &reason)
+                    .unwrap_or_else(|| http::error_response(status, &reason));
+                session.client.write_all(response.as_bytes()).await?;
+                return Ok(());
             }
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((client_socket, _addr)) => {
-                        let config = config.clone();
-                        let token = connections_token.clone();
-                        tokio::spawn(async move {
-                            // Get the current token for this connection
-                            let current_token = { token.lock().unwrap().clone() };
-                            let _ = handle_client(client_socket, config, current_token).await;
-                        });
-                    }
-                    Err(e) => {
-                        error!("Accept error on {}: {:?}", addr, e);
+
+            // Clone what we need from the config to avoid holding the lock
+
+            let Some(profile) = profile_named(&config_guard, &profile_name) else {
+                drop(config_guard);
+                if !std::mem::replace(&mut reloaded, true)
+                    && reload_for_missing_profile(&state, &profile_name).await
+                {
+                    continue;
+                }
+                error!("Profile {} not found in configuration", profile_name);
+                let reason = format!("Profile {profile_name} is not defined");
+                let response = error_page(&state, 500, &target_host, &profile_name, &reason)
+                    .await
+                    .unwrap_or_else(|| http::HTTP_SERVER_ERROR.to_string());
+                session.client.write_all(response.as_bytes()).await?;
+                return Ok(());
+            };
+
+            // A mirror relays through its primary; tunnels cannot be duplicated, so only
+            // plain-HTTP requests are copied to the secondary
+            let (profile, mirror) = match profile {
+                crate::config::Profile::Mirror {
+                    primary,
+                    secondary,
+                    all_methods,
+                } => {
+                    let mirrored = request.method != "CONNECT"
+                        && (all_methods || matches!(request.method.as_str(), "GET" | "HEAD"));
+                    let mirror = mirrored
+                        .then(|| config_guard.profiles.get(&secondary).cloned())
+                        .flatten();
+                    match config_guard.profiles.get(&primary) {
+                        Some(p) => (p.clone(), mirror),
+                        None => {
+                            error!("Profile {} not found in configuration", primary);
+                            let reason = format!("Profile {primary} is not defined");
+                            let response = config_guard
+                                .error_page(
+                                    "500 Internal Server Error",
+                                    &target_host,
+                                    &profile_name,
+                                    &reason,
+                                )
+                                .unwrap_or_else(|| http::HTTP_SERVER_ERROR.to_string());
+                            session.client.write_all(response.as_bytes()).await?;
+                            return Ok(());
+                        }
                     }
                 }
+                profile => (profile, None),
+            };
+            let rewrite = rule.and_then(|rule| rule.rewrite.clone());
+            break (
+                profile_name,
+                tag,
+                profile,
+                mirror,
+                config_guard.strip_hop_by_hop,
+                rewrite,
+            );
+        }; // read lock is released here
+
+        let client_close = request.headers.get("connection").is_some_and(|connection| {
+            connection
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("close"))
+        });
+
+        // Header rewriting only makes sense where we see the request; tunnels stay opaque
+        if request.method != "CONNECT" {
+            if strip_hop_by_hop {
+                // Only the direct path re-frames the body (through hyper)
+                let reframed = matches!(proxy_config, crate::config::Profile::Direct { .. });
+                http::strip_hop_by_hop(&mut request.headers, reframed);
+            }
+            proxy_config.headers().apply(&mut request.headers);
+        }
+
+        if let Some(tag) = &tag {
+            state.metrics.record_tag(tag);
+        }
+        let span = connection_span(&state, &profile_name, tag.as_deref(), &target_host);
+
+        // The rules saw the original target; from here on, the connection goes to the rewrite
+        let (target_host, port) = match &rewrite {
+            Some(rewrite) => {
+                let (host, port) = rewrite.apply(&target_host, port);
+                debug!("Rewriting {} to {}:{}", target_host, host, port);
+                request.set_authority(&crate::utils::join_host_port(&host, port));
+                (host, port)
             }
+            None => (target_host, port),
+        };
+
+        // Plain HTTP through a proxy is relayed one request at a time, so that every request
+        // of a keep-alive connection is routed on its own and exactly one response is relayed
+        let forwarded = request.method != "CONNECT"
+            && matches!(
+                proxy_config,
+                crate::config::Profile::Http { .. }
+                    | crate::config::Profile::Socks5 { .. }
+                    | crate::config::Profile::Fastest { .. }
+                    | crate::config::Profile::Hedged { .. }
+                    | crate::config::Profile::Chain { .. }
+            );
+        // Direct plain-HTTP requests take turns on the connection just the same
+        let sent_direct = request.method != "CONNECT"
+            && matches!(proxy_config, crate::config::Profile::Direct { .. });
+
+        // Only requests relayed as they are can leave `100 Continue` to the upstream; the
+        // others need their body up front
+        if request.body_pending() && (!forwarded || mirror.is_some()) {
+            session
+                .client
+                .write_all(http::HTTP_CONTINUE.as_bytes())
+                .await?;
+            request.body = http::read_body(&mut session.client, request.content_length()).await?;
+        }
+
+        if let Some(secondary) = mirror {
+            let (state, request, target_host) =
+                (state.clone(), request.clone(), target_host.clone());
+            tokio::spawn(
+                async move {
+                    if let Err(e) =
+                        mirror_request(state, secondary, request, target_host, port).await
+                    {
+                        debug!("Mirrored request failed: {e}");
+                    }
+                }
+                .instrument(tracing::debug_span!(parent: &span, "mirror")),
+            );
         }
+
+        if forwarded || sent_direct {
+            let keep_open = if forwarded {
+                session
+                    .forward(
+                        &request,
+                        &target_host,
+                        port,
+                        &profile_name,
+                        &proxy_config,
+                        &state,
+                    )
+                    .instrument(span)
+                    .await?
+            } else {
+                send_direct_request(
+                    &mut session.client,
+                    &request,
+                    &target_host,
+                    port,
+                    &profile_name,
+                    &proxy_config,
+                    &state,
+                )
+                .instrument(span)
+                .await?;
+                true
+            };
+            if !keep_open || client_close {
+                return Ok(());
+            }
+            // Requests the client pipelined are still buffered in `session.client`
+            request = match read_client_request(&mut session.client).await {
+                Ok(request) => request,
+                Err(e) => {
+                    // The client closed the connection or stayed idle for too long
+                    debug!("Keep-alive session ended: {}", e);
+                    return Ok(());
+                }
+            };
+            continue;
+        }
+
+        // Process the request with our cloned data, without holding the lock
+        let client: ClientStream = Box::new(session.client);
+        return async {
+            match proxy_config {
+                crate::config::Profile::Direct { .. } => {
+                    handle_direct_connection(
+                        client,
+                        &request,
+                        &target_host,
+                        port,
+                        &profile_name,
+                        &proxy_config,
+                        &state,
+                    )
+                    .await?;
+                }
+                crate::config::Profile::Socks5 { .. }
+                | crate::config::Profile::Http { .. }
+                | crate::config::Profile::Mirror { .. }
+                | crate::config::Profile::Fastest { .. }
+                | crate::config::Profile::Hedged { .. }
+                | crate::config::Profile::Chain { .. } => {
+                    handle_proxy_connection(
+                        client,
+                        &target_host,
+                        port,
+                        &profile_name,
+                        &proxy_config,
+                        &state,
+                    )
+                    .await?;
+                }
+                crate::config::Profile::Tarpit {
+                    delay_ms,
+                    max_duration_ms,
+                } => {
+                    debug!("Tarpitting {} {}:{}", request.method, target_host, port);
+                    let response: &[u8] = if request.method == "CONNECT" {
+                        b"HTTP/1.1 200 Connection Established\r\n\r\n"
+                    } else {
+                        http::HTTP_SERVER_ERROR.as_bytes()
+                    };
+                    tarpit(client, response, delay_ms, max_duration_ms).await?;
+                }
+            }
+            Ok::<_, tokio::io::Error>(())
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+/// Resolve `name` the way `profile` reaches it: through the upstream for SOCKS5 profiles,
+/// locally otherwise
+async fn resolve_through(
+    state: &ProxyState,
+    profile: &crate::config::Profile,
+    name: &str,
+) -> tokio::io::Result<std::net::IpAddr> {
+    match profile {
+        crate::config::Profile::Socks5 { .. } => {
+            let auth = proxy_auth(state, profile).await?;
+            let auth = auth.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            let result = try_endpoints(state, profile, |proxy_host, proxy_port, _| async move {
+                socks::resolve_via_proxy(name, &proxy_host, proxy_port, auth).await
+            })
+            .await;
+            forget_rejected_credentials(state, profile, result).await
+        }
+        crate::config::Profile::Direct { .. }
+        | crate::config::Profile::Http { .. }
+        | crate::config::Profile::Mirror { .. }
+        | crate::config::Profile::Fastest { .. }
+        | crate::config::Profile::Hedged { .. }
+        | crate::config::Profile::Chain { .. }
+        | crate::config::Profile::Tarpit { .. } => Ok(resolve_local(state, name, 0).await?[0].ip()),
+    }
+}
+
+/// Resolve `host:port` on this machine, through the shared DNS cache
+async fn resolve_local(
+    state: &ProxyState,
+    host: &str,
+    port: u16,
+) -> tokio::io::Result<Vec<std::net::SocketAddr>> {
+    let options = state.config.read().await.dns_cache.clone();
+    state.dns.resolve(host, port, &options).await
+}
+
+/// Serve a SOCKS5 client whose version byte was already read: CONNECT tunnels and RESOLVE
+async fn handle_socks_client(
+    mut client: ClientStream,
+    mut state: ProxyState,
+) -> tokio::io::Result<()> {
+    let handshake = tokio::time::timeout(
+        http::CLIENT_READ_TIMEOUT,
+        socks::accept_client_request(&mut client),
+    );
+    let Ok(request) = handshake.await else {
+        debug!("Client did not complete the SOCKS5 handshake in time");
+        state.metrics.record_client_timeout();
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::TimedOut,
+            "Timeout during the SOCKS5 handshake",
+        ));
+    };
+    let request = request?;
+    trace!(
+        "SOCKS5 {:?} request for {}:{}",
+        request.command, request.target, request.port
+    );
+
+    let mut reloaded = false;
+    let (profile_name, tag, profile, rewrite) = loop {
+        let config_guard = state.config.read().await;
+        let forced = state.route_override.get();
+        let (profile_name, rule) = select_profile(
+            &config_guard,
+            &state.listener,
+            &state.breaker,
+            forced,
+            &request.target,
+            None,
+        );
+        let tag = rule.and_then(|rule| rule.tag.clone());
+        let rewrite = rule.and_then(|rule| rule.rewrite.clone());
+        state.connect_timeout = config_guard.connect_timeout(rule);
+        state.fallbacks = default_fallbacks(
+            &config_guard,
+            &state.listener,
+            &state.breaker,
+            forced,
+            &profile_name,
+            rule,
+        );
+        debug!(
+            "SOCKS5 target is '{}', using '{}' profile",
+            request.target, profile_name
+        );
+        let Some(profile) = tunnel_profile_named(&config_guard, &profile_name) else {
+            drop(config_guard);
+            if !std::mem::replace(&mut reloaded, true)
+                && reload_for_missing_profile(&state, &profile_name).await
+            {
+                continue;
+            }
+            error!("Profile {} not found in configuration", profile_name);
+            socks::write_reply(&mut client, socks::GENERAL_FAILURE_REPLY, None).await?;
+            return Ok(());
+        };
+        break (profile_name, tag, profile, rewrite);
+    };
+
+    if let Some(tag) = &tag {
+        state.metrics.record_tag(tag);
+    }
+    let span = connection_span(&state, &profile_name, tag.as_deref(), &request.target);
+    async {
+        // Never answering leaves the client waiting for its reply
+        if let crate::config::Profile::Tarpit {
+            delay_ms,
+            max_duration_ms,
+        } = profile
+        {
+            debug!("Tarpitting SOCKS5 request for {}", request.target);
+            return tarpit(&mut client, &[], delay_ms, max_duration_ms).await;
+        }
+        let command = match request.command {
+            socks::ClientCommand::Connect => SocksCommand::Connect,
+            socks::ClientCommand::Bind => SocksCommand::Bind,
+            socks::ClientCommand::UdpAssociate => SocksCommand::Udp,
+            socks::ClientCommand::Resolve => SocksCommand::Resolve,
+        };
+        if let crate::config::Profile::Socks5 { commands, .. } = &profile
+            && !commands.contains(&command)
+        {
+            debug!(
+                "Profile {} does not support SOCKS5 {:?}, refusing it",
+                profile_name, request.command
+            );
+            return socks::write_reply(&mut client, socks::COMMAND_NOT_SUPPORTED_REPLY, None).await;
+        }
+        match request.command {
+            socks::ClientCommand::Connect => {
+                let (target_host, port) = match &rewrite {
+                    Some(rewrite) => rewrite.apply(&request.target, request.port),
+                    None => (request.target.clone(), request.port),
+                };
+                let (profile_name, result) =
+                    connect_with_failover(&state, &profile_name, &profile, &target_host, port)
+                        .await;
+                match result {
+                    Ok(upstream) => {
+                        state.record_connect(&profile_name, true);
+                        let bound = upstream.tcp().local_addr().ok();
+                        socks::write_reply(&mut client, socks::SUCCESS_REPLY, bound).await?;
+                        relay(client, upstream, &state.byte_meter(&profile_name)).await?;
+                    }
+                    Err(e) => {
+                        state.record_connect(&profile_name, false);
+                        error!("Could not connect to {}:{} : {}", target_host, port, e);
+                        socks::write_reply(&mut client, socks::HOST_UNREACHABLE_REPLY, None)
+                            .await?;
+                    }
+                }
+            }
+            socks::ClientCommand::Resolve => {
+                match resolve_through(&state, &profile, &request.target).await {
+                    Ok(ip) => {
+                        let resolved = std::net::SocketAddr::new(ip, 0);
+                        socks::write_reply(&mut client, socks::SUCCESS_REPLY, Some(resolved))
+                            .await?;
+                    }
+                    Err(e) => {
+                        debug!("Could not resolve {} : {}", request.target, e);
+                        socks::write_reply(&mut client, socks::HOST_UNREACHABLE_REPLY, None)
+                            .await?;
+                    }
+                }
+            }
+            // Only streams are relayed, whatever the upstream supports
+            socks::ClientCommand::Bind | socks::ClientCommand::UdpAssociate => {
+                debug!("SOCKS5 {:?} is not relayed, refusing it", request.command);
+                socks::write_reply(&mut client, socks::COMMAND_NOT_SUPPORTED_REPLY, None).await?;
+            }
+        }
+        Ok::<_, tokio::io::Error>(())
+    }
+    .instrument(span)
+    .await
+}
+
+/// Whether the listener of `state` takes a PROXY protocol header before each connection
+async fn accepts_proxy_protocol(state: &ProxyState) -> bool {
+    state
+        .config
+        .read()
+        .await
+        .listeners
+        .get(&state.listener)
+        .is_some_and(|options| options.accept_proxy_protocol)
+}
+
+/// Fill in the client addresses of a freshly accepted socket, as its PROXY protocol header
+/// announces them when its listener accepts one
+async fn identify_client(
+    socket: &mut tokio::net::TcpStream,
+    state: &mut ProxyState,
+) -> tokio::io::Result<()> {
+    if let (Ok(source), Ok(destination)) = (socket.peer_addr(), socket.local_addr()) {
+        state.client_addrs = Some(ClientAddrs {
+            source,
+            destination,
+        });
+    }
+    if accepts_proxy_protocol(state).await {
+        // The load balancer announces the real client before anything else, even TLS
+        let header =
+            tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(socket))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(tokio::io::Error::new(
+                        tokio::io::ErrorKind::TimedOut,
+                        "Timeout reading PROXY protocol header",
+                    ))
+                });
+        match header {
+            Ok(Some(client)) => state.client_addrs = Some(client),
+            Ok(None) => {}
+            Err(e) => {
+                debug!("Dropping connection without a valid PROXY protocol header: {e}");
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Identify and serve a freshly accepted socket, as `run_listener` does past the limiter
+#[cfg(test)]
+async fn accept_client(
+    mut socket: tokio::net::TcpStream,
+    mut state: ProxyState,
+    cancel_token: CancellationToken,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+) -> tokio::io::Result<()> {
+    identify_client(&mut socket, &mut state).await?;
+    serve_client(socket, state, cancel_token, tls_acceptor).await
+}
+
+/// Serve an accepted socket whose client addresses are known: transparent routing, TLS
+/// termination, or plain proxying
+async fn serve_client(
+    socket: tokio::net::TcpStream,
+    mut state: ProxyState,
+    cancel_token: CancellationToken,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+) -> tokio::io::Result<()> {
+    state.leases = Arc::default();
+    let (max_lifetime, max_bytes, flow_export) = {
+        let config_guard = state.config.read().await;
+        (
+            config_guard.max_connection_secs,
+            config_guard.max_bytes_per_connection,
+            config_guard.flow_export.clone(),
+        )
+    };
+    state.flow = Arc::new(Flow::new().with_max_bytes(max_bytes));
+    // Puts the client address on debug logs, e.g. of clients that time out
+    let client_span = match state.client_addrs {
+        Some(client) => {
+            tracing::debug_span!("client", conn_id = state.connection_id, addr = %client.source)
+        }
+        None => tracing::debug_span!("client", conn_id = state.connection_id),
+    };
+    let (flow, flow_exporter, client_addrs, closing) = (
+        state.flow.clone(),
+        state.flow_exporter.clone(),
+        state.client_addrs,
+        state.closing.clone(),
+    );
+    let connection = async move {
+        if state.transparent {
+            return handle_transparent_client(socket, state, cancel_token).await;
+        }
+        let client: ClientStream = match tls_acceptor {
+            Some(acceptor) => {
+                // Clients that connect and stay silent would otherwise hold the socket forever
+                let handshake =
+                    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await;
+                let Ok(tls) = handshake else {
+                    debug!("Client did not complete the TLS handshake in time");
+                    state.metrics.record_client_timeout();
+                    return Err(tokio::io::Error::new(
+                        tokio::io::ErrorKind::TimedOut,
+                        "Timeout during the TLS handshake",
+                    ));
+                };
+                Box::new(tls?)
+            }
+            None => Box::new(socket),
+        };
+        handle_client(client, state, cancel_token).await
+    }
+    .instrument(client_span);
+
+    let lifetime = async {
+        match max_lifetime {
+            Some(secs) => tokio::time::sleep(std::time::Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+    // Dropping the connection future closes both the client and the upstream side
+    let result = tokio::select! {
+        result = connection => result,
+        _ = lifetime => {
+            debug!(
+                "Closing connection that exceeded max_connection_secs ({}s)",
+                max_lifetime.unwrap_or_default()
+            );
+            Ok(())
+        }
+        _ = closing.cancelled() => {
+            debug!("Closing connection still open at shutdown");
+            Ok(())
+        }
+    };
+    if let (Some(options), Some(addrs)) = (flow_export, client_addrs) {
+        let record = flow.record(addrs, std::time::SystemTime::now());
+        if let Err(e) = flow_exporter.export(&options, &record).await {
+            debug!("Failed to export flow record to {}: {e}", options.collector);
+        }
+    }
+    result
+}
+
+/// Bind a listening socket on `addr` with the backlog and reuse options of its listener
+///
+/// Like `TcpListener::bind`, every address `addr` resolves to is tried until one binds.
+pub async fn bind_listener(
+    addr: &str,
+    options: &crate::config::ListenerOptions,
+) -> tokio::io::Result<TcpListener> {
+    let mut last_error = None;
+    for socket_addr in tokio::net::lookup_host(addr).await? {
+        match bind_socket(socket_addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            format!("'{addr}' did not resolve to any address"),
+        )
+    }))
+}
+
+fn bind_socket(
+    addr: std::net::SocketAddr,
+    options: &crate::config::ListenerOptions,
+) -> tokio::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(options.reuse_address)?;
+    #[cfg(all(
+        unix,
+        not(target_os = "solaris"),
+        not(target_os = "illumos"),
+        not(target_os = "cygwin")
+    ))]
+    socket.set_reuseport(options.reuse_port)?;
+    socket.bind(addr)?;
+    socket.listen(options.backlog)
+}
+
+/// Build the TLS acceptor of listener `addr` if its options ask for TLS
+pub fn listener_tls_acceptor(
+    addr: &str,
+    options: &crate::config::ListenerOptions,
+) -> Result<Option<tokio_rustls::TlsAcceptor>, String> {
+    match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            let server_config = crate::protocols::tls::server_config(cert, key)
+                .map_err(|e| format!("Failed to set up TLS for listener {addr}: {e}"))?;
+            Ok(Some(tokio_rustls::TlsAcceptor::from(Arc::new(
+                server_config,
+            ))))
+        }
+        (None, None) => Ok(None),
+        _ => Err(format!(
+            "Failed to set up TLS for listener {addr}: both tls_cert and tls_key must be set"
+        )),
+    }
+}
+
+/// Accept connections on an already bound listener until the shutdown token is cancelled
+///
+/// `addr` is the listen address as configured, used to look up its `listeners` options.
+/// Clients complete a handshake with `tls_acceptor` first when it is set.
+pub async fn run_listener(
+    listener: TcpListener,
+    addr: String,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    state: ProxyState,
+    connections_token: Arc<Mutex<CancellationToken>>,
+    shutdown_token: CancellationToken,
+) {
+    if tls_acceptor.is_some() {
+        info!("Listening on {} (TLS)", addr);
+    } else {
+        info!("Listening on {}", addr);
+    }
+    loop {
+        tokio::select! {
+            _ = shutdown_token.cancelled() => {
+                info!("Listener on {} received shutdown signal", addr);
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((mut client_socket, client_addr)) => {
+                        let mut state = state.clone();
+                        let token = connections_token.clone();
+                        let tls_acceptor = tls_acceptor.clone();
+                        let limiter = state.limiter.clone();
+                        let behind_balancer = accepts_proxy_protocol(&state).await;
+                        let admit = async move {
+                            if identify_client(&mut client_socket, &mut state).await.is_err() {
+                                return;
+                            }
+                            // Behind a load balancer, the client it announces is the one limited
+                            let client = state
+                                .client_addrs
+                                .map_or(client_addr.ip(), |addrs| addrs.source.ip());
+                            let limits = state.config.read().await.connection_limits;
+                            let job = Box::pin(async move {
+                                // Counts the connection as active until it is done
+                                let active = state.metrics.connection_opened();
+                                state.connection_id = active.id();
+                                // Get the current token for this connection
+                                let current_token = { token.lock().unwrap().clone() };
+                                let _ =
+                                    serve_client(client_socket, state, current_token, tls_acceptor)
+                                        .await;
+                            });
+                            limiter.submit(client, job, limits.as_ref());
+                        };
+                        if behind_balancer {
+                            // Reading the header must not hold up accepting other connections
+                            tokio::spawn(admit);
+                        } else {
+                            admit.await;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Accept error on {}: {:?}", addr, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Profile;
+
+    /// A connected client/server socket pair on the loopback interface
+    async fn socket_pair() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) =
+            tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    /// A loopback port with nothing listening on it
+    async fn unused_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn test_state() -> ProxyState {
+        test_state_with(r#"{ switch: { default: "direct", rules: [] }, profiles: {} }"#)
+    }
+
+    fn test_state_with(config: &str) -> ProxyState {
+        let config: Config = json5::from_str(config).unwrap();
+        let metrics = Arc::new(Metrics::new());
+        ProxyState {
+            config: Arc::new(RwLock::new(config)),
+            metrics: metrics.clone(),
+            resolver: Arc::new(UpstreamResolver::new()),
+            round_robin: Arc::new(RoundRobin::new()),
+            dns: Arc::new(DnsCache::new()),
+            breaker: Arc::new(Breaker::new()),
+            route_override: Arc::new(RouteOverride::new()),
+            hedges: Arc::new(HedgeSlots::new()),
+            credentials: Arc::new(CredentialCache::new()),
+            missing_profile_reload: Arc::new(tokio::sync::Mutex::new(None)),
+            limiter: Arc::new(ConnectionLimiter::new(metrics.clone())),
+            flow_exporter: Arc::new(FlowExporter::new()),
+            flow: Arc::new(Flow::new()),
+            leases: Arc::default(),
+            closing: CancellationToken::new(),
+            listener: String::new(),
+            client_addrs: None,
+            connection_id: 0,
+            connect_timeout: None,
+            fallbacks: Vec::new(),
+            route_headers: None,
+            transparent: false,
+        }
+    }
+
+    /// Spawn a one-shot origin that answers with `response` and yields the request head it saw
+    async fn spawn_origin(response: &'static str) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&head).to_string()
+        });
+        (port, handle)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_credential_command_output_is_offered_and_refreshed_on_rejection() {
+        let counter = std::env::temp_dir().join(format!(
+            "proxy-twister-credential-runs-{}",
+            std::process::id()
+        ));
+        let command = format!(
+            "echo run >> '{0}'; echo username=alice; echo password=t0ken$(wc -l < '{0}' | tr -d ' ')",
+            counter.display()
+        );
+        let state = test_state();
+        let (rejecting_port, rejected) =
+            spawn_origin("HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+        let (accepting_port, accepted) =
+            spawn_origin("HTTP/1.1 200 Connection Established\r\n\r\n").await;
+        let profile = |port| Profile::Http {
+            host: "127.0.0.1".to_string(),
+            port,
+            endpoints: Vec::new(),
+            username: None,
+            password: None,
+            credential_command: Some(command.clone()),
+            credential_ttl_secs: 300,
+            send_proxy_protocol: None,
+            tls: None,
+            headers: Default::default(),
+        };
+
+        let Err(e) = connect_via(&state, &profile(rejecting_port), "example.com", 443).await else {
+            panic!("the proxy rejected the credentials");
+        };
+        assert_eq!(e.kind(), tokio::io::ErrorKind::PermissionDenied);
+        let head = rejected.await.unwrap();
+        assert!(
+            head.contains("Proxy-Authorization: Basic YWxpY2U6dDBrZW4x\r\n"),
+            "{head}"
+        );
+
+        // The rejected token is not reused
+        connect_via(&state, &profile(accepting_port), "example.com", 443)
+            .await
+            .unwrap();
+        let head = accepted.await.unwrap();
+        std::fs::remove_file(&counter).unwrap();
+        assert!(
+            head.contains("Proxy-Authorization: Basic YWxpY2U6dDBrZW4y\r\n"),
+            "{head}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_https_proxy_is_offered_the_client_certificate() {
+        let dir = std::env::temp_dir();
+        let write = |name: &str, pem: String| {
+            let path = dir.join(format!("proxy-twister-{name}-{}.pem", std::process::id()));
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+
+        // CA issuing the client certificate, and the proxy's own self-signed certificate
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let ca = rcgen::Issuer::new(ca_params, ca_key);
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let client_cert = rcgen::CertificateParams::new(vec!["client.example".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca)
+            .unwrap();
+        let proxy_key = rcgen::KeyPair::generate().unwrap();
+        let proxy_cert = rcgen::CertificateParams::new(vec!["proxy.example".to_string()])
+            .unwrap()
+            .self_signed(&proxy_key)
+            .unwrap();
+
+        // HTTPS proxy requiring a client certificate, accepting CONNECT and echoing the tunnel
+        let mut client_roots = rustls::RootCertStore::empty();
+        client_roots.add(ca_cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let client_verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            Arc::new(client_roots),
+            provider.clone(),
+        )
+        .build()
+        .unwrap();
+        let server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(
+                vec![proxy_cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::try_from(proxy_key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut tls) = acceptor.accept(socket).await else {
+                        return;
+                    };
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match tls.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    assert!(head.starts_with(b"CONNECT example.com:443 "));
+                    tls.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let (mut r, mut w) = tokio::io::split(tls);
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        let pin: [u8; 32] = {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(proxy_cert.der().as_ref()).into()
+        };
+        let anonymous = crate::config::TlsOptions {
+            pinned_fingerprints: vec![pin.iter().map(|b| format!("{b:02x}")).collect()],
+            ..Default::default()
+        };
+        let identified = crate::config::TlsOptions {
+            client_cert: Some(write("proxy-client-cert", client_cert.pem())),
+            client_key: Some(write("proxy-client-key", client_key.serialize_pem())),
+            ..anonymous.clone()
+        };
+        let profile = |options: &crate::config::TlsOptions| {
+            let mut profile = Profile::http("127.0.0.1", proxy_port);
+            if let Profile::Http { tls, .. } = &mut profile {
+                *tls = Some(options.clone());
+            }
+            profile
+        };
+        let state = test_state();
+
+        let mut tunnel = connect_via(&state, &profile(&identified), "example.com", 443)
+            .await
+            .unwrap();
+        tunnel.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        tunnel.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        // Without the certificate the proxy never answers the CONNECT
+        let rejected = connect_via(&state, &profile(&anonymous), "example.com", 443).await;
+        assert!(rejected.is_err());
+
+        std::fs::remove_file(identified.client_cert.unwrap()).unwrap();
+        std::fs::remove_file(identified.client_key.unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_credentials_rejected_on_plain_http_are_refreshed() {
+        let counter = std::env::temp_dir().join(format!(
+            "proxy-twister-credential-runs-plain-{}",
+            std::process::id()
+        ));
+        let command = format!(
+            "echo run >> '{0}'; echo username=alice; echo password=t0ken$(wc -l < '{0}' | tr -d ' ')",
+            counter.display()
+        );
+        // Proxy rejecting every request, yielding the credentials each one carried
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut offered = Vec::new();
+            while let Ok(request) = http::read_request(&mut stream).await {
+                offered.extend(request.headers.get("proxy-authorization").cloned());
+                stream
+                    .write_all(
+                        b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+            }
+            offered
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "corp", rules: [] }},
+                profiles: {{ corp: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port}, credential_command: "{command}" }} }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        for _ in 0..2 {
+            user.get_mut()
+                .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            http::relay_response(&mut user, &mut response, false)
+                .await
+                .unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 407"));
+        }
+        drop(user);
+        proxy.await.unwrap().unwrap();
+        std::fs::remove_file(&counter).unwrap();
+
+        // The rejected token is not reused for the next request
+        assert_eq!(
+            upstream.await.unwrap(),
+            ["Basic YWxpY2U6dDBrZW4x", "Basic YWxpY2U6dDBrZW4y"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_failures_are_counted_per_profile() {
+        let state = test_state();
+        let dead_proxy = Profile::Http {
+            host: "127.0.0.1".to_string(),
+            port: unused_port().await,
+            endpoints: Vec::new(),
+            username: None,
+            password: None,
+            credential_command: None,
+            credential_ttl_secs: 300,
+            send_proxy_protocol: None,
+            tls: None,
+            headers: Default::default(),
+        };
+
+        for _ in 0..2 {
+            let (mut user, client) = socket_pair().await;
+            handle_proxy_connection(
+                Box::new(client),
+                "example.com",
+                443,
+                "flaky",
+                &dead_proxy,
+                &state,
+            )
+            .await
+            .unwrap();
+
+            let mut response = String::new();
+            user.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 500"));
+        }
+
+        let counters = state.metrics.profile("flaky");
+        assert_eq!(counters.failures, 2);
+        assert_eq!(counters.successes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_non_http_proxy_response_is_bad_gateway() {
+        let junk: [&[u8]; 3] = [
+            b"\x16\x03\x01\x00\xa5 not http at all\r\n\r\n",
+            b"<html><body>Please log in</body></html>\r\n",
+            // An endless line without a newline
+            &[b'A'; 64 * 1024],
+        ];
+        for reply in junk {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 256];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(reply).await;
+            });
+            let state = test_state();
+            let portal = Profile::Http {
+                host: "127.0.0.1".to_string(),
+                port: proxy_port,
+                endpoints: Vec::new(),
+                username: None,
+                password: None,
+                credential_command: None,
+                credential_ttl_secs: 300,
+                send_proxy_protocol: None,
+                tls: None,
+                headers: Default::default(),
+            };
+
+            let (mut user, client) = socket_pair().await;
+            handle_proxy_connection(
+                Box::new(client),
+                "example.com",
+                443,
+                "portal",
+                &portal,
+                &state,
+            )
+            .await
+            .unwrap();
+
+            let mut response = String::new();
+            user.read_to_string(&mut response).await.unwrap();
+            assert!(
+                response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+                "{response}"
+            );
+            assert!(response.contains("upstream returned an invalid response"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_bad_gateway_page_names_the_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 256];
+            let _ = stream.read(&mut request).await;
+            let _ = stream.write_all(b"<html>captive portal</html>\r\n").await;
+        });
+        let page = std::env::temp_dir().join(format!(
+            "proxy-twister-502-{}-{proxy_port}.html",
+            std::process::id()
+        ));
+        std::fs::write(&page, "<p>{host} is unreachable through {profile}</p>").unwrap();
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "portal", rules: [] }},
+                profiles: {{ portal: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+                errorPages: {{ "502": {page:?} }},
+            }}"#
+        ));
+        state.config.write().await.load_error_pages().unwrap();
+        let portal = state.config.read().await.profiles["portal"].clone();
+
+        let (mut user, client) = socket_pair().await;
+        handle_proxy_connection(
+            Box::new(client),
+            "example.com",
+            443,
+            "portal",
+            &portal,
+            &state,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&page).unwrap();
+
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Content-Type: text/html"), "{response}");
+        assert!(
+            response.ends_with("<p>example.com is unreachable through portal</p>"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_page_is_used_for_tunnels() {
+        // HTTP proxy that never answers the CONNECT
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let _ = stream.read_to_end(&mut request).await;
+        });
+        let page = std::env::temp_dir().join(format!(
+            "proxy-twister-504-{}-{proxy_port}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&page, "{host} took too long through {profile}").unwrap();
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "slow", rules: [] }},
+                profiles: {{ slow: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+                connectTimeoutMs: 100,
+                errorPages: {{ "504": {page:?} }},
+            }}"#
+        ));
+        state.config.write().await.load_error_pages().unwrap();
+
+        let (mut user, client) = socket_pair().await;
+        user.write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        handle_client(Box::new(client), state, CancellationToken::new())
+            .await
+            .unwrap();
+        std::fs::remove_file(&page).unwrap();
+
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.ends_with("example.com took too long through slow"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tls_handshake_times_out() {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![cert.der().clone()],
+            rustls::pki_types::PrivateKeyDer::try_from(key_pair.serialize_der()).unwrap(),
+        )
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let state = test_state();
+        // Connects and never says a word
+        let (_user, client) = socket_pair().await;
+
+        let err = accept_client(
+            client,
+            state.clone(),
+            CancellationToken::new(),
+            Some(acceptor),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), tokio::io::ErrorKind::TimedOut);
+        assert_eq!(state.metrics.client_timeouts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_request_timeout_is_counted() {
+        let state = test_state();
+        let (mut user, client) = socket_pair().await;
+        // Never finishes the header block
+        user.write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n")
+            .await
+            .unwrap();
+
+        let err = handle_client(Box::new(client), state.clone(), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), tokio::io::ErrorKind::TimedOut);
+        assert_eq!(state.metrics.client_timeouts(), 1);
+        assert!(
+            state
+                .metrics
+                .render()
+                .contains("proxy_twister_client_request_timeouts_total 1")
+        );
+    }
+
+    #[cfg(all(target_os = "linux", feature = "transparent"))]
+    #[tokio::test]
+    async fn test_transparent_client_not_redirected_is_refused() {
+        let mut state = test_state();
+        state.transparent = true;
+        // Connects straight to the listener, without an iptables REDIRECT in between
+        let (_user, client) = socket_pair().await;
+
+        let served = accept_client(client, state, CancellationToken::new(), None).await;
+        assert!(served.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_client_times_out_and_is_counted() {
+        let state = test_state();
+        // Connects and never writes a byte
+        let (_user, client) = socket_pair().await;
+
+        let err = handle_client(Box::new(client), state.clone(), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), tokio::io::ErrorKind::TimedOut);
+        assert_eq!(state.metrics.client_timeouts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_socks_handshake_times_out_and_is_counted() {
+        let state = test_state();
+        let (mut user, client) = socket_pair().await;
+        // Offers its methods and never sends the request
+        user.write_all(&[socks::SOCKS_VERSION, 1, 0]).await.unwrap();
+
+        let err = handle_client(Box::new(client), state.clone(), CancellationToken::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), tokio::io::ErrorKind::TimedOut);
+        assert_eq!(state.metrics.client_timeouts(), 1);
+    }
+
+    #[cfg(all(
+        unix,
+        not(target_os = "solaris"),
+        not(target_os = "illumos"),
+        not(target_os = "cygwin")
+    ))]
+    #[tokio::test]
+    async fn test_reuse_port_shares_an_address() {
+        let options = crate::config::ListenerOptions {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind_listener("127.0.0.1:0", &options).await.unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+
+        let second = bind_listener(&addr, &options).await.unwrap();
+        assert_eq!(second.local_addr().unwrap().to_string(), addr);
+
+        let exclusive = crate::config::ListenerOptions::default();
+        assert!(bind_listener(&addr, &exclusive).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_method_policy() {
+        let (origin_port, origin) =
+            spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let config = r#"{
+            switch: { default: "direct", rules: [] },
+            profiles: { direct: { scheme: "direct" } },
+            methodPolicy: { denied_methods: ["TRACE"], connect_ports: [443] },
+        }"#;
+        let exchange = |request: String| async move {
+            let (mut user, client) = socket_pair().await;
+            user.write_all(request.as_bytes()).await.unwrap();
+            handle_client(
+                Box::new(client),
+                test_state_with(config),
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+            let mut response = String::new();
+            user.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let allowed = exchange(format!(
+            "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\nHost: 127.0.0.1:{origin_port}\r\n\
+             Connection: close\r\n\r\n"
+        ))
+        .await;
+        assert!(allowed.starts_with("HTTP/1.1 200"), "{allowed}");
+        assert!(origin.await.unwrap().starts_with("GET / "));
+
+        let denied = exchange(format!(
+            "TRACE http://127.0.0.1:{origin_port}/ HTTP/1.1\r\nHost: 127.0.0.1:{origin_port}\r\n\r\n"
+        ))
+        .await;
+        assert!(
+            denied.starts_with("HTTP/1.1 405 Method Not Allowed"),
+            "{denied}"
+        );
+
+        let tunnel =
+            exchange("CONNECT 127.0.0.1:22 HTTP/1.1\r\nHost: 127.0.0.1:22\r\n\r\n".to_string())
+                .await;
+        assert!(tunnel.starts_with("HTTP/1.1 403 Forbidden"), "{tunnel}");
+    }
+
+    #[tokio::test]
+    async fn test_header_rewrite_on_direct_path() {
+        let (origin_port, origin) =
+            spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+        let state = test_state_with(
+            r#"{
+                switch: { default: "direct", rules: [] },
+                profiles: {
+                    direct: {
+                        scheme: "direct",
+                        headers: { set: { "X-Api-Key": "secret" }, remove: ["X-Internal"] },
+                    },
+                },
+            }"#,
+        );
+
+        let (mut user, client) = socket_pair().await;
+        user.write_all(
+            format!(
+                "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\n\
+                 Host: 127.0.0.1:{origin_port}\r\n\
+                 X-Internal: leak\r\n\
+                 Connection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        handle_client(Box::new(client), state, CancellationToken::new())
+            .await
+            .unwrap();
+
+        let head = origin.await.unwrap().to_lowercase();
+        assert!(head.contains("x-api-key: secret"));
+        assert!(!head.contains("x-internal"));
+    }
+
+    #[tokio::test]
+    async fn test_rule_rewrite_connects_to_the_new_destination() {
+        let state_for = |origin_port: u16| {
+            // Anything not matching the original host would go nowhere
+            test_state_with(&format!(
+                r#"{{
+                    switch: {{
+                        default: "nowhere",
+                        rules: [{{
+                            pattern: "old.example.com",
+                            profile: "direct",
+                            rewrite: {{ host: "127.0.0.1", port: {origin_port} }},
+                        }}],
+                    }},
+                    profiles: {{
+                        direct: {{ scheme: "direct" }},
+                        nowhere: {{ scheme: "socks5", host: "127.0.0.1", port: 9 }},
+                    }},
+                }}"#
+            ))
+        };
+
+        // Plain HTTP keeps the client's Host header
+        let (origin_port, origin) =
+            spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let (mut user, client) = socket_pair().await;
+        user.write_all(
+            b"GET http://old.example.com/path?q=1 HTTP/1.1\r\n\
+              Host: old.example.com\r\n\
+              Connection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        handle_client(
+            Box::new(client),
+            state_for(origin_port),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let head = origin.await.unwrap();
+        assert!(head.starts_with("GET /path?q=1 HTTP/1.1\r\n"), "{head}");
+        assert!(
+            head.to_lowercase().contains("host: old.example.com\r\n"),
+            "{head}"
+        );
+
+        // Tunnels lead to the rewritten destination too
+        let (origin_port, origin) = spawn_origin("rewritten").await;
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state_for(origin_port),
+            CancellationToken::new(),
+        ));
+        user.write_all(
+            b"CONNECT old.example.com:443 HTTP/1.1\r\nHost: old.example.com:443\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut established = [0u8; 39];
+        user.read_exact(&mut established).await.unwrap();
+        assert_eq!(&established, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+        user.write_all(b"hello\r\n\r\n").await.unwrap();
+        let mut greeting = String::new();
+        user.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "rewritten");
+        assert_eq!(origin.await.unwrap(), "hello\r\n\r\n");
+        drop(user);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_route_headers_only_when_enabled() {
+        for enabled in [false, true] {
+            let (origin_port, _origin) =
+                spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await;
+            let state = test_state_with(&format!(
+                r#"{{
+                    debugRouteHeaders: {enabled},
+                    switch: {{
+                        default: "direct",
+                        rules: [{{ pattern: "*.invalid", profile: "direct" }}, {{ pattern: "127.0.0.1", profile: "local" }}],
+                    }},
+                    profiles: {{ direct: {{ scheme: "direct" }}, local: {{ scheme: "direct" }} }},
+                }}"#
+            ));
+
+            let (mut user, client) = socket_pair().await;
+            user.write_all(
+                format!(
+                    "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\n\
+                     Host: 127.0.0.1:{origin_port}\r\n\
+                     Connection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+            handle_client(Box::new(client), state, CancellationToken::new())
+                .await
+                .unwrap();
+
+            let mut response = String::new();
+            user.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+            assert_eq!(
+                response.contains("X-Proxy-Twister-Profile: local\r\n"),
+                enabled,
+                "{response}"
+            );
+            assert_eq!(
+                response.contains("X-Proxy-Twister-Rule: 2 (127.0.0.1)\r\n"),
+                enabled,
+                "{response}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_direct_response_times_out_with_504() {
+        // Origin sends its headers and part of the body, then stalls
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial")
+                .await
+                .unwrap();
+            let _ = stream.read(&mut buf).await;
+        });
+        let state = test_state_with(
+            r#"{
+                responseTimeoutMs: 200,
+                switch: { default: "direct", rules: [] },
+                profiles: { direct: { scheme: "direct" } },
+            }"#,
+        );
+
+        let (mut user, client) = socket_pair().await;
+        user.write_all(
+            format!(
+                "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\n\
+                 Host: 127.0.0.1:{origin_port}\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let served = handle_client(Box::new(client), state, CancellationToken::new());
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), served)
+            .await
+            .expect("stalled origin held the request open");
+        assert!(result.is_err());
+
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 504"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_mirror_copies_request_to_secondary() {
+        let (origin_port, origin) =
+            spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nprimary").await;
+        // The secondary is an HTTP proxy, so it receives the request in absolute form
+        let (shadow_port, shadow) =
+            spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nshadow").await;
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "mirrored", rules: [] }},
+                profiles: {{
+                    direct: {{ scheme: "direct" }},
+                    shadow: {{ scheme: "http", host: "127.0.0.1", port: {shadow_port} }},
+                    mirrored: {{ scheme: "mirror", primary: "direct", secondary: "shadow" }},
+                }},
+            }}"#
+        ));
+
+        let (mut user, client) = socket_pair().await;
+        user.write_all(
+            format!(
+                "GET http://127.0.0.1:{origin_port}/probe HTTP/1.1\r\n\
+                 Host: 127.0.0.1:{origin_port}\r\n\
+                 Connection: close\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        handle_client(Box::new(client), state, CancellationToken::new())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("primary"));
+        assert!(origin.await.unwrap().contains("/probe"));
+
+        let mirrored = tokio::time::timeout(std::time::Duration::from_secs(5), shadow)
+            .await
+            .expect("secondary never received the mirrored request")
+            .unwrap();
+        assert!(mirrored.starts_with(&format!("GET http://127.0.0.1:{origin_port}/probe ")));
+    }
+
+    #[test]
+    fn test_scheduled_rule_only_matches_in_window() {
+        let config: Config = json5::from_str(
+            r#"{
+                switch: {
+                    default: "direct",
+                    rules: [{
+                        pattern: "*.corp.example",
+                        profile: "expensive",
+                        schedule: { from: "09:00", to: "18:00", days: ["mon", "tue", "wed", "thu", "fri"] },
+                    }],
+                },
+                profiles: {},
+            }"#,
+        )
+        .unwrap();
+        // Monday 2024-01-01 00:00 UTC
+        let monday = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_704_067_200);
+        let hour = std::time::Duration::from_secs(3600);
+
+        let (in_window, rule) = select_profile_at(
+            &config,
+            "",
+            &Breaker::new(),
+            None,
+            "app.corp.example",
+            None,
+            monday + 10 * hour,
+        );
+        assert_eq!(in_window, "expensive");
+        assert!(rule.is_some());
+
+        let (evening, rule) = select_profile_at(
+            &config,
+            "",
+            &Breaker::new(),
+            None,
+            "app.corp.example",
+            None,
+            monday + 20 * hour,
+        );
+        assert_eq!(evening, "direct");
+        assert!(rule.is_none());
+    }
+
+    #[test]
+    fn test_header_rule_routes_plain_http_requests() {
+        let config: Config = json5::from_str(
+            r#"{
+                switch: {
+                    default: "direct",
+                    rules: [{
+                        pattern: "*",
+                        header: { name: "X-Route", pattern: "canary*" },
+                        profile: "canary",
+                    }],
+                },
+                profiles: {},
+            }"#,
+        )
+        .unwrap();
+        let breaker = Breaker::new();
+        let headers = |route: &str| http::Headers::from([("X-Route", route)]);
+        let select = |headers: Option<&http::Headers>| {
+            select_profile(&config, "", &breaker, None, "app.example.com", headers).0
+        };
+
+        assert_eq!(select(Some(&headers("canary-eu"))), "canary");
+        assert_eq!(select(Some(&headers("stable"))), "direct");
+        assert_eq!(select(Some(&http::Headers::new())), "direct");
+        // CONNECT and SOCKS requests have no headers to match
+        assert_eq!(select(None), "direct");
+    }
+
+    #[test]
+    fn test_tunnel_rule_routes_connect_and_plain_http_apart() {
+        let config: Config = json5::from_str(
+            r#"{
+                switch: {
+                    default: "direct",
+                    rules: [
+                        { pattern: "example.com", tunnel: true, profile: "proxy" },
+                        { pattern: "www.example.com", tunnel: false, profile: "proxy" },
+                    ],
+                },
+                profiles: {},
+            }"#,
+        )
+        .unwrap();
+        let breaker = Breaker::new();
+        let plain_http = http::Headers::from([("Host", "example.com")]);
+        let select = |host: &str, headers: Option<&http::Headers>| {
+            select_profile(&config, "", &breaker, None, host, headers).0
+        };
+
+        // CONNECT (no headers) goes through the proxy, plain HTTP to the same host goes direct
+        assert_eq!(select("example.com", None), "proxy");
+        assert_eq!(select("example.com", Some(&plain_http)), "direct");
+        assert_eq!(select("www.example.com", None), "direct");
+        assert_eq!(select("www.example.com", Some(&plain_http)), "proxy");
+
+        let invalid = Config::from_parts(
+            Switch::new("direct").rule(
+                Rule::new("*", "direct")
+                    .with_header("X-Route", "*")
+                    .with_tunnel(true),
+            ),
+            HashMap::from([("direct".to_string(), Profile::direct())]),
+        );
+        let err = invalid.validate().unwrap_err().to_string();
+        assert!(err.contains("tunnels never carry"), "{err}");
+    }
+
+    #[test]
+    fn test_open_breaker_falls_through_to_next_rule() {
+        let state = test_state_with(
+            r#"{
+                switch: {
+                    default: "direct",
+                    rules: [
+                        { pattern: "*.example.com", profile: "tor" },
+                        { pattern: "*.com", profile: "backup" },
+                    ],
+                },
+                profiles: {},
+                breaker: { failures: 2, cooldown_secs: 60 },
+            }"#,
+        );
+        let config = state.config.try_read().unwrap();
+        let select =
+            |host| select_profile(&config, &state.listener, &state.breaker, None, host, None).0;
+
+        state.record_connect("tor", false);
+        assert_eq!(select("a.example.com"), "tor");
+        state.record_connect("tor", false);
+        assert_eq!(select("a.example.com"), "backup");
+
+        state.record_connect("backup", false);
+        state.record_connect("backup", false);
+        assert_eq!(select("a.example.com"), "direct");
+        assert_eq!(select("a.example.org"), "direct");
+
+        state.record_connect("tor", true);
+        assert_eq!(select("a.example.com"), "tor");
+    }
+
+    #[test]
+    fn test_forced_route_overrides_rules_until_cleared() {
+        let state = test_state_with(
+            r#"{
+                switch: {
+                    default: "tor",
+                    rules: [{ pattern: "*.example.com", profile: "corp" }],
+                },
+                profiles: {
+                    tor: { scheme: "socks5", host: "127.0.0.1", port: 9050 },
+                    corp: { scheme: "http", host: "127.0.0.1", port: 3128 },
+                    backup: { scheme: "http", host: "127.0.0.1", port: 8080 },
+                },
+                emergencyProfile: "backup",
+            }"#,
+        );
+        let config = state.config.try_read().unwrap();
+        let select = |host| {
+            let forced = state.route_override.get();
+            select_profile(&config, &state.listener, &state.breaker, forced, host, None)
+        };
+        assert_eq!(select("a.example.com").0, "corp");
+
+        state.route_override.set(Some(ForcedRoute::Direct));
+        for host in ["a.example.com", "elsewhere.org"] {
+            let (profile_name, rule) = select(host);
+            assert_eq!(profile_name, FORCED_DIRECT);
+            assert!(rule.is_none());
+            assert!(matches!(
+                profile_named(&config, &profile_name),
+                Some(crate::config::Profile::Direct { .. })
+            ));
+        }
+
+        state.route_override.set(Some(ForcedRoute::Emergency));
+        assert_eq!(select("a.example.com").0, "backup");
+        assert_eq!(select("elsewhere.org").0, "backup");
+
+        state.route_override.set(None);
+        assert_eq!(select("a.example.com").0, "corp");
+        assert_eq!(select("elsewhere.org").0, "tor");
+
+        let mut undefined = Config::from_parts(
+            Switch::new("direct"),
+            HashMap::from([("direct".to_string(), Profile::direct())]),
+        );
+        undefined.emergency_profile = Some("missing".to_string());
+        let err = undefined.validate().unwrap_err().to_string();
+        assert!(err.contains("emergencyProfile"), "{err}");
+    }
+
+    #[test]
+    fn test_weighted_default_prefers_healthy_profiles() {
+        let state = test_state_with(
+            r#"{
+                switch: {
+                    default: [
+                        { profile: "heavy", weight: 1000 },
+                        { profile: "light" },
+                        { profile: "idle", weight: 1 },
+                    ],
+                    rules: [],
+                },
+                profiles: {},
+                breaker: { failures: 1, cooldown_secs: 60 },
+            }"#,
+        );
+        let config = state.config.try_read().unwrap();
+        let select = || {
+            select_profile(
+                &config,
+                &state.listener,
+                &state.breaker,
+                None,
+                "a.test",
+                None,
+            )
+            .0
+        };
+
+        assert!(["heavy", "light", "idle"].contains(&select().as_str()));
+        state.record_connect("heavy", false);
+        state.record_connect("idle", false);
+        for _ in 0..20 {
+            assert_eq!(select(), "light");
+        }
+
+        // With nothing healthy left, the first entry is still tried
+        state.record_connect("light", false);
+        assert_eq!(select(), "heavy");
+
+        let entries = [
+            WeightedProfile {
+                profile: "a".to_string(),
+                weight: 3,
+            },
+            WeightedProfile {
+                profile: "b".to_string(),
+                weight: 1,
+            },
+            WeightedProfile {
+                profile: "c".to_string(),
+                weight: 2,
+            },
+        ];
+        let order = weighted_order(&entries, |profile| profile != "a");
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[2], "a");
+    }
+
+    #[tokio::test]
+    async fn test_weighted_default_fails_over_within_the_connection() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = origin.accept().await {
+                open.push(stream);
+            }
+        });
+        let dead_port = unused_port().await;
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{
+                    default: [
+                        {{ profile: "dead", weight: 1000 }},
+                        {{ profile: "direct", weight: 1 }},
+                    ],
+                    rules: [],
+                }},
+                profiles: {{
+                    dead: {{ scheme: "http", host: "127.0.0.1", port: {dead_port} }},
+                    direct: {{ scheme: "direct" }},
+                }},
+            }}"#
+        ));
+
+        // Whichever leads, the tunnel is established through the one that works
+        for _ in 0..5 {
+            let (mut user, client) = socket_pair().await;
+            let proxy = tokio::spawn(handle_client(
+                Box::new(client),
+                state.clone(),
+                CancellationToken::new(),
+            ));
+            let request = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+            user.write_all(request.as_bytes()).await.unwrap();
+            let mut established = [0u8; 12];
+            user.read_exact(&mut established).await.unwrap();
+            assert_eq!(&established, b"HTTP/1.1 200");
+            drop(user);
+            let _ = proxy.await;
+        }
+        assert!(!state.breaker.is_healthy("dead"));
+        assert!(state.breaker.is_healthy("direct"));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failure_keeps_connection_open_for_a_retry() {
+        let dead_port = unused_port().await;
+        let (origin_port, origin) =
+            spawn_origin("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let state = test_state_with(&format!(
+            r#"{{
+                keepAliveOnUpstreamFailure: true,
+                switch: {{
+                    default: "direct",
+                    match_strategy: "first-healthy",
+                    rules: [
+                        {{ pattern: "127.0.0.1", profile: "down" }},
+                        {{ pattern: "127.0.0.1", profile: "direct" }},
+                    ],
+                }},
+                profiles: {{
+                    down: {{ scheme: "http", host: "127.0.0.1", port: {dead_port} }},
+                    direct: {{ scheme: "direct" }},
+                }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        let request = format!(
+            "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\nHost: 127.0.0.1:{origin_port}\r\n"
+        );
+
+        user.get_mut()
+            .write_all(format!("{request}\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let (end, _) = http::relay_response(&mut user, &mut response, false)
+            .await
+            .unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.contains("\r\nConnection: keep-alive\r\n"),
+            "{response}"
+        );
+        assert_eq!(end, http::ResponseEnd::KeepAlive);
+
+        // The retry skips the profile that just failed
+        user.get_mut()
+            .write_all(format!("{request}Connection: close\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        user.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+        origin.await.unwrap();
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_first_healthy_skips_down_proxy() {
+        let dead_port = unused_port().await;
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{
+                    default: "direct",
+                    match_strategy: "first-healthy",
+                    rules: [
+                        {{ pattern: "*.example.com", profile: "down" }},
+                        {{ pattern: "*.example.com", profile: "up" }},
+                    ],
+                }},
+                profiles: {{
+                    down: {{ scheme: "http", host: "127.0.0.1", port: {dead_port} }},
+                    up: {{ scheme: "http", host: "127.0.0.1", port: 3128 }},
+                }},
+            }}"#
+        ));
+        let select = |state: &ProxyState| {
+            let config = state.config.try_read().unwrap();
+            select_profile(
+                &config,
+                &state.listener,
+                &state.breaker,
+                None,
+                "a.example.com",
+                None,
+            )
+            .0
+        };
+        // Nothing known yet, so the first rule wins
+        assert_eq!(select(&state), "down");
+
+        let (_user, client) = socket_pair().await;
+        let down = state.config.read().await.profiles["down"].clone();
+        handle_proxy_connection(
+            Box::new(client),
+            "a.example.com",
+            443,
+            "down",
+            &down,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(select(&state), "up");
+
+        // The default strategy keeps using the first rule
+        state.config.try_write().unwrap().switch.match_strategy = MatchStrategy::First;
+        assert_eq!(select(&state), "down");
+    }
+
+    #[tokio::test]
+    async fn test_socks_resolve_through_upstream() {
+        // Tor-like upstream answering RESOLVE for any name with 10.1.2.3
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut header = [0u8; 5];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[1], socks::RESOLVE_COMMAND);
+            let mut name = vec![0u8; header[4] as usize + 2];
+            stream.read_exact(&mut name).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 10, 1, 2, 3, 0, 0])
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&name[..header[4] as usize]).to_string()
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "tor", rules: [] }},
+                profiles: {{ tor: {{ scheme: "socks5", host: "127.0.0.1", port: {upstream_port} }} }},
+            }}"#
+        ));
+
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        user.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        user.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+
+        let name = b"example.onion";
+        let mut request = vec![5, socks::RESOLVE_COMMAND, 0, 3, name.len() as u8];
+        request.extend_from_slice(name);
+        request.extend_from_slice(&[0, 0]);
+        user.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        user.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 0, 1, 10, 1, 2, 3, 0, 0]);
+        assert_eq!(upstream_task.await.unwrap(), "example.onion");
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks_command_outside_profile_capabilities_is_refused() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "tor", rules: [] }},
+                profiles: {{
+                    tor: {{ scheme: "socks5", host: "127.0.0.1", port: {upstream_port}, commands: ["connect"] }},
+                }},
+            }}"#
+        ));
+
+        for command in [socks::UDP_ASSOCIATE_COMMAND, socks::RESOLVE_COMMAND] {
+            let (mut user, client) = socket_pair().await;
+            let proxy = tokio::spawn(handle_client(
+                Box::new(client),
+                state.clone(),
+                CancellationToken::new(),
+            ));
+            user.write_all(&[5, 1, 0]).await.unwrap();
+            let mut method = [0u8; 2];
+            user.read_exact(&mut method).await.unwrap();
+            user.write_all(&[5, command, 0, 1, 127, 0, 0, 1, 0, 53])
+                .await
+                .unwrap();
+
+            let mut reply = [0u8; 10];
+            user.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[1], socks::COMMAND_NOT_SUPPORTED_REPLY);
+            proxy.await.unwrap().unwrap();
+        }
+        // Refused up front, without asking the upstream
+        let contacted =
+            tokio::time::timeout(std::time::Duration::from_millis(50), upstream.accept()).await;
+        assert!(contacted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_local_dns_sends_ip() {
+        for local_dns in [true, false] {
+            // Upstream that records the CONNECT request and then refuses it
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream_port = upstream.local_addr().unwrap().port();
+            let upstream_task = tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                stream.read_exact(&mut greeting).await.unwrap();
+                stream.write_all(&[5, 0]).await.unwrap();
+                let mut request = vec![0u8; 64];
+                let n = stream.read(&mut request).await.unwrap();
+                request.truncate(n);
+                stream
+                    .write_all(&[5, socks::HOST_UNREACHABLE_REPLY, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                request
+            });
+            let state = test_state_with(&format!(
+                r#"{{
+                    switch: {{ default: "proxy", rules: [] }},
+                    profiles: {{ proxy: {{ scheme: "socks5", host: "127.0.0.1", port: {upstream_port}, local_dns: {local_dns} }} }},
+                }}"#
+            ));
+            let profile = state.config.read().await.profiles["proxy"].clone();
+
+            assert!(
+                connect_upstream(&state, &profile, "localhost", 8080)
+                    .await
+                    .is_err()
+            );
+            let request = upstream_task.await.unwrap();
+            assert_eq!(&request[..3], &[5, socks::CONNECT_COMMAND, 0]);
+            assert_eq!(&request[request.len() - 2..], &8080u16.to_be_bytes());
+            let address = &request[4..request.len() - 2];
+            if local_dns {
+                let ip: std::net::IpAddr = match request[3] {
+                    socks::IPV4_TYPE => <[u8; 4]>::try_from(address).unwrap().into(),
+                    socks::IPV6_TYPE => <[u8; 16]>::try_from(address).unwrap().into(),
+                    other => panic!("expected an IP address type, got {other}"),
+                };
+                assert!(ip.is_loopback());
+            } else {
+                assert_eq!(request[3], socks::DOMAIN_TYPE);
+                assert_eq!(address, [&[9][..], b"localhost"].concat());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fastest_uses_first_tunnel_and_drops_others() {
+        // Fast upstream completes the handshake and then greets the tunnel
+        let fast = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fast_port = fast.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = fast.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 64];
+            let _ = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            stream.write_all(b"fast").await.unwrap();
+            let _ = stream.read(&mut request).await;
+        });
+        // Slow upstream never answers the greeting; it reports when its client goes away
+        let slow = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let slow_port = slow.local_addr().unwrap().port();
+        let slow_task = tokio::spawn(async move {
+            let (mut stream, _) = slow.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            while stream.read(&mut buf).await.unwrap_or(0) > 0 {}
+        });
+
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "race", rules: [] }},
+                profiles: {{
+                    slow: {{ scheme: "socks5", host: "127.0.0.1", port: {slow_port} }},
+                    fast: {{ scheme: "socks5", host: "127.0.0.1", port: {fast_port} }},
+                    race: {{ scheme: "fastest", candidates: ["slow", "fast"] }},
+                }},
+            }}"#
+        ));
+        let profile = state.config.read().await.profiles["race"].clone();
+
+        let mut tunnel = connect_upstream(&state, &profile, "example.com", 80)
+            .await
+            .unwrap();
+        let mut hello = [0u8; 4];
+        tunnel.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"fast");
+
+        // The losing attempt must not keep its connection open
+        tokio::time::timeout(std::time::Duration::from_secs(2), slow_task)
+            .await
+            .expect("slow upstream connection was not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hedged_tries_secondary_once_primary_is_over_budget() {
+        // SOCKS5 upstream taking `delay` over each handshake, then greeting the tunnel with
+        // `hello`; returns its port and how many connections it accepted
+        async fn upstream(
+            delay: std::time::Duration,
+            hello: &'static [u8],
+        ) -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let counter = accepted.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let mut greeting = [0u8; 3];
+                        stream.read_exact(&mut greeting).await?;
+                        tokio::time::sleep(delay).await;
+                        stream.write_all(&[5, 0]).await?;
+                        let mut request = [0u8; 64];
+                        let _ = stream.read(&mut request).await?;
+                        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
+                        stream.write_all(hello).await?;
+                        stream.read(&mut request).await
+                    });
+                }
+            });
+            (port, accepted)
+        }
+        let (slow_port, _) = upstream(std::time::Duration::from_millis(800), b"slow").await;
+        let (fast_port, fast_accepted) = upstream(std::time::Duration::ZERO, b"fast").await;
+
+        for (max_hedges, expected, hedged) in [(16, b"fast", true), (0, b"slow", false)] {
+            let state = test_state_with(&format!(
+                r#"{{
+                    switch: {{ default: "quick", rules: [] }},
+                    profiles: {{
+                        slow: {{ scheme: "socks5", host: "127.0.0.1", port: {slow_port} }},
+                        fast: {{ scheme: "socks5", host: "127.0.0.1", port: {fast_port} }},
+                        quick: {{
+                            scheme: "hedged",
+                            primary: "slow",
+                            secondary: "fast",
+                            budget_ms: 100,
+                            max_hedges: {max_hedges},
+                        }},
+                    }},
+                }}"#
+            ));
+            let profile = state.config.read().await.profiles["quick"].clone();
+
+            let started = std::time::Instant::now();
+            let mut tunnel = connect_upstream(&state, &profile, "example.com", 80)
+                .await
+                .unwrap();
+            let mut hello = [0u8; 4];
+            tunnel.read_exact(&mut hello).await.unwrap();
+            assert_eq!(&hello, expected);
+            let elapsed = started.elapsed();
+            assert_eq!(
+                elapsed < std::time::Duration::from_millis(800),
+                hedged,
+                "{elapsed:?}"
+            );
+        }
+        // Only the hedged run reached the secondary
+        assert_eq!(fast_accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chain_tunnels_http_connect_into_socks5() {
+        // Second hop: SOCKS5 proxy that accepts a CONNECT and then greets the tunnel
+        let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_port = socks.local_addr().unwrap().port();
+        let socks_task = tokio::spawn(async move {
+            let (mut stream, _) = socks.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = vec![0u8; 64];
+            let n = stream.read(&mut request).await.unwrap();
+            request.truncate(n);
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            stream.write_all(b"chained").await.unwrap();
+            let _ = stream.read(&mut [0u8; 1]).await;
+            request
+        });
+        // First hop: HTTP proxy that really relays its CONNECT tunnels
+        let http_proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_port = http_proxy.local_addr().unwrap().port();
+        let http_task = tokio::spawn(async move {
+            let (mut stream, _) = http_proxy.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let authority = head.split_whitespace().nth(1).unwrap().to_string();
+            let mut upstream = tokio::net::TcpStream::connect(&authority).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            head
+        });
+
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "chain", rules: [] }},
+                profiles: {{
+                    corporate: {{ scheme: "http", host: "127.0.0.1", port: {http_port} }},
+                    external: {{ scheme: "socks5", host: "127.0.0.1", port: {socks_port} }},
+                    chain: {{ scheme: "chain", hops: ["corporate", "external"] }},
+                }},
+            }}"#
+        ));
+        let profile = state.config.read().await.profiles["chain"].clone();
+
+        let mut tunnel = connect_upstream(&state, &profile, "example.com", 80)
+            .await
+            .unwrap();
+        let mut hello = [0u8; 7];
+        tunnel.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"chained");
+        drop(tunnel);
+
+        let request = socks_task.await.unwrap();
+        assert_eq!(
+            request,
+            [
+                &[5, socks::CONNECT_COMMAND, 0, socks::DOMAIN_TYPE, 11][..],
+                b"example.com",
+                &[0, 80]
+            ]
+            .concat()
+        );
+        let head = http_task.await.unwrap();
+        assert!(
+            head.starts_with(&format!("CONNECT 127.0.0.1:{socks_port} HTTP/1.1\r\n")),
+            "{head}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_bytes_are_metered_per_profile() {
+        // Origin that takes 300 bytes and answers with 700 before closing
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut request = [0u8; 300];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&[b'd'; 700]).await.unwrap();
+        });
+        let state = test_state_with(
+            r#"{ switch: { default: "direct", rules: [] }, profiles: { direct: { scheme: "direct" } } }"#,
+        );
+        let metrics = state.metrics.clone();
+
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        user.write_all(format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut established = [0u8; 39];
+        user.read_exact(&mut established).await.unwrap();
+        assert!(established.starts_with(b"HTTP/1.1 200"));
+        user.write_all(&[b'u'; 300]).await.unwrap();
+        let mut response = [0u8; 700];
+        user.read_exact(&mut response).await.unwrap();
+        drop(user);
+        proxy.await.unwrap().unwrap();
+
+        assert_eq!(metrics.bytes("direct"), (300, 700));
+        assert!(metrics.render().contains(
+            "proxy_twister_profile_bytes_total{profile=\"direct\",direction=\"down\"} 700"
+        ));
+    }
+
+    /// Spawn an HTTP proxy answering every request on its first connection with `body`,
+    /// yielding the request lines it saw, each followed by the request body if there was one
+    async fn spawn_keep_alive_proxy(
+        body: &'static str,
+    ) -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut request_lines = Vec::new();
+            while let Ok(request) = http::read_request(&mut stream).await {
+                let mut line = format!("{} {}", request.method, request.target);
+                if !request.body.is_empty() {
+                    line.push_str(&format!(" {}", String::from_utf8_lossy(&request.body)));
+                }
+                request_lines.push(line);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            request_lines
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_requests_are_routed_independently() {
+        let (first_port, first_proxy) = spawn_keep_alive_proxy("first").await;
+        let (second_port, second_proxy) = spawn_keep_alive_proxy("second").await;
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "first", rules: [{{ pattern: "b.example", profile: "second" }}] }},
+                profiles: {{
+                    first: {{ scheme: "http", host: "127.0.0.1", port: {first_port} }},
+                    second: {{ scheme: "http", host: "127.0.0.1", port: {second_port} }},
+                }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        for (host, expected) in [
+            ("a.example", "first"),
+            ("b.example", "second"),
+            ("a.example", "first"),
+        ] {
+            let request = format!("GET http://{host}/ HTTP/1.1\r\nHost: {host}\r\n\r\n");
+            user.get_mut().write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            let (end, _) = http::relay_response(&mut user, &mut response, false)
+                .await
+                .unwrap();
+            assert_eq!(end, http::ResponseEnd::KeepAlive);
+            assert!(response.ends_with(expected.as_bytes()), "{host}");
+        }
+        drop(user);
+        proxy.await.unwrap().unwrap();
+
+        assert_eq!(
+            first_proxy.await.unwrap(),
+            ["GET http://a.example/", "GET http://a.example/"]
+        );
+        assert_eq!(second_proxy.await.unwrap(), ["GET http://b.example/"]);
+    }
+
+    #[tokio::test]
+    async fn test_least_connections_counts_pooled_upstreams_until_closed() {
+        // Origin keeping the connection after the first response and closing it after the second
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = origin.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            for connection in ["keep-alive", "close"] {
+                http::read_request(&mut stream).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: {connection}\r\n\r\n"
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let state = test_state_with(
+            r#"{
+                switch: { default: "race", rules: [] },
+                profiles: {
+                    race: { scheme: "fastest", candidates: ["cdn"] },
+                    cdn: { scheme: "direct", balance: { strategy: "least_connections" } },
+                },
+            }"#,
+        );
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state.clone(),
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        let mut open_tunnels = Vec::new();
+        for _ in 0..2 {
+            let request =
+                format!("GET http://{origin_addr}/ HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n");
+            user.get_mut().write_all(request.as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            http::relay_response(&mut user, &mut response, false)
+                .await
+                .unwrap();
+            open_tunnels.push(state.round_robin.open_tunnels(origin_addr));
+        }
+        // The pooled connection counted while kept, and no longer once the origin closed it
+        assert_eq!(open_tunnels, [1, 0]);
+        drop(user);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body_is_forwarded_on_keep_alive() {
+        let (proxy_port, upstream) = spawn_keep_alive_proxy("ok").await;
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "proxy", rules: [] }},
+                profiles: {{ proxy: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        user.get_mut()
+            .write_all(
+                b"POST http://a.example/upload HTTP/1.1\r\nHost: a.example\r\n\
+                  Transfer-Encoding: chunked\r\n\r\n\
+                  5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n\
+                  GET http://a.example/next HTTP/1.1\r\nHost: a.example\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let mut response = Vec::new();
+            let (end, _) = http::relay_response(&mut user, &mut response, false)
+                .await
+                .unwrap();
+            assert_eq!(end, http::ResponseEnd::KeepAlive);
+            assert!(response.ends_with(b"ok"));
+        }
+        drop(user);
+        proxy.await.unwrap().unwrap();
+
+        assert_eq!(
+            upstream.await.unwrap(),
+            [
+                "POST http://a.example/upload hello, world",
+                "GET http://a.example/next"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_request_framing_is_refused() {
+        let state = test_state_with(
+            r#"{
+                switch: { default: "proxy", rules: [] },
+                profiles: { proxy: { scheme: "http", host: "127.0.0.1", port: 9 } },
+            }"#,
+        );
+        for framing in [
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+        ] {
+            let (mut user, client) = socket_pair().await;
+            let request = format!(
+                "POST http://a.example/ HTTP/1.1\r\nHost: a.example\r\n{framing}\r\n\
+                 5\r\nhello\r\n0\r\n\r\n"
+            );
+            user.write_all(request.as_bytes()).await.unwrap();
+            let result =
+                handle_client(Box::new(client), state.clone(), CancellationToken::new()).await;
+            assert_eq!(
+                result.unwrap_err().kind(),
+                tokio::io::ErrorKind::InvalidData
+            );
+            let mut response = String::new();
+            user.read_to_string(&mut response).await.unwrap();
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request"),
+                "{response}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_direct_requests_are_answered_in_order() {
+        // Origin answering each request with its path, chunked to check the re-framing too
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = origin.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    while let Ok(request) = http::read_request(&mut stream).await {
+                        let path = request.target;
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                             {:x}\r\n{path}\r\n0\r\n\r\n",
+                            path.len()
+                        );
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let state = test_state_with(
+            r#"{
+                switch: { default: "direct", rules: [] },
+                profiles: { direct: { scheme: "direct" } },
+            }"#,
+        );
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        // All three are sent before any response is read
+        let pipelined: String = ["/first", "/second", "/third"]
+            .iter()
+            .map(|path| {
+                format!(
+                    "GET http://127.0.0.1:{origin_port}{path} HTTP/1.1\r\n\
+                     Host: 127.0.0.1:{origin_port}\r\n\r\n"
+                )
+            })
+            .collect();
+        user.get_mut()
+            .write_all(pipelined.as_bytes())
+            .await
+            .unwrap();
+
+        for path in ["/first", "/second", "/third"] {
+            let mut response = Vec::new();
+            let (end, _) = http::relay_response(&mut user, &mut response, false)
+                .await
+                .unwrap();
+            assert_eq!(end, http::ResponseEnd::KeepAlive);
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(
+                response.contains(&format!("\r\nContent-Length: {}\r\n", path.len())),
+                "{response}"
+            );
+            assert!(response.ends_with(&format!("\r\n\r\n{path}")), "{response}");
+        }
+        drop(user);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_is_relayed_before_the_body() {
+        // Upstream proxy that only takes the body after telling the client to go ahead
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let request = http::read_request_head(&mut stream).await.unwrap();
+            assert!(request.body_pending());
+            stream
+                .write_all(http::HTTP_CONTINUE.as_bytes())
+                .await
+                .unwrap();
+            let body = http::read_body(&mut stream, request.content_length())
+                .await
+                .unwrap();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "corp", rules: [] }},
+                profiles: {{ corp: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        user.get_mut()
+            .write_all(
+                b"POST http://example.com/upload HTTP/1.1\r\nHost: example.com\r\n\
+                  Expect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut interim = vec![0u8; http::HTTP_CONTINUE.len()];
+        user.read_exact(&mut interim).await.unwrap();
+        assert_eq!(interim, http::HTTP_CONTINUE.as_bytes());
+
+        user.get_mut().write_all(b"hello").await.unwrap();
+        let mut response = Vec::new();
+        http::relay_response(&mut user, &mut response, false)
+            .await
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nhello"));
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_body_reaches_upstream_that_never_answers() {
+        // HTTP/1.0-style upstream proxy waiting for the body without sending 100 Continue
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let request = http::read_request_head(&mut stream).await.unwrap();
+            let body = http::read_body(&mut stream, request.content_length())
+                .await
+                .unwrap();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "corp", rules: [] }},
+                profiles: {{ corp: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        user.get_mut()
+            .write_all(
+                b"POST http://example.com/upload HTTP/1.1\r\nHost: example.com\r\n\
+                  Expect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        // The go-ahead comes from us once the upstream stayed silent for a while
+        let mut interim = vec![0u8; http::HTTP_CONTINUE.len()];
+        user.read_exact(&mut interim).await.unwrap();
+        assert_eq!(interim, http::HTTP_CONTINUE.as_bytes());
+
+        user.get_mut().write_all(b"hello").await.unwrap();
+        let mut response = Vec::new();
+        http::relay_response(&mut user, &mut response, false)
+            .await
+            .unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nhello"));
+        proxy.await.unwrap().unwrap();
+    }
+
+    /// Collects the `conn_id` of every `connection` span created while it is installed
+    #[derive(Clone, Default)]
+    struct ConnectionIds(Arc<Mutex<Vec<u64>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for ConnectionIds {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Visitor(Option<u64>);
+            impl tracing::field::Visit for Visitor {
+                fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+                    if field.name() == "conn_id" {
+                        self.0 = Some(value);
+                    }
+                }
+                fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+            }
+            if attrs.metadata().name() == "connection" {
+                let mut visitor = Visitor(None);
+                attrs.record(&mut visitor);
+                self.0.lock().unwrap().extend(visitor.0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_is_stable_within_a_connection() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let ids = ConnectionIds::default();
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(ids.clone()));
+
+        // Origin answering every request with an empty keep-alive response
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = origin.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    while http::read_request(&mut stream).await.is_ok() {
+                        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                        if stream.write_all(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let shutdown_token = CancellationToken::new();
+        tokio::spawn(run_listener(
+            listener,
+            "test".to_string(),
+            None,
+            test_state_with(
+                r#"{
+                    switch: { default: "direct", rules: [] },
+                    profiles: { direct: { scheme: "direct" } },
+                }"#,
+            ),
+            Arc::new(Mutex::new(CancellationToken::new())),
+            shutdown_token.clone(),
+        ));
+
+        for _ in 0..2 {
+            let mut user = tokio::io::BufReader::new(
+                tokio::net::TcpStream::connect(proxy_addr).await.unwrap(),
+            );
+            for _ in 0..2 {
+                let request = format!(
+                    "GET http://127.0.0.1:{origin_port}/ HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n"
+                );
+                user.get_mut().write_all(request.as_bytes()).await.unwrap();
+                let (end, _) = http::relay_response(&mut user, &mut Vec::<u8>::new(), false)
+                    .await
+                    .unwrap();
+                assert_eq!(end, http::ResponseEnd::KeepAlive);
+            }
+        }
+        shutdown_token.cancel();
+
+        let ids = ids.0.lock().unwrap().clone();
+        assert_eq!(ids.len(), 4, "{ids:?}");
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(ids[2], ids[3]);
+        assert!(ids[2] > ids[0], "{ids:?}");
+    }
+
+    #[tokio::test]
+    async fn test_limiter_counts_clients_announced_by_proxy_protocol() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = origin.accept().await {
+                open.push(stream);
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let mut state = test_state_with(
+            r#"{
+                switch: { default: "direct", rules: [] },
+                profiles: { direct: { scheme: "direct" } },
+                listeners: { "lb": { accept_proxy_protocol: true } },
+                connectionLimits: { max_active: 1, max_queued: 2 },
+            }"#,
+        );
+        state.listener = "lb".to_string();
+        let metrics = state.metrics.clone();
+        let shutdown_token = CancellationToken::new();
+        tokio::spawn(run_listener(
+            listener,
+            "lb".to_string(),
+            None,
+            state,
+            Arc::new(Mutex::new(CancellationToken::new())),
+            shutdown_token.clone(),
+        ));
+        // Every connection comes from the balancer, announcing one of two clients
+        let connect = |client: &str| {
+            let announced = ClientAddrs {
+                source: format!("{client}:40000").parse().unwrap(),
+                destination: "203.0.113.1:3128".parse().unwrap(),
+            };
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+                let mut data =
+                    proxy_protocol::header(crate::config::ProxyProtocol::V2, Some(&announced));
+                data.extend_from_slice(
+                    format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n").as_bytes(),
+                );
+                stream.write_all(&data).await.unwrap();
+                stream
+            }
+        };
+        let queued = |count: u64| {
+            let metrics = metrics.clone();
+            async move {
+                while metrics.connection_queue().0 < count {
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                }
+            }
+        };
+
+        // One client takes the only slot and fills the queue
+        let mut busy = connect("198.51.100.7").await;
+        let mut established = [0u8; 12];
+        busy.read_exact(&mut established).await.unwrap();
+        assert_eq!(&established, b"HTTP/1.1 200");
+        let _waiting = connect("198.51.100.7").await;
+        queued(1).await;
+        let mut newest = connect("198.51.100.7").await;
+        queued(2).await;
+
+        // Another client behind the same balancer still gets a place, at the first one's expense
+        let _other = connect("198.51.100.8").await;
+        // Closed with its request unread, which may come across as a reset
+        let closed = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            newest.read(&mut established),
+        )
+        .await
+        .expect("the busiest client's newest connection was not dropped");
+        assert!(matches!(closed, Ok(0) | Err(_)), "{closed:?}");
+        assert_eq!(metrics.connection_queue().0, 2);
+        shutdown_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_only_on_own_requests() {
+        // HTTP proxy recording the method and User-Agent of each request, one per connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = tokio::io::BufReader::new(stream);
+                let request = http::read_request(&mut stream).await.unwrap();
+                let response: &[u8] = if request.method == "CONNECT" {
+                    b"HTTP/1.1 200 Connection Established\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"
+                };
+                stream.write_all(response).await.unwrap();
+                seen.push((request.method, request.headers.get("user-agent").cloned()));
+            }
+            seen
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "proxy", rules: [] }},
+                profiles: {{ proxy: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+            }}"#
+        ));
+        let profile = state.config.read().await.profiles["proxy"].clone();
+
+        connect_via(&state, &profile, "example.com", 443)
+            .await
+            .unwrap();
+
+        let (mut user, client) = socket_pair().await;
+        user.write_all(
+            b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/8.0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        handle_client(Box::new(client), state, CancellationToken::new())
+            .await
+            .unwrap();
+
+        let own = concat!("proxy-twister/", env!("CARGO_PKG_VERSION")).to_string();
+        assert_eq!(
+            upstream.await.unwrap(),
+            [
+                ("CONNECT".to_string(), Some(own)),
+                ("GET".to_string(), Some("curl/8.0".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_header_is_sent_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut preface = String::new();
+            stream.read_line(&mut preface).await.unwrap();
+            let request = http::read_request(&mut stream).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await
+                .unwrap();
+            (preface, request.method)
+        });
+        let mut state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "proxy", rules: [] }},
+                profiles: {{
+                    proxy: {{
+                        scheme: "http",
+                        host: "127.0.0.1",
+                        port: {proxy_port},
+                        send_proxy_protocol: "v1",
+                    }},
+                }},
+            }}"#
+        ));
+        state.client_addrs = Some(ClientAddrs {
+            source: "192.0.2.10:51000".parse().unwrap(),
+            destination: "127.0.0.1:8080".parse().unwrap(),
+        });
+        let profile = state.config.read().await.profiles["proxy"].clone();
+
+        connect_via(&state, &profile, "example.com", 443)
+            .await
+            .unwrap();
+        let (preface, method) = upstream.await.unwrap();
+        assert_eq!(preface, "PROXY TCP4 192.0.2.10 127.0.0.1 51000 8080\r\n");
+        assert_eq!(method, "CONNECT");
+    }
+
+    #[tokio::test]
+    async fn test_accepted_proxy_protocol_header_sets_client() {
+        // Upstream recording the PROXY v1 header it receives, which names the announced client
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut preface = String::new();
+            stream.read_line(&mut preface).await.unwrap();
+            let request = http::read_request(&mut stream).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await
+                .unwrap();
+            (preface, request.target)
+        });
+        let mut state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "proxy", rules: [] }},
+                profiles: {{
+                    proxy: {{
+                        scheme: "http",
+                        host: "127.0.0.1",
+                        port: {proxy_port},
+                        send_proxy_protocol: "v1",
+                    }},
+                }},
+                listeners: {{ "lb": {{ accept_proxy_protocol: true }} }},
+            }}"#
+        ));
+        state.listener = "lb".to_string();
+
+        let (mut balancer, client) = socket_pair().await;
+        let proxy = tokio::spawn(accept_client(client, state, CancellationToken::new(), None));
+        let announced = ClientAddrs {
+            source: "198.51.100.7:40000".parse().unwrap(),
+            destination: "203.0.113.1:3128".parse().unwrap(),
+        };
+        let mut data = proxy_protocol::header(crate::config::ProxyProtocol::V2, Some(&announced));
+        data.extend_from_slice(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n");
+        balancer.write_all(&data).await.unwrap();
+
+        let mut established = [0u8; 12];
+        balancer.read_exact(&mut established).await.unwrap();
+        assert_eq!(&established, b"HTTP/1.1 200");
+        let (preface, target) = upstream.await.unwrap();
+        assert_eq!(
+            preface,
+            "PROXY TCP4 198.51.100.7 203.0.113.1 40000 3128\r\n"
+        );
+        assert_eq!(target, "example.com:443");
+        drop(balancer);
+        let _ = proxy.await;
+    }
+
+    #[tokio::test]
+    async fn test_next_endpoint_after_connect_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let upstream = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let request = http::read_request(&mut stream).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await
+                .unwrap();
+            request.target
+        });
+        let dead_port = unused_port().await;
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "proxy", rules: [] }},
+                profiles: {{
+                    proxy: {{
+                        scheme: "http",
+                        endpoints: ["127.0.0.1:{dead_port}", "127.0.0.1:{proxy_port}"],
+                    }},
+                }},
+            }}"#
+        ));
+        let profile = state.config.read().await.profiles["proxy"].clone();
+
+        connect_via(&state, &profile, "example.com", 443)
+            .await
+            .unwrap();
+        assert_eq!(upstream.await.unwrap(), "example.com:443");
+    }
+
+    #[tokio::test]
+    async fn test_follow_up_request_through_socks_tunnel() {
+        // SOCKS5 upstream accepting one tunnel and answering two requests on it
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        let upstream_task = tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            let mut stream = tokio::io::BufReader::new(stream);
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.get_mut().write_all(&[5, 0]).await.unwrap();
+            let mut header = [0u8; 5];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut rest = vec![0u8; header[4] as usize + 2];
+            stream.read_exact(&mut rest).await.unwrap();
+            stream
+                .get_mut()
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            let mut paths = Vec::new();
+            while let Ok(request) = http::read_request(&mut stream).await {
+                let body = format!("reply to {}", request.target);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
+                paths.push(request.target);
+            }
+            paths
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "tor", rules: [] }},
+                profiles: {{ tor: {{ scheme: "socks5", host: "127.0.0.1", port: {upstream_port} }} }},
+            }}"#
+        ));
+
+        let (user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let mut user = tokio::io::BufReader::new(user);
+        // The follow-up is sent before the first response arrives (pipelined)
+        user.get_mut()
+            .write_all(
+                b"GET /one HTTP/1.1\r\nHost: site.example\r\n\r\n\
+                  GET /two HTTP/1.1\r\nHost: site.example\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        for expected in ["reply to /one", "reply to /two"] {
+            let mut response = Vec::new();
+            let (end, _) = http::relay_response(&mut user, &mut response, false)
+                .await
+                .unwrap();
+            assert_eq!(end, http::ResponseEnd::KeepAlive);
+            assert!(response.ends_with(expected.as_bytes()));
+        }
+        drop(user);
+        proxy.await.unwrap().unwrap();
+        assert_eq!(upstream_task.await.unwrap(), ["/one", "/two"]);
+    }
+
+    #[tokio::test]
+    async fn test_max_connection_lifetime_closes_busy_tunnel() {
+        // Origin that echoes forever
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+        let state = test_state_with(
+            r#"{
+                switch: { default: "direct", rules: [] },
+                profiles: { direct: { scheme: "direct" } },
+                maxConnectionSecs: 1,
+            }"#,
+        );
+
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(accept_client(client, state, CancellationToken::new(), None));
+        user.write_all(format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut established = [0u8; 39];
+        user.read_exact(&mut established).await.unwrap();
+
+        // Keep the tunnel busy; it must still be closed after about a second
+        let keep_busy = async {
+            let mut buf = [0u8; 4];
+            loop {
+                if user.write_all(b"ping").await.is_err() {
+                    break;
+                }
+                match user.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), keep_busy)
+            .await
+            .expect("tunnel outlived max_connection_secs");
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_byte_cap_terminates_oversized_transfers() {
+        // Origin that sends 64 bytes on its first connection and counts what it receives on
+        // the second
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            stream.write_all(&[b'd'; 64]).await.unwrap();
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut upload = Vec::new();
+            let _ = stream.read_to_end(&mut upload).await;
+            upload
+        });
+        let state = test_state_with(
+            r#"{
+                switch: { default: "direct", rules: [] },
+                profiles: { direct: { scheme: "direct" } },
+                maxBytesPerConnection: 10,
+            }"#,
+        );
+        let connect = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+
+        // Download: the client gets the first 10 bytes, then the connection closes
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(accept_client(
+            client,
+            state.clone(),
+            CancellationToken::new(),
+            None,
+        ));
+        user.write_all(connect.as_bytes()).await.unwrap();
+        let mut established = [0u8; 39];
+        user.read_exact(&mut established).await.unwrap();
+        let mut download = Vec::new();
+        let _ = user.read_to_end(&mut download).await;
+        assert_eq!(download, [b'd'; 10]);
+        assert!(proxy.await.unwrap().is_err());
+
+        // Upload: the origin gets the first 10 bytes, then the tunnel closes
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(accept_client(client, state, CancellationToken::new(), None));
+        user.write_all(connect.as_bytes()).await.unwrap();
+        user.read_exact(&mut established).await.unwrap();
+        let _ = user.write_all(&[b'u'; 64]).await;
+        assert!(proxy.await.unwrap().is_err());
+        assert_eq!(received.await.unwrap(), [b'u'; 10]);
+    }
+
+    /// Log sink shared with a test subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rule_tag_in_logs_and_metrics() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{
+                    default: "direct",
+                    rules: [{{ pattern: "*.example.com", profile: "dead", tag: "streaming" }}],
+                }},
+                profiles: {{
+                    direct: {{ scheme: "direct" }},
+                    dead: {{ scheme: "http", host: "127.0.0.1", port: {} }},
+                }},
+            }}"#,
+            unused_port().await
+        ));
+
+        let (mut user, client) = socket_pair().await;
+        user.write_all(b"CONNECT video.example.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        handle_client(Box::new(client), state.clone(), CancellationToken::new())
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Could not connect through proxy"));
+        assert!(logs.contains("tag=\"streaming\""));
+        assert_eq!(state.metrics.tag("streaming"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tls_terminated_listener() {
+        // Origin that echoes whatever it receives
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+
+        // Self-signed certificate for the proxy itself
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("proxy-twister-cert-{}.pem", std::process::id()));
+        let key_path = dir.join(format!("proxy-twister-key-{}.pem", std::process::id()));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        let server_config = crate::protocols::tls::server_config(&cert_path, &key_path).unwrap();
+        std::fs::remove_file(&cert_path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let state = test_state_with(
+            r#"{ switch: { default: "direct", rules: [] }, profiles: { direct: { scheme: "direct" } } }"#,
+        );
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = accept_client(socket, state, CancellationToken::new(), Some(acceptor)).await;
+        });
+
+        // HTTPS-proxy-aware client: TLS to the proxy, then CONNECT inside it
+        let fingerprint: [u8; 32] = {
+            use sha2::{Digest, Sha256};
+            Sha256::digest(cert.der().as_ref()).into()
+        };
+        let client_config = crate::protocols::tls::client_config(&crate::config::TlsOptions {
+            pinned_fingerprints: vec![fingerprint.iter().map(|b| format!("{b:02x}")).collect()],
+            ..Default::default()
+        })
+        .unwrap();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let tcp = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+        let server_name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let mut tls = connector.connect(server_name, tcp).await.unwrap();
+
+        tls.write_all(
+            format!(
+                "CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\nHost: 127.0.0.1:{origin_port}\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let mut response = [0u8; 39];
+        tls.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+
+        tls.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        tls.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_raw_passthrough_tunnels_unknown_protocols() {
+        // Origin that echoes whatever it receives
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = origin.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let mut state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "direct", rules: [] }},
+                profiles: {{ direct: {{ scheme: "direct" }} }},
+                listeners: {{ "raw": {{ raw_passthrough: "127.0.0.1:{origin_port}" }} }},
+            }}"#
+        ));
+        state.listener = "raw".to_string();
+
+        // Looks like neither SOCKS5 nor HTTP: a TLS ClientHello, or an SSH banner whose first
+        // byte could start an HTTP method
+        for hello in [&b"\x16\x03\x01 raw bytes"[..], b"SSH-2.0-OpenSSH_9.6\r\n"] {
+            let (mut user, client) = socket_pair().await;
+            let proxy = tokio::spawn(handle_client(
+                Box::new(client),
+                state.clone(),
+                CancellationToken::new(),
+            ));
+            user.write_all(hello).await.unwrap();
+            let mut echoed = vec![0u8; hello.len()];
+            user.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, hello);
+            drop(user);
+            proxy.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raw_passthrough_through_mirror_uses_primary() {
+        // Origin that echoes whatever it receives
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+        let shadow_port = unused_port().await;
+        let mut state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "mirrored", rules: [] }},
+                profiles: {{
+                    direct: {{ scheme: "direct" }},
+                    shadow: {{ scheme: "http", host: "127.0.0.1", port: {shadow_port} }},
+                    mirrored: {{ scheme: "mirror", primary: "direct", secondary: "shadow" }},
+                }},
+                listeners: {{ "raw": {{ raw_passthrough: "127.0.0.1:{origin_port}" }} }},
+            }}"#
+        ));
+        state.listener = "raw".to_string();
+
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        user.write_all(b"\x16\x03\x01 raw bytes").await.unwrap();
+        let mut echoed = [0u8; 13];
+        user.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"\x16\x03\x01 raw bytes");
+        drop(user);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_protocol_restricted_listeners_close_other_clients() {
+        let mut state = test_state_with(
+            r#"{
+                switch: { default: "direct", rules: [] },
+                profiles: { direct: { scheme: "direct" } },
+                listeners: { "web": { protocol: "http" }, "socks": { protocol: "socks5" } },
+                methodPolicy: { denied_methods: ["TRACE"] },
+            }"#,
+        );
+        for (listener, hello) in [
+            ("web", &b"\x05\x01\x00"[..]),
+            ("socks", b"GET http://example.com/ HTTP/1.1\r\n\r\n"),
+        ] {
+            state.listener = listener.to_string();
+            let (mut user, client) = socket_pair().await;
+            user.write_all(hello).await.unwrap();
+            handle_client(Box::new(client), state.clone(), CancellationToken::new())
+                .await
+                .unwrap();
+            let mut reply = Vec::new();
+            user.read_to_end(&mut reply).await.unwrap();
+            assert!(reply.is_empty(), "{listener}: {reply:?}");
+        }
+
+        // The listener's own protocol still gets through, here to the method policy
+        state.listener = "web".to_string();
+        let (mut user, client) = socket_pair().await;
+        user.write_all(b"TRACE http://example.com/ HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        drop(handle_client(Box::new(client), state, CancellationToken::new()).await);
+        let mut reply = Vec::new();
+        user.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.starts_with(b"HTTP/1.1 405"), "{reply:?}");
+    }
+
+    #[tokio::test]
+    async fn test_rule_connect_timeout_overrides_default() {
+        // HTTP proxy taking 300 ms to answer each CONNECT
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = tokio::io::BufReader::new(stream);
+                    if http::read_request(&mut stream).await.is_ok() {
+                        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                            .await;
+                        let _ = stream.read_u8().await;
+                    }
+                });
+            }
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{
+                    default: "proxy",
+                    rules: [{{ pattern: "slow.example", profile: "proxy", connect_timeout_ms: 2000 }}],
+                }},
+                profiles: {{ proxy: {{ scheme: "http", host: "127.0.0.1", port: {proxy_port} }} }},
+                connectTimeoutMs: 100,
+            }}"#
+        ));
+
+        for (host, expected) in [
+            ("slow.example", "HTTP/1.1 200"),
+            ("other.example", "HTTP/1.1 504"),
+        ] {
+            let (mut user, client) = socket_pair().await;
+            let proxy = tokio::spawn(handle_client(
+                Box::new(client),
+                state.clone(),
+                CancellationToken::new(),
+            ));
+            let request = format!("CONNECT {host}:443 HTTP/1.1\r\nHost: {host}:443\r\n\r\n");
+            user.write_all(request.as_bytes()).await.unwrap();
+            let mut response = [0u8; 12];
+            user.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, expected.as_bytes(), "{host}");
+            drop(user);
+            let _ = proxy.await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_profile_triggers_reload() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        let path = std::env::temp_dir().join(format!(
+            "proxy-twister-missing-profile-{}.json",
+            std::process::id()
+        ));
+        let config = |profiles: &str| {
+            format!(
+                r#"{{
+                    switch: {{ default: "direct", rules: [{{ pattern: "127.0.0.1", profile: "fresh" }}] }},
+                    profiles: {{ direct: {{ scheme: "direct" }}, {profiles} }},
+                    reloadOnMissingProfile: true,
+                }}"#
+            )
+        };
+        // Loading refuses the dangling reference, so start from a config that skipped validation
+        let state = test_state_with(&config(""));
+        state.config.write().await.source_path = Some(path.clone());
+
+        // The file gains the profile, but the watcher has not caught up yet
+        std::fs::write(&path, config(r#"fresh: { scheme: "direct" }"#)).unwrap();
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state.clone(),
+            CancellationToken::new(),
+        ));
+        let request = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+        user.write_all(request.as_bytes()).await.unwrap();
+        let mut response = [0u8; 12];
+        user.read_exact(&mut response).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&response, b"HTTP/1.1 200");
+        origin.accept().await.unwrap();
+        assert!(state.config.read().await.profiles.contains_key("fresh"));
+        drop(user);
+        let _ = proxy.await;
+    }
+
+    #[tokio::test]
+    async fn test_missing_profile_reloads_are_rate_limited() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move { while origin.accept().await.is_ok() {} });
+        let path = std::env::temp_dir().join(format!(
+            "proxy-twister-missing-profile-limit-{}.json",
+            std::process::id()
+        ));
+        let config = |profiles: &str| {
+            format!(
+                r#"{{
+                    switch: {{ default: "direct", rules: [{{ pattern: "127.0.0.1", profile: "fresh" }}] }},
+                    profiles: {{ direct: {{ scheme: "direct" }}, {profiles} }},
+                    reloadOnMissingProfile: true,
+                }}"#
+            )
+        };
+        std::fs::write(&path, config("")).unwrap();
+        let state = test_state_with(&config(""));
+        state.config.write().await.source_path = Some(path.clone());
+        let connect = || async {
+            let (mut user, client) = socket_pair().await;
+            let proxy = tokio::spawn(handle_client(
+                Box::new(client),
+                state.clone(),
+                CancellationToken::new(),
+            ));
+            let request = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+            user.write_all(request.as_bytes()).await.unwrap();
+            let mut response = [0u8; 12];
+            user.read_exact(&mut response).await.unwrap();
+            drop(user);
+            let _ = proxy.await;
+            response
+        };
+
+        // The first miss re-reads the file, which does not define the profile either
+        assert_eq!(&connect().await, b"HTTP/1.1 500");
+        std::fs::write(&path, config(r#"fresh: { scheme: "direct" }"#)).unwrap();
+        // Right after that, the file is not read again
+        assert_eq!(&connect().await, b"HTTP/1.1 500");
+        assert!(!state.config.read().await.profiles.contains_key("fresh"));
+
+        tokio::time::sleep(MISSING_PROFILE_RELOAD_INTERVAL).await;
+        assert_eq!(&connect().await, b"HTTP/1.1 200");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tarpit_trickles_and_closes_after_max_duration() {
+        let state = test_state_with(
+            r#"{
+                switch: { default: "tarpit", rules: [] },
+                profiles: { tarpit: { scheme: "tarpit", delay_ms: 1000, max_duration_ms: 10500 } },
+            }"#,
+        );
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(handle_client(
+            Box::new(client),
+            state,
+            CancellationToken::new(),
+        ));
+        let started = tokio::time::Instant::now();
+        user.write_all(
+            b"CONNECT scanner.example:443 HTTP/1.1\r\nHost: scanner.example:443\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+        let first = user.read_u8().await.unwrap();
+        assert_eq!(first, b'H');
+        assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+
+        // One byte a second until the connection is cut off at 10.5 s
+        let mut rest = Vec::new();
+        user.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"TTP/1.1 2");
+        let elapsed = started.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(10500));
+        assert!(elapsed < std::time::Duration::from_secs(11));
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flow_record_sent_to_collector_on_close() {
+        let collector = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "direct", rules: [] }},
+                profiles: {{ direct: {{ scheme: "direct" }} }},
+                flowExport: {{
+                    collector: "{}",
+                    fields: ["source_port", "bytes_up", "bytes_down", "profile"],
+                    observation_domain_id: 7,
+                }},
+            }}"#,
+            collector.local_addr().unwrap()
+        ));
+
+        let (mut user, client) = socket_pair().await;
+        let client_port = user.local_addr().unwrap().port();
+        let proxy = tokio::spawn(accept_client(client, state, CancellationToken::new(), None));
+        let request = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+        user.write_all(request.as_bytes()).await.unwrap();
+        let mut established = [0u8; 39];
+        user.read_exact(&mut established).await.unwrap();
+        user.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 4];
+        user.read_exact(&mut pong).await.unwrap();
+        drop(user);
+        proxy.await.unwrap().unwrap();
+
+        let mut message = [0u8; 512];
+        let (len, _) = collector.recv_from(&mut message).await.unwrap();
+        let message = &message[..len];
+        let u16_at = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]);
+        let u64_at = |at: usize| u64::from_be_bytes(message[at..at + 8].try_into().unwrap());
+        // IPFIX header, then the template set announcing the four fields
+        assert_eq!(u16_at(0), 10);
+        assert_eq!(usize::from(u16_at(2)), len);
+        assert_eq!(&message[12..16], &7u32.to_be_bytes());
+        assert_eq!((u16_at(16), u16_at(18)), (2, 28));
+        assert_eq!((u16_at(20), u16_at(22)), (256, 4));
+        // Data set of that template with the single record
+        assert_eq!((u16_at(44), u16_at(46)), (256, 29));
+        assert_eq!(u16_at(48), client_port);
+        assert_eq!((u64_at(50), u64_at(58)), (4, 4));
+        assert_eq!(&message[66..], b"\x06direct");
+    }
+
+    #[tokio::test]
+    async fn test_flow_record_carries_egress_and_upstream_address() {
+        let collector = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        });
+        let state = test_state_with(&format!(
+            r#"{{
+                switch: {{ default: "direct", rules: [] }},
+                profiles: {{ direct: {{ scheme: "direct" }} }},
+                flowExport: {{
+                    collector: "{}",
+                    fields: ["egress_address", "upstream_address"],
+                }},
+            }}"#,
+            collector.local_addr().unwrap()
+        ));
+
+        let (mut user, client) = socket_pair().await;
+        let proxy = tokio::spawn(accept_client(client, state, CancellationToken::new(), None));
+        let request = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+        user.write_all(request.as_bytes()).await.unwrap();
+        let mut established = [0u8; 39];
+        user.read_exact(&mut established).await.unwrap();
+        drop(user);
+        proxy.await.unwrap().unwrap();
+
+        let mut message = [0u8; 512];
+        let (len, _) = collector.recv_from(&mut message).await.unwrap();
+        let message = &message[..len];
+        let u16_at = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]);
+        // postNATSourceIPv4Address and postNATDestinationIPv4Address
+        assert_eq!((u16_at(24), u16_at(28)), (225, 226));
+        assert_eq!((u16_at(32), u16_at(34)), (256, 12));
+        assert_eq!(&message[36..40], &[127, 0, 0, 1]);
+        assert_eq!(&message[40..44], &[127, 0, 0, 1]);
     }
 }
@@ -0,0 +1,162 @@
+/// First file descriptor passed by the service manager, right after stdin, stdout and stderr
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Number of sockets passed to process `pid`, from the `LISTEN_PID` and `LISTEN_FDS` variables
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Result<i32, String> {
+    let Some(listen_pid) = listen_pid else {
+        return Err("no sockets were passed by systemd (LISTEN_PID is not set)".to_string());
+    };
+    if listen_pid.trim().parse::<u32>() != Ok(pid) {
+        return Err(format!(
+            "sockets passed by systemd are meant for process {listen_pid}, not {pid}"
+        ));
+    }
+    let count = listen_fds
+        .and_then(|count| count.trim().parse::<i32>().ok())
+        .ok_or("LISTEN_FDS is missing or invalid")?;
+    if count <= 0 {
+        return Err("systemd passed no sockets".to_string());
+    }
+    Ok(count)
+}
+
+/// Listening sockets passed by systemd socket activation (the `sd_listen_fds` protocol)
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub fn listeners() -> Result<Vec<std::net::TcpListener>, String> {
+    let count = passed_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(adopt)
+        .collect()
+}
+
+/// Listening sockets passed by systemd socket activation (the `sd_listen_fds` protocol)
+#[cfg(not(all(target_os = "linux", feature = "systemd")))]
+pub fn listeners() -> Result<Vec<std::net::TcpListener>, String> {
+    Err("socket activation requires Linux and the `systemd` feature".to_string())
+}
+
+/// Take ownership of the inherited listening TCP socket `fd`
+///
+/// The socket is closed on exec, so credential commands do not inherit it.
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+fn adopt(fd: i32) -> Result<std::net::TcpListener, String> {
+    use std::os::fd::FromRawFd;
+
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `listening` and `len` describe a valid, writable c_int buffer
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(format!("fd {fd} passed by systemd is not a socket: {e}"));
+    }
+    if listening == 0 {
+        return Err(format!(
+            "fd {fd} passed by systemd is not listening (use ListenStream= with Accept=no)"
+        ));
+    }
+    // SAFETY: fcntl on a descriptor checked above to be an open socket
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(format!("cannot set close-on-exec on fd {fd}: {e}"));
+    }
+    // SAFETY: the service manager handed `fd` over to this process, nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener
+        .local_addr()
+        .map_err(|e| format!("fd {fd} passed by systemd is not a TCP socket: {e}"))?;
+    Ok(listener)
+}
+
+#[cfg(all(test, target_os = "linux", feature = "systemd"))]
+mod tests {
+    use super::*;
+    use crate::config::{Profile, Switch};
+    use crate::{Config, ProxyServer};
+    use std::collections::HashMap;
+    use std::os::fd::IntoRawFd;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_passed_fds_follow_the_protocol() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42), Ok(2));
+        assert!(
+            passed_fds(None, None, 42)
+                .unwrap_err()
+                .contains("LISTEN_PID")
+        );
+        // Variables inherited from a parent that was socket-activated itself
+        assert!(passed_fds(Some("41"), Some("2"), 42).is_err());
+        assert!(passed_fds(Some("42"), None, 42).is_err());
+        assert!(passed_fds(Some("42"), Some("0"), 42).is_err());
+    }
+
+    #[test]
+    fn test_only_listening_sockets_are_adopted() {
+        let (one, _other) = std::os::unix::net::UnixStream::pair().unwrap();
+        let err = adopt(one.into_raw_fd()).unwrap_err();
+        assert!(err.contains("not listening"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_adopted_listener_serves_requests() {
+        let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+
+        // Stands in for the socket systemd bound and passed on
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = adopt(inherited.into_raw_fd()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let config = Config::from_parts(
+            Switch::new("direct"),
+            HashMap::from([("direct".to_string(), Profile::direct())]),
+        );
+        let server = Arc::new(
+            ProxyServer::builder(config)
+                .listen_on(listener)
+                .build()
+                .await
+                .unwrap(),
+        );
+        assert_eq!(server.local_addrs(), vec![server_addr]);
+        let runner = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await })
+        };
+
+        let mut client = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+        let request = format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut established = [0u8; 39];
+        client.read_exact(&mut established).await.unwrap();
+        assert!(established.starts_with(b"HTTP/1.1 200"));
+        client.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        client.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+
+        drop(client);
+        server.shutdown();
+        runner.await.unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::OnceLock;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry, reload};
+
+use crate::config::TelemetryOptions;
+
+/// Log level the subscriber starts with
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Filter of the log lines written by the subscriber `init` installed
+static LOG_FILTER: OnceLock<FilterHandle> = OnceLock::new();
+
+/// Keeps the span exporter running; pending spans are flushed when it is dropped
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush exported spans: {e}");
+        }
+    }
+}
+
+/// Install the global log subscriber, exporting spans over OTLP when `options` is set
+///
+/// Without `options` (or without the `otel` feature) no exporter layer is installed, so
+/// spans cost no more than with plain logging.
+pub fn init(options: Option<&TelemetryOptions>) -> Result<TelemetryGuard, String> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(DEFAULT_LOG_LEVEL));
+    let fmt = tracing_subscriber::fmt::layer().with_filter(filter);
+    #[cfg(feature = "otel")]
+    let provider = options.map(otel::tracer_provider).transpose()?;
+    #[cfg(feature = "otel")]
+    let otel = provider.as_ref().map(otel::layer);
+    #[cfg(not(feature = "otel"))]
+    let otel: Option<tracing_subscriber::layer::Identity> = None;
+
+    tracing_subscriber::registry().with(fmt).with(otel).init();
+    let _ = LOG_FILTER.set(handle);
+    #[cfg(not(feature = "otel"))]
+    if options.is_some() {
+        tracing::warn!("Built without the `otel` feature, ignoring the telemetry settings");
+    }
+    Ok(TelemetryGuard {
+        #[cfg(feature = "otel")]
+        provider,
+    })
+}
+
+/// Change which log lines are written, e.g. to `debug` or `info,proxy_twister=trace`
+///
+/// Takes `RUST_LOG`-style directives. Exported spans are not affected. Only works once `init`
+/// installed the log subscriber.
+pub fn set_log_level(directives: &str) -> Result<(), String> {
+    let handle = LOG_FILTER
+        .get()
+        .ok_or("the log subscriber was not installed by proxy-twister")?;
+    reload_filter(handle, directives)
+}
+
+/// Directives currently filtering the log lines, if `init` installed the log subscriber
+pub fn log_level() -> Option<String> {
+    LOG_FILTER
+        .get()
+        .and_then(|handle| handle.with_current(|filter| filter.to_string()).ok())
+}
+
+fn reload_filter(handle: &FilterHandle, directives: &str) -> Result<(), String> {
+    let directives = directives.trim();
+    // A bare word is taken as a target by EnvFilter, so a mistyped level would silence
+    // everything else; only levels and `target=level` directives are accepted
+    if let Some(directive) = directives.split(',').find(|directive| {
+        !directive.contains('=') && directive.trim().parse::<LevelFilter>().is_err()
+    }) {
+        return Err(format!("invalid log level '{directive}'"));
+    }
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|e| format!("invalid log level '{directives}': {e}"))?;
+    handle
+        .reload(filter)
+        .map_err(|e| format!("cannot change the log level: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the events that reach it
+    struct Events(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for Events {
+        fn on_event(
+            &self,
+            _event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_log_level_can_be_changed_at_runtime() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let (filter, handle) = reload::Layer::new(EnvFilter::new(DEFAULT_LOG_LEVEL));
+        let subscriber =
+            tracing_subscriber::registry().with(Events(events.clone()).with_filter(filter));
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+        tracing::trace!("hidden");
+        assert_eq!(events.load(Ordering::Relaxed), 0);
+
+        reload_filter(&handle, "trace").unwrap();
+        tracing::trace!("shown");
+        assert_eq!(events.load(Ordering::Relaxed), 1);
+
+        reload_filter(&handle, "info\n").unwrap();
+        tracing::trace!("hidden again");
+        tracing::info!("shown");
+        assert_eq!(events.load(Ordering::Relaxed), 2);
+        assert_eq!(handle.with_current(|f| f.to_string()).unwrap(), "info");
+
+        for invalid in ["debgu", "info,==", "info,proxy_twister"] {
+            let err = reload_filter(&handle, invalid).unwrap_err();
+            assert!(err.contains("invalid log level"), "{err}");
+        }
+        assert_eq!(handle.with_current(|f| f.to_string()).unwrap(), "info");
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use tracing::Subscriber;
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    use crate::config::TelemetryOptions;
+
+    pub(super) fn tracer_provider(options: &TelemetryOptions) -> Result<SdkTracerProvider, String> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(options.otlp_endpoint.clone())
+            .build()
+            .map_err(|e| format!("cannot export spans to '{}': {e}", options.otlp_endpoint))?;
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(options.service_name.clone())
+                    .build(),
+            )
+            .build())
+    }
+
+    /// Layer turning `info` and higher spans into exported OpenTelemetry spans
+    pub(super) fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S> + use<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("proxy-twister"))
+            .with_filter(LevelFilter::INFO)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use tracing_subscriber::layer::SubscriberExt;
+
+        #[test]
+        fn test_connection_span_reaches_collector() {
+            // Minimal OTLP/HTTP collector answering every export with 200
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let collector = std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+                (request_line, body)
+            });
+
+            let provider = tracer_provider(&TelemetryOptions {
+                otlp_endpoint: format!("http://127.0.0.1:{port}/v1/traces"),
+                service_name: "proxy-twister-test".to_string(),
+            })
+            .unwrap();
+            let subscriber = tracing_subscriber::registry().with(layer(&provider));
+            tracing::subscriber::with_default(subscriber, || {
+                let span =
+                    tracing::info_span!("connection", profile = "tor", target = "example.com");
+                span.in_scope(|| tracing::info!("relaying"));
+            });
+            provider.shutdown().unwrap();
+
+            let (request_line, body) = collector.join().unwrap();
+            assert!(request_line.starts_with("POST /v1/traces "));
+            // The protobuf payload carries the span name and attributes as plain strings
+            let body = String::from_utf8_lossy(&body);
+            assert!(body.contains("connection"));
+            assert!(body.contains("example.com"));
+            assert!(body.contains("proxy-twister-test"));
+        }
+    }
+}
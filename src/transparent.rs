@@ -0,0 +1,84 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Whether transparent mode can be used in this build
+pub const SUPPORTED: bool = cfg!(all(target_os = "linux", feature = "transparent"));
+
+/// Read the pre-NAT destination of a connection redirected with iptables `REDIRECT`
+#[cfg(all(target_os = "linux", feature = "transparent"))]
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::os::fd::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    if stream.local_addr()?.is_ipv4() {
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        // SAFETY: `addr` and `len` describe a valid, writable sockaddr_in buffer
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_IP,
+                libc::SO_ORIGINAL_DST,
+                &mut addr as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+        Ok(SocketAddr::from((ip, u16::from_be(addr.sin_port))))
+    } else {
+        let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        // SAFETY: `addr` and `len` describe a valid, writable sockaddr_in6 buffer.
+        // IP6T_SO_ORIGINAL_DST shares its value with SO_ORIGINAL_DST.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_IPV6,
+                libc::SO_ORIGINAL_DST,
+                &mut addr as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+        Ok(SocketAddr::from((ip, u16::from_be(addr.sin6_port))))
+    }
+}
+
+/// Read the pre-NAT destination of a connection redirected with iptables `REDIRECT`
+#[cfg(not(all(target_os = "linux", feature = "transparent")))]
+pub fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent mode requires Linux and the `transparent` feature",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux", feature = "transparent"))]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_original_destination_without_redirect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (stream, _) = accepted.unwrap();
+
+        // Without an iptables REDIRECT (or without conntrack) there is no original destination,
+        // but the socket option path must report an OS error rather than garbage. Conntrack
+        // reports the listener itself, which the server refuses rather than connect back to.
+        match original_destination(&stream) {
+            Ok(original) => assert_eq!(original, addr),
+            Err(e) => assert!(e.raw_os_error().is_some()),
+        }
+    }
+}
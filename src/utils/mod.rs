@@ -1,9 +1,10 @@
 use regex::Regex;
+use std::net::IpAddr;
 
 /// Convert a simple wildcard pattern (only '*' supported) to a Regex
 ///
 /// If the pattern is in the form "*.domain.com", it will also match "domain.com"
-fn wildcard_to_regex(pattern: &str) -> Regex {
+pub(crate) fn wildcard_to_regex(pattern: &str) -> Regex {
     if let Some(domain) = pattern.strip_prefix("*.") {
         // For patterns like "*.example.com", also match "example.com"
         let regex_string = format!("^(.*\\.)?{}$", regex::escape(domain));
@@ -15,16 +16,229 @@ fn wildcard_to_regex(pattern: &str) -> Regex {
     }
 }
 
+/// Patterns standing for a kind of host rather than for names
+pub(crate) const HOST_TOKENS: [&str; 5] = ["$ip", "$host", "$loopback", "$private", "$public"];
+
 /// Check if a hostname matches a wildcard pattern
+///
+/// The special patterns `$ip` and `$host` match any IP literal and any non-IP name respectively.
+/// `$loopback`, `$private` (RFC 1918, link-local and unique local addresses) and `$public`
+/// (any other unicast address) match IP literals by range; names are never resolved for them,
+/// so `localhost` or a name pointing into the LAN matches none of them.
 pub fn matches_pattern(host: &str, pattern: &str) -> bool {
-    let re = wildcard_to_regex(pattern);
-    re.is_match(host)
+    match pattern {
+        "$ip" => ip_literal(host).is_some(),
+        "$host" => ip_literal(host).is_none(),
+        "$loopback" => ip_literal(host).is_some_and(|ip| ip.is_loopback()),
+        "$private" => ip_literal(host).is_some_and(is_private),
+        "$public" => ip_literal(host).is_some_and(is_public),
+        _ => wildcard_to_regex(pattern).is_match(host),
+    }
+}
+
+/// The address `host` is a literal of (brackets allowed), if it is not a name
+///
+/// IPv4-mapped IPv6 addresses are returned as the IPv4 address they carry.
+fn ip_literal(host: &str) -> Option<IpAddr> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    host.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+/// Addresses of the local network: RFC 1918, link-local and IPv6 unique local
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/// Unicast addresses reachable beyond the local network
+fn is_public(ip: IpAddr) -> bool {
+    let special = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    !(special || ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || is_private(ip))
+}
+
+/// Split an authority like `host`, `host:port`, `[v6]` or `[v6]:port` into host and port
+///
+/// IPv6 literals are returned without brackets. A bare IPv6 literal without brackets is
+/// treated as a host with no port. Unparsable ports are reported as `None`.
+pub fn split_host_port(authority: &str) -> (String, Option<u16>) {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((host, tail)) => {
+                let port = tail.strip_prefix(':').and_then(|p| p.parse().ok());
+                (host.to_string(), port)
+            }
+            None => (authority.to_string(), None),
+        };
+    }
+
+    match authority.rsplit_once(':') {
+        // More than one colon without brackets can only be a bare IPv6 literal
+        Some((host, _)) if host.contains(':') => (authority.to_string(), None),
+        Some((host, port)) => (host.to_string(), port.parse().ok()),
+        None => (authority.to_string(), None),
+    }
+}
+
+/// Host and port of an authority as clients send it, in a Host header or a request target
+///
+/// Userinfo (`user@`) is dropped, as is anything from a path, query or fragment on. What
+/// remains must be an IP address or a hostname, with a valid port if it has one.
+pub fn parse_authority(authority: &str) -> Result<(String, Option<u16>), String> {
+    let end = authority.find(['/', '?', '#']).unwrap_or(authority.len());
+    let authority = &authority[..end];
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let (host, port) = split_host_port(authority);
+    let (bracketed, port_given) = match authority.strip_prefix('[') {
+        Some(rest) => (
+            true,
+            rest.split_once(']')
+                .is_some_and(|(_, tail)| !tail.is_empty()),
+        ),
+        None => (false, authority.matches(':').count() == 1),
+    };
+    if port_given && port.is_none() {
+        return Err(format!("'{authority}' does not end in a valid port"));
+    }
+    let valid = if bracketed {
+        host.parse::<std::net::Ipv6Addr>().is_ok()
+    } else {
+        host.parse::<IpAddr>().is_ok() || is_hostname(&host)
+    };
+    if !valid {
+        return Err(format!("'{host}' is not a valid hostname or IP address"));
+    }
+    Ok((host, port))
+}
+
+/// Whether `host` is made of DNS labels (underscores allowed), with an optional trailing dot
+fn is_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// Join a host and port into an authority, bracketing IPv6 literals
+pub fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Escape `text` for use inside HTML elements and quoted attributes
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `items` in random order, each equally likely to lead as its share of the total weight
+///
+/// Items of weight 0 follow the others, in the order they were given.
+pub fn weighted_shuffle<T>(items: impl IntoIterator<Item = (T, u64)>) -> Vec<T> {
+    use rand::Rng;
+
+    let (mut weighted, unweighted): (Vec<_>, Vec<_>) =
+        items.into_iter().partition(|(_, weight)| *weight > 0);
+    let mut rng = rand::rng();
+    let mut order = Vec::with_capacity(weighted.len() + unweighted.len());
+    while !weighted.is_empty() {
+        let total: u64 = weighted.iter().map(|(_, weight)| weight).sum();
+        let mut pick = rng.random_range(0..total);
+        let index = weighted
+            .iter()
+            .position(|(_, weight)| match pick.checked_sub(*weight) {
+                Some(rest) => {
+                    pick = rest;
+                    false
+                }
+                None => true,
+            })
+            .unwrap_or(0);
+        order.push(weighted.remove(index).0);
+    }
+    order.extend(unweighted.into_iter().map(|(item, _)| item));
+    order
+}
+
+/// Largest number of ports a single `--listen` range may expand to
+const MAX_PORT_RANGE: u32 = 1024;
+
+/// Expand a listen address with a port range (`127.0.0.1:1080-1090`) into one address per port
+///
+/// Addresses without a range are returned unchanged.
+pub fn expand_listen_address(addr: &str) -> Result<Vec<String>, String> {
+    let Some((host, ports)) = addr.rsplit_once(':') else {
+        return Ok(vec![addr.to_string()]);
+    };
+    let Some((start, end)) = ports.split_once('-') else {
+        return Ok(vec![addr.to_string()]);
+    };
+    let parse = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("invalid port '{port}' in listen address '{addr}'"))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start > end {
+        return Err(format!(
+            "inverted port range {start}-{end} in listen address '{addr}'"
+        ));
+    }
+    if u32::from(end - start) + 1 > MAX_PORT_RANGE {
+        return Err(format!(
+            "port range {start}-{end} in listen address '{addr}' exceeds {MAX_PORT_RANGE} ports"
+        ));
+    }
+    Ok((start..=end).map(|port| format!("{host}:{port}")).collect())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_weighted_shuffle() {
+        let mut heavy_leads = 0;
+        for _ in 0..1000 {
+            let order = weighted_shuffle([("light", 1), ("none", 0), ("heavy", 99), ("zero", 0)]);
+            assert_eq!(order.len(), 4);
+            assert_eq!(order[2..], ["none", "zero"]);
+            if order[0] == "heavy" {
+                heavy_leads += 1;
+            }
+        }
+        assert!(heavy_leads > 900, "{heavy_leads}");
+    }
+
     #[test]
     fn test_wildcard_matching() {
         assert!(matches_pattern("abc.discord.gg", "*.discord.gg"));
@@ -40,4 +254,137 @@ mod tests {
         assert!(matches_pattern("test.wildcard.match", "test.*.match"));
         assert!(!matches_pattern("wrong.wildcard.com", "test.*.com"));
     }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("example.com:8080"),
+            ("example.com".to_string(), Some(8080))
+        );
+        assert_eq!(
+            split_host_port("example.com"),
+            ("example.com".to_string(), None)
+        );
+
+        // IPv6 literals keep all their colons and lose the brackets
+        assert_eq!(
+            split_host_port("[2001:db8::1]:8080"),
+            ("2001:db8::1".to_string(), Some(8080))
+        );
+        assert_eq!(split_host_port("[::1]"), ("::1".to_string(), None));
+        assert_eq!(split_host_port("::1"), ("::1".to_string(), None));
+    }
+
+    #[test]
+    fn test_parse_authority_strips_userinfo_and_path() {
+        assert_eq!(
+            parse_authority("user:secret@example.com:8080"),
+            Ok(("example.com".to_string(), Some(8080)))
+        );
+        assert_eq!(
+            parse_authority("example.com:8080/path?query#fragment"),
+            Ok(("example.com".to_string(), Some(8080)))
+        );
+        assert_eq!(
+            parse_authority("user@[2001:db8::1]:443/"),
+            Ok(("2001:db8::1".to_string(), Some(443)))
+        );
+        assert_eq!(
+            parse_authority("example.com?x=1"),
+            Ok(("example.com".to_string(), None))
+        );
+
+        for authority in [
+            "exa mple.com",
+            "example.com:http",
+            "-example.com",
+            "[example.com]:443",
+            "@:80",
+            "user@",
+        ] {
+            assert!(parse_authority(authority).is_err(), "{authority}");
+        }
+    }
+
+    #[test]
+    fn test_ip_and_host_tokens() {
+        assert!(matches_pattern("192.0.2.1", "$ip"));
+        assert!(matches_pattern("2001:db8::1", "$ip"));
+        assert!(matches_pattern("[::1]", "$ip"));
+        assert!(!matches_pattern("example.com", "$ip"));
+        // Looks numeric, but is not an address
+        assert!(!matches_pattern("1.2.3.example", "$ip"));
+
+        assert!(matches_pattern("example.com", "$host"));
+        assert!(matches_pattern("localhost", "$host"));
+        assert!(!matches_pattern("192.0.2.1", "$host"));
+
+        // Plain wildcards still match both kinds
+        assert!(matches_pattern("192.0.2.1", "*"));
+        assert!(matches_pattern("example.com", "*"));
+    }
+
+    #[test]
+    fn test_address_range_tokens() {
+        for host in [
+            "10.1.2.3",
+            "192.168.0.10",
+            "172.16.5.4",
+            "169.254.1.1",
+            "fd12::1",
+        ] {
+            assert!(matches_pattern(host, "$private"), "{host}");
+            assert!(!matches_pattern(host, "$public"), "{host}");
+            assert!(!matches_pattern(host, "$loopback"), "{host}");
+        }
+        assert!(matches_pattern("[fe80::1]", "$private"));
+        assert!(matches_pattern("::ffff:192.168.1.1", "$private"));
+        // Just outside 172.16.0.0/12
+        assert!(matches_pattern("172.32.0.1", "$public"));
+
+        for host in ["127.0.0.1", "127.8.8.8", "::1", "[::1]"] {
+            assert!(matches_pattern(host, "$loopback"), "{host}");
+            assert!(!matches_pattern(host, "$private"), "{host}");
+            assert!(!matches_pattern(host, "$public"), "{host}");
+        }
+
+        for host in ["8.8.8.8", "1.1.1.1", "2606:4700::1111"] {
+            assert!(matches_pattern(host, "$public"), "{host}");
+            assert!(!matches_pattern(host, "$private"), "{host}");
+        }
+        for host in ["0.0.0.0", "255.255.255.255", "224.0.0.1", "ff02::1"] {
+            assert!(!matches_pattern(host, "$public"), "{host}");
+        }
+
+        // Names are never resolved for these
+        for pattern in ["$loopback", "$private", "$public"] {
+            assert!(!matches_pattern("localhost", pattern));
+            assert!(!matches_pattern("example.com", pattern));
+        }
+    }
+
+    #[test]
+    fn test_join_host_port() {
+        assert_eq!(join_host_port("example.com", 80), "example.com:80");
+        assert_eq!(join_host_port("2001:db8::1", 443), "[2001:db8::1]:443");
+    }
+
+    #[test]
+    fn test_expand_listen_address() {
+        assert_eq!(
+            expand_listen_address("127.0.0.1:1080-1082").unwrap(),
+            ["127.0.0.1:1080", "127.0.0.1:1081", "127.0.0.1:1082"]
+        );
+        assert_eq!(
+            expand_listen_address("[::1]:8080-8081").unwrap(),
+            ["[::1]:8080", "[::1]:8081"]
+        );
+        assert_eq!(
+            expand_listen_address("127.0.0.1:1080").unwrap(),
+            ["127.0.0.1:1080"]
+        );
+        assert!(expand_listen_address("127.0.0.1:1090-1080").is_err());
+        assert!(expand_listen_address("127.0.0.1:1-65535").is_err());
+        assert!(expand_listen_address("127.0.0.1:1080-x").is_err());
+    }
 }
@@ -0,0 +1,208 @@
+//! Byte-for-byte integrity of bodies relayed through every routing mode
+//!
+//! The origin serves a fixed payload, so the SHA256 of what arrives at the client can be
+//! compared with a known digest instead of only checking the length.
+
+use axum::Router;
+use axum::body::{Body, Bytes};
+use axum::routing::{get, post};
+use proxy_twister::config::{Profile, Switch};
+use proxy_twister::{Config, ProxyServer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const PAYLOAD_SIZE: usize = 1 << 20;
+
+/// SHA256 of `payload()`
+const PAYLOAD_SHA256: &str = "631b84027d6b9e52b539c4e8373622d23032dfadc64d60af87339c9037e4f769";
+
+/// 1 MiB whose byte pattern (period 251) never lines up with buffer sizes, so a dropped,
+/// duplicated or reordered chunk changes the digest
+fn payload() -> Vec<u8> {
+    (0..PAYLOAD_SIZE).map(|i| (i % 251) as u8).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Origin serving the payload with a Content-Length (`/fixed`), chunked (`/chunked`), and
+/// echoing request bodies back (`/echo`)
+async fn spawn_origin() -> u16 {
+    let app = Router::new()
+        .route("/fixed", get(|| async { payload() }))
+        .route(
+            "/chunked",
+            get(|| async {
+                let chunks = payload()
+                    .chunks(4093)
+                    .map(|chunk| Ok::<_, Infallible>(Bytes::copy_from_slice(chunk)))
+                    .collect::<Vec<_>>();
+                Body::from_stream(futures::stream::iter(chunks))
+            }),
+        )
+        .route("/echo", post(|body: Bytes| async move { body }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    port
+}
+
+/// Upstream HTTP proxy that hands every connection to the origin: CONNECT is answered and
+/// tunnelled, anything else is passed on verbatim (the origin accepts absolute-form targets)
+async fn spawn_http_proxy(origin_port: u16) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if client.read(&mut byte).await.unwrap() == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                let mut origin = TcpStream::connect(("127.0.0.1", origin_port))
+                    .await
+                    .unwrap();
+                if head.starts_with(b"CONNECT ") {
+                    client
+                        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                        .await
+                        .unwrap();
+                } else {
+                    origin.write_all(&head).await.unwrap();
+                }
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut origin).await;
+            });
+        }
+    });
+    port
+}
+
+/// Upstream SOCKS5 proxy without authentication that connects every request to the origin
+async fn spawn_socks5_proxy(origin_port: u16) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0u8; greeting[1] as usize];
+                client.read_exact(&mut methods).await.unwrap();
+                client.write_all(&[0x05, 0x00]).await.unwrap();
+
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await.unwrap();
+                let address_len = match request[3] {
+                    0x01 => 4,
+                    0x04 => 16,
+                    _ => client.read_u8().await.unwrap() as usize,
+                };
+                let mut address = vec![0u8; address_len + 2];
+                client.read_exact(&mut address).await.unwrap();
+
+                let mut origin = TcpStream::connect(("127.0.0.1", origin_port))
+                    .await
+                    .unwrap();
+                client
+                    .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+                    .await
+                    .unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut origin).await;
+            });
+        }
+    });
+    port
+}
+
+/// Start proxy-twister routing everything through `profile`, returning a client that uses it
+async fn proxied_client(profile: Profile) -> (Arc<ProxyServer>, reqwest::Client) {
+    let config = Config::from_parts(
+        Switch::new("only"),
+        HashMap::from([("only".to_string(), profile)]),
+    );
+    let server = Arc::new(
+        ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .build()
+            .await
+            .unwrap(),
+    );
+    let proxy_url = format!("http://{}", server.local_addrs()[0]);
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await });
+    }
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_url).unwrap())
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap();
+    (server, client)
+}
+
+/// Download both payload variants and round-trip an upload through `profile`
+async fn assert_bodies_intact(profile: Profile, origin_port: u16) {
+    let (server, client) = proxied_client(profile).await;
+    let origin = format!("http://127.0.0.1:{origin_port}");
+
+    // Twice over the same client, so that reused keep-alive connections are covered too
+    for _ in 0..2 {
+        for path in ["/fixed", "/chunked"] {
+            let response = client.get(format!("{origin}{path}")).send().await.unwrap();
+            assert_eq!(response.status(), 200, "{path}");
+            let body = response.bytes().await.unwrap();
+            assert_eq!(body.len(), PAYLOAD_SIZE, "{path}");
+            assert_eq!(sha256_hex(&body), PAYLOAD_SHA256, "{path}");
+        }
+
+        let response = client
+            .post(format!("{origin}/echo"))
+            .header("Content-Type", "application/octet-stream")
+            .body(payload())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.bytes().await.unwrap();
+        assert_eq!(sha256_hex(&body), PAYLOAD_SHA256, "/echo");
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn test_payload_matches_known_digest() {
+    assert_eq!(sha256_hex(&payload()), PAYLOAD_SHA256);
+}
+
+#[tokio::test]
+async fn test_direct_bodies_are_intact() {
+    let origin_port = spawn_origin().await;
+    assert_bodies_intact(Profile::direct(), origin_port).await;
+}
+
+#[tokio::test]
+async fn test_http_proxy_bodies_are_intact() {
+    let origin_port = spawn_origin().await;
+    let proxy_port = spawn_http_proxy(origin_port).await;
+    assert_bodies_intact(Profile::http("127.0.0.1", proxy_port), origin_port).await;
+}
+
+#[tokio::test]
+async fn test_socks5_bodies_are_intact() {
+    let origin_port = spawn_origin().await;
+    let proxy_port = spawn_socks5_proxy(origin_port).await;
+    assert_bodies_intact(Profile::socks5("127.0.0.1", proxy_port), origin_port).await;
+}
@@ -0,0 +1,364 @@
+use proxy_twister::config::{
+    FlowExportOptions, FlowField, Profile, ReadinessOptions, Rule, Switch,
+};
+use proxy_twister::{Config, ProxyServer};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Upstream HTTP proxy that refuses every CONNECT, yielding the first request line it received
+///
+/// Connections closed without a request, like the startup probe of loopback upstreams, are
+/// skipped.
+async fn spawn_refusing_proxy() -> (u16, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                if n == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+            if head.is_empty() {
+                continue;
+            }
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+            let head = String::from_utf8_lossy(&head).to_string();
+            break head.lines().next().unwrap_or_default().to_string();
+        }
+    });
+    (port, handle)
+}
+
+/// Origin that echoes whatever it receives on its first connection
+async fn spawn_echo_origin() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        let _ = tokio::io::copy(&mut r, &mut w).await;
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_library_routes_by_pattern() {
+    let origin_port = spawn_echo_origin().await;
+    let (proxy_port, proxy_request) = spawn_refusing_proxy().await;
+    let switch = Switch::new("direct").rule(Rule::new("*.proxied.test", "upstream"));
+    let profiles = HashMap::from([
+        ("direct".to_string(), Profile::direct()),
+        (
+            "upstream".to_string(),
+            Profile::http("127.0.0.1", proxy_port),
+        ),
+    ]);
+    let config = Config::from_parts(switch, profiles);
+
+    let server = Arc::new(
+        ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .build()
+            .await
+            .unwrap(),
+    );
+    let local_addrs = server.local_addrs();
+    assert_eq!(local_addrs.len(), 1);
+    let server_addr = local_addrs[0];
+    let runner = {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    };
+
+    // Unmatched hosts go direct, through a working tunnel
+    let mut client = TcpStream::connect(server_addr).await.unwrap();
+    client
+        .write_all(format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut established = [0u8; 39];
+    client.read_exact(&mut established).await.unwrap();
+    assert!(established.starts_with(b"HTTP/1.1 200"));
+    client.write_all(b"hello").await.unwrap();
+    let mut echoed = [0u8; 5];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+    drop(client);
+
+    // Matching hosts are handed to the upstream proxy
+    let mut client = TcpStream::connect(server_addr).await.unwrap();
+    client
+        .write_all(b"CONNECT app.proxied.test:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 500"));
+    assert_eq!(
+        proxy_request.await.unwrap(),
+        "CONNECT app.proxied.test:443 HTTP/1.1"
+    );
+
+    server.shutdown();
+    runner.await.unwrap();
+    assert_eq!(server.metrics().profile("direct").successes, 1);
+    assert_eq!(server.metrics().profile("upstream").failures, 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_listen_addresses_from_config_are_bound() {
+    let mut config = Config::from_parts(
+        Switch::new("direct"),
+        HashMap::from([("direct".to_string(), Profile::direct())]),
+    );
+    config.listen = vec!["127.0.0.1:0".to_string()];
+    let server = ProxyServer::builder(config).build().await.unwrap();
+    let addrs = server.local_addrs();
+    assert_eq!(addrs.len(), 1);
+    assert!(addrs[0].ip().is_loopback() && addrs[0].port() != 0);
+    assert!(TcpStream::connect(addrs[0]).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_admin_endpoint_on_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let socket =
+        std::env::temp_dir().join(format!("proxy-twister-admin-{}.sock", std::process::id()));
+    let config = Config::from_parts(
+        Switch::new("direct"),
+        HashMap::from([("direct".to_string(), Profile::direct())]),
+    );
+    let server = Arc::new(
+        ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .admin(format!("unix:{}", socket.display()))
+            .build()
+            .await
+            .unwrap(),
+    );
+    let runner = {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    };
+
+    // The admin endpoint binds once the server runs
+    let mut client = loop {
+        match tokio::net::UnixStream::connect(&socket).await {
+            Ok(client) => break client,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // The private directory it was bound in is gone
+    let name = socket.file_name().unwrap().to_string_lossy();
+    let staging = socket.with_file_name(format!(".{name}.{}", std::process::id()));
+    assert!(!staging.exists());
+    client
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: admin\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    server.shutdown();
+    runner.await.unwrap();
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn test_invalid_reload_blocks_new_connections() {
+    let path =
+        std::env::temp_dir().join(format!("proxy-twister-block-{}.json", std::process::id()));
+    let valid = r#"{
+        switch: { default: "direct", rules: [] },
+        profiles: { direct: { scheme: "direct" } },
+        reloadFailureMode: "block",
+    }"#;
+    std::fs::write(&path, valid).unwrap();
+    let config = Config::load(&path.to_string_lossy()).unwrap();
+    let server = Arc::new(
+        ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .watch_config(&path)
+            .build()
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addrs()[0];
+    let runner = {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    };
+    let status = || async {
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        client
+            .write_all(b"GET http://127.0.0.1:1/ HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response).await;
+        response.get(9..12).unwrap_or_default().to_string()
+    };
+    // Give the watcher time to start watching
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // A half-edited file fails to parse
+    std::fs::write(&path, "{ switch: { default: ").unwrap();
+    let blocked = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        while status().await != "503" {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(
+        blocked.is_ok(),
+        "invalid config did not block new connections"
+    );
+
+    // Restoring a valid config lets connections through again
+    std::fs::write(&path, valid).unwrap();
+    let unblocked = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+        while status().await == "503" {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    std::fs::remove_file(&path).unwrap();
+    assert!(
+        unblocked.is_ok(),
+        "valid config did not unblock new connections"
+    );
+
+    server.shutdown();
+    runner.await.unwrap();
+}
+
+/// Status code of a GET of `path` on the admin endpoint at `addr`, once it accepts connections
+async fn admin_status(addr: std::net::SocketAddr, path: &str) -> u16 {
+    let mut client = loop {
+        match TcpStream::connect(addr).await {
+            Ok(client) => break client,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    client
+        .write_all(
+            format!("GET {path} HTTP/1.1\r\nHost: admin\r\nConnection: close\r\n\r\n").as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    response[9..12].parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_readiness_follows_upstream_health() {
+    // Ports nothing listens on, for now
+    let free_port = || async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    let upstream_addr = free_port().await;
+    let admin_addr = free_port().await;
+
+    let mut config = Config::from_parts(
+        Switch::new("upstream"),
+        HashMap::from([(
+            "upstream".to_string(),
+            Profile::http("127.0.0.1", upstream_addr.port()),
+        )]),
+    );
+    config.readiness = Some(ReadinessOptions {
+        probe_profiles: vec!["upstream".to_string()],
+        probe_timeout_ms: 500,
+    });
+    let server = Arc::new(
+        ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .admin(admin_addr.to_string())
+            .build()
+            .await
+            .unwrap(),
+    );
+    let runner = {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    };
+
+    assert_eq!(admin_status(admin_addr, "/healthz").await, 200);
+    assert_eq!(admin_status(admin_addr, "/readyz").await, 503);
+
+    // The upstream comes up
+    let _upstream = TcpListener::bind(upstream_addr).await.unwrap();
+    assert_eq!(admin_status(admin_addr, "/readyz").await, 200);
+
+    server.shutdown();
+    runner.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_flushes_records_of_open_connections() {
+    let origin_port = spawn_echo_origin().await;
+    // Checked without waiting, once the server has stopped
+    let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_nonblocking(true).unwrap();
+    let mut config = Config::from_parts(
+        Switch::new("direct"),
+        HashMap::from([("direct".to_string(), Profile::direct())]),
+    );
+    config.flow_export = Some(FlowExportOptions {
+        collector: collector.local_addr().unwrap().to_string(),
+        fields: vec![FlowField::BytesUp],
+        observation_domain_id: 0,
+    });
+    let server = Arc::new(
+        ProxyServer::builder(config)
+            .listen("127.0.0.1:0")
+            .build()
+            .await
+            .unwrap(),
+    );
+    let server_addr = server.local_addrs()[0];
+    let runner = {
+        let server = server.clone();
+        tokio::spawn(async move { server.run().await })
+    };
+
+    // A tunnel still open when shutdown starts
+    let mut client = TcpStream::connect(server_addr).await.unwrap();
+    client
+        .write_all(format!("CONNECT 127.0.0.1:{origin_port} HTTP/1.1\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut established = [0u8; 39];
+    client.read_exact(&mut established).await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let mut echoed = [0u8; 5];
+    client.read_exact(&mut echoed).await.unwrap();
+
+    server.shutdown();
+    runner.await.unwrap();
+    // The connection was closed, and its record sent before `run` returned
+    let mut rest = Vec::new();
+    assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+    let mut message = [0u8; 256];
+    let (len, _) = collector.recv_from(&mut message).unwrap();
+    assert_eq!(&message[len - 8..len], &5u64.to_be_bytes());
+}